@@ -0,0 +1,61 @@
+use std::hint::black_box;
+
+use confidante::{AnyStreamParser, ElementLimits, Frame, ParserConfig, ParserKind};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use tokio_stream::StreamExt;
+
+const STREAM_HEADER: &str = "<stream:stream xmlns='jabber:client' \
+    xmlns:stream='http://etherx.jabber.org/streams' to='localhost' version='1.0'>";
+
+const MESSAGES: usize = 20_000;
+
+fn stanza_heavy_stream() -> String {
+    let mut input = STREAM_HEADER.to_string();
+    for i in 0..MESSAGES {
+        input.push_str(&format!(
+            "<message to='romeo@example.net' from='juliet@example.com' id='{i}' type='chat'>\
+            <body>Wherefore art thou, Romeo? &amp; more</body>\
+            <active xmlns='http://jabber.org/protocol/chatstates'/></message>"
+        ));
+    }
+    input.push_str("</stream:stream>");
+
+    input
+}
+
+async fn parse(kind: ParserKind, input: &[u8]) -> usize {
+    let config = ParserConfig {
+        kind,
+        limits: ElementLimits::default(),
+    };
+    let mut parser = AnyStreamParser::new(config, input);
+
+    let mut fragments = 0;
+    while let Some(frame) = parser.next().await {
+        if let Frame::XmlFragment(element) = frame.unwrap() {
+            black_box(element);
+            fragments += 1;
+        }
+    }
+
+    fragments
+}
+
+fn stanza_heavy_workload(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let input = stanza_heavy_stream();
+
+    let mut group = c.benchmark_group("stanza_heavy_workload");
+    group.throughput(Throughput::Bytes(input.len() as u64));
+    for kind in [ParserKind::RustyXml, ParserKind::QuickXml] {
+        assert_eq!(runtime.block_on(parse(kind, input.as_bytes())), MESSAGES);
+        group.bench_function(format!("{kind:?}"), |b| {
+            b.to_async(&runtime)
+                .iter(|| parse(kind, black_box(input.as_bytes())))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, stanza_heavy_workload);
+criterion_main!(benches);
@@ -0,0 +1,2 @@
+DROP TABLE roster_groups;
+DROP TABLE roster;
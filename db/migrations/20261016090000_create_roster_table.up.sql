@@ -0,0 +1,14 @@
+CREATE TABLE roster (
+    id INTEGER PRIMARY KEY,
+    owner VARCHAR(255) NOT NULL REFERENCES users (bare_jid) ON DELETE CASCADE,
+    contact VARCHAR(255) NOT NULL,
+    name VARCHAR(255),
+    subscription VARCHAR(4) NOT NULL DEFAULT 'none',
+    UNIQUE (owner, contact)
+);
+
+CREATE TABLE roster_groups (
+    roster_id INTEGER NOT NULL REFERENCES roster (id) ON DELETE CASCADE,
+    name VARCHAR(255) NOT NULL,
+    PRIMARY KEY (roster_id, name)
+)
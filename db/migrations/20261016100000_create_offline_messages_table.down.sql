@@ -0,0 +1 @@
+DROP TABLE offline_messages;
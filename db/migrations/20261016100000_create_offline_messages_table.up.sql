@@ -0,0 +1,8 @@
+CREATE TABLE offline_messages (
+    id INTEGER PRIMARY KEY,
+    recipient VARCHAR(255) NOT NULL REFERENCES users (bare_jid) ON DELETE CASCADE,
+    stanza TEXT NOT NULL,
+    stored_at INTEGER NOT NULL
+);
+
+CREATE INDEX offline_messages_recipient ON offline_messages (recipient)
@@ -0,0 +1,4 @@
+CREATE TABLE roster_versions (
+    owner VARCHAR(255) PRIMARY KEY REFERENCES users (bare_jid) ON DELETE CASCADE,
+    version INTEGER NOT NULL
+)
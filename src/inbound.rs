@@ -1,20 +1,33 @@
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, bail, Error};
 use tokio::select;
 use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::watch;
 use tokio_stream::StreamExt;
+use tracing::{debug, info, instrument, trace, warn, Span};
 
+use crate::services::metrics;
+use crate::services::router::CloseReason;
+use crate::services::router::DeliveryOutcome;
 use crate::services::router::ManagementCommand;
+use crate::services::router::Registration;
 use crate::services::router::RouterHandle;
 use crate::services::store::StoreHandle;
+use crate::settings::HeaderFromPolicy;
+use crate::utils::rate_limiter::TokenBucket;
 use crate::xml::namespaces;
 use crate::xmpp::jid::Jid;
 use crate::xmpp::stanza::Stanza;
+use crate::xmpp::stanza_error::StanzaError;
+use crate::xmpp::stream::ChannelBinding;
 use crate::xmpp::stream::Connection;
 use crate::xmpp::stream::StreamId;
 use crate::xmpp::stream::XmppStream;
+use crate::xmpp::stream_error::StreamError;
 use crate::xmpp::stream_header::LanguageTag;
 use crate::xmpp::stream_header::StreamHeader;
 use crate::{
@@ -22,51 +35,229 @@ use crate::{
     xml::{stream_parser::Frame, Element, Node},
 };
 
+use self::iq::IqContext;
+use self::ping::{ClientPing, PingAction};
+use self::register::RegistrationNegotiator;
 use self::sasl::SaslNegotiator;
 use bind::ResourceBindingNegotiator;
-use starttls::StarttlsNegotiator;
+use starttls::{StarttlsFailure, StarttlsNegotiator};
+use stream_management::{
+    Negotiated, ResumableSessions, ResumableState, StreamManagement, StreamManagementNegotiator,
+    Takeover,
+};
 
+pub use self::iq::IqHandlers;
 pub use self::sasl::StoredPasswordArgon2;
 pub use self::sasl::StoredPasswordScram;
+pub use self::sasl::{SaslMechanisms, SecurityContext};
 pub use self::sasl::{StoredPassword, StoredPasswordKind};
 
 mod bind;
 pub mod connection;
+mod disco;
+mod iq;
+mod ping;
+mod register;
+mod roster;
 mod sasl;
 mod starttls;
+mod stream_management;
+mod time;
+mod version;
 
 const STANZA_CHANNEL_BUFFER_SIZE: usize = 8;
 
 enum ConnectionType {
     Client,
     Server,
+    Component,
+}
+
+impl ConnectionType {
+    // The peer says what it is through the content namespace of its stream header.
+    fn from_namespace(namespace: Option<&str>) -> Option<Self> {
+        match namespace? {
+            namespaces::XMPP_CLIENT => Some(ConnectionType::Client),
+            namespaces::XMPP_SERVER => Some(ConnectionType::Server),
+            namespaces::XMPP_COMPONENT_ACCEPT => Some(ConnectionType::Component),
+            _ => None,
+        }
+    }
+
+    fn namespace(&self) -> &'static str {
+        match self {
+            ConnectionType::Client => namespaces::XMPP_CLIENT,
+            ConnectionType::Server => namespaces::XMPP_SERVER,
+            ConnectionType::Component => namespaces::XMPP_COMPONENT_ACCEPT,
+        }
+    }
 }
 
-#[derive(Debug, Hash, Eq, PartialEq)]
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
 enum StreamFeatures {
     Tls,
     Authentication,
+    Registration,
     ResourceBinding,
+    StreamManagement,
+}
+
+// The features advertisement only depends on the key, so it can be shared across connections.
+// Anything specific to a single connection must not be added to a cached advertisement.
+#[derive(Hash, Eq, PartialEq)]
+struct FeaturesCacheKey {
+    features: Vec<StreamFeatures>,
+    secure: bool,
+    authenticated: bool,
+    channel_binding: Option<&'static str>,
+}
+
+impl FeaturesCacheKey {
+    // Always read from the stream, since STARTTLS replaces the connection underneath it.
+    fn for_stream<C: Connection>(features: Vec<StreamFeatures>, stream: &XmppStream<C>) -> Self {
+        FeaturesCacheKey {
+            features,
+            secure: stream.is_secure(),
+            authenticated: stream.is_authenticated(),
+            channel_binding: stream.channel_binding().map(ChannelBinding::cb_name),
+        }
+    }
+}
+
+static FEATURES_CACHE: OnceLock<Mutex<HashMap<FeaturesCacheKey, Arc<str>>>> = OnceLock::new();
+
+static GLOBAL_RATE_LIMITER: OnceLock<Mutex<TokenBucket>> = OnceLock::new();
+
+static RESUMABLE_SESSIONS: OnceLock<ResumableSessions> = OnceLock::new();
+
+// The connection ended without the peer closing its stream first (RFC 6120, section 4.4), so
+// there is nobody left to send a stream error to. A resumable session is kept for the client.
+#[derive(thiserror::Error, Debug)]
+#[error("connection closed without closing the stream")]
+struct ConnectionLost;
+
+// Limits how many elements a peer may send without completing a negotiation step.
+struct ElementBudget {
+    limit: usize,
+    spent: usize,
+}
+
+impl ElementBudget {
+    fn new(limit: usize) -> Self {
+        ElementBudget { limit, spent: 0 }
+    }
+
+    fn spend(&mut self) -> Result<(), StreamError> {
+        self.spent += 1;
+        if self.spent > self.limit {
+            return Err(StreamError::PolicyViolation);
+        }
+
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.spent = 0;
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum KeepaliveAction {
+    Ping,
+    Disconnect,
+    Wait,
+}
+
+// Pings keep NAT mappings alive while the idle timeout looks at the traffic on the stream, so a
+// quiet peer is kept as long as anything (even whitespace) arrives, or a stanza is delivered to
+// it, within the timeout. Anything written to the peer does as well as a ping, so pings are only
+// sent on streams that are quiet in both directions.
+struct Keepalive {
+    ping_interval: Duration,
+    idle_timeout: Duration,
+    last_ping: Instant,
+    last_activity: Instant,
+    bytes_read: u64,
+    bytes_written: u64,
+}
+
+impl Keepalive {
+    fn new(ping_interval: Duration, idle_timeout: Duration, now: Instant) -> Self {
+        Keepalive {
+            ping_interval,
+            idle_timeout,
+            last_ping: now,
+            last_activity: now,
+            bytes_read: 0,
+            bytes_written: 0,
+        }
+    }
+
+    fn deadline(&self) -> Instant {
+        (self.last_ping + self.ping_interval).min(self.last_activity + self.idle_timeout)
+    }
+
+    fn record_activity(&mut self, now: Instant) {
+        self.last_activity = now;
+    }
+
+    // The ping itself must not count as output, or the next one would be put off.
+    fn ping_sent(&mut self, bytes_written: u64) {
+        self.bytes_written = bytes_written;
+    }
+
+    fn poll(&mut self, bytes_read: u64, bytes_written: u64, now: Instant) -> KeepaliveAction {
+        if bytes_read != self.bytes_read {
+            self.bytes_read = bytes_read;
+            self.last_activity = now;
+        }
+        if bytes_written != self.bytes_written {
+            self.bytes_written = bytes_written;
+            self.last_ping = now;
+        }
+
+        if now >= self.last_activity + self.idle_timeout {
+            return KeepaliveAction::Disconnect;
+        }
+
+        if now >= self.last_ping + self.ping_interval {
+            self.last_ping = now;
+            return KeepaliveAction::Ping;
+        }
+
+        KeepaliveAction::Wait
+    }
 }
 
 struct StreamInfo {
     stream_id: StreamId,
+    // the hosted domain the peer asked for, see `check_header_to`
+    domain: Jid,
     jid: Option<Jid>,
     peer_jid: Option<Jid>,
+    peer_header_from: Option<Jid>,
+    // only servers have to send one, e.g. for dialback
+    peer_stream_id: Option<StreamId>,
     peer_language: Option<LanguageTag>,
     connection_type: Option<ConnectionType>,
     features: HashSet<StreamFeatures>,
+    // what handlers may base decisions on, e.g. to refuse requests over cleartext
+    security: SecurityContext,
 }
 
-impl Default for StreamInfo {
-    fn default() -> Self {
+impl StreamInfo {
+    fn new(domain: Jid) -> Self {
         StreamInfo {
             stream_id: StreamId::new(),
+            domain,
             jid: None,
             peer_jid: None,
+            peer_header_from: None,
+            peer_stream_id: None,
             peer_language: None,
             connection_type: None,
             features: HashSet::new(),
+            security: SecurityContext::default(),
         }
     }
 }
@@ -81,16 +272,46 @@ where
     stanza_tx: Sender<Stanza>,
     stanza_rx: Receiver<Stanza>,
     store: StoreHandle,
+    rate_limiter: TokenBucket,
+    pre_auth_budget: ElementBudget,
+    keepalive: Keepalive,
+    // dropped along with the stream, taking the peer out of the router
+    registration: Option<Registration>,
+    client_ping: Option<ClientPing>,
+    stream_management: Option<StreamManagement>,
+    // requests from new streams to resume the session, see `ResumableSessions::take`
+    takeover_rx: Option<Receiver<Takeover>>,
+    // the one this stream is giving up its session for
+    takeover: Option<Takeover>,
+    shutdown: watch::Receiver<bool>,
 }
 
 impl<C> InboundStream<C>
 where
     C: Connection,
 {
-    pub fn new(connection: C, router: RouterHandle, store: StoreHandle) -> Self {
-        let stream = XmppStream::new(connection);
-        let info = StreamInfo::default();
+    pub fn new(
+        connection: C,
+        router: RouterHandle,
+        store: StoreHandle,
+        shutdown: watch::Receiver<bool>,
+    ) -> Self {
+        let stream = XmppStream::new(connection, get_settings().xml_parser);
+        let info = StreamInfo::new(get_settings().domain.clone());
         let (stanza_tx, stanza_rx) = mpsc::channel(STANZA_CHANNEL_BUFFER_SIZE);
+        let rate_limits = &get_settings().rate_limits;
+        let rate_limiter = TokenBucket::new(
+            rate_limits.stanzas_per_second,
+            rate_limits.stanza_burst,
+            Instant::now(),
+        );
+        let pre_auth_budget = ElementBudget::new(get_settings().max_pre_auth_elements);
+        let inbound_stream_settings = &get_settings().inbound_stream;
+        let keepalive = Keepalive::new(
+            inbound_stream_settings.whitespace_ping_interval,
+            inbound_stream_settings.idle_timeout,
+            Instant::now(),
+        );
 
         InboundStream {
             stream,
@@ -99,15 +320,39 @@ where
             stanza_tx,
             stanza_rx,
             store,
+            rate_limiter,
+            pre_auth_budget,
+            keepalive,
+            registration: None,
+            client_ping: None,
+            stream_management: None,
+            takeover_rx: None,
+            takeover: None,
+            shutdown,
         }
     }
 
+    // The span takes on the id of each stream header sent, see `send_stream_header`.
+    #[instrument(name = "stream", skip_all, fields(id, peer_id))]
     pub async fn handle(&mut self) {
-        match self.inner_handle().await {
-            Ok(()) => (),
-            Err(error) => {
-                let _ = self.handle_unrecoverable_error(error).await;
-            }
+        let _connection = metrics::get().connection_opened();
+        let result = self.inner_handle().await;
+        // a client that closed the stream itself is done with the session
+        if result.is_err() {
+            self.park_session();
+        }
+
+        let closed = match result {
+            // the peer closed the stream first, so there is nothing left to wait for
+            Ok(()) => return,
+            Err(error) if error.downcast_ref::<StarttlsFailure>().is_some() => Ok(()),
+            Err(error) if error.downcast_ref::<ConnectionLost>().is_some() => return,
+            Err(error) => self.handle_unrecoverable_error(error).await,
+        };
+
+        if closed.is_ok() {
+            let close_timeout = get_settings().inbound_stream.close_timeout;
+            discard_until_closed(&mut self.stream, close_timeout).await;
         }
     }
 
@@ -119,7 +364,16 @@ where
             select! {
                 frame = self.stream.reader().next() => {
                     match frame {
-                        Some(Ok(Frame::XmlFragment(element))) => self.process_element(element).await?,
+                        Some(Ok(Frame::XmlFragment(element))) => {
+                            debug!(name = %element.name, "received element");
+                            self.process_element(element).await?
+                        }
+                        // the keepalive already saw these bytes arrive
+                        Some(Ok(Frame::Whitespace)) => {}
+                        Some(Err(error)) => return Err(error),
+                        None if !self.stream.reader().stream_closed() => {
+                            return Err(ConnectionLost.into());
+                        }
                         _ => {
                             // assume peer terminated stream
                             let _ = self.stream.writer().write_stream_close().await;
@@ -127,44 +381,317 @@ where
                         }
                     }
                 }
-                Some(Stanza { element }) = self.stanza_rx.recv() => {
-                    self.stream.writer().write_xml_element(&element).await?;
+                Some(stanza) = self.stanza_rx.recv() => {
+                    send_stanza(&mut self.stream, &mut self.stream_management, &stanza).await?;
+                    self.keepalive.record_activity(Instant::now());
                 }
+                _ = tokio::time::sleep_until(self.keepalive.deadline().into()) => {
+                    let action = self.keepalive.poll(
+                        self.stream.bytes_read(),
+                        self.stream.bytes_written(),
+                        Instant::now(),
+                    );
+                    match action {
+                        KeepaliveAction::Ping => {
+                            self.stream.writer().write_whitespace().await?;
+                            self.keepalive.ping_sent(self.stream.bytes_written());
+                        }
+                        KeepaliveAction::Disconnect => {
+                            let err = anyhow!("no data received from peer");
+                            return Err(err.context(StreamError::ConnectionTimeout));
+                        }
+                        KeepaliveAction::Wait => {}
+                    }
+                }
+                _ = tokio::time::sleep_until(client_ping_deadline(&self.client_ping).into()),
+                    if self.client_ping.is_some() =>
+                {
+                    self.ping_client().await?;
+                }
+                _ = tokio::time::sleep_until(ack_request_deadline(&self.stream_management).into()),
+                    if self.stream_management.is_some() =>
+                {
+                    self.request_ack().await?;
+                }
+                Some(takeover) = next_takeover(&mut self.takeover_rx) => {
+                    self.takeover = Some(takeover);
+                    let err = anyhow!("session resumed on another stream");
+                    return Err(err.context(StreamError::Conflict));
+                }
+                error = shutdown_requested(&mut self.shutdown) => return Err(error),
             }
         }
     }
 
     async fn process_element(&mut self, element: Element) -> Result<(), Error> {
+        self.throttle().await?;
+
+        if !self.info.features.contains(&StreamFeatures::Authentication) {
+            self.pre_auth_budget.spend()?;
+        }
+
         for feature in self.negotiable_features() {
-            if let Ok(()) = dbg!(self.negotiate_feature(feature, &element).await) {
+            // registering does not get the peer any closer to authenticating
+            let restores_budget = feature != StreamFeatures::Registration;
+            match self.negotiate_feature(feature, &element).await {
+                Ok(()) => {
+                    if restores_budget {
+                        self.pre_auth_budget.reset();
+                    }
+                    return Ok(());
+                }
+                // the element was meant for this feature, but the peer has to be cut off
+                Err(err)
+                    if err.downcast_ref::<StreamError>().is_some()
+                        || err.downcast_ref::<StarttlsFailure>().is_some() =>
+                {
+                    return Err(err)
+                }
+                Err(_) => {}
+            }
+        }
+
+        // TLS is either in place already or not on offer
+        if StarttlsNegotiator::is_request(&element) {
+            return StarttlsNegotiator::refuse(&mut self.stream).await;
+        }
+
+        if let Some(stream_management) = &mut self.stream_management {
+            if stream_management::is_nonza(&element) {
+                if let Some(reply) = stream_management.process(&element)? {
+                    self.stream.writer().write_xml_element(&reply).await?;
+                }
                 return Ok(());
             }
         }
 
         // element must be a stanza at this point
-        self.router
-            .stanzas
-            .send(Stanza { element })
-            .await
-            .map_err(|_| anyhow!("failed to route stanza"))
+        check_stanza(
+            &element,
+            self.info.features.contains(&StreamFeatures::Authentication),
+        )?;
+        if let Some(stream_management) = &mut self.stream_management {
+            stream_management.stanza_received();
+        }
+        if let Some(ConnectionType::Server | ConnectionType::Component) = self.info.connection_type
+        {
+            if get_settings().require_from_match {
+                validate_from(
+                    element.get_attribute("from", None),
+                    &self.authorized_domains(),
+                )?;
+            }
+        }
+
+        trace!(stanza = %element, "received stanza");
+        let mut stanza = Stanza { element };
+        if let (Some(ConnectionType::Client), Some(peer_jid)) =
+            (&self.info.connection_type, &self.info.peer_jid)
+        {
+            // whatever the client put there, its stanzas are from its session (RFC 6120,
+            // section 8.1.2.1)
+            stanza.set_from(peer_jid);
+            stanza.default_to(peer_jid);
+        }
+        if let Err(error) = stanza.validate_type() {
+            let language = self.info.peer_language.as_ref().map(|tag| tag.0.as_str());
+            if let Some(reply) = stanza.error_reply(error, language) {
+                send_stanza(&mut self.stream, &mut self.stream_management, &reply).await?;
+            }
+            return Ok(());
+        }
+        if let Some(client_ping) = &mut self.client_ping {
+            if client_ping.answered(&stanza) {
+                return Ok(());
+            }
+        }
+        if let (Some(ConnectionType::Client), Some(peer_jid)) =
+            (&self.info.connection_type, &self.info.peer_jid)
+        {
+            if is_presence_broadcast(&stanza) {
+                // the router broadcasts it to whoever is subscribed
+                let command = ManagementCommand::UpdatePresence(peer_jid.clone(), stanza);
+                self.router
+                    .management
+                    .send(command)
+                    .await
+                    .map_err(|_| anyhow!("failed to update presence"))?;
+                return Ok(());
+            }
+        }
+        if stanza.element.name == "message" {
+            stanza.stamp_stanza_id(&self.info.domain);
+        }
+
+        // IQs to the server or to an account's bare JID are for the server to answer, though
+        // most of them only on behalf of the server itself
+        let language = self.info.peer_language.as_ref().map(|tag| tag.0.as_str());
+        if let Some(for_account) = self.addressed_to_server(&stanza).await {
+            let account = match self.info.connection_type {
+                Some(ConnectionType::Client) => self.info.peer_jid.as_ref(),
+                _ => None,
+            };
+            let context = IqContext {
+                security: &self.info.security,
+                account,
+                store: &self.store,
+                router: &self.router,
+                language,
+            };
+            let reply = IqHandlers::get()
+                .dispatch(&stanza, &context, for_account)
+                .await;
+            // responses only ever answer what the server asked, like roster pushes, and go no
+            // further
+            return match reply {
+                Some(reply) => {
+                    send_stanza(&mut self.stream, &mut self.stream_management, &reply).await
+                }
+                None => Ok(()),
+            };
+        }
+
+        let unhandled_reply = stanza.unhandled_reply(language);
+        // the recipient may well catch up, so the sender can try again later
+        let overloaded_reply = stanza.error_reply(StanzaError::ResourceConstraint, language);
+        // offline storage is full, so the message bounces instead of being dropped (XEP-0160)
+        let storage_full_reply = stanza.error_reply(StanzaError::ServiceUnavailable, language);
+
+        match self.router.route(stanza).await {
+            Ok(DeliveryOutcome::RouterUnavailable) => bail!("failed to route stanza"),
+            Ok(DeliveryOutcome::NoSuchRecipient) => match unhandled_reply {
+                Some(reply) => {
+                    send_stanza(&mut self.stream, &mut self.stream_management, &reply).await
+                }
+                None => Ok(()),
+            },
+            Ok(DeliveryOutcome::RecipientOverloaded) => match overloaded_reply {
+                Some(reply) => {
+                    send_stanza(&mut self.stream, &mut self.stream_management, &reply).await
+                }
+                None => Ok(()),
+            },
+            Ok(DeliveryOutcome::StorageFull) => match storage_full_reply {
+                Some(reply) => {
+                    send_stanza(&mut self.stream, &mut self.stream_management, &reply).await
+                }
+                None => Ok(()),
+            },
+            Ok(DeliveryOutcome::Delivered | DeliveryOutcome::Stored) => Ok(()),
+            Err(err) => {
+                warn!("Dropping stanza: {}", err);
+                Ok(())
+            }
+        }
+    }
+
+    // Whether the server answers an IQ itself, and if so, whether on behalf of one of its
+    // accounts rather than itself. IQs for remote domains or for accounts that do not exist are
+    // routed like any other stanza.
+    async fn addressed_to_server(&self, stanza: &Stanza) -> Option<bool> {
+        if stanza.element.name != "iq" {
+            return None;
+        }
+        let Ok(Some(to)) = stanza.to() else {
+            return None;
+        };
+        let settings = get_settings();
+        if to != to.to_bare() || !is_hosted(&settings.domain, &settings.virtual_hosts, &to) {
+            return None;
+        }
+        if to.is_domain() {
+            return Some(false);
+        }
+
+        // the peer's own account exists for as long as it is logged in, even if it is not stored
+        let own_account = self
+            .info
+            .peer_jid
+            .as_ref()
+            .is_some_and(|peer_jid| peer_jid.to_bare() == to);
+        if own_account {
+            return Some(true);
+        }
+        match self.store.user_exists(to.clone()).await {
+            Ok(exists) => exists.then_some(true),
+            Err(err) => {
+                warn!("Failed to look up account {}: {}", to, err);
+                None
+            }
+        }
+    }
+
+    async fn ping_client(&mut self) -> Result<(), Error> {
+        let (Some(client_ping), Some(peer_jid)) = (&mut self.client_ping, &self.info.peer_jid)
+        else {
+            return Ok(());
+        };
+
+        match client_ping.poll(&self.info.domain, peer_jid, Instant::now()) {
+            PingAction::Send(ping) => {
+                send_stanza(&mut self.stream, &mut self.stream_management, &ping).await
+            }
+            PingAction::Timeout => {
+                let err = anyhow!("no answer to ping from {peer_jid}");
+                Err(err.context(StreamError::ConnectionTimeout))
+            }
+            PingAction::Wait => Ok(()),
+        }
+    }
+
+    async fn request_ack(&mut self) -> Result<(), Error> {
+        let Some(stream_management) = &mut self.stream_management else {
+            return Ok(());
+        };
+
+        match stream_management.poll(Instant::now()) {
+            Some(request) => self.stream.writer().write_xml_element(&request).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn throttle(&mut self) -> Result<(), Error> {
+        let now = Instant::now();
+        let global_rate_limiter = GLOBAL_RATE_LIMITER.get_or_init(|| {
+            let rate_limits = &get_settings().rate_limits;
+            Mutex::new(TokenBucket::new(
+                rate_limits.global_stanzas_per_second,
+                rate_limits.global_stanza_burst,
+                now,
+            ))
+        });
+
+        let delay = throttle_delay(&mut self.rate_limiter, global_rate_limiter, now)?;
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+
+        Ok(())
     }
 
     fn negotiable_features(&self) -> Vec<StreamFeatures> {
         let mut features = vec![];
 
-        if self.stream.is_starttls_allowed() && !self.info.features.contains(&StreamFeatures::Tls) {
+        if offers_starttls(&self.stream, &self.info.features) {
             features.push(StreamFeatures::Tls);
         }
 
         let tls_required = match self.info.connection_type {
             Some(ConnectionType::Client) => get_settings().tls.required_for_clients,
-            Some(ConnectionType::Server) => get_settings().tls.required_for_servers,
+            Some(ConnectionType::Server | ConnectionType::Component) => {
+                get_settings().tls.required_for_servers
+            }
             None => false,
         };
         if (!tls_required || self.info.features.contains(&StreamFeatures::Tls))
             && !self.info.features.contains(&StreamFeatures::Authentication)
         {
             features.push(StreamFeatures::Authentication);
+            if get_settings().allow_registration
+                && !self.info.features.contains(&StreamFeatures::Registration)
+            {
+                features.push(StreamFeatures::Registration);
+            }
         }
 
         if let Some(ConnectionType::Client) = self.info.connection_type {
@@ -176,6 +703,11 @@ where
             {
                 features.push(StreamFeatures::ResourceBinding);
             }
+            if self.info.features.contains(&StreamFeatures::Authentication)
+                && self.stream_management.is_none()
+            {
+                features.push(StreamFeatures::StreamManagement);
+            }
         }
 
         features
@@ -189,123 +721,302 @@ where
         match feature {
             StreamFeatures::Tls => {
                 StarttlsNegotiator::negotiate_feature(&mut self.stream, element).await?;
+                metrics::get().tls_upgraded();
+                if let Some(tls_info) = self.stream.tls_info() {
+                    info!("TLS established: {}", tls_info);
+                }
                 self.info.features.insert(StreamFeatures::Tls);
                 self.stream.reset();
                 self.exchange_stream_headers().await?;
                 self.advertise_features().await?;
             }
             StreamFeatures::Authentication => {
-                let peer_jid = Some(
-                    SaslNegotiator::negotiate_feature(
-                        &mut self.stream,
-                        element,
-                        self.store.clone(),
-                    )
-                    .await?,
-                );
-                self.register_peer_jid(peer_jid).await;
+                let (peer_jid, security) = SaslNegotiator::negotiate_feature(
+                    &mut self.stream,
+                    element,
+                    self.store.clone(),
+                    &self.info.domain,
+                    &get_settings().sasl_mechanisms,
+                    get_settings().max_auth_retries,
+                )
+                .await?;
+                self.register_peer_jid(Some(peer_jid), security).await;
                 self.info.features.insert(StreamFeatures::Authentication);
                 self.stream.reset();
                 self.exchange_stream_headers().await?;
                 self.advertise_features().await?;
             }
+            StreamFeatures::Registration => {
+                let registered = RegistrationNegotiator::negotiate_feature(
+                    &mut self.stream,
+                    element,
+                    &self.store,
+                    &self.info.domain,
+                    get_settings().password_pepper.as_ref(),
+                    &get_settings().password_hashing,
+                    self.info.peer_language.as_ref().map(|tag| tag.0.as_str()),
+                )
+                .await?;
+                // each account costs several password hashes, so a stream only gets to create one
+                if registered {
+                    self.info.features.insert(StreamFeatures::Registration);
+                }
+            }
             StreamFeatures::ResourceBinding => {
-                let peer_jid = Some(
-                    ResourceBindingNegotiator::negotiate_feature(
-                        &mut self.stream,
-                        element,
-                        &self.info.peer_jid,
-                    )
-                    .await?,
-                );
-                self.register_peer_jid(peer_jid).await;
-                self.info.features.insert(StreamFeatures::ResourceBinding);
+                let peer_jid = ResourceBindingNegotiator::negotiate_feature(
+                    &mut self.stream,
+                    element,
+                    &self.info.peer_jid,
+                    &self.router,
+                    get_settings().resource_conflict,
+                )
+                .await?;
+                if peer_jid.is_some() {
+                    self.register_peer_jid(peer_jid, self.info.security).await;
+                    self.info.features.insert(StreamFeatures::ResourceBinding);
+                    self.start_client_ping();
+                }
+            }
+            StreamFeatures::StreamManagement => {
+                let bound = self
+                    .info
+                    .features
+                    .contains(&StreamFeatures::ResourceBinding);
+                let negotiated = StreamManagementNegotiator::negotiate_feature(
+                    &mut self.stream,
+                    element,
+                    self.info.peer_jid.as_ref(),
+                    bound,
+                    resumable_sessions(),
+                )
+                .await?;
+                match negotiated {
+                    Negotiated::Enabled { resumption_id } => {
+                        let settings = &get_settings().inbound_stream;
+                        self.stream_management = Some(StreamManagement::new(
+                            resumption_id,
+                            settings.ack_request_interval,
+                            settings.max_unacked_stanzas,
+                            Instant::now(),
+                        ));
+                        if let Some(peer_jid) = self.info.peer_jid.clone() {
+                            self.watch_session(&peer_jid);
+                        }
+                        self.info.features.insert(StreamFeatures::StreamManagement);
+                    }
+                    Negotiated::Resumed(state) => self.resume_session(state).await,
+                    Negotiated::Failed => {}
+                }
             }
         }
 
         Ok(())
     }
 
-    async fn register_peer_jid(&mut self, peer_jid: Option<Jid>) {
-        if let Some(entity) = self.info.peer_jid.take() {
-            self.router
-                .management
-                .send(ManagementCommand::Unregister(entity))
-                .await
-                .unwrap();
+    fn start_client_ping(&mut self) {
+        let settings = &get_settings().inbound_stream;
+        self.client_ping = Some(ClientPing::new(
+            settings.client_ping_interval,
+            settings.client_ping_timeout,
+            Instant::now(),
+        ));
+    }
+
+    // The resumed session takes the place of binding a resource. Registering it again ends the
+    // suspension.
+    async fn resume_session(&mut self, state: ResumableState) {
+        info!("Resumed session of {}", state.jid);
+        self.stanza_tx = state.stanza_tx;
+        self.stanza_rx = state.stanza_rx;
+        self.stream_management = Some(state.stream_management);
+        self.register_peer_jid(Some(state.jid.clone()), self.info.security)
+            .await;
+        self.watch_session(&state.jid);
+        self.info.features.insert(StreamFeatures::ResourceBinding);
+        self.info.features.insert(StreamFeatures::StreamManagement);
+        self.start_client_ping();
+    }
+
+    // Lets a new stream take the session over while this one is still connected.
+    fn watch_session(&mut self, jid: &Jid) {
+        let id = self
+            .stream_management
+            .as_ref()
+            .and_then(StreamManagement::resumption_id);
+        if let Some(id) = id {
+            self.takeover_rx = Some(resumable_sessions().watch(id.clone(), jid.clone()));
         }
+    }
+
+    // Keeps a resumable session around after its stream failed, so the client can pick it up
+    // again on a new one. Stanzas routed to it in the meantime wait in its channel. A session
+    // taken over by a new stream goes straight to it instead.
+    fn park_session(&mut self) {
+        let Some(stream_management) = self.stream_management.take() else {
+            return;
+        };
+        let (Some(id), Some(jid), Some(registration)) = (
+            stream_management.resumption_id().cloned(),
+            self.info.peer_jid.clone(),
+            self.registration.take(),
+        ) else {
+            return;
+        };
+        let timeout = get_settings().inbound_stream.resumption_timeout;
+        registration.close(CloseReason::Resumable(timeout));
+
+        let (stanza_tx, stanza_rx) = mpsc::channel(STANZA_CHANNEL_BUFFER_SIZE);
+        let state = ResumableState {
+            jid,
+            stream_management,
+            stanza_tx: std::mem::replace(&mut self.stanza_tx, stanza_tx),
+            stanza_rx: std::mem::replace(&mut self.stanza_rx, stanza_rx),
+        };
+        let state = match self.takeover.take() {
+            Some(takeover) => match takeover.send(state) {
+                Ok(()) => return,
+                Err(state) => state,
+            },
+            None => state,
+        };
+        info!("Keeping session of {} for resumption", state.jid);
+        resumable_sessions().park(id, state);
+    }
 
+    async fn register_peer_jid(&mut self, peer_jid: Option<Jid>, security: SecurityContext) {
+        self.registration = None;
         self.info.peer_jid = peer_jid;
+        self.info.security = security;
 
         if let Some(entity) = self.info.peer_jid.clone() {
-            self.router
-                .management
-                .send(ManagementCommand::Register(entity, self.stanza_tx.clone()))
-                .await
-                .unwrap();
+            // a client is no recipient until it has bound a resource (RFC 6120, section 7.1)
+            if let Some(ConnectionType::Client) = self.info.connection_type {
+                if entity.resource().is_none() {
+                    return;
+                }
+            }
+
+            let registration = self
+                .router
+                .register(entity, self.info.security, self.stanza_tx.clone())
+                .await;
+            self.registration = Some(registration);
         }
     }
 
     async fn advertise_features(&mut self) -> Result<(), Error> {
-        let features = self
-            .negotiable_features()
-            .into_iter()
-            .map(|feature| match feature {
-                StreamFeatures::Tls => Node::Element(StarttlsNegotiator::advertise_feature()),
-                StreamFeatures::Authentication => Node::Element(SaslNegotiator::advertise_feature(
-                    self.stream.is_secure(),
-                    self.stream.is_authenticated(),
-                )),
-                StreamFeatures::ResourceBinding => {
-                    Node::Element(ResourceBindingNegotiator::advertise_feature())
-                }
-            })
-            .collect();
+        let key = FeaturesCacheKey::for_stream(self.negotiable_features(), &self.stream);
+
+        if !can_authenticate(&key, &get_settings().sasl_mechanisms) {
+            let err = anyhow!("no SASL mechanism can become available on this connection");
+            return Err(err.context(StreamError::PolicyViolation));
+        }
+
+        if !get_settings().cache_stream_features {
+            let features = features_element(&key, &get_settings().sasl_mechanisms);
+            return self.stream.writer().write_xml_element(&features).await;
+        }
 
-        let features = Element {
-            name: "features".to_string(),
-            namespace: Some(namespaces::XMPP_STREAMS.to_string()),
-            attributes: HashMap::new(),
-            children: features,
+        let cache = FEATURES_CACHE.get_or_init(Default::default);
+        let cached = cache.lock().unwrap().get(&key).cloned();
+        let xml = match cached {
+            Some(xml) => xml,
+            None => {
+                let features = features_element(&key, &get_settings().sasl_mechanisms);
+                let xml: Arc<str> = self.stream.writer().serialize_xml_element(&features).into();
+                cache.lock().unwrap().insert(key, xml.clone());
+                xml
+            }
         };
 
-        self.stream.writer().write_xml_element(&features).await
+        self.stream.writer().write_serialized_xml(&xml).await
     }
 
     async fn exchange_stream_headers(&mut self) -> Result<(), Error> {
-        let Ok(frame) = self
-            .stream
-            .reader()
-            .next()
+        let frame = match next_frame(&mut self.stream, self.keepalive.idle_timeout)
             .await
             .ok_or(anyhow!("stream closed by peer"))?
-        else {
-            self.send_stream_header(None).await?;
-            self.handle_unrecoverable_error(anyhow!("expected xml frame"))
-                .await?;
-            bail!("expected xml frame");
+        {
+            Ok(frame) => frame,
+            Err(error) => {
+                self.send_stream_header(None).await?;
+                return Err(error);
+            }
         };
 
         let Frame::StreamStart(inbound_header) = frame else {
             self.send_stream_header(None).await?;
-            self.handle_unrecoverable_error(anyhow!("expected stream header"))
-                .await?;
-            bail!("expected stream header");
+            return Err(anyhow!("expected stream header").context(StreamError::BadFormat));
         };
 
+        let checked = check_header(&inbound_header);
+        self.info.connection_type =
+            ConnectionType::from_namespace(inbound_header.content_namespace.as_deref());
+        let settings = get_settings();
+        let domain = check_header_to(
+            &settings.domain,
+            &settings.virtual_hosts,
+            inbound_header.to.as_ref(),
+        );
+        if let Ok(domain) = domain {
+            self.info.domain = domain.clone();
+        }
         self.info.jid = inbound_header.to;
+        self.info.peer_header_from = inbound_header.from;
+        self.info.peer_stream_id = inbound_header.id;
+        if let Some(peer_stream_id) = &self.info.peer_stream_id {
+            Span::current().record("peer_id", tracing::field::display(peer_stream_id));
+        }
         self.info.peer_language = inbound_header.language;
-        self.info.connection_type = Some(ConnectionType::Client);
 
-        self.send_stream_header(self.info.peer_jid.clone()).await
+        self.send_stream_header(self.info.peer_jid.clone()).await?;
+
+        checked?;
+        domain?;
+        check_header_from(
+            settings.client_header_from,
+            self.info.peer_header_from.as_ref(),
+        )?;
+        // once a server or component has authenticated, it has to say which domain it speaks for
+        if let Some(ConnectionType::Server | ConnectionType::Component) = self.info.connection_type
+        {
+            if settings.require_from_match && self.info.peer_jid.is_some() {
+                let from = self.info.peer_header_from.as_ref().map(Jid::to_string);
+                validate_from(from.as_deref(), &self.authorized_domains())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // The domains a server or component stream speaks for. An account authenticated over SASL
+    // speaks for none, whatever its domain.
+    fn authorized_domains(&self) -> Vec<Jid> {
+        self.info
+            .peer_jid
+            .iter()
+            .filter(|jid| jid.is_domain())
+            .cloned()
+            .collect()
     }
 
+    // Every header, including those after a stream restart, gets an id of its own.
     async fn send_stream_header(&mut self, to: Option<Jid>) -> Result<(), Error> {
+        self.info.stream_id = StreamId::new();
+        Span::current().record("id", tracing::field::display(&self.info.stream_id));
+
         let outbound_header = StreamHeader {
-            from: Some(get_settings().domain.clone()),
+            from: Some(self.info.domain.clone()),
             to,
             id: Some(self.info.stream_id.clone()),
-            language: None,
+            language: self.info.peer_language.clone(),
+            stream_namespace: None,
+            content_namespace: self
+                .info
+                .connection_type
+                .as_ref()
+                .map(|connection_type| connection_type.namespace().to_string()),
+            version: None,
         };
 
         self.stream
@@ -315,26 +1026,1204 @@ where
     }
 
     async fn handle_unrecoverable_error(&mut self, error: Error) -> Result<(), Error> {
-        dbg!(error);
-
-        let error = Element {
-            name: "error".to_string(),
-            namespace: Some(namespaces::XMPP_STREAMS.to_string()),
-            attributes: HashMap::new(),
-            children: vec![Node::Element(Element {
-                name: "internal-server-error".to_string(),
-                namespace: Some(namespaces::XMPP_STREAM_ERRORS.to_string()),
-                attributes: vec![(
-                    ("xmlns".to_string(), None),
-                    namespaces::XMPP_STREAM_ERRORS.to_string(),
-                )]
-                .into_iter()
-                .collect(),
-                children: vec![],
-            })],
+        info!("Closing stream: {:#}", error);
+
+        let language = self.info.peer_language.as_ref().map(|tag| tag.0.as_str());
+        close_with_error(&mut self.stream, &error, language).await
+    }
+}
+
+// Once stream management is enabled, everything sent is held on to until the peer acknowledges it.
+// That starts before writing, so a stanza the lost connection did not take is sent again on
+// resumption.
+async fn send_stanza<C: Connection>(
+    stream: &mut XmppStream<C>,
+    stream_management: &mut Option<StreamManagement>,
+    stanza: &Stanza,
+) -> Result<(), Error> {
+    if let Some(stream_management) = stream_management {
+        stream_management.stanza_sent(stanza)?;
+    }
+
+    stream.writer().write_stanza(stanza).await
+}
+
+async fn close_with_error<C: Connection>(
+    stream: &mut XmppStream<C>,
+    error: &Error,
+    language: Option<&str>,
+) -> Result<(), Error> {
+    let stream_error = error
+        .downcast_ref::<StreamError>()
+        .copied()
+        .unwrap_or(StreamError::InternalServerError);
+
+    stream
+        .writer()
+        .write_xml_element(&stream_error.to_element(language))
+        .await?;
+    stream.writer().write_stream_close().await
+}
+
+// Once our closing tag is out, whatever the peer still had in flight is read and dropped until it
+// closes its side as well (RFC 6120 §4.4), so the connection is not torn down under it.
+async fn discard_until_closed<C: Connection>(stream: &mut XmppStream<C>, close_timeout: Duration) {
+    let discard = async { while let Some(Ok(_)) = stream.reader().next().await {} };
+    let _ = tokio::time::timeout(close_timeout, discard).await;
+}
+
+// Resolves with the next request to hand over this stream's session, if it can be resumed.
+async fn next_takeover(takeover_rx: &mut Option<Receiver<Takeover>>) -> Option<Takeover> {
+    match takeover_rx {
+        Some(takeover_rx) => takeover_rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+// Resolves with the error to close the stream with once the server is shutting down. Should the
+// server go away without saying so, the stream is left alone.
+async fn shutdown_requested(shutdown: &mut watch::Receiver<bool>) -> Error {
+    if shutdown.wait_for(|requested| *requested).await.is_err() {
+        std::future::pending::<()>().await;
+    }
+
+    anyhow!("server is shutting down").context(StreamError::SystemShutdown)
+}
+
+fn features_element(key: &FeaturesCacheKey, sasl_mechanisms: &SaslMechanisms) -> Element {
+    let mut features = Vec::new();
+    for feature in &key.features {
+        match feature {
+            StreamFeatures::Tls => {
+                features.push(Node::Element(StarttlsNegotiator::advertise_feature()));
+            }
+            StreamFeatures::Authentication => {
+                let context = SecurityContext {
+                    secure: key.secure,
+                    channel_binding: key.channel_binding.is_some(),
+                    peer_cert: key.authenticated,
+                    mechanism: None,
+                };
+                let Some(mechanisms) = SaslNegotiator::advertise_feature(&context, sasl_mechanisms)
+                else {
+                    continue;
+                };
+                features.push(Node::Element(mechanisms));
+                if let Some(cb_name) = key.channel_binding {
+                    features.push(Node::Element(SaslNegotiator::advertise_channel_binding(
+                        cb_name,
+                    )));
+                }
+            }
+            StreamFeatures::Registration => {
+                features.push(Node::Element(RegistrationNegotiator::advertise_feature()));
+            }
+            StreamFeatures::ResourceBinding => {
+                features.push(Node::Element(ResourceBindingNegotiator::advertise_feature()));
+                // informational, roster requests are only possible once bound anyway
+                features.push(Node::Element(roster::advertise_versioning()));
+            }
+            StreamFeatures::StreamManagement => {
+                features.push(Node::Element(
+                    StreamManagementNegotiator::advertise_feature(),
+                ));
+            }
+        }
+    }
+
+    Element {
+        name: "features".to_string(),
+        namespace: Some(namespaces::XMPP_STREAMS.to_string()),
+        attributes: HashMap::new(),
+        children: features,
+    }
+}
+
+// The keepalive only runs once the stream is up, so a peer that never sends its stream header
+// is held to the idle timeout here.
+async fn next_frame<C: Connection>(
+    stream: &mut XmppStream<C>,
+    idle_timeout: Duration,
+) -> Option<Result<Frame, Error>> {
+    match tokio::time::timeout(idle_timeout, stream.reader().next()).await {
+        Ok(frame) => frame,
+        Err(_) => {
+            let err = anyhow!("no stream header received from peer");
+            Some(Err(err.context(StreamError::ConnectionTimeout)))
+        }
+    }
+}
+
+fn resumable_sessions() -> &'static ResumableSessions {
+    RESUMABLE_SESSIONS
+        .get_or_init(|| ResumableSessions::new(get_settings().inbound_stream.resumption_timeout))
+}
+
+fn ack_request_deadline(stream_management: &Option<StreamManagement>) -> Instant {
+    stream_management
+        .as_ref()
+        .map_or_else(Instant::now, StreamManagement::deadline)
+}
+
+fn client_ping_deadline(client_ping: &Option<ClientPing>) -> Instant {
+    client_ping
+        .as_ref()
+        .map_or_else(Instant::now, ClientPing::deadline)
+}
+
+// Connections that did TLS right away (or upgraded already) report STARTTLS as not allowed.
+fn offers_starttls<C: Connection>(
+    stream: &XmppStream<C>,
+    negotiated: &HashSet<StreamFeatures>,
+) -> bool {
+    stream.is_starttls_allowed() && !negotiated.contains(&StreamFeatures::Tls)
+}
+
+fn is_presence_broadcast(stanza: &Stanza) -> bool {
+    stanza.element.name == "presence"
+        && stanza.element.get_attribute("to", None).is_none()
+        && matches!(
+            stanza.element.get_attribute("type", None),
+            None | Some("unavailable")
+        )
+}
+
+fn check_header_from(policy: HeaderFromPolicy, from: Option<&Jid>) -> Result<(), StreamError> {
+    match (policy, from) {
+        (HeaderFromPolicy::Required, None) | (HeaderFromPolicy::Forbidden, Some(_)) => {
+            Err(StreamError::InvalidFrom)
+        }
+        _ => Ok(()),
+    }
+}
+
+// RFC 6120, sections 4.7.5 and 4.8. Anything without a version predates 1.0, and minor
+// versions above ours are compatible.
+fn check_header(header: &StreamHeader) -> Result<(), StreamError> {
+    if header.stream_namespace.as_deref() != Some(namespaces::XMPP_STREAMS)
+        || ConnectionType::from_namespace(header.content_namespace.as_deref()).is_none()
+    {
+        return Err(StreamError::InvalidNamespace);
+    }
+    // servers and components have no way to authenticate as a domain yet, so on this listener
+    // their namespaces would only let a logged-in client speak for other accounts
+    if !matches!(
+        ConnectionType::from_namespace(header.content_namespace.as_deref()),
+        Some(ConnectionType::Client)
+    ) {
+        return Err(StreamError::InvalidNamespace);
+    }
+
+    let version = header.version.as_deref().unwrap_or("0.9");
+    let Some((major, minor)) = version.split_once('.') else {
+        return Err(StreamError::BadFormat);
+    };
+    match (major.parse::<u32>(), minor.parse::<u32>()) {
+        (Ok(1), Ok(_)) => Ok(()),
+        (Ok(_), Ok(_)) => Err(StreamError::UnsupportedVersion),
+        _ => Err(StreamError::BadFormat),
+    }
+}
+
+// Picks the hosted domain a stream is for. A missing `to` is taken to mean the main domain.
+fn check_header_to<'d>(
+    domain: &'d Jid,
+    virtual_hosts: &'d [Jid],
+    to: Option<&Jid>,
+) -> Result<&'d Jid, StreamError> {
+    let Some(to) = to else {
+        return Ok(domain);
+    };
+
+    std::iter::once(domain)
+        .chain(virtual_hosts)
+        .find(|hosted| *hosted == to)
+        .ok_or(StreamError::HostUnknown)
+}
+
+// Whether `jid` is at one of the domains served here, as opposed to a remote one.
+fn is_hosted(domain: &Jid, virtual_hosts: &[Jid], jid: &Jid) -> bool {
+    check_header_to(domain, virtual_hosts, Some(&jid.domain_jid())).is_ok()
+}
+
+// Whatever negotiation did not consume has to be a stanza, and stanzas are only accepted from
+// authenticated peers (RFC 6120, sections 4.9.3.12 and 4.9.3.22).
+fn check_stanza(element: &Element, authenticated: bool) -> Result<(), StreamError> {
+    if !matches!(element.name.as_str(), "message" | "presence" | "iq") {
+        return Err(StreamError::UnsupportedStanzaType);
+    }
+    if !authenticated {
+        return Err(StreamError::NotAuthorized);
+    }
+
+    Ok(())
+}
+
+// Authentication is only impossible if it is up next, no mechanism is acceptable yet and
+// nothing else can be negotiated first to change that.
+fn can_authenticate(key: &FeaturesCacheKey, sasl_mechanisms: &SaslMechanisms) -> bool {
+    // registering is no way forward on its own, the new account still has to log in
+    let features = key
+        .features
+        .iter()
+        .filter(|feature| **feature != StreamFeatures::Registration)
+        .collect::<Vec<_>>();
+    if features != [&StreamFeatures::Authentication] {
+        return true;
+    }
+
+    let context = SecurityContext {
+        secure: key.secure,
+        channel_binding: key.channel_binding.is_some(),
+        peer_cert: key.authenticated,
+        mechanism: None,
+    };
+    SaslNegotiator::advertise_feature(&context, sasl_mechanisms).is_some()
+}
+
+// How long to hold off the next stanza. Only a connection that keeps breaking its own limit is
+// at fault, everybody else merely waits their turn under the global one.
+fn throttle_delay(
+    rate_limiter: &mut TokenBucket,
+    global_rate_limiter: &Mutex<TokenBucket>,
+    now: Instant,
+) -> Result<Duration, StreamError> {
+    let delay = rate_limiter
+        .acquire(now)
+        .map_err(|_| StreamError::PolicyViolation)?;
+    let global_delay = global_rate_limiter.lock().unwrap().delay(now);
+
+    Ok(delay.max(global_delay))
+}
+
+// Anything a server or component sends has to be from one of the domains it was authorized for.
+fn validate_from(from: Option<&str>, authorized_domains: &[Jid]) -> Result<(), StreamError> {
+    if authorized_domains.is_empty() {
+        return Err(StreamError::NotAuthorized);
+    }
+
+    let Some(from) = from.and_then(|from| from.parse::<Jid>().ok()) else {
+        return Err(StreamError::InvalidFrom);
+    };
+
+    if !authorized_domains.contains(&from.domain_jid()) {
+        return Err(StreamError::InvalidFrom);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU32;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::rustls::server::ResolvesServerCertUsingSni;
+    use tokio_rustls::rustls::ServerConfig;
+
+    use crate::inbound::connection::builder::ConnectionBuilder;
+    use crate::inbound::connection::fake::FakeConnection;
+    use crate::services::store::fake::FakeStoreBackend;
+    use crate::settings::Settings;
+    use crate::xml::stream_parser::{ElementLimits, ParserConfig, ParserKind};
+    use crate::xml::stream_writer::StreamWriter;
+
+    use super::sasl::SecurityLevel;
+
+    use super::*;
+
+    async fn serialize_features(features: Vec<StreamFeatures>, cached: bool) -> String {
+        let header = StreamHeader {
+            from: Some("localhost".parse().unwrap()),
+            to: None,
+            id: None,
+            language: None,
+            stream_namespace: None,
+            content_namespace: None,
+            version: None,
         };
+        let key = FeaturesCacheKey {
+            features,
+            secure: false,
+            authenticated: false,
+            channel_binding: None,
+        };
+        let features = features_element(&key, &SaslMechanisms::default());
+
+        let mut writer = StreamWriter::new(Vec::new());
+        writer.write_stream_header(&header, false).await.unwrap();
+        if cached {
+            let xml = writer.serialize_xml_element(&features);
+            writer.write_serialized_xml(&xml).await.unwrap();
+        } else {
+            writer.write_xml_element(&features).await.unwrap();
+        }
+
+        let output = String::from_utf8(writer.into_inner()).unwrap();
+        output[output.find("<stream:features").unwrap()..].to_string()
+    }
+
+    #[tokio::test]
+    async fn cached_features_match_dynamic_features() {
+        let features = vec![StreamFeatures::Tls, StreamFeatures::Authentication];
+        assert_eq!(
+            serialize_features(features.clone(), true).await,
+            serialize_features(features, false).await
+        );
+    }
+
+    #[test]
+    fn flooding_connection_does_not_get_others_disconnected() {
+        let now = Instant::now();
+        let rate = NonZeroU32::new(10).unwrap();
+        let global_rate_limiter = Mutex::new(TokenBucket::new(rate, 10, now));
+        let mut flooding = TokenBucket::new(rate, 100, now);
+        let mut quiet = TokenBucket::new(rate, 100, now);
+
+        // the flood runs the global bucket dry long before its own one
+        let mut delay = Duration::ZERO;
+        for _ in 0..50 {
+            delay = throttle_delay(&mut flooding, &global_rate_limiter, now).unwrap();
+        }
+        assert!(delay > Duration::ZERO);
+
+        let delay = throttle_delay(&mut quiet, &global_rate_limiter, now).unwrap();
+        assert!(delay > Duration::ZERO);
+
+        // only breaking its own limit gets a connection closed
+        let result = (0..200)
+            .try_for_each(|_| throttle_delay(&mut flooding, &global_rate_limiter, now).map(|_| ()));
+        assert_eq!(result, Err(StreamError::PolicyViolation));
+        assert!(throttle_delay(&mut quiet, &global_rate_limiter, now).is_ok());
+    }
+
+    #[test]
+    fn component_may_send_from_its_own_domain() {
+        let component = "component.localhost".parse::<Jid>().unwrap();
+        let result = validate_from(Some("user@component.localhost"), &[component]);
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn component_may_not_spoof_other_domains() {
+        let domains = [
+            "component.localhost".parse::<Jid>().unwrap(),
+            "other.localhost".parse::<Jid>().unwrap(),
+        ];
+        let result = validate_from(Some("admin@localhost"), &domains);
+        assert_eq!(result, Err(StreamError::InvalidFrom));
+        let result = validate_from(Some("admin@other.localhost"), &domains);
+        assert_eq!(result, Ok(()));
+    }
+
+    #[test]
+    fn unauthenticated_component_is_not_authorized() {
+        let result = validate_from(Some("user@component.localhost"), &[]);
+        assert_eq!(result, Err(StreamError::NotAuthorized));
+    }
+
+    fn inbound_header(stream_namespace: Option<&str>, version: Option<&str>) -> StreamHeader {
+        StreamHeader {
+            from: None,
+            to: Some("localhost".parse().unwrap()),
+            id: None,
+            language: None,
+            stream_namespace: stream_namespace.map(str::to_string),
+            content_namespace: Some(namespaces::XMPP_CLIENT.to_string()),
+            version: version.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn valid_header_is_accepted() {
+        let header = inbound_header(Some(namespaces::XMPP_STREAMS), Some("1.0"));
+        assert_eq!(check_header(&header), Ok(()));
+
+        let header = inbound_header(Some(namespaces::XMPP_STREAMS), Some("1.1"));
+        assert_eq!(check_header(&header), Ok(()));
+    }
+
+    #[test]
+    fn header_without_stream_namespace_is_rejected() {
+        let header = inbound_header(None, Some("1.0"));
+        assert_eq!(check_header(&header), Err(StreamError::InvalidNamespace));
+
+        let header = inbound_header(Some(namespaces::XMPP_CLIENT), Some("1.0"));
+        assert_eq!(check_header(&header), Err(StreamError::InvalidNamespace));
+    }
+
+    #[test]
+    fn header_content_namespace_must_be_supported() {
+        let mut header = inbound_header(Some(namespaces::XMPP_STREAMS), Some("1.0"));
+        header.content_namespace = Some("jabber:component:connect".to_string());
+        assert_eq!(check_header(&header), Err(StreamError::InvalidNamespace));
+
+        // nothing can authenticate as a server or component on this listener yet
+        header.content_namespace = Some(namespaces::XMPP_COMPONENT_ACCEPT.to_string());
+        assert_eq!(check_header(&header), Err(StreamError::InvalidNamespace));
+        header.content_namespace = Some(namespaces::XMPP_SERVER.to_string());
+        assert_eq!(check_header(&header), Err(StreamError::InvalidNamespace));
+    }
+
+    #[test]
+    fn pre_1_0_header_is_unsupported_version() {
+        let header = inbound_header(Some(namespaces::XMPP_STREAMS), Some("0.9"));
+        assert_eq!(check_header(&header), Err(StreamError::UnsupportedVersion));
+
+        let header = inbound_header(Some(namespaces::XMPP_STREAMS), None);
+        assert_eq!(check_header(&header), Err(StreamError::UnsupportedVersion));
+    }
+
+    #[test]
+    fn garbled_version_is_bad_format() {
+        let header = inbound_header(Some(namespaces::XMPP_STREAMS), Some("one"));
+        assert_eq!(check_header(&header), Err(StreamError::BadFormat));
+    }
+
+    #[test]
+    fn header_to_must_be_the_served_domain() {
+        let domain = "localhost".parse::<Jid>().unwrap();
+        let other = "example.com".parse::<Jid>().unwrap();
+
+        assert_eq!(check_header_to(&domain, &[], Some(&domain)), Ok(&domain));
+        assert_eq!(check_header_to(&domain, &[], None), Ok(&domain));
+        assert_eq!(
+            check_header_to(&domain, &[], Some(&other)),
+            Err(StreamError::HostUnknown)
+        );
+    }
+
+    #[test]
+    fn header_to_selects_a_virtual_host() {
+        let domain = "localhost".parse::<Jid>().unwrap();
+        let virtual_hosts = ["example.com".parse::<Jid>().unwrap()];
+        let unknown = "example.net".parse::<Jid>().unwrap();
+
+        assert_eq!(
+            check_header_to(&domain, &virtual_hosts, Some(&virtual_hosts[0])),
+            Ok(&virtual_hosts[0])
+        );
+        assert_eq!(check_header_to(&domain, &virtual_hosts, None), Ok(&domain));
+        assert_eq!(
+            check_header_to(&domain, &virtual_hosts, Some(&unknown)),
+            Err(StreamError::HostUnknown)
+        );
+    }
+
+    #[test]
+    fn stanzas_require_authentication() {
+        let message = Element::parse("<message xmlns='jabber:client'/>").unwrap();
+        assert_eq!(check_stanza(&message, true), Ok(()));
+        assert_eq!(
+            check_stanza(&message, false),
+            Err(StreamError::NotAuthorized)
+        );
+    }
+
+    #[test]
+    fn unknown_first_level_elements_are_unsupported() {
+        let element = Element::parse("<foo xmlns='jabber:client'/>").unwrap();
+        assert_eq!(
+            check_stanza(&element, true),
+            Err(StreamError::UnsupportedStanzaType)
+        );
+        assert_eq!(
+            check_stanza(&element, false),
+            Err(StreamError::UnsupportedStanzaType)
+        );
+    }
+
+    #[test]
+    fn pre_auth_budget_is_enforced() {
+        let mut budget = ElementBudget::new(3);
+
+        for _ in 0..3 {
+            assert!(budget.spend().is_ok());
+        }
+        assert_eq!(budget.spend(), Err(StreamError::PolicyViolation));
+    }
+
+    #[test]
+    fn pre_auth_budget_is_restored_by_negotiation() {
+        let mut budget = ElementBudget::new(2);
+
+        budget.spend().unwrap();
+        budget.spend().unwrap();
+        budget.reset();
+        assert!(budget.spend().is_ok());
+    }
+
+    #[test]
+    fn pings_are_sent_at_the_interval() {
+        let start = Instant::now();
+        let mut keepalive =
+            Keepalive::new(Duration::from_secs(60), Duration::from_secs(300), start);
+        assert_eq!(keepalive.deadline(), start + Duration::from_secs(60));
+
+        let now = start + Duration::from_secs(30);
+        assert_eq!(keepalive.poll(0, 0, now), KeepaliveAction::Wait);
+
+        let now = start + Duration::from_secs(60);
+        assert_eq!(keepalive.poll(0, 0, now), KeepaliveAction::Ping);
+        assert_eq!(keepalive.deadline(), now + Duration::from_secs(60));
+        assert_eq!(keepalive.poll(0, 0, now), KeepaliveAction::Wait);
+    }
+
+    #[test]
+    fn quiet_but_responsive_peer_is_not_disconnected() {
+        let start = Instant::now();
+        let mut keepalive = Keepalive::new(Duration::from_secs(60), Duration::from_secs(90), start);
+
+        // the peer only answers with a little whitespace every now and then
+        let mut bytes_read = 0;
+        for minute in 1..=10 {
+            bytes_read += 1;
+            let now = start + Duration::from_secs(60 * minute);
+            assert_eq!(keepalive.poll(bytes_read, 0, now), KeepaliveAction::Ping);
+        }
+    }
+
+    #[test]
+    fn silent_peer_is_disconnected_after_the_timeout() {
+        let start = Instant::now();
+        let mut keepalive = Keepalive::new(Duration::from_secs(60), Duration::from_secs(90), start);
+
+        assert_eq!(
+            keepalive.poll(0, 0, start + Duration::from_secs(60)),
+            KeepaliveAction::Ping
+        );
+        assert_eq!(keepalive.deadline(), start + Duration::from_secs(90));
+        assert_eq!(
+            keepalive.poll(0, 0, start + Duration::from_secs(90)),
+            KeepaliveAction::Disconnect
+        );
+    }
+
+    #[test]
+    fn output_puts_off_the_next_ping() {
+        let start = Instant::now();
+        let mut keepalive =
+            Keepalive::new(Duration::from_secs(60), Duration::from_secs(300), start);
+
+        let now = start + Duration::from_secs(50);
+        assert_eq!(keepalive.poll(0, 100, now), KeepaliveAction::Wait);
+        assert_eq!(keepalive.deadline(), now + Duration::from_secs(60));
+        assert_eq!(
+            keepalive.poll(0, 100, start + Duration::from_secs(60)),
+            KeepaliveAction::Wait
+        );
+
+        let now = start + Duration::from_secs(110);
+        assert_eq!(keepalive.poll(0, 100, now), KeepaliveAction::Ping);
+        keepalive.ping_sent(101);
+        assert_eq!(
+            keepalive.poll(0, 101, now + Duration::from_secs(60)),
+            KeepaliveAction::Ping
+        );
+    }
+
+    #[test]
+    fn delivered_stanzas_count_as_activity() {
+        let start = Instant::now();
+        let mut keepalive = Keepalive::new(Duration::from_secs(60), Duration::from_secs(90), start);
+
+        keepalive.record_activity(start + Duration::from_secs(50));
+        assert_eq!(
+            keepalive.poll(0, 0, start + Duration::from_secs(90)),
+            KeepaliveAction::Ping
+        );
+        assert_eq!(
+            keepalive.poll(0, 0, start + Duration::from_secs(140)),
+            KeepaliveAction::Disconnect
+        );
+    }
+
+    #[tokio::test]
+    async fn silent_peer_times_out_before_stream_header() {
+        let (connection, _peer) = tokio::io::duplex(64);
+        let parser_config = ParserConfig {
+            kind: ParserKind::RustyXml,
+            limits: ElementLimits::default(),
+        };
+        let mut stream = XmppStream::new(FakeConnection::new(connection), parser_config);
+
+        let frame = next_frame(&mut stream, Duration::from_millis(10)).await;
+
+        let err = frame.unwrap().err().unwrap();
+        assert_eq!(
+            err.downcast_ref::<StreamError>(),
+            Some(&StreamError::ConnectionTimeout)
+        );
+    }
+
+    #[tokio::test]
+    async fn open_stream_is_closed_with_system_shutdown() {
+        let (connection, mut peer) = tokio::io::duplex(1024);
+        let parser_config = ParserConfig {
+            kind: ParserKind::RustyXml,
+            limits: ElementLimits::default(),
+        };
+        let mut stream = XmppStream::new(FakeConnection::new(connection), parser_config);
+        let header = StreamHeader {
+            from: Some("localhost".parse().unwrap()),
+            to: None,
+            id: None,
+            language: None,
+            stream_namespace: None,
+            content_namespace: None,
+            version: None,
+        };
+        stream
+            .writer()
+            .write_stream_header(&header, true)
+            .await
+            .unwrap();
+        let (shutdown_tx, mut shutdown_rx) = watch::channel(false);
+
+        let closing = tokio::spawn(async move {
+            let error = shutdown_requested(&mut shutdown_rx).await;
+            close_with_error(&mut stream, &error, None).await.unwrap();
+        });
+        tokio::task::yield_now().await;
+        assert!(!closing.is_finished());
+        shutdown_tx.send(true).unwrap();
+        closing.await.unwrap();
+
+        let mut received = String::new();
+        while !received.contains("</stream:stream>") {
+            let mut buffer = [0u8; 256];
+            let n = peer.read(&mut buffer).await.unwrap();
+            assert_ne!(n, 0);
+            received.push_str(std::str::from_utf8(&buffer[..n]).unwrap());
+        }
+        assert!(received.contains("<system-shutdown"));
+    }
+
+    #[tokio::test]
+    async fn closing_waits_for_the_peer_to_close() {
+        let (connection, mut peer) = tokio::io::duplex(1024);
+        let parser_config = ParserConfig {
+            kind: ParserKind::RustyXml,
+            limits: ElementLimits::default(),
+        };
+        let mut stream = XmppStream::new(FakeConnection::new(connection), parser_config);
+        peer.write_all(
+            b"<?xml version='1.0'?><stream:stream xmlns='jabber:client' \
+            xmlns:stream='http://etherx.jabber.org/streams' version='1.0'>",
+        )
+        .await
+        .unwrap();
+        let header = stream.reader().next().await.unwrap().unwrap();
+        assert!(matches!(header, Frame::StreamStart(_)));
+
+        let closing = tokio::spawn(async move {
+            discard_until_closed(&mut stream, Duration::from_secs(5)).await;
+        });
+        peer.write_all(b"<message><body>in flight</body></message>")
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!closing.is_finished());
+
+        peer.write_all(b"</stream:stream>").await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), closing)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn closing_gives_up_on_a_silent_peer() {
+        let (connection, _peer) = tokio::io::duplex(64);
+        let parser_config = ParserConfig {
+            kind: ParserKind::RustyXml,
+            limits: ElementLimits::default(),
+        };
+        let mut stream = XmppStream::new(FakeConnection::new(connection), parser_config);
+
+        let closed = tokio::time::timeout(
+            Duration::from_secs(5),
+            discard_until_closed(&mut stream, Duration::from_millis(10)),
+        )
+        .await;
+        assert!(closed.is_ok());
+    }
+
+    #[tokio::test]
+    async fn dropped_shutdown_sender_leaves_stream_open() {
+        let (shutdown_tx, mut shutdown_rx) = watch::channel(false);
+        drop(shutdown_tx);
+
+        let waiting = tokio::time::timeout(
+            Duration::from_millis(10),
+            shutdown_requested(&mut shutdown_rx),
+        )
+        .await;
+        assert!(waiting.is_err());
+    }
+
+    fn plaintext_key(features: Vec<StreamFeatures>) -> FeaturesCacheKey {
+        FeaturesCacheKey {
+            features,
+            secure: false,
+            authenticated: false,
+            channel_binding: None,
+        }
+    }
+
+    fn tls_only_mechanisms() -> SaslMechanisms {
+        SaslMechanisms {
+            external: SecurityLevel::AuthenticatedTls,
+            plain: SecurityLevel::Tls,
+            scram_sha1: SecurityLevel::Tls,
+            scram_sha1_plus: SecurityLevel::TlsWithChannelBinding,
+            scram_sha256: SecurityLevel::Tls,
+            scram_sha256_plus: SecurityLevel::TlsWithChannelBinding,
+            allow_anonymous: false,
+        }
+    }
+
+    #[test]
+    fn sasl_is_not_advertised_without_available_mechanism() {
+        let key = plaintext_key(vec![StreamFeatures::Tls, StreamFeatures::Authentication]);
+
+        let features = features_element(&key, &tls_only_mechanisms());
+
+        let names = features
+            .children
+            .iter()
+            .filter_map(|child| match child {
+                Node::Element(element) => Some(element.name.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["starttls"]);
+        assert!(can_authenticate(&key, &tls_only_mechanisms()));
+    }
+
+    #[test]
+    fn authentication_is_impossible_without_tls_or_mechanism() {
+        let key = plaintext_key(vec![StreamFeatures::Authentication]);
+
+        assert!(!can_authenticate(&key, &tls_only_mechanisms()));
+        assert!(can_authenticate(&key, &SaslMechanisms::default()));
+
+        let key = plaintext_key(vec![
+            StreamFeatures::Authentication,
+            StreamFeatures::Registration,
+        ]);
+        assert!(!can_authenticate(&key, &tls_only_mechanisms()));
+    }
+
+    fn advertised_mechanisms(key: &FeaturesCacheKey) -> Vec<String> {
+        features_element(key, &SaslMechanisms::default())
+            .get_child("mechanisms", Some(namespaces::XMPP_SASL))
+            .map(|mechanisms| mechanisms.children().map(Element::get_text).collect())
+            .unwrap_or_default()
+    }
+
+    #[tokio::test]
+    async fn direct_tls_stream_does_not_offer_starttls() {
+        let (connection, _peer) = tokio::io::duplex(64);
+        let mut connection = FakeConnection::new(connection);
+        connection.starttls_allowed = true;
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(ResolvesServerCertUsingSni::new()));
+        let connection = ConnectionBuilder::new()
+            .implicit_tls(Some(Arc::new(config)))
+            .build(connection)
+            .await
+            .unwrap();
+        let parser_config = ParserConfig {
+            kind: ParserKind::RustyXml,
+            limits: ElementLimits::default(),
+        };
+        let stream = XmppStream::new(connection, parser_config);
+
+        assert!(stream.is_secure());
+        assert!(!offers_starttls(&stream, &HashSet::new()));
+    }
+
+    #[tokio::test]
+    async fn plain_is_only_advertised_after_starttls() {
+        let (connection, _peer) = tokio::io::duplex(64);
+        let mut connection = FakeConnection::new(connection);
+        connection.starttls_allowed = true;
+        let parser_config = ParserConfig {
+            kind: ParserKind::RustyXml,
+            limits: ElementLimits::default(),
+        };
+        let mut stream = XmppStream::new(connection, parser_config);
+
+        let features = vec![StreamFeatures::Tls, StreamFeatures::Authentication];
+        let key = FeaturesCacheKey::for_stream(features, &stream);
+        assert!(!advertised_mechanisms(&key).contains(&"PLAIN".to_string()));
+
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(ResolvesServerCertUsingSni::new()));
+        stream
+            .upgrade_to_tls_with_config(Arc::new(config))
+            .await
+            .unwrap();
+        stream.reset();
+
+        let key = FeaturesCacheKey::for_stream(vec![StreamFeatures::Authentication], &stream);
+        assert!(advertised_mechanisms(&key).contains(&"PLAIN".to_string()));
+    }
+
+    #[test]
+    fn required_header_from_may_be_present() {
+        let from = "juliet@localhost".parse::<Jid>().unwrap();
+        let result = check_header_from(HeaderFromPolicy::Required, Some(&from));
+        assert_eq!(result, Ok(()));
+    }
+
+    #[test]
+    fn required_header_from_must_not_be_absent() {
+        let result = check_header_from(HeaderFromPolicy::Required, None);
+        assert_eq!(result, Err(StreamError::InvalidFrom));
+    }
+
+    #[test]
+    fn forbidden_header_from_must_not_be_present() {
+        let from = "juliet@localhost".parse::<Jid>().unwrap();
+        let result = check_header_from(HeaderFromPolicy::Forbidden, Some(&from));
+        assert_eq!(result, Err(StreamError::InvalidFrom));
+        assert_eq!(check_header_from(HeaderFromPolicy::Forbidden, None), Ok(()));
+    }
+
+    const CLIENT_HEADER: &str = "<stream:stream xmlns='jabber:client' \
+        xmlns:stream='http://etherx.jabber.org/streams' to='localhost' version='1.0'>";
+
+    // The far end of a whole `InboundStream`, run with `Settings::init_for_tests`.
+    struct TestPeer {
+        connection: tokio::io::DuplexStream,
+        received: String,
+        // the stream stays open as long as this is around
+        _shutdown: watch::Sender<bool>,
+    }
+
+    impl TestPeer {
+        fn connect(router: &RouterHandle, store: &StoreHandle) -> Self {
+            Self::connect_over(FakeConnection::new, router, store)
+        }
+
+        fn connect_over(
+            connection: impl FnOnce(tokio::io::DuplexStream) -> FakeConnection,
+            router: &RouterHandle,
+            store: &StoreHandle,
+        ) -> Self {
+            let settings = Settings::init_for_tests();
+            let _ = IqHandlers::init(settings);
+
+            let (server, client) = tokio::io::duplex(16384);
+            let (shutdown_tx, shutdown_rx) = watch::channel(false);
+            let mut stream = InboundStream::new(
+                connection(server),
+                router.clone(),
+                store.clone(),
+                shutdown_rx,
+            );
+            tokio::spawn(async move { stream.handle().await });
+
+            TestPeer {
+                connection: client,
+                received: String::new(),
+                _shutdown: shutdown_tx,
+            }
+        }
+
+        async fn send(&mut self, xml: &str) {
+            self.connection.write_all(xml.as_bytes()).await.unwrap();
+        }
+
+        // Everything received up to and including `expected`, which is taken off what is left.
+        async fn receive_until(&mut self, expected: &str) -> String {
+            loop {
+                if let Some(position) = self.received.find(expected) {
+                    let rest = self.received.split_off(position + expected.len());
+                    return std::mem::replace(&mut self.received, rest);
+                }
+
+                let mut buffer = [0u8; 4096];
+                let read =
+                    tokio::time::timeout(Duration::from_secs(5), self.connection.read(&mut buffer))
+                        .await
+                        .unwrap_or_else(|_| panic!("no `{expected}` in {:?}", self.received))
+                        .unwrap();
+                assert_ne!(read, 0, "no `{expected}` in {:?}", self.received);
+                self.received
+                    .push_str(std::str::from_utf8(&buffer[..read]).unwrap());
+            }
+        }
+
+        // Logs in with ANONYMOUS and binds `resource`, returning the bound JID.
+        async fn log_in(&mut self, resource: &str) -> Jid {
+            self.authenticate(CLIENT_HEADER).await;
+            self.receive_until("</stream:features>").await;
+            self.bind(resource).await
+        }
+
+        // Authenticates with ANONYMOUS on a client stream and restarts it with `header`.
+        async fn authenticate(&mut self, header: &str) {
+            self.authenticate_with(header, "ANONYMOUS", "=").await;
+        }
+
+        async fn authenticate_with(&mut self, header: &str, mechanism: &str, payload: &str) {
+            self.send(&format!("<?xml version='1.0'?>{CLIENT_HEADER}"))
+                .await;
+            self.receive_until("</stream:features>").await;
+            self.send(&format!(
+                "<auth xmlns='urn:ietf:params:xml:ns:xmpp-sasl' mechanism='{mechanism}'>\
+                    {payload}</auth>"
+            ))
+            .await;
+            self.receive_until("<success").await;
+            self.send(header).await;
+        }
+
+        async fn bind(&mut self, resource: &str) -> Jid {
+            self.send(&format!(
+                "<iq type='set' id='bind1'>\
+                    <bind xmlns='urn:ietf:params:xml:ns:xmpp-bind'>\
+                        <resource>{resource}</resource>\
+                    </bind>\
+                </iq>"
+            ))
+            .await;
+            let bound = self.receive_until("</jid>").await;
+            bound[bound.rfind('>').unwrap() + 1..bound.len() - "</jid>".len()]
+                .parse()
+                .unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn client_stanzas_are_from_the_bound_jid() {
+        let store = StoreHandle::new(FakeStoreBackend::default());
+        let router = RouterHandle::new(store.clone());
+        let romeo: Jid = "romeo@localhost/orchard".parse().unwrap();
+        let (tx, mut romeo_rx) = mpsc::channel(8);
+        let _registration = router
+            .register(romeo.clone(), SecurityContext::default(), tx)
+            .await;
+        let mut juliet = TestPeer::connect(&router, &store);
+        let juliet_jid = juliet.log_in("balcony").await;
+
+        juliet
+            .send(
+                "<message to='romeo@localhost/orchard' from='nurse@localhost/chamber' \
+                    type='chat'><body>Wherefore art thou?</body></message>",
+            )
+            .await;
+        juliet
+            .send("<message to='romeo@localhost/orchard' type='chat'><body>Hi</body></message>")
+            .await;
+
+        for _ in 0..2 {
+            let received = tokio::time::timeout(Duration::from_secs(5), romeo_rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(received.from(), Ok(Some(juliet_jid.clone())));
+        }
+        assert!(juliet_jid.to_string().ends_with("/balcony"));
+    }
+
+    #[tokio::test]
+    async fn unbound_stream_gets_nothing_for_its_account() {
+        // "\0juliet\0balcony"
+        const PLAIN: &str = "AGp1bGlldABiYWxjb255";
+        let stored_password =
+            StoredPasswordArgon2::new_with_pepper("balcony", None, &Default::default()).unwrap();
+        let store = StoreHandle::new(FakeStoreBackend {
+            stored_password_argon2: Some(stored_password.to_string()),
+            users: vec!["juliet@localhost".parse().unwrap()],
+            ..Default::default()
+        });
+        let router = RouterHandle::new(store.clone());
+        let secure = |connection| {
+            let mut connection = FakeConnection::new(connection);
+            connection.secure = true;
+            connection
+        };
+        let mut balcony = TestPeer::connect_over(secure, &router, &store);
+        balcony
+            .authenticate_with(CLIENT_HEADER, "PLAIN", PLAIN)
+            .await;
+        balcony.receive_until("</stream:features>").await;
+        balcony.bind("balcony").await;
+        balcony.send("<presence/>").await;
+        // answered only once the presence before it has been passed on to the router
+        balcony
+            .send("<iq to='localhost' type='get' id='sync'><ping xmlns='urn:xmpp:ping'/></iq>")
+            .await;
+        balcony.receive_until("sync").await;
+        let mut unbound = TestPeer::connect_over(secure, &router, &store);
+        unbound
+            .authenticate_with(CLIENT_HEADER, "PLAIN", PLAIN)
+            .await;
+        unbound.receive_until("</stream:features>").await;
+
+        let message = Element::parse(
+            "<message xmlns='jabber:client' to='juliet@localhost' from='romeo@localhost/orchard' \
+                type='chat'><body>Wherefore art thou?</body></message>",
+        )
+        .unwrap();
+        let outcome = router.route(Stanza { element: message }).await;
+
+        assert_eq!(outcome, Ok(DeliveryOutcome::Delivered));
+        balcony.receive_until("Wherefore art thou?").await;
+        let mut buffer = [0u8; 256];
+        let read = tokio::time::timeout(
+            Duration::from_millis(100),
+            unbound.connection.read(&mut buffer),
+        )
+        .await;
+        assert!(read.is_err(), "unbound stream received {:?}", read);
+    }
+
+    #[tokio::test]
+    async fn lost_connection_leaves_the_session_resumable() {
+        // "\0juliet\0balcony"
+        const PLAIN: &str = "AGp1bGlldABiYWxjb255";
+        let stored_password =
+            StoredPasswordArgon2::new_with_pepper("balcony", None, &Default::default()).unwrap();
+        let store = StoreHandle::new(FakeStoreBackend {
+            stored_password_argon2: Some(stored_password.to_string()),
+            users: vec!["juliet@localhost".parse().unwrap()],
+            ..Default::default()
+        });
+        let router = RouterHandle::new(store.clone());
+        let secure = |connection| {
+            let mut connection = FakeConnection::new(connection);
+            connection.secure = true;
+            connection
+        };
+        let message = |body: &str| {
+            let xml = format!(
+                "<message xmlns='jabber:client' to='juliet@localhost/balcony' \
+                    from='romeo@localhost/orchard' type='chat'><body>{body}</body></message>"
+            );
+            Stanza {
+                element: Element::parse(&xml).unwrap(),
+            }
+        };
+        let mut balcony = TestPeer::connect_over(secure, &router, &store);
+        balcony
+            .authenticate_with(CLIENT_HEADER, "PLAIN", PLAIN)
+            .await;
+        balcony.receive_until("</stream:features>").await;
+        balcony.bind("balcony").await;
+        balcony
+            .send("<enable xmlns='urn:xmpp:sm:3' resume='true'/>")
+            .await;
+        let enabled = balcony.receive_until("/>").await;
+        let id = enabled.split(" id=\"").nth(1).unwrap();
+        let id = id[..id.find('"').unwrap()].to_string();
+        router.route(message("Wherefore art thou?")).await.unwrap();
+        balcony.receive_until("Wherefore art thou?").await;
+
+        // the connection goes away without a closing stream tag
+        let TestPeer {
+            connection,
+            _shutdown,
+            ..
+        } = balcony;
+        drop(connection);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let outcome = router.route(message("Deny thy father")).await;
+        assert_eq!(outcome, Ok(DeliveryOutcome::Delivered));
+
+        let mut resumed = TestPeer::connect_over(secure, &router, &store);
+        resumed
+            .authenticate_with(CLIENT_HEADER, "PLAIN", PLAIN)
+            .await;
+        resumed.receive_until("</stream:features>").await;
+        resumed
+            .send(&format!(
+                "<resume xmlns='urn:xmpp:sm:3' previd='{id}' h='0'/>"
+            ))
+            .await;
+        resumed.receive_until("<resumed").await;
+        // unacknowledged before the connection was lost, then routed while it was gone
+        resumed.receive_until("Wherefore art thou?").await;
+        resumed.receive_until("Deny thy father").await;
+    }
+
+    #[tokio::test]
+    async fn roster_is_answered_for_the_account() {
+        let store = StoreHandle::new(FakeStoreBackend::default());
+        let router = RouterHandle::new(store.clone());
+        let mut juliet = TestPeer::connect(&router, &store);
+        juliet.log_in("balcony").await;
+
+        juliet
+            .send("<iq type='get' id='roster1'><query xmlns='jabber:iq:roster'/></iq>")
+            .await;
+        juliet.receive_until("roster1").await;
+        let reply = juliet.receive_until("</iq>").await;
+        assert!(reply.contains("jabber:iq:roster"), "{reply}");
+
+        // as a client acknowledges a roster push, which needs no answer
+        juliet.send("<iq type='result' id='roster-push-1'/>").await;
+        juliet
+            .send("<iq to='localhost' type='get' id='sync'><ping xmlns='urn:xmpp:ping'/></iq>")
+            .await;
+        let received = juliet.receive_until("sync").await;
+        assert!(!received.contains("roster-push-1"), "{received}");
+    }
+
+    #[tokio::test]
+    async fn server_only_answers_for_what_it_hosts() {
+        let store = StoreHandle::new(FakeStoreBackend::default());
+        let router = RouterHandle::new(store.clone());
+        let mut juliet = TestPeer::connect(&router, &store);
+        juliet.log_in("balcony").await;
+        let ping = |id: &str, to: &str| {
+            format!("<iq to='{to}' type='get' id='{id}'><ping xmlns='urn:xmpp:ping'/></iq>")
+        };
+
+        // the result has no payload, unlike an error
+        juliet.send(&ping("local", "localhost")).await;
+        juliet.receive_until("local").await;
+        assert!(juliet.receive_until(">").await.ends_with("/>"));
+
+        // neither a remote domain nor an account that does not exist is for the server to answer
+        for (id, to) in [
+            ("remote", "remote.example"),
+            ("unknown", "nobody@localhost"),
+        ] {
+            juliet.send(&ping(id, to)).await;
+            juliet.receive_until(id).await;
+            let reply = juliet.receive_until("</iq>").await;
+            assert!(reply.contains("<service-unavailable"), "{reply}");
+        }
+    }
+
+    #[tokio::test]
+    async fn logged_in_client_may_not_speak_for_other_accounts() {
+        const COMPONENT_HEADER: &str = "<stream:stream xmlns='jabber:component:accept' \
+            xmlns:stream='http://etherx.jabber.org/streams' to='localhost' version='1.0'>";
+        let store = StoreHandle::new(FakeStoreBackend::default());
+        let router = RouterHandle::new(store.clone());
+        let romeo: Jid = "romeo@localhost/orchard".parse().unwrap();
+        let (tx, mut romeo_rx) = mpsc::channel(8);
+        let _registration = router.register(romeo, SecurityContext::default(), tx).await;
+        let mut mallory = TestPeer::connect(&router, &store);
+
+        // the spoofed stanza goes out together with the header, before the stream is closed
+        mallory
+            .authenticate(&format!(
+                "{COMPONENT_HEADER}<message to='romeo@localhost/orchard' \
+                    from='admin@localhost'><body>Hi</body></message>"
+            ))
+            .await;
 
-        self.stream.writer().write_xml_element(&error).await?;
-        self.stream.writer().write_stream_close().await
+        mallory.receive_until("<invalid-namespace").await;
+        mallory.receive_until("</stream:stream>").await;
+        assert!(romeo_rx.try_recv().is_err());
     }
 }
@@ -3,9 +3,13 @@ use std::{collections::HashMap, vec};
 use anyhow::{bail, Error};
 
 use crate::{
+    services::router::RouterHandle,
+    settings::ResourceConflictPolicy,
     xml::{namespaces, Element, Node},
     xmpp::{
         jid::Jid,
+        stanza::Stanza,
+        stanza_error::{StanzaError, StanzaErrorBuilder},
         stream::{Connection, XmppStream},
     },
 };
@@ -34,11 +38,15 @@ impl ResourceBindingNegotiator {
         }
     }
 
+    // A rejected request has been answered, but leaves the stream without a bound resource, so
+    // the client can ask for another one.
     pub async fn negotiate_feature<C>(
         stream: &mut XmppStream<C>,
         element: &Element,
         entity: &Option<Jid>,
-    ) -> Result<Jid, Error>
+        router: &RouterHandle,
+        conflict_policy: ResourceConflictPolicy,
+    ) -> Result<Option<Jid>, Error>
     where
         C: Connection,
     {
@@ -59,19 +67,39 @@ impl ResourceBindingNegotiator {
         };
 
         let resource = match bind_request.get_child("resource", Some(namespaces::XMPP_BIND)) {
-            Some(requested_resource) => requested_resource.get_text(),
-            None => uuid::Uuid::new_v4().to_string(),
+            Some(requested_resource) => Jid::normalize_resource(&requested_resource.get_text()),
+            None => Ok(uuid::Uuid::new_v4().to_string()),
+        };
+        let resource = match resource {
+            Ok(resource) => resource,
+            Err(err) => {
+                let error = StanzaError::BadRequest
+                    .builder()
+                    .text("en", err.to_string());
+                return Self::reject(stream, element, error).await;
+            }
         };
 
         let Some(entity) = entity else {
             bail!("entity to bind is unknown");
         };
 
-        let bound_entity = entity.bind(resource);
+        let mut bound_entity = entity.bind(resource);
+        if router.is_registered(bound_entity.clone()).await {
+            // RFC 6120, section 7.7.2.2
+            match conflict_policy {
+                ResourceConflictPolicy::Reject => {
+                    return Self::reject(stream, element, StanzaError::Conflict).await;
+                }
+                ResourceConflictPolicy::Generate => {
+                    bound_entity = entity.bind(uuid::Uuid::new_v4().to_string());
+                }
+            }
+        }
 
         let bind_response = Element {
             name: "iq".to_string(),
-            namespace: None,
+            namespace: Some(namespaces::XMPP_CLIENT.to_string()),
             attributes: vec![
                 (("id".to_string(), None), request_id.to_string()),
                 (("type".to_string(), None), "result".to_string()),
@@ -89,15 +117,161 @@ impl ResourceBindingNegotiator {
                 .collect(),
                 children: vec![Node::Element(Element {
                     name: "jid".to_string(),
-                    namespace: None,
+                    namespace: Some(namespaces::XMPP_BIND.to_string()),
                     attributes: HashMap::new(),
                     children: vec![Node::Text(format!("{}", bound_entity))],
                 })],
             })],
         };
 
-        stream.writer().write_xml_element(&bind_response).await?;
+        let bind_response = Stanza {
+            element: bind_response,
+        };
+        stream.writer().write_stanza(&bind_response).await?;
+
+        Ok(Some(bound_entity))
+    }
+
+    async fn reject<C>(
+        stream: &mut XmppStream<C>,
+        element: &Element,
+        error: impl Into<StanzaErrorBuilder>,
+    ) -> Result<Option<Jid>, Error>
+    where
+        C: Connection,
+    {
+        let request = Stanza {
+            element: element.clone(),
+        };
+        if let Some(reply) = request.error_reply(error, None) {
+            stream.writer().write_stanza(&reply).await?;
+        }
+
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+    use tokio::sync::mpsc;
+
+    use crate::inbound::connection::fake::FakeConnection;
+    use crate::inbound::SecurityContext;
+    use crate::services::router::ManagementCommand;
+    use crate::services::store::fake::FakeStoreBackend;
+    use crate::services::store::StoreHandle;
+    use crate::xml::stream_parser::{ElementLimits, ParserConfig, ParserKind};
+
+    use super::*;
+
+    fn bind_request(resource: &str) -> Element {
+        let resource = Element {
+            name: "resource".to_string(),
+            namespace: Some(namespaces::XMPP_BIND.to_string()),
+            attributes: HashMap::new(),
+            children: vec![Node::Text(resource.to_string())],
+        };
+
+        Element {
+            name: "iq".to_string(),
+            namespace: Some(namespaces::XMPP_CLIENT.to_string()),
+            attributes: vec![
+                (("id".to_string(), None), "bind_1".to_string()),
+                (("type".to_string(), None), "set".to_string()),
+            ]
+            .into_iter()
+            .collect(),
+            children: vec![Node::Element(Element {
+                name: "bind".to_string(),
+                namespace: Some(namespaces::XMPP_BIND.to_string()),
+                attributes: HashMap::new(),
+                children: vec![Node::Element(resource)],
+            })],
+        }
+    }
+
+    fn juliet() -> Jid {
+        Jid::new(Some("juliet".to_string()), "localhost".to_string(), None)
+    }
+
+    // Returns the outcome of requesting `resource` along with everything written to the client.
+    async fn bind(
+        router: &RouterHandle,
+        resource: &str,
+        policy: ResourceConflictPolicy,
+    ) -> (Option<Jid>, String) {
+        let (connection, mut peer) = tokio::io::duplex(4096);
+        let parser_config = ParserConfig {
+            kind: ParserKind::RustyXml,
+            limits: ElementLimits::default(),
+        };
+        let mut stream = XmppStream::new(FakeConnection::new(connection), parser_config);
+
+        let bound = ResourceBindingNegotiator::negotiate_feature(
+            &mut stream,
+            &bind_request(resource),
+            &Some(juliet()),
+            router,
+            policy,
+        )
+        .await
+        .unwrap();
+
+        drop(stream);
+        let mut output = String::new();
+        peer.read_to_string(&mut output).await.unwrap();
+        (bound, output)
+    }
+
+    async fn bind_taken_resource(policy: ResourceConflictPolicy) -> (Option<Jid>, String) {
+        let router = RouterHandle::new(StoreHandle::new(FakeStoreBackend::default()));
+        let (tx, _rx) = mpsc::channel(8);
+        let jid = juliet().bind("balcony".to_string());
+        let command = ManagementCommand::Register(jid, SecurityContext::default(), tx);
+        router.management.send(command).await.unwrap();
+
+        bind(&router, "balcony", policy).await
+    }
+
+    #[tokio::test]
+    async fn taken_resource_is_a_conflict() {
+        let (bound, output) = bind_taken_resource(ResourceConflictPolicy::Reject).await;
+
+        assert!(bound.is_none());
+        assert!(output.contains("type=\"error\""));
+        assert!(output.contains("<conflict"));
+    }
+
+    #[tokio::test]
+    async fn taken_resource_is_replaced_by_a_generated_one() {
+        let (bound, output) = bind_taken_resource(ResourceConflictPolicy::Generate).await;
+
+        let bound = bound.unwrap();
+        assert_eq!(bound.to_bare().to_string(), "juliet@localhost");
+        assert_ne!(bound.to_string(), "juliet@localhost/balcony");
+        assert!(output.contains(&bound.to_string()));
+    }
+
+    #[tokio::test]
+    async fn invalid_resources_are_bad_requests() {
+        let router = RouterHandle::new(StoreHandle::new(FakeStoreBackend::default()));
+        let too_long = "a".repeat(1024);
+
+        for resource in ["", too_long.as_str(), "bal\u{7}cony"] {
+            let (bound, output) = bind(&router, resource, ResourceConflictPolicy::Reject).await;
+
+            assert!(bound.is_none());
+            assert!(output.contains("<bad-request"));
+        }
+    }
+
+    #[tokio::test]
+    async fn requested_resource_is_normalized() {
+        let router = RouterHandle::new(StoreHandle::new(FakeStoreBackend::default()));
+
+        let (bound, _) = bind(&router, "cafe\u{301}", ResourceConflictPolicy::Reject).await;
 
-        Ok(bound_entity)
+        assert_eq!(bound.unwrap(), juliet().bind("caf\u{e9}".to_string()));
     }
 }
@@ -1,2 +1,7 @@
+pub mod builder;
+pub mod counting;
 pub mod debug;
+#[cfg(test)]
+pub mod fake;
+pub mod proxy_protocol;
 pub mod tcp;
@@ -0,0 +1,362 @@
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use anyhow::Error;
+use futures::Future;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use tokio_rustls::rustls::ServerConfig;
+use uuid::Uuid;
+
+use crate::settings::Settings;
+use crate::xmpp::stream::{Connection, TlsInfo};
+
+use super::counting::{ConnectionCounters, CountingConnection};
+use super::debug::DebugConnection;
+use super::proxy_protocol::read_proxy_header;
+
+// Composes the layers every inbound connection is made of, outermost first: the optional
+// recorder, the optional implicit TLS session, and the byte counters on the wire.
+#[derive(Default)]
+pub struct ConnectionBuilder {
+    proxy_protocol: bool,
+    implicit_tls: Option<Arc<ServerConfig>>,
+    recording_directory: Option<PathBuf>,
+    recording_rotate_after: Option<u64>,
+}
+
+impl ConnectionBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_settings(settings: &Settings) -> Self {
+        let connection = &settings.connection;
+        let implicit_tls = connection
+            .implicit_tls
+            .then(|| settings.tls.server_config.config.clone());
+
+        Self::new()
+            .proxy_protocol(connection.proxy_protocol)
+            .implicit_tls(implicit_tls)
+            .recording_directory(connection.recording_directory.clone())
+            .recording_rotate_after(connection.recording_rotate_after)
+    }
+
+    pub fn proxy_protocol(mut self, enabled: bool) -> Self {
+        self.proxy_protocol = enabled;
+        self
+    }
+
+    pub fn implicit_tls(mut self, config: Option<Arc<ServerConfig>>) -> Self {
+        self.implicit_tls = config;
+        self
+    }
+
+    pub fn recording_directory(mut self, directory: Option<PathBuf>) -> Self {
+        self.recording_directory = directory;
+        self
+    }
+
+    pub fn recording_rotate_after(mut self, max_bytes: Option<u64>) -> Self {
+        self.recording_rotate_after = max_bytes;
+        self
+    }
+
+    pub async fn build<C>(&self, mut connection: C) -> Result<LayeredConnection<C>, Error>
+    where
+        C: Connection + Send + 'static,
+    {
+        // the PROXY header precedes everything else, including the TLS handshake
+        let source_address = if self.proxy_protocol {
+            read_proxy_header(&mut connection).await?
+        } else {
+            None
+        };
+
+        let mut counting = CountingConnection::new(connection);
+        let counters = counting.counters();
+
+        if let Some(config) = &self.implicit_tls {
+            counting = counting.upgrade(config.clone())?.await?;
+        }
+
+        let layers = match &self.recording_directory {
+            Some(directory) => {
+                let recorded =
+                    DebugConnection::try_new_in(counting, directory, self.recording_rotate_after);
+                Layers::Recorded(recorded.await?)
+            }
+            None => Layers::Direct(counting),
+        };
+
+        Ok(LayeredConnection {
+            layers,
+            counters,
+            source_address,
+        })
+    }
+}
+
+enum Layers<C>
+where
+    C: Connection + Send + 'static,
+{
+    Direct(CountingConnection<C>),
+    Recorded(DebugConnection<CountingConnection<C>>),
+}
+
+pub struct LayeredConnection<C>
+where
+    C: Connection + Send + 'static,
+{
+    layers: Layers<C>,
+    counters: Arc<ConnectionCounters>,
+    source_address: Option<SocketAddr>,
+}
+
+impl<C> LayeredConnection<C>
+where
+    C: Connection + Send + 'static,
+{
+    pub fn counters(&self) -> Arc<ConnectionCounters> {
+        self.counters.clone()
+    }
+
+    pub fn recording_id(&self) -> Option<Uuid> {
+        match &self.layers {
+            Layers::Direct(_) => None,
+            Layers::Recorded(connection) => Some(connection.uuid()),
+        }
+    }
+
+    // The client address reported by a PROXY header, if any.
+    pub fn source_address(&self) -> Option<SocketAddr> {
+        self.source_address
+    }
+
+    fn inner_io(&mut self) -> Pin<&mut dyn AsyncIo> {
+        match &mut self.layers {
+            Layers::Direct(connection) => Pin::new(connection),
+            Layers::Recorded(connection) => Pin::new(connection),
+        }
+    }
+}
+
+trait AsyncIo: AsyncRead + AsyncWrite + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> AsyncIo for T {}
+
+macro_rules! delegate {
+    ($self:ident.$method:ident()) => {
+        match &$self.layers {
+            Layers::Direct(connection) => connection.$method(),
+            Layers::Recorded(connection) => connection.$method(),
+        }
+    };
+}
+
+impl<C> Connection for LayeredConnection<C>
+where
+    C: Connection + Send + 'static,
+{
+    type Upgrade = Pin<Box<dyn Future<Output = Result<Self, Error>> + Send>>;
+
+    fn upgrade(self, config: Arc<ServerConfig>) -> Result<Self::Upgrade, Error> {
+        let LayeredConnection {
+            layers,
+            counters,
+            source_address,
+        } = self;
+
+        let upgrade: Pin<Box<dyn Future<Output = Result<Layers<C>, Error>> + Send>> = match layers {
+            Layers::Direct(connection) => {
+                let upgrade = connection.upgrade(config)?;
+                Box::pin(async move { Ok(Layers::Direct(upgrade.await?)) })
+            }
+            Layers::Recorded(connection) => {
+                let upgrade = connection.upgrade(config)?;
+                Box::pin(async move { Ok(Layers::Recorded(upgrade.await?)) })
+            }
+        };
+
+        Ok(Box::pin(async move {
+            Ok(LayeredConnection {
+                layers: upgrade.await?,
+                counters,
+                source_address,
+            })
+        }))
+    }
+
+    fn is_starttls_allowed(&self) -> bool {
+        delegate!(self.is_starttls_allowed())
+    }
+
+    fn is_secure(&self) -> bool {
+        delegate!(self.is_secure())
+    }
+
+    fn is_authenticated(&self) -> bool {
+        delegate!(self.is_authenticated())
+    }
+
+    fn tls_info(&self) -> Option<TlsInfo> {
+        delegate!(self.tls_info())
+    }
+
+    fn tls_exporter(&self) -> Option<Vec<u8>> {
+        delegate!(self.tls_exporter())
+    }
+
+    fn tls_server_end_point(&self) -> Option<Vec<u8>> {
+        delegate!(self.tls_server_end_point())
+    }
+
+    fn peer_certificate(&self) -> Option<CertificateDer<'static>> {
+        delegate!(self.peer_certificate())
+    }
+}
+
+impl<C> AsyncRead for LayeredConnection<C>
+where
+    C: Connection + Send + 'static,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        self.inner_io().poll_read(cx, buf)
+    }
+}
+
+impl<C> AsyncWrite for LayeredConnection<C>
+where
+    C: Connection + Send + 'static,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.inner_io().poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.inner_io().poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.inner_io().poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::rustls::server::ResolvesServerCertUsingSni;
+
+    use crate::inbound::connection::fake::FakeConnection;
+
+    use super::*;
+
+    fn tls_config() -> Arc<ServerConfig> {
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(ResolvesServerCertUsingSni::new()));
+        Arc::new(config)
+    }
+
+    fn recording_directory() -> PathBuf {
+        std::env::temp_dir()
+    }
+
+    #[tokio::test]
+    async fn plain_connection_is_not_secure_or_recorded() {
+        let (stream, _peer) = duplex(64);
+        let connection = ConnectionBuilder::new()
+            .build(FakeConnection::new(stream))
+            .await
+            .unwrap();
+
+        assert!(!connection.is_secure());
+        assert_eq!(connection.recording_id(), None);
+        assert_eq!(connection.source_address(), None);
+    }
+
+    #[tokio::test]
+    async fn implicit_tls_connection_is_secure() {
+        let (stream, _peer) = duplex(64);
+        let connection = ConnectionBuilder::new()
+            .implicit_tls(Some(tls_config()))
+            .build(FakeConnection::new(stream))
+            .await
+            .unwrap();
+
+        assert!(connection.is_secure());
+        assert!(connection.tls_info().is_some());
+        assert_eq!(connection.recording_id(), None);
+    }
+
+    #[tokio::test]
+    async fn recorded_connection_is_recorded() {
+        let (stream, _peer) = duplex(64);
+        let connection = ConnectionBuilder::new()
+            .recording_directory(Some(recording_directory()))
+            .build(FakeConnection::new(stream))
+            .await
+            .unwrap();
+
+        assert!(!connection.is_secure());
+        let uuid = connection.recording_id().unwrap();
+        let recording = recording_directory().join(format!("{uuid}.in.xml"));
+        assert!(recording.exists());
+    }
+
+    #[tokio::test]
+    async fn recorded_implicit_tls_connection_stays_recorded_and_secure() {
+        let (stream, _peer) = duplex(64);
+        let connection = ConnectionBuilder::new()
+            .implicit_tls(Some(tls_config()))
+            .recording_directory(Some(recording_directory()))
+            .build(FakeConnection::new(stream))
+            .await
+            .unwrap();
+
+        assert!(connection.is_secure());
+        assert!(connection.recording_id().is_some());
+    }
+
+    #[tokio::test]
+    async fn proxied_connection_reports_source_and_counts_bytes() {
+        let (stream, mut peer) = duplex(256);
+        peer.write_all(b"PROXY TCP6 2001:db8::1 2001:db8::2 40000 5222\r\n<stream:stream>")
+            .await
+            .unwrap();
+
+        let mut connection = ConnectionBuilder::new()
+            .proxy_protocol(true)
+            .build(FakeConnection::new(stream))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            connection.source_address(),
+            Some("[2001:db8::1]:40000".parse().unwrap())
+        );
+
+        let mut buffer = [0; 15];
+        connection.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, b"<stream:stream>");
+        assert_eq!(connection.counters().bytes_read(), 15);
+
+        connection.write_all(b"<stream:stream>").await.unwrap();
+        assert_eq!(connection.counters().bytes_written(), 15);
+    }
+}
@@ -0,0 +1,148 @@
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{ready, Context, Poll},
+};
+
+use anyhow::Error;
+use futures::Future;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use tokio_rustls::rustls::ServerConfig;
+
+use crate::xmpp::stream::{Connection, TlsInfo};
+
+#[derive(Debug, Default)]
+pub struct ConnectionCounters {
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+}
+
+impl ConnectionCounters {
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written.load(Ordering::Relaxed)
+    }
+}
+
+// Counts the bytes exchanged on the wire. The counters survive upgrading the connection.
+pub struct CountingConnection<C>
+where
+    C: Connection,
+{
+    inner: C,
+    counters: Arc<ConnectionCounters>,
+}
+
+impl<C> CountingConnection<C>
+where
+    C: Connection,
+{
+    pub fn new(inner: C) -> Self {
+        CountingConnection {
+            inner,
+            counters: Arc::default(),
+        }
+    }
+
+    pub fn counters(&self) -> Arc<ConnectionCounters> {
+        self.counters.clone()
+    }
+}
+
+impl<C> Connection for CountingConnection<C>
+where
+    C: Connection + Send + 'static,
+{
+    type Upgrade = Pin<Box<dyn Future<Output = Result<Self, Error>> + Send>>;
+
+    fn upgrade(self, config: Arc<ServerConfig>) -> Result<Self::Upgrade, Error> {
+        let upgrade = self.inner.upgrade(config)?;
+        let counters = self.counters;
+
+        Ok(Box::pin(async move {
+            let inner = upgrade.await?;
+            Ok(CountingConnection { inner, counters })
+        }))
+    }
+
+    fn is_starttls_allowed(&self) -> bool {
+        self.inner.is_starttls_allowed()
+    }
+
+    fn is_secure(&self) -> bool {
+        self.inner.is_secure()
+    }
+
+    fn is_authenticated(&self) -> bool {
+        self.inner.is_authenticated()
+    }
+
+    fn tls_info(&self) -> Option<TlsInfo> {
+        self.inner.tls_info()
+    }
+
+    fn tls_exporter(&self) -> Option<Vec<u8>> {
+        self.inner.tls_exporter()
+    }
+
+    fn tls_server_end_point(&self) -> Option<Vec<u8>> {
+        self.inner.tls_server_end_point()
+    }
+
+    fn peer_certificate(&self) -> Option<CertificateDer<'static>> {
+        self.inner.peer_certificate()
+    }
+}
+
+impl<C> AsyncRead for CountingConnection<C>
+where
+    C: Connection,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let filled_before = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        let bytes_read = (buf.filled().len() - filled_before) as u64;
+        self.counters
+            .bytes_read
+            .fetch_add(bytes_read, Ordering::Relaxed);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<C> AsyncWrite for CountingConnection<C>
+where
+    C: Connection,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let bytes_written = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        self.counters
+            .bytes_written
+            .fetch_add(bytes_written as u64, Ordering::Relaxed);
+
+        Poll::Ready(Ok(bytes_written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
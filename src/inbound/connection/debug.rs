@@ -1,4 +1,5 @@
 use std::{
+    path::{Path, PathBuf},
     pin::Pin,
     sync::Arc,
     task::{ready, Poll},
@@ -7,17 +8,20 @@ use std::{
 use anyhow::Error;
 use futures::Future;
 use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::rustls::pki_types::CertificateDer;
 use tokio_rustls::rustls::ServerConfig;
 use uuid::Uuid;
 
 use crate::utils::recorder::StreamRecorder;
-use crate::xmpp::stream::Connection;
+use crate::xmpp::stream::{Connection, TlsInfo};
 
 pub struct DebugConnection<C>
 where
     C: Connection,
 {
     uuid: Uuid,
+    directory: PathBuf,
+    rotate_after: Option<u64>,
     recorder: StreamRecorder<C>,
 }
 
@@ -25,11 +29,22 @@ impl<C> DebugConnection<C>
 where
     C: Connection,
 {
-    pub async fn try_new(inner: C) -> std::io::Result<Self> {
+    pub async fn try_new_in(
+        inner: C,
+        directory: &Path,
+        rotate_after: Option<u64>,
+    ) -> std::io::Result<Self> {
         let uuid = uuid::Uuid::new_v4();
-        let recorder = StreamRecorder::try_new(inner, uuid).await?;
-
-        Ok(DebugConnection { uuid, recorder })
+        let recorder = StreamRecorder::try_new_in(inner, uuid, directory)
+            .await?
+            .rotate_after(rotate_after);
+
+        Ok(DebugConnection {
+            uuid,
+            directory: directory.to_path_buf(),
+            rotate_after,
+            recorder,
+        })
     }
 
     pub fn uuid(&self) -> Uuid {
@@ -46,7 +61,12 @@ where
 
     fn upgrade(self, config: Arc<ServerConfig>) -> Result<Self::Upgrade, Error> {
         let upgrade = self.recorder.into_inner().upgrade(config)?;
-        Ok(DebugConnectionUpgrade::new(Box::pin(upgrade), self.uuid))
+        Ok(DebugConnectionUpgrade::new(
+            Box::pin(upgrade),
+            self.uuid,
+            self.directory,
+            self.rotate_after,
+        ))
     }
 
     fn is_starttls_allowed(&self) -> bool {
@@ -60,6 +80,22 @@ where
     fn is_authenticated(&self) -> bool {
         self.recorder.get_ref().is_authenticated()
     }
+
+    fn tls_info(&self) -> Option<TlsInfo> {
+        self.recorder.get_ref().tls_info()
+    }
+
+    fn tls_exporter(&self) -> Option<Vec<u8>> {
+        self.recorder.get_ref().tls_exporter()
+    }
+
+    fn tls_server_end_point(&self) -> Option<Vec<u8>> {
+        self.recorder.get_ref().tls_server_end_point()
+    }
+
+    fn peer_certificate(&self) -> Option<CertificateDer<'static>> {
+        self.recorder.get_ref().peer_certificate()
+    }
 }
 
 impl<C> AsyncRead for DebugConnection<C>
@@ -106,17 +142,17 @@ enum DebugConnectionUpgradeState<C>
 where
     C: Connection,
 {
-    Upgrading(Uuid, Pin<Box<dyn Future<Output = Result<C, Error>> + Send>>),
-    ConstructingRecorder(
-        Uuid,
-        Pin<Box<dyn Future<Output = std::io::Result<StreamRecorder<C>>> + Send>>,
-    ),
+    Upgrading(Pin<Box<dyn Future<Output = Result<C, Error>> + Send>>),
+    ConstructingRecorder(Pin<Box<dyn Future<Output = std::io::Result<StreamRecorder<C>>> + Send>>),
 }
 
 pub struct DebugConnectionUpgrade<C>
 where
     C: Connection + Send,
 {
+    uuid: Uuid,
+    directory: PathBuf,
+    rotate_after: Option<u64>,
     state: DebugConnectionUpgradeState<C>,
 }
 
@@ -127,9 +163,16 @@ where
     pub fn new(
         upgrade: Pin<Box<dyn Future<Output = Result<C, Error>> + Send>>,
         uuid: Uuid,
+        directory: PathBuf,
+        rotate_after: Option<u64>,
     ) -> Self {
-        let state = DebugConnectionUpgradeState::Upgrading(uuid, upgrade);
-        DebugConnectionUpgrade { state }
+        let state = DebugConnectionUpgradeState::Upgrading(upgrade);
+        DebugConnectionUpgrade {
+            uuid,
+            directory,
+            rotate_after,
+            state,
+        }
     }
 }
 
@@ -143,17 +186,30 @@ where
         mut self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Self::Output> {
+        let this = &mut *self;
         loop {
-            self.state = match self.state {
-                DebugConnectionUpgradeState::Upgrading(uuid, ref mut upgrade) => {
+            let uuid = this.uuid;
+            let rotate_after = this.rotate_after;
+            this.state = match this.state {
+                DebugConnectionUpgradeState::Upgrading(ref mut upgrade) => {
                     let upgraded = ready!(upgrade.as_mut().poll(cx))?;
-                    let recorder_constructor = Box::pin(StreamRecorder::try_new(upgraded, uuid));
+                    let directory = this.directory.clone();
+                    let recorder_constructor = Box::pin(async move {
+                        let recorder = StreamRecorder::try_new_in(upgraded, uuid, &directory);
+                        Ok(recorder.await?.rotate_after(rotate_after))
+                    });
 
-                    DebugConnectionUpgradeState::ConstructingRecorder(uuid, recorder_constructor)
+                    DebugConnectionUpgradeState::ConstructingRecorder(recorder_constructor)
                 }
-                DebugConnectionUpgradeState::ConstructingRecorder(uuid, ref mut constructor) => {
+                DebugConnectionUpgradeState::ConstructingRecorder(ref mut constructor) => {
                     let recorder = ready!(constructor.as_mut().poll(cx))?;
-                    return Poll::Ready(Ok(DebugConnection { uuid, recorder }));
+                    let directory = this.directory.clone();
+                    return Poll::Ready(Ok(DebugConnection {
+                        uuid,
+                        directory,
+                        rotate_after,
+                        recorder,
+                    }));
                 }
             }
         }
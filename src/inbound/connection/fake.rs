@@ -0,0 +1,114 @@
+use std::{
+    future::{ready, Ready},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use anyhow::{bail, Error};
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use tokio_rustls::rustls::{CipherSuite, ProtocolVersion, ServerConfig};
+
+use crate::xmpp::stream::{Connection, TlsInfo};
+
+pub struct FakeConnection {
+    stream: DuplexStream,
+    pub starttls_allowed: bool,
+    pub secure: bool,
+    pub authenticated: bool,
+    pub tls_info: Option<TlsInfo>,
+    pub tls_exporter: Option<Vec<u8>>,
+    pub tls_server_end_point: Option<Vec<u8>>,
+    pub peer_certificate: Option<CertificateDer<'static>>,
+}
+
+impl FakeConnection {
+    pub fn new(stream: DuplexStream) -> Self {
+        FakeConnection {
+            stream,
+            starttls_allowed: false,
+            secure: false,
+            authenticated: false,
+            tls_info: None,
+            tls_exporter: None,
+            tls_server_end_point: None,
+            peer_certificate: None,
+        }
+    }
+}
+
+impl Connection for FakeConnection {
+    type Upgrade = Ready<Result<Self, Error>>;
+
+    fn upgrade(mut self, _config: Arc<ServerConfig>) -> Result<Self::Upgrade, Error> {
+        if self.secure {
+            bail!("Connection is already secure");
+        }
+
+        self.starttls_allowed = false;
+        self.secure = true;
+        self.tls_info = Some(TlsInfo {
+            protocol_version: ProtocolVersion::TLSv1_3,
+            cipher_suite: CipherSuite::TLS13_AES_128_GCM_SHA256,
+        });
+
+        Ok(ready(Ok(self)))
+    }
+
+    fn is_starttls_allowed(&self) -> bool {
+        self.starttls_allowed
+    }
+
+    fn is_secure(&self) -> bool {
+        self.secure
+    }
+
+    fn is_authenticated(&self) -> bool {
+        self.authenticated
+    }
+
+    fn tls_info(&self) -> Option<TlsInfo> {
+        self.tls_info
+    }
+
+    fn tls_exporter(&self) -> Option<Vec<u8>> {
+        self.tls_exporter.clone()
+    }
+
+    fn tls_server_end_point(&self) -> Option<Vec<u8>> {
+        self.tls_server_end_point.clone()
+    }
+
+    fn peer_certificate(&self) -> Option<CertificateDer<'static>> {
+        self.peer_certificate.clone()
+    }
+}
+
+impl AsyncRead for FakeConnection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for FakeConnection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
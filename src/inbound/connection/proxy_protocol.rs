@@ -0,0 +1,80 @@
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::{anyhow, bail, Error};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// A version 1 header is at most 107 bytes including the trailing CRLF.
+const MAX_HEADER_LENGTH: usize = 107;
+
+// Reads a PROXY protocol version 1 header and returns the address of the original client, if
+// the proxy knew it. Reads byte by byte so nothing after the header is consumed.
+pub async fn read_proxy_header<R>(reader: &mut R) -> Result<Option<SocketAddr>, Error>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut header = Vec::new();
+    while !header.ends_with(b"\r\n") {
+        if header.len() == MAX_HEADER_LENGTH {
+            bail!("PROXY header is too long");
+        }
+        header.push(reader.read_u8().await?);
+    }
+
+    let header = std::str::from_utf8(&header[..header.len() - 2])?;
+    let mut fields = header.split(' ');
+    if fields.next() != Some("PROXY") {
+        bail!("expected PROXY header");
+    }
+
+    match fields.next() {
+        Some("TCP4" | "TCP6") => {}
+        Some("UNKNOWN") => return Ok(None),
+        _ => bail!("unsupported PROXY protocol family"),
+    }
+
+    let (Some(source), Some(_destination), Some(source_port), Some(_destination_port), None) = (
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+    ) else {
+        bail!("malformed PROXY header");
+    };
+
+    let source = source
+        .parse::<IpAddr>()
+        .map_err(|err| anyhow!(err).context("invalid PROXY source address"))?;
+    let source_port = source_port
+        .parse::<u16>()
+        .map_err(|err| anyhow!(err).context("invalid PROXY source port"))?;
+
+    Ok(Some(SocketAddr::new(source, source_port)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn source_address_is_extracted() {
+        let mut input: &[u8] = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 5222\r\n<stream:stream";
+
+        let source = read_proxy_header(&mut input).await.unwrap();
+
+        assert_eq!(source, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(input, b"<stream:stream");
+    }
+
+    #[tokio::test]
+    async fn unknown_family_has_no_source() {
+        let mut input: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_proxy_header(&mut input).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn missing_header_is_rejected() {
+        let mut input: &[u8] = b"<stream:stream>\r\n";
+        assert!(read_proxy_header(&mut input).await.is_err());
+    }
+}
@@ -2,13 +2,22 @@ use std::{pin::Pin, sync::Arc, task::ready};
 
 use anyhow::{anyhow, Error};
 use futures::Future;
+use sha2::{Digest, Sha256};
 use tokio::{
     io::{AsyncRead, AsyncWrite},
     net::TcpStream,
 };
-use tokio_rustls::{rustls::ServerConfig, server::TlsStream, Accept, TlsAcceptor};
+use tokio_rustls::{
+    rustls::{pki_types::CertificateDer, ServerConfig},
+    server::TlsStream,
+    Accept, TlsAcceptor,
+};
+
+use crate::settings::get_settings;
+use crate::xmpp::stream::{Connection, TlsInfo};
 
-use crate::xmpp::stream::Connection;
+const TLS_EXPORTER_LABEL: &[u8] = b"EXPORTER-Channel-Binding";
+const TLS_EXPORTER_LENGTH: usize = 32;
 
 enum Socket {
     Plain(TcpStream),
@@ -59,6 +68,57 @@ impl Connection for TcpConnection {
             Socket::Tls(socket) => socket.get_ref().1.peer_certificates().is_some(),
         }
     }
+
+    fn tls_info(&self) -> Option<TlsInfo> {
+        match &self.socket {
+            Socket::Plain(_) => None,
+            Socket::Tls(socket) => {
+                let session = socket.get_ref().1;
+                Some(TlsInfo {
+                    protocol_version: session.protocol_version()?,
+                    cipher_suite: session.negotiated_cipher_suite()?.suite(),
+                })
+            }
+        }
+    }
+
+    fn tls_exporter(&self) -> Option<Vec<u8>> {
+        match &self.socket {
+            Socket::Plain(_) => None,
+            Socket::Tls(socket) => socket
+                .get_ref()
+                .1
+                .export_keying_material(vec![0; TLS_EXPORTER_LENGTH], TLS_EXPORTER_LABEL, None)
+                .ok(),
+        }
+    }
+
+    fn tls_server_end_point(&self) -> Option<Vec<u8>> {
+        match &self.socket {
+            Socket::Plain(_) => None,
+            Socket::Tls(socket) => {
+                let server_name = socket.get_ref().1.server_name();
+                let certificate_chain = get_settings()
+                    .tls
+                    .server_config
+                    .certificate_chain(server_name);
+                let certificate = certificate_chain.first()?;
+                Some(Sha256::digest(certificate.as_ref()).to_vec())
+            }
+        }
+    }
+
+    fn peer_certificate(&self) -> Option<CertificateDer<'static>> {
+        match &self.socket {
+            Socket::Plain(_) => None,
+            Socket::Tls(socket) => {
+                let certificates = socket.get_ref().1.peer_certificates()?;
+                certificates
+                    .first()
+                    .map(|certificate| certificate.clone().into_owned())
+            }
+        }
+    }
 }
 
 impl AsyncRead for TcpConnection {
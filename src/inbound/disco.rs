@@ -0,0 +1,221 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::OnceLock;
+
+use futures::future::{self, BoxFuture, FutureExt};
+
+use crate::xml::{namespaces, Element, Node};
+use crate::xmpp::stanza::Stanza;
+
+use super::iq::{IqContext, IqHandler};
+
+static SERVER_INFO: OnceLock<ServerInfo> = OnceLock::new();
+
+// What the server tells others about itself through service discovery (XEP-0030). Support for
+// a new protocol registers its namespace in `builtin`.
+pub struct ServerInfo {
+    features: BTreeSet<&'static str>,
+}
+
+impl ServerInfo {
+    pub fn get() -> &'static ServerInfo {
+        SERVER_INFO.get_or_init(ServerInfo::builtin)
+    }
+
+    fn builtin() -> Self {
+        let mut info = ServerInfo {
+            features: BTreeSet::new(),
+        };
+        info.register_feature(namespaces::DISCO_INFO);
+        info.register_feature(namespaces::DISCO_ITEMS);
+        info.register_feature(namespaces::XMPP_SASL);
+        info.register_feature(namespaces::XMPP_BIND);
+        info.register_feature(namespaces::PING);
+        info.register_feature(namespaces::STANZA_ID);
+        info.register_feature(namespaces::VERSION);
+        info.register_feature(namespaces::TIME);
+        info
+    }
+
+    pub fn register_feature(&mut self, namespace: &'static str) {
+        self.features.insert(namespace);
+    }
+
+    // The result for a disco#info or disco#items query, or `None` if the stanza is something
+    // else.
+    pub fn answer(&self, stanza: &Stanza) -> Option<Stanza> {
+        if stanza.element.name != "iq" || stanza.element.get_attribute("type", None) != Some("get")
+        {
+            return None;
+        }
+
+        if let Some(query) = stanza
+            .element
+            .get_child("query", Some(namespaces::DISCO_INFO))
+        {
+            return Some(stanza.result_reply(vec![Node::Element(self.info(query))]));
+        }
+        if let Some(query) = stanza
+            .element
+            .get_child("query", Some(namespaces::DISCO_ITEMS))
+        {
+            // nothing is hosted on the server yet
+            let items = query_element(namespaces::DISCO_ITEMS, query, vec![]);
+            return Some(stanza.result_reply(vec![Node::Element(items)]));
+        }
+
+        None
+    }
+
+    fn info(&self, query: &Element) -> Element {
+        let identity = Element {
+            name: "identity".to_string(),
+            namespace: Some(namespaces::DISCO_INFO.to_string()),
+            attributes: vec![
+                (("category".to_string(), None), "server".to_string()),
+                (("type".to_string(), None), "im".to_string()),
+            ]
+            .into_iter()
+            .collect(),
+            children: vec![],
+        };
+        let features = self.features.iter().map(|feature| {
+            Node::Element(Element {
+                name: "feature".to_string(),
+                namespace: Some(namespaces::DISCO_INFO.to_string()),
+                attributes: vec![(("var".to_string(), None), feature.to_string())]
+                    .into_iter()
+                    .collect(),
+                children: vec![],
+            })
+        });
+
+        let children = std::iter::once(Node::Element(identity))
+            .chain(features)
+            .collect();
+        query_element(namespaces::DISCO_INFO, query, children)
+    }
+}
+
+// Answers disco#info or disco#items queries with the `ServerInfo`.
+pub struct DiscoHandler {
+    namespace: &'static str,
+}
+
+impl DiscoHandler {
+    pub fn new(namespace: &'static str) -> Self {
+        DiscoHandler { namespace }
+    }
+}
+
+impl IqHandler for DiscoHandler {
+    fn namespace(&self) -> &'static str {
+        self.namespace
+    }
+
+    fn handle<'a>(
+        &'a self,
+        iq: &'a Stanza,
+        _context: &'a IqContext<'a>,
+    ) -> BoxFuture<'a, Option<Stanza>> {
+        future::ready(ServerInfo::get().answer(iq)).boxed()
+    }
+}
+
+// Answers keep the node that was queried, if any.
+fn query_element(namespace: &str, query: &Element, children: Vec<Node>) -> Element {
+    let mut attributes = HashMap::new();
+    attributes.insert(("xmlns".to_string(), None), namespace.to_string());
+    if let Some(node) = query.get_attribute("node", None) {
+        attributes.insert(("node".to_string(), None), node.to_string());
+    }
+
+    Element {
+        name: "query".to_string(),
+        namespace: Some(namespace.to_string()),
+        attributes,
+        children,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(namespace: &str) -> Stanza {
+        let query = Element {
+            name: "query".to_string(),
+            namespace: Some(namespace.to_string()),
+            attributes: HashMap::new(),
+            children: vec![],
+        };
+
+        Stanza {
+            element: Element {
+                name: "iq".to_string(),
+                namespace: Some(namespaces::XMPP_CLIENT.to_string()),
+                attributes: vec![
+                    (("id".to_string(), None), "disco1".to_string()),
+                    (("type".to_string(), None), "get".to_string()),
+                    (("to".to_string(), None), "localhost".to_string()),
+                ]
+                .into_iter()
+                .collect(),
+                children: vec![Node::Element(query)],
+            },
+        }
+    }
+
+    #[test]
+    fn info_lists_identity_and_implemented_features() {
+        let reply = ServerInfo::get()
+            .answer(&query(namespaces::DISCO_INFO))
+            .unwrap();
+
+        assert_eq!(reply.element.get_attribute("type", None), Some("result"));
+        let query = reply
+            .element
+            .get_child("query", Some(namespaces::DISCO_INFO))
+            .unwrap();
+        let identity = query
+            .get_child("identity", Some(namespaces::DISCO_INFO))
+            .unwrap();
+        assert_eq!(identity.get_attribute("category", None), Some("server"));
+        assert_eq!(identity.get_attribute("type", None), Some("im"));
+
+        let features = query
+            .find_children("feature", Some(namespaces::DISCO_INFO))
+            .filter_map(|feature| feature.get_attribute("var", None))
+            .collect::<BTreeSet<_>>();
+        let implemented = BTreeSet::from([
+            namespaces::DISCO_INFO,
+            namespaces::DISCO_ITEMS,
+            namespaces::XMPP_SASL,
+            namespaces::XMPP_BIND,
+            namespaces::PING,
+            namespaces::STANZA_ID,
+            namespaces::VERSION,
+            namespaces::TIME,
+        ]);
+        assert_eq!(features, implemented);
+    }
+
+    #[test]
+    fn items_are_empty() {
+        let reply = ServerInfo::get()
+            .answer(&query(namespaces::DISCO_ITEMS))
+            .unwrap();
+
+        let query = reply
+            .element
+            .get_child("query", Some(namespaces::DISCO_ITEMS))
+            .unwrap();
+        assert!(query.children.is_empty());
+    }
+
+    #[test]
+    fn other_queries_are_not_answered() {
+        assert!(ServerInfo::get()
+            .answer(&query(namespaces::ROSTER))
+            .is_none());
+    }
+}
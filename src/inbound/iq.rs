@@ -0,0 +1,293 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use anyhow::{anyhow, Error};
+use futures::future::BoxFuture;
+
+use crate::services::router::RouterHandle;
+use crate::services::store::StoreHandle;
+use crate::settings::Settings;
+use crate::xml::namespaces;
+use crate::xmpp::jid::Jid;
+use crate::xmpp::stanza::Stanza;
+use crate::xmpp::stanza_error::StanzaError;
+
+use super::disco::DiscoHandler;
+use super::ping::PingHandler;
+use super::register::RegisterHandler;
+use super::roster::RosterHandler;
+use super::sasl::SecurityContext;
+use super::time::TimeHandler;
+use super::version::VersionHandler;
+
+static IQ_HANDLERS: OnceLock<IqHandlers> = OnceLock::new();
+
+// What a handler knows about the stream a request came in on.
+pub struct IqContext<'a> {
+    pub security: &'a SecurityContext,
+    // the authenticated account of a client stream
+    pub account: Option<&'a Jid>,
+    pub store: &'a StoreHandle,
+    pub router: &'a RouterHandle,
+    // what errors are described in, if there is text for it
+    pub language: Option<&'a str>,
+}
+
+// Answers IQ requests to the server whose payload is in one namespace.
+pub trait IqHandler: Send + Sync {
+    fn namespace(&self) -> &'static str;
+
+    // Whether requests to an account's bare JID are answered as if they were to the server.
+    fn answers_for_accounts(&self) -> bool {
+        false
+    }
+
+    // The reply, or `None` if the request is not one the handler supports.
+    fn handle<'a>(
+        &'a self,
+        iq: &'a Stanza,
+        context: &'a IqContext<'a>,
+    ) -> BoxFuture<'a, Option<Stanza>>;
+}
+
+// The IQ handlers of the server by the namespace of the payload they answer. Support for a new
+// protocol registers its handler in `builtin`.
+#[derive(Default)]
+pub struct IqHandlers {
+    handlers: HashMap<&'static str, Box<dyn IqHandler>>,
+}
+
+impl IqHandlers {
+    pub fn init(settings: &Settings) -> Result<(), Error> {
+        IQ_HANDLERS
+            .set(IqHandlers::builtin(settings))
+            .map_err(|_| anyhow!("IQ handlers already initialized"))
+    }
+
+    pub fn get() -> &'static IqHandlers {
+        IQ_HANDLERS.get().expect("IQ handlers not initialized")
+    }
+
+    fn builtin(settings: &Settings) -> Self {
+        let mut handlers = IqHandlers::default();
+        handlers.register(PingHandler);
+        handlers.register(DiscoHandler::new(namespaces::DISCO_INFO));
+        handlers.register(DiscoHandler::new(namespaces::DISCO_ITEMS));
+        handlers.register(VersionHandler::new(settings.disclose_os));
+        handlers.register(TimeHandler::new(settings.time_zone_offset));
+        handlers.register(RosterHandler);
+        handlers.register(RegisterHandler::new(
+            settings.password_pepper.clone(),
+            settings.password_hashing,
+        ));
+        handlers
+    }
+
+    pub fn register(&mut self, handler: impl IqHandler + 'static) {
+        self.handlers.insert(handler.namespace(), Box::new(handler));
+    }
+
+    // The reply to an IQ request addressed to the server, or to an account's bare JID if
+    // `for_account` is set, or `None` if the stanza is not a request. Requests no handler
+    // supports are answered with <service-unavailable/> (RFC 6120, section 8.4).
+    pub async fn dispatch(
+        &self,
+        stanza: &Stanza,
+        context: &IqContext<'_>,
+        for_account: bool,
+    ) -> Option<Stanza> {
+        if stanza.element.name != "iq"
+            || !matches!(
+                stanza.element.get_attribute("type", None),
+                Some("get" | "set")
+            )
+        {
+            return None;
+        }
+
+        let handler = stanza
+            .element
+            .children()
+            .next()
+            .and_then(|payload| payload.namespace.as_deref())
+            .and_then(|namespace| self.handlers.get(namespace))
+            .filter(|handler| !for_account || handler.answers_for_accounts());
+        if let Some(handler) = handler {
+            if let Some(reply) = handler.handle(stanza, context).await {
+                return Some(reply);
+            }
+        }
+
+        stanza.error_reply(StanzaError::ServiceUnavailable, context.language)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+
+    use crate::services::store::fake::FakeStoreBackend;
+    use crate::xml::{Element, Node};
+
+    use super::*;
+
+    struct Echo {
+        namespace: &'static str,
+        for_accounts: bool,
+    }
+
+    impl IqHandler for Echo {
+        fn namespace(&self) -> &'static str {
+            self.namespace
+        }
+
+        fn answers_for_accounts(&self) -> bool {
+            self.for_accounts
+        }
+
+        fn handle<'a>(
+            &'a self,
+            iq: &'a Stanza,
+            context: &'a IqContext<'a>,
+        ) -> BoxFuture<'a, Option<Stanza>> {
+            // stands in for a handler that will not answer over cleartext
+            if !context.security.secure {
+                return async { None }.boxed();
+            }
+
+            let answer = Element {
+                name: "answered-by".to_string(),
+                namespace: None,
+                attributes: HashMap::new(),
+                children: vec![Node::Text(self.namespace.to_string())],
+            };
+            async move { Some(iq.result_reply(vec![Node::Element(answer)])) }.boxed()
+        }
+    }
+
+    fn handlers() -> IqHandlers {
+        let mut handlers = IqHandlers::default();
+        handlers.register(Echo {
+            namespace: "urn:example:first",
+            for_accounts: false,
+        });
+        handlers.register(Echo {
+            namespace: "urn:example:second",
+            for_accounts: true,
+        });
+        handlers
+    }
+
+    fn request(iq_type: &str, namespace: &str) -> Stanza {
+        Stanza {
+            element: Element::parse(&format!(
+                "<iq xmlns='jabber:client' type='{iq_type}' id='iq1'><query xmlns='{namespace}'/></iq>"
+            ))
+            .unwrap(),
+        }
+    }
+
+    fn tls() -> SecurityContext {
+        SecurityContext {
+            secure: true,
+            ..SecurityContext::default()
+        }
+    }
+
+    async fn dispatch(
+        handlers: &IqHandlers,
+        request: &Stanza,
+        security: SecurityContext,
+        for_account: bool,
+    ) -> Option<Stanza> {
+        let store = StoreHandle::new(FakeStoreBackend::default());
+        let router = RouterHandle::new(store.clone());
+        let context = IqContext {
+            security: &security,
+            account: None,
+            store: &store,
+            router: &router,
+            language: None,
+        };
+        handlers.dispatch(request, &context, for_account).await
+    }
+
+    fn answered_by(reply: &Stanza) -> Option<String> {
+        reply.element.path_text(&[("answered-by", None)])
+    }
+
+    #[tokio::test]
+    async fn requests_go_to_the_handler_for_their_namespace() {
+        let handlers = handlers();
+
+        for namespace in ["urn:example:first", "urn:example:second"] {
+            for iq_type in ["get", "set"] {
+                let reply = dispatch(&handlers, &request(iq_type, namespace), tls(), false)
+                    .await
+                    .unwrap();
+
+                assert_eq!(reply.element.get_attribute("type", None), Some("result"));
+                assert_eq!(answered_by(&reply), Some(namespace.to_string()));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn unknown_namespaces_are_service_unavailable() {
+        let reply = dispatch(
+            &handlers(),
+            &request("get", "urn:example:third"),
+            tls(),
+            false,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(reply.element.get_attribute("type", None), Some("error"));
+        assert!(reply
+            .element
+            .path(&[
+                ("error", Some(namespaces::XMPP_CLIENT)),
+                ("service-unavailable", Some(namespaces::XMPP_STANZAS)),
+            ])
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn only_some_handlers_answer_for_accounts() {
+        let handlers = handlers();
+
+        let reply = dispatch(&handlers, &request("get", "urn:example:first"), tls(), true)
+            .await
+            .unwrap();
+        assert_eq!(reply.element.get_attribute("type", None), Some("error"));
+
+        let reply = dispatch(
+            &handlers,
+            &request("get", "urn:example:second"),
+            tls(),
+            true,
+        )
+        .await
+        .unwrap();
+        assert_eq!(answered_by(&reply), Some("urn:example:second".to_string()));
+    }
+
+    #[tokio::test]
+    async fn responses_are_not_dispatched() {
+        let result = request("result", "urn:example:first");
+
+        assert!(dispatch(&handlers(), &result, tls(), false).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn handlers_see_the_security_of_the_stream() {
+        let request = request("get", "urn:example:first");
+
+        let reply = dispatch(&handlers(), &request, SecurityContext::default(), false)
+            .await
+            .unwrap();
+
+        assert_eq!(reply.element.get_attribute("type", None), Some("error"));
+    }
+}
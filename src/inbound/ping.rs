@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use futures::future::{self, BoxFuture, FutureExt};
+
+use crate::xml::{namespaces, Element, Node};
+use crate::xmpp::jid::Jid;
+use crate::xmpp::stanza::Stanza;
+
+use super::iq::{IqContext, IqHandler};
+
+// The result for an XMPP Ping (XEP-0199) addressed to the server, or `None` if the stanza is
+// something else.
+pub fn answer(stanza: &Stanza) -> Option<Stanza> {
+    if stanza.element.name != "iq"
+        || stanza.element.get_attribute("type", None) != Some("get")
+        || stanza
+            .element
+            .get_child("ping", Some(namespaces::PING))
+            .is_none()
+    {
+        return None;
+    }
+
+    Some(stanza.result_reply(vec![]))
+}
+
+pub struct PingHandler;
+
+impl IqHandler for PingHandler {
+    fn namespace(&self) -> &'static str {
+        namespaces::PING
+    }
+
+    // the server answers for its accounts, as it would for any other request it handles
+    fn answers_for_accounts(&self) -> bool {
+        true
+    }
+
+    fn handle<'a>(
+        &'a self,
+        iq: &'a Stanza,
+        _context: &'a IqContext<'a>,
+    ) -> BoxFuture<'a, Option<Stanza>> {
+        future::ready(answer(iq)).boxed()
+    }
+}
+
+fn request(id: &str, from: &Jid, to: &Jid) -> Stanza {
+    let ping = Element {
+        name: "ping".to_string(),
+        namespace: Some(namespaces::PING.to_string()),
+        attributes: vec![(("xmlns".to_string(), None), namespaces::PING.to_string())]
+            .into_iter()
+            .collect(),
+        children: vec![],
+    };
+
+    let mut attributes = HashMap::new();
+    attributes.insert(("id".to_string(), None), id.to_string());
+    attributes.insert(("type".to_string(), None), "get".to_string());
+    attributes.insert(("from".to_string(), None), from.to_string());
+    attributes.insert(("to".to_string(), None), to.to_string());
+
+    Stanza {
+        element: Element {
+            name: "iq".to_string(),
+            namespace: Some(namespaces::XMPP_CLIENT.to_string()),
+            attributes,
+            children: vec![Node::Element(ping)],
+        },
+    }
+}
+
+#[derive(Debug)]
+pub enum PingAction {
+    Send(Stanza),
+    Timeout,
+    Wait,
+}
+
+// Pings a bound client every `interval`, giving it `timeout` to answer. Unlike whitespace
+// keepalives, this notices clients whose connection is still open but that stopped processing
+// stanzas. Any answer counts, even an error.
+pub struct ClientPing {
+    interval: Duration,
+    timeout: Duration,
+    last_ping: Instant,
+    outstanding: Option<String>,
+}
+
+impl ClientPing {
+    pub fn new(interval: Duration, timeout: Duration, now: Instant) -> Self {
+        ClientPing {
+            interval,
+            timeout,
+            last_ping: now,
+            outstanding: None,
+        }
+    }
+
+    pub fn deadline(&self) -> Instant {
+        match self.outstanding {
+            Some(_) => self.last_ping + self.timeout,
+            None => self.last_ping + self.interval,
+        }
+    }
+
+    pub fn poll(&mut self, server: &Jid, client: &Jid, now: Instant) -> PingAction {
+        if now < self.deadline() {
+            return PingAction::Wait;
+        }
+        if self.outstanding.is_some() {
+            return PingAction::Timeout;
+        }
+
+        let id = format!("ping-{}", uuid::Uuid::new_v4());
+        let ping = request(&id, server, client);
+        self.outstanding = Some(id);
+        self.last_ping = now;
+        PingAction::Send(ping)
+    }
+
+    // Whether the stanza answers the outstanding ping, and so is not to be routed anywhere.
+    pub fn answered(&mut self, stanza: &Stanza) -> bool {
+        let is_answer = stanza.element.name == "iq"
+            && matches!(
+                stanza.element.get_attribute("type", None),
+                Some("result" | "error")
+            )
+            && stanza.element.get_attribute("id", None).is_some()
+            && stanza.element.get_attribute("id", None) == self.outstanding.as_deref();
+        if is_answer {
+            self.outstanding = None;
+        }
+
+        is_answer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server() -> Jid {
+        "localhost".parse().unwrap()
+    }
+
+    fn client() -> Jid {
+        Jid::new(
+            Some("juliet".to_string()),
+            "localhost".to_string(),
+            Some("balcony".to_string()),
+        )
+    }
+
+    #[test]
+    fn ping_is_answered_with_an_empty_result() {
+        let ping = request("c2s1", &client(), &server());
+
+        let reply = answer(&ping).unwrap();
+
+        assert_eq!(reply.element.name, "iq");
+        assert_eq!(reply.element.get_attribute("type", None), Some("result"));
+        assert_eq!(reply.element.get_attribute("id", None), Some("c2s1"));
+        assert_eq!(reply.element.get_attribute("from", None), Some("localhost"));
+        assert_eq!(
+            reply.element.get_attribute("to", None),
+            Some("juliet@localhost/balcony")
+        );
+        assert!(reply.element.children.is_empty());
+    }
+
+    #[test]
+    fn other_iqs_are_not_pings() {
+        let mut iq = request("c2s1", &client(), &server());
+        iq.element.children.clear();
+        assert!(answer(&iq).is_none());
+    }
+
+    fn answer_to(ping: &Stanza) -> Stanza {
+        let mut answer = ping.result_reply(vec![]);
+        answer.element.attributes.remove(&("to".to_string(), None));
+        answer
+    }
+
+    #[test]
+    fn unanswered_ping_times_out() {
+        let start = Instant::now();
+        let mut ping = ClientPing::new(Duration::from_secs(300), Duration::from_secs(30), start);
+        assert!(matches!(
+            ping.poll(&server(), &client(), start + Duration::from_secs(299)),
+            PingAction::Wait
+        ));
+
+        let now = start + Duration::from_secs(300);
+        let PingAction::Send(request) = ping.poll(&server(), &client(), now) else {
+            panic!("expected a ping");
+        };
+        assert!(request
+            .element
+            .get_child("ping", Some(namespaces::PING))
+            .is_some());
+        assert_eq!(ping.deadline(), now + Duration::from_secs(30));
+
+        assert!(matches!(
+            ping.poll(&server(), &client(), now + Duration::from_secs(30)),
+            PingAction::Timeout
+        ));
+    }
+
+    #[test]
+    fn answered_ping_schedules_the_next_one() {
+        let start = Instant::now();
+        let mut ping = ClientPing::new(Duration::from_secs(300), Duration::from_secs(30), start);
+
+        let now = start + Duration::from_secs(300);
+        let PingAction::Send(request) = ping.poll(&server(), &client(), now) else {
+            panic!("expected a ping");
+        };
+        let mut unrelated = answer_to(&request);
+        unrelated
+            .element
+            .attributes
+            .insert(("id".to_string(), None), "other".to_string());
+        assert!(!ping.answered(&unrelated));
+        assert!(ping.answered(&answer_to(&request)));
+
+        assert!(matches!(
+            ping.poll(&server(), &client(), now + Duration::from_secs(30)),
+            PingAction::Wait
+        ));
+        assert_eq!(ping.deadline(), now + Duration::from_secs(300));
+    }
+}
@@ -0,0 +1,672 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Error};
+use futures::future::{BoxFuture, FutureExt};
+use scram_rs::{ScramSha1Ring, ScramSha256Ring};
+use tracing::error;
+
+use crate::services::store::StoreHandle;
+use crate::settings::{PasswordHashing, PasswordPepper};
+use crate::utils::blocking;
+use crate::xml::{namespaces, Element, Node};
+use crate::xmpp::jid::Jid;
+use crate::xmpp::stanza::Stanza;
+use crate::xmpp::stanza_error::{StanzaError, StanzaErrorBuilder};
+use crate::xmpp::stream::{Connection, XmppStream};
+
+use super::iq::{IqContext, IqHandler};
+use super::sasl::{StoredPassword, StoredPasswordArgon2, StoredPasswordScram};
+
+// In-band registration (XEP-0077), offered to clients that have not authenticated yet.
+// Authenticated clients use the same protocol to change their password, see
+// `RegisterHandler`.
+pub struct RegistrationNegotiator {
+    _private: (),
+}
+
+impl RegistrationNegotiator {
+    pub fn advertise_feature() -> Element {
+        element("register", namespaces::REGISTER_FEATURE, vec![])
+    }
+
+    // Registering does not log the client in, so the stream stays where it was and the new
+    // account still has to authenticate. Returns whether an account was created.
+    pub async fn negotiate_feature<C>(
+        stream: &mut XmppStream<C>,
+        element: &Element,
+        store: &StoreHandle,
+        domain: &Jid,
+        pepper: Option<&PasswordPepper>,
+        hashing: &PasswordHashing,
+        language: Option<&str>,
+    ) -> Result<bool, Error>
+    where
+        C: Connection,
+    {
+        let request = Stanza {
+            element: element.clone(),
+        };
+        let Some(reply) = answer(&request, store, domain, pepper, hashing, language).await else {
+            bail!("expected registration request");
+        };
+
+        stream.writer().write_stanza(&reply).await?;
+        Ok(element.get_attribute("type", None) == Some("set")
+            && reply.element.get_attribute("type", None) == Some("result"))
+    }
+}
+
+// The reply to a registration request, or `None` if the stanza is something else. Errors are
+// described in `language`.
+async fn answer(
+    request: &Stanza,
+    store: &StoreHandle,
+    domain: &Jid,
+    pepper: Option<&PasswordPepper>,
+    hashing: &PasswordHashing,
+    language: Option<&str>,
+) -> Option<Stanza> {
+    if request.element.name != "iq" {
+        return None;
+    }
+    let query = request
+        .element
+        .get_child("query", Some(namespaces::REGISTER))?;
+
+    let result = match request.element.get_attribute("type", None) {
+        Some("get") => Ok(vec![Node::Element(form())]),
+        Some("set") => register(query, store, domain, pepper, hashing)
+            .await
+            .map(|()| vec![]),
+        _ => return None,
+    };
+
+    reply(request, result, language)
+}
+
+// Answers registration requests of authenticated clients, addressed to their server or their own
+// account.
+pub struct RegisterHandler {
+    pepper: Option<PasswordPepper>,
+    hashing: PasswordHashing,
+}
+
+impl RegisterHandler {
+    pub fn new(pepper: Option<PasswordPepper>, hashing: PasswordHashing) -> Self {
+        RegisterHandler { pepper, hashing }
+    }
+}
+
+impl IqHandler for RegisterHandler {
+    fn namespace(&self) -> &'static str {
+        namespaces::REGISTER
+    }
+
+    fn answers_for_accounts(&self) -> bool {
+        true
+    }
+
+    fn handle<'a>(
+        &'a self,
+        iq: &'a Stanza,
+        context: &'a IqContext<'a>,
+    ) -> BoxFuture<'a, Option<Stanza>> {
+        async move {
+            let account = context.account?;
+            let to = iq.to().ok()??;
+            if to != account.domain_jid() && to != account.to_bare() {
+                return None;
+            }
+            let pepper = self.pepper.as_ref();
+            let hashing = &self.hashing;
+            answer_account(
+                iq,
+                context.store,
+                account,
+                pepper,
+                hashing,
+                context.language,
+            )
+            .await
+        }
+        .boxed()
+    }
+}
+
+// The reply to a registration request from an authenticated `account`, or `None` if the stanza
+// is something else. The account is already registered, so all it can do is change its own
+// password.
+async fn answer_account(
+    request: &Stanza,
+    store: &StoreHandle,
+    account: &Jid,
+    pepper: Option<&PasswordPepper>,
+    hashing: &PasswordHashing,
+    language: Option<&str>,
+) -> Option<Stanza> {
+    if request.element.name != "iq" {
+        return None;
+    }
+    let query = request
+        .element
+        .get_child("query", Some(namespaces::REGISTER))?;
+
+    let result = match request.element.get_attribute("type", None) {
+        Some("get") => Ok(vec![Node::Element(registered_form(account))]),
+        Some("set") => change_password(query, store, account, pepper, hashing)
+            .await
+            .map(|()| vec![]),
+        _ => return None,
+    };
+
+    reply(request, result, language)
+}
+
+fn reply(
+    request: &Stanza,
+    result: Result<Vec<Node>, StanzaErrorBuilder>,
+    language: Option<&str>,
+) -> Option<Stanza> {
+    match result {
+        Ok(children) => Some(request.result_reply(children)),
+        Err(error) => request.error_reply(error, language),
+    }
+}
+
+fn form() -> Element {
+    let instructions = Element {
+        name: "instructions".to_string(),
+        namespace: Some(namespaces::REGISTER.to_string()),
+        attributes: HashMap::new(),
+        children: vec![Node::Text(
+            "Choose a username and password to register with this server.".to_string(),
+        )],
+    };
+    let field = |name: &str| Element {
+        name: name.to_string(),
+        namespace: Some(namespaces::REGISTER.to_string()),
+        attributes: HashMap::new(),
+        children: vec![],
+    };
+
+    element(
+        "query",
+        namespaces::REGISTER,
+        vec![instructions, field("username"), field("password")],
+    )
+}
+
+fn registered_form(account: &Jid) -> Element {
+    let registered = Element {
+        name: "registered".to_string(),
+        namespace: Some(namespaces::REGISTER.to_string()),
+        attributes: HashMap::new(),
+        children: vec![],
+    };
+    let username = Element {
+        name: "username".to_string(),
+        namespace: Some(namespaces::REGISTER.to_string()),
+        attributes: HashMap::new(),
+        children: account
+            .local()
+            .map(|local| Node::Text(local.to_string()))
+            .into_iter()
+            .collect(),
+    };
+    let password = Element {
+        name: "password".to_string(),
+        namespace: Some(namespaces::REGISTER.to_string()),
+        attributes: HashMap::new(),
+        children: vec![],
+    };
+
+    element(
+        "query",
+        namespaces::REGISTER,
+        vec![registered, username, password],
+    )
+}
+
+async fn register(
+    query: &Element,
+    store: &StoreHandle,
+    domain: &Jid,
+    pepper: Option<&PasswordPepper>,
+    hashing: &PasswordHashing,
+) -> Result<(), StanzaErrorBuilder> {
+    let field = |name| {
+        query
+            .get_child(name, Some(namespaces::REGISTER))
+            .map(Element::get_text)
+            .filter(|text| !text.is_empty())
+    };
+    let (Some(username), Some(password)) = (field("username"), field("password")) else {
+        return Err(StanzaError::NotAcceptable.into());
+    };
+
+    let jid = Jid::from_parts(Some(&username), domain.domain(), None)
+        .map_err(|_| StanzaError::JidMalformed)?;
+    if store
+        .user_exists(jid.clone())
+        .await
+        .map_err(internal_error)?
+    {
+        return Err(StanzaError::Conflict.into());
+    }
+
+    let pepper = pepper.cloned();
+    let hashing = *hashing;
+    let (stored_password_argon2, stored_password_scram_sha1, stored_password_scram_sha256) =
+        blocking::run(move || stored_passwords(&password, pepper.as_ref(), &hashing))
+            .await
+            .and_then(|stored_passwords| stored_passwords)
+            .map_err(internal_error)?;
+    // somebody else may have taken the username while the passwords were hashed
+    let added = store
+        .add_user(
+            jid,
+            stored_password_argon2,
+            stored_password_scram_sha1,
+            stored_password_scram_sha256,
+        )
+        .await
+        .map_err(internal_error)?;
+    if !added {
+        return Err(StanzaError::Conflict.into());
+    }
+
+    Ok(())
+}
+
+async fn change_password(
+    query: &Element,
+    store: &StoreHandle,
+    account: &Jid,
+    pepper: Option<&PasswordPepper>,
+    hashing: &PasswordHashing,
+) -> Result<(), StanzaErrorBuilder> {
+    let field = |name| {
+        query
+            .get_child(name, Some(namespaces::REGISTER))
+            .map(Element::get_text)
+    };
+
+    // the username may be left out, but must not name anybody else
+    if let Some(username) = field("username") {
+        let jid = Jid::from_parts(Some(&username), account.domain(), None);
+        if jid.ok() != Some(account.to_bare()) {
+            return Err(StanzaError::NotAuthorized.into());
+        }
+    }
+    let Some(password) = field("password").filter(|password| !password.is_empty()) else {
+        return Err(StanzaError::NotAcceptable.into());
+    };
+
+    let pepper = pepper.cloned();
+    let hashing = *hashing;
+    let (stored_password_argon2, stored_password_scram_sha1, stored_password_scram_sha256) =
+        blocking::run(move || stored_passwords(&password, pepper.as_ref(), &hashing))
+            .await
+            .and_then(|stored_passwords| stored_passwords)
+            .map_err(internal_error)?;
+    store
+        .set_stored_passwords(
+            account.to_bare(),
+            stored_password_argon2,
+            stored_password_scram_sha1,
+            stored_password_scram_sha256,
+        )
+        .await
+        .map_err(internal_error)
+}
+
+fn stored_passwords(
+    plaintext: &str,
+    pepper: Option<&PasswordPepper>,
+    hashing: &PasswordHashing,
+) -> Result<(String, String, String), Error> {
+    Ok((
+        StoredPasswordArgon2::new_with_pepper(plaintext, pepper, hashing)?.to_string(),
+        StoredPasswordScram::<ScramSha1Ring>::new(plaintext, hashing)?.to_string(),
+        StoredPasswordScram::<ScramSha256Ring>::new(plaintext, hashing)?.to_string(),
+    ))
+}
+
+fn internal_error(err: Error) -> StanzaErrorBuilder {
+    error!("Registration failed: {}", err);
+    StanzaError::InternalServerError.into()
+}
+
+fn element(name: &str, namespace: &str, children: Vec<Element>) -> Element {
+    Element {
+        name: name.to_string(),
+        namespace: Some(namespace.to_string()),
+        attributes: vec![(("xmlns".to_string(), None), namespace.to_string())]
+            .into_iter()
+            .collect(),
+        children: children.into_iter().map(Node::Element).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::inbound::connection::fake::FakeConnection;
+    use crate::inbound::sasl::{SaslNegotiator, StoredPasswordKind};
+    use crate::inbound::SaslMechanisms;
+    use crate::services::store::fake::FakeStoreBackend;
+    use crate::settings::Settings;
+    use crate::xml::stream_parser::{ElementLimits, ParserConfig, ParserKind};
+
+    use super::*;
+
+    fn request(xml: &str) -> Stanza {
+        Stanza {
+            element: Element::parse(xml).unwrap(),
+        }
+    }
+
+    fn domain() -> Jid {
+        "localhost".parse().unwrap()
+    }
+
+    fn condition(reply: &Stanza) -> Option<String> {
+        reply
+            .element
+            .get_child("error", Some(namespaces::XMPP_CLIENT))?
+            .children()
+            .find(|child| child.namespace.as_deref() == Some(namespaces::XMPP_STANZAS))
+            .map(|condition| condition.name.clone())
+    }
+
+    #[tokio::test]
+    async fn get_returns_the_required_fields() {
+        let store = StoreHandle::new(FakeStoreBackend::default());
+        let request = request(
+            "<iq xmlns='jabber:client' type='get' id='reg1'>\
+                <query xmlns='jabber:iq:register'/></iq>",
+        );
+
+        let reply = answer(
+            &request,
+            &store,
+            &domain(),
+            None,
+            &PasswordHashing::default(),
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(reply.element.get_attribute("type", None), Some("result"));
+        assert_eq!(reply.element.get_attribute("id", None), Some("reg1"));
+        let query = reply
+            .element
+            .get_child("query", Some(namespaces::REGISTER))
+            .unwrap();
+        assert!(query
+            .get_child("username", Some(namespaces::REGISTER))
+            .is_some());
+        assert!(query
+            .get_child("password", Some(namespaces::REGISTER))
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn set_creates_the_account() {
+        let store = StoreHandle::new(FakeStoreBackend::default());
+        let request = request(
+            "<iq xmlns='jabber:client' type='set' id='reg2'>\
+                <query xmlns='jabber:iq:register'>\
+                    <username>juliet</username><password>balcony</password>\
+                </query></iq>",
+        );
+
+        let reply = answer(
+            &request,
+            &store,
+            &domain(),
+            None,
+            &PasswordHashing::default(),
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(reply.element.get_attribute("type", None), Some("result"));
+        let jid = "juliet@localhost".parse::<Jid>().unwrap();
+        assert!(store.user_exists(jid.clone()).await.unwrap());
+        let stored_password = store
+            .get_stored_password(jid, StoredPasswordKind::Argon2)
+            .await
+            .unwrap()
+            .parse::<StoredPasswordArgon2>()
+            .unwrap();
+        assert!(stored_password.verify_with_peppers("balcony", []).unwrap());
+    }
+
+    #[tokio::test]
+    async fn existing_username_is_a_conflict() {
+        let store = StoreHandle::new(FakeStoreBackend::default());
+        let jid = "juliet@localhost".parse::<Jid>().unwrap();
+        store
+            .add_user(jid, "".into(), "".into(), "".into())
+            .await
+            .unwrap();
+        let request = request(
+            "<iq xmlns='jabber:client' type='set' id='reg3'>\
+                <query xmlns='jabber:iq:register'>\
+                    <username>juliet</username><password>balcony</password>\
+                </query></iq>",
+        );
+
+        let reply = answer(
+            &request,
+            &store,
+            &domain(),
+            None,
+            &PasswordHashing::default(),
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(condition(&reply).as_deref(), Some("conflict"));
+    }
+
+    #[tokio::test]
+    async fn missing_password_is_not_acceptable() {
+        let store = StoreHandle::new(FakeStoreBackend::default());
+        let request = request(
+            "<iq xmlns='jabber:client' type='set' id='reg4'>\
+                <query xmlns='jabber:iq:register'><username>juliet</username></query></iq>",
+        );
+
+        let reply = answer(
+            &request,
+            &store,
+            &domain(),
+            None,
+            &PasswordHashing::default(),
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(condition(&reply).as_deref(), Some("not-acceptable"));
+        let jid = "juliet@localhost".parse::<Jid>().unwrap();
+        assert!(!store.user_exists(jid).await.unwrap());
+    }
+
+    async fn negotiate(store: &StoreHandle, xml: &str) -> bool {
+        let (connection, _peer) = tokio::io::duplex(4096);
+        let parser_config = ParserConfig {
+            kind: ParserKind::RustyXml,
+            limits: ElementLimits::default(),
+        };
+        let mut stream = XmppStream::new(FakeConnection::new(connection), parser_config);
+
+        RegistrationNegotiator::negotiate_feature(
+            &mut stream,
+            &Element::parse(xml).unwrap(),
+            store,
+            &domain(),
+            None,
+            &PasswordHashing::default(),
+            None,
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn only_created_accounts_count_as_registered() {
+        let store = StoreHandle::new(FakeStoreBackend::default());
+        let get = "<iq xmlns='jabber:client' type='get' id='reg6'>\
+            <query xmlns='jabber:iq:register'/></iq>";
+        let set = "<iq xmlns='jabber:client' type='set' id='reg7'>\
+            <query xmlns='jabber:iq:register'>\
+                <username>juliet</username><password>balcony</password>\
+            </query></iq>";
+
+        assert!(!negotiate(&store, get).await);
+        assert!(negotiate(&store, set).await);
+        assert!(!negotiate(&store, set).await);
+    }
+
+    // Whether juliet gets in with PLAIN, given the base64 of the PLAIN payload.
+    async fn logs_in(store: &StoreHandle, payload: &str) -> bool {
+        Settings::init_for_tests();
+        let (connection, _peer) = tokio::io::duplex(4096);
+        let connection = FakeConnection {
+            secure: true,
+            ..FakeConnection::new(connection)
+        };
+        let parser_config = ParserConfig {
+            kind: ParserKind::RustyXml,
+            limits: ElementLimits::default(),
+        };
+        let mut stream = XmppStream::new(connection, parser_config);
+        let auth = format!(
+            "<auth xmlns='urn:ietf:params:xml:ns:xmpp-sasl' mechanism='PLAIN'>{payload}</auth>"
+        );
+
+        SaslNegotiator::negotiate_feature(
+            &mut stream,
+            &Element::parse(&auth).unwrap(),
+            store.clone(),
+            &domain(),
+            &SaslMechanisms::default(),
+            0,
+        )
+        .await
+        .is_ok()
+    }
+
+    #[tokio::test]
+    async fn changed_password_replaces_the_old_one() {
+        let store = StoreHandle::new(FakeStoreBackend::default());
+        let register = request(
+            "<iq xmlns='jabber:client' type='set' id='reg5'>\
+                <query xmlns='jabber:iq:register'>\
+                    <username>juliet</username><password>balcony</password>\
+                </query></iq>",
+        );
+        answer(
+            &register,
+            &store,
+            &domain(),
+            None,
+            &PasswordHashing::default(),
+            None,
+        )
+        .await
+        .unwrap();
+        let account = "juliet@localhost/balcony".parse::<Jid>().unwrap();
+        let old_scram = store
+            .get_stored_password(account.to_bare(), StoredPasswordKind::ScramSha1)
+            .await
+            .unwrap();
+
+        let change = request(
+            "<iq xmlns='jabber:client' type='set' id='change1'>\
+                <query xmlns='jabber:iq:register'>\
+                    <username>juliet</username><password>nightingale</password>\
+                </query></iq>",
+        );
+        let reply = answer_account(
+            &change,
+            &store,
+            &account,
+            None,
+            &PasswordHashing::default(),
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(reply.element.get_attribute("type", None), Some("result"));
+        // "\0juliet\0nightingale" and "\0juliet\0balcony"
+        assert!(logs_in(&store, "AGp1bGlldABuaWdodGluZ2FsZQ==").await);
+        assert!(!logs_in(&store, "AGp1bGlldABiYWxjb255").await);
+        let new_scram = store
+            .get_stored_password(account.to_bare(), StoredPasswordKind::ScramSha1)
+            .await
+            .unwrap();
+        assert_ne!(old_scram, new_scram);
+    }
+
+    #[tokio::test]
+    async fn errors_are_described_in_the_language_of_the_stream() {
+        let store = StoreHandle::new(FakeStoreBackend::default());
+        let account = "juliet@localhost".parse::<Jid>().unwrap();
+        let change = request(
+            "<iq xmlns='jabber:client' type='set' id='change3'>\
+                <query xmlns='jabber:iq:register'><password/></query></iq>",
+        );
+
+        let hashing = PasswordHashing::default();
+        let reply = answer_account(&change, &store, &account, None, &hashing, Some("de"))
+            .await
+            .unwrap();
+
+        assert_eq!(condition(&reply).as_deref(), Some("not-acceptable"));
+        let text = reply
+            .element
+            .get_child("error", Some(namespaces::XMPP_CLIENT))
+            .and_then(|error| error.get_child("text", Some(namespaces::XMPP_STANZAS)))
+            .unwrap();
+        assert_eq!(
+            text.get_attribute("lang", Some(namespaces::XML)),
+            Some("de")
+        );
+    }
+
+    #[tokio::test]
+    async fn password_of_another_user_cannot_be_changed() {
+        let store = StoreHandle::new(FakeStoreBackend::default());
+        let account = "juliet@localhost".parse::<Jid>().unwrap();
+        let change = request(
+            "<iq xmlns='jabber:client' type='set' id='change2'>\
+                <query xmlns='jabber:iq:register'>\
+                    <username>romeo</username><password>nightingale</password>\
+                </query></iq>",
+        );
+
+        let reply = answer_account(
+            &change,
+            &store,
+            &account,
+            None,
+            &PasswordHashing::default(),
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(condition(&reply).as_deref(), Some("not-authorized"));
+        assert!(store
+            .get_stored_password(account, StoredPasswordKind::Argon2)
+            .await
+            .is_err());
+    }
+}
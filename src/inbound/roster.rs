@@ -0,0 +1,380 @@
+use std::collections::HashMap;
+
+use anyhow::Error;
+use futures::future::{BoxFuture, FutureExt};
+use tracing::error;
+
+use crate::services::router::{ManagementCommand, RouterHandle};
+use crate::services::store::StoreHandle;
+use crate::xml::{namespaces, Element, Node};
+use crate::xmpp::jid::Jid;
+use crate::xmpp::roster::{RosterItem, RosterSet, Subscription};
+use crate::xmpp::stanza::Stanza;
+use crate::xmpp::stanza_error::{StanzaError, StanzaErrorBuilder};
+
+use super::iq::{IqContext, IqHandler};
+
+const PUSH_ID_PREFIX: &str = "roster-push-";
+
+// Answers requests of authenticated clients for their own roster.
+pub struct RosterHandler;
+
+impl IqHandler for RosterHandler {
+    fn namespace(&self) -> &'static str {
+        namespaces::ROSTER
+    }
+
+    fn answers_for_accounts(&self) -> bool {
+        true
+    }
+
+    fn handle<'a>(
+        &'a self,
+        iq: &'a Stanza,
+        context: &'a IqContext<'a>,
+    ) -> BoxFuture<'a, Option<Stanza>> {
+        async move {
+            let account = context.account?;
+            if iq.to().ok()?? != account.to_bare() {
+                return None;
+            }
+            answer(iq, context.store, context.router, account, context.language).await
+        }
+        .boxed()
+    }
+}
+
+// The reply to a roster request (RFC 6121, section 2) from an authenticated `account`, or `None`
+// if the stanza is something else. Changes are pushed to all of the account's resources,
+// including the one that made them, along with the version of the roster they lead to. Errors
+// are described in `language`, if there is text for it.
+async fn answer(
+    request: &Stanza,
+    store: &StoreHandle,
+    router: &RouterHandle,
+    account: &Jid,
+    language: Option<&str>,
+) -> Option<Stanza> {
+    if request.element.name != "iq" {
+        return None;
+    }
+    let query = request
+        .element
+        .get_child("query", Some(namespaces::ROSTER))?;
+
+    let result = match request.element.get_attribute("type", None) {
+        Some("get") => get(query, store, account).await,
+        Some("set") => set(query, store, router, account).await.map(|()| vec![]),
+        _ => return None,
+    };
+
+    match result {
+        Ok(children) => Some(request.result_reply(children)),
+        Err(error) => request.error_reply(error, language),
+    }
+}
+
+// Clients that cache the roster send the version they have (RFC 6121, section 2.6). There is no
+// history to send only what changed since, so an outdated one gets the whole roster.
+async fn get(
+    request: &Element,
+    store: &StoreHandle,
+    account: &Jid,
+) -> Result<Vec<Node>, StanzaErrorBuilder> {
+    let roster = store
+        .get_roster(account.to_bare())
+        .await
+        .map_err(internal_error)?;
+
+    let cached_version = request.get_attribute("ver", None);
+    if cached_version == Some(roster.version.to_string().as_str()) {
+        return Ok(vec![]);
+    }
+
+    let items = roster.items.iter().map(RosterItem::to_element).collect();
+    let mut query = query(items);
+    if cached_version.is_some() {
+        set_version(&mut query, roster.version);
+    }
+    Ok(vec![Node::Element(query)])
+}
+
+async fn set(
+    query: &Element,
+    store: &StoreHandle,
+    router: &RouterHandle,
+    account: &Jid,
+) -> Result<(), StanzaErrorBuilder> {
+    let (pushed, version) = match RosterSet::try_from(query)? {
+        RosterSet::Update(item) => {
+            let (item, version) = store
+                .set_roster_item(account.to_bare(), item)
+                .await
+                .map_err(internal_error)?;
+            (item.to_element(), version)
+        }
+        RosterSet::Remove(contact) => {
+            let version = store
+                .remove_roster_item(account.to_bare(), contact.clone())
+                .await
+                .map_err(internal_error)?
+                .ok_or(StanzaError::ItemNotFound)?;
+            (removal(contact), version)
+        }
+    };
+
+    let command = ManagementCommand::DeliverToResources(account.to_bare(), push(pushed, version));
+    if router.management.send(command).await.is_err() {
+        error!("Failed to push roster change to {}", account.to_bare());
+    }
+
+    Ok(())
+}
+
+fn removal(contact: Jid) -> Element {
+    let item = RosterItem {
+        jid: contact,
+        name: None,
+        subscription: Subscription::None,
+        groups: vec![],
+    };
+
+    let mut element = item.to_element();
+    element
+        .attributes
+        .insert(("subscription".to_string(), None), "remove".to_string());
+    element
+}
+
+// Offered to clients once they are authenticated.
+pub fn advertise_versioning() -> Element {
+    Element {
+        name: "ver".to_string(),
+        namespace: Some(namespaces::ROSTER_VERSIONING.to_string()),
+        attributes: vec![(
+            ("xmlns".to_string(), None),
+            namespaces::ROSTER_VERSIONING.to_string(),
+        )]
+        .into_iter()
+        .collect(),
+        children: vec![],
+    }
+}
+
+fn push(item: Element, version: u64) -> Stanza {
+    let mut query = query(vec![item]);
+    set_version(&mut query, version);
+
+    let mut attributes = HashMap::new();
+    attributes.insert(
+        ("id".to_string(), None),
+        format!("{PUSH_ID_PREFIX}{}", uuid::Uuid::new_v4()),
+    );
+    attributes.insert(("type".to_string(), None), "set".to_string());
+
+    Stanza {
+        element: Element {
+            name: "iq".to_string(),
+            namespace: Some(namespaces::XMPP_CLIENT.to_string()),
+            attributes,
+            children: vec![Node::Element(query)],
+        },
+    }
+}
+
+fn query(items: Vec<Element>) -> Element {
+    Element {
+        name: "query".to_string(),
+        namespace: Some(namespaces::ROSTER.to_string()),
+        attributes: vec![(("xmlns".to_string(), None), namespaces::ROSTER.to_string())]
+            .into_iter()
+            .collect(),
+        children: items.into_iter().map(Node::Element).collect(),
+    }
+}
+
+fn set_version(query: &mut Element, version: u64) {
+    query
+        .attributes
+        .insert(("ver".to_string(), None), version.to_string());
+}
+
+fn internal_error(err: Error) -> StanzaErrorBuilder {
+    error!("Roster request failed: {}", err);
+    StanzaError::InternalServerError.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use crate::inbound::SecurityContext;
+    use crate::services::store::fake::FakeStoreBackend;
+
+    use super::*;
+
+    fn request(xml: &str) -> Stanza {
+        Stanza {
+            element: Element::parse(xml).unwrap(),
+        }
+    }
+
+    fn juliet() -> Jid {
+        Jid::new(
+            Some("juliet".to_string()),
+            "localhost".to_string(),
+            Some("balcony".to_string()),
+        )
+    }
+
+    fn items(stanza: &Stanza) -> Vec<&Element> {
+        stanza
+            .element
+            .get_child("query", Some(namespaces::ROSTER))
+            .unwrap()
+            .find_children("item", Some(namespaces::ROSTER))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn added_item_is_pushed_and_returned() {
+        let store = StoreHandle::new(FakeStoreBackend::default());
+        let router = RouterHandle::new(store.clone());
+        let (tx, mut resource) = mpsc::channel(8);
+        let _registration = router
+            .register(juliet(), SecurityContext::default(), tx)
+            .await;
+        let set = request(
+            "<iq xmlns='jabber:client' type='set' id='set1'>\
+                <query xmlns='jabber:iq:roster'>\
+                    <item jid='nurse@example.com' name='Nurse'><group>Servants</group></item>\
+                </query>\
+            </iq>",
+        );
+
+        let reply = answer(&set, &store, &router, &juliet(), None)
+            .await
+            .unwrap();
+        assert_eq!(reply.element.get_attribute("type", None), Some("result"));
+        assert!(reply.element.children.is_empty());
+
+        let pushed = resource.recv().await.unwrap();
+        assert_eq!(pushed.element.get_attribute("type", None), Some("set"));
+        assert_eq!(pushed.to(), Ok(Some(juliet())));
+        assert_eq!(
+            items(&pushed)[0].get_attribute("jid", None),
+            Some("nurse@example.com")
+        );
+
+        let get = request(
+            "<iq xmlns='jabber:client' type='get' id='get1'>\
+                <query xmlns='jabber:iq:roster'/></iq>",
+        );
+        let reply = answer(&get, &store, &router, &juliet(), None)
+            .await
+            .unwrap();
+        assert_eq!(reply.element.get_attribute("type", None), Some("result"));
+        let items = items(&reply);
+        assert_eq!(items.len(), 1);
+        assert_eq!(
+            items[0].get_attribute("jid", None),
+            Some("nurse@example.com")
+        );
+        assert_eq!(items[0].get_attribute("name", None), Some("Nurse"));
+        assert_eq!(items[0].get_attribute("subscription", None), Some("none"));
+        assert_eq!(
+            items[0].path_text(&[("group", Some(namespaces::ROSTER))]),
+            Some("Servants".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn cached_roster_is_only_sent_again_when_outdated() {
+        let store = StoreHandle::new(FakeStoreBackend::default());
+        let router = RouterHandle::new(store.clone());
+        let (tx, mut resource) = mpsc::channel(8);
+        let _registration = router
+            .register(juliet(), SecurityContext::default(), tx)
+            .await;
+        let set = request(
+            "<iq xmlns='jabber:client' type='set' id='set1'>\
+                <query xmlns='jabber:iq:roster'><item jid='nurse@example.com'/></query>\
+            </iq>",
+        );
+        answer(&set, &store, &router, &juliet(), None)
+            .await
+            .unwrap();
+        let pushed = resource.recv().await.unwrap();
+        let version = pushed
+            .element
+            .get_child("query", Some(namespaces::ROSTER))
+            .and_then(|query| query.get_attribute("ver", None))
+            .unwrap()
+            .to_string();
+        let get = |version: &str| {
+            request(&format!(
+                "<iq xmlns='jabber:client' type='get' id='get1'>\
+                    <query xmlns='jabber:iq:roster' ver='{version}'/></iq>"
+            ))
+        };
+
+        let unchanged = answer(&get(&version), &store, &router, &juliet(), None)
+            .await
+            .unwrap();
+        assert_eq!(
+            unchanged.element.get_attribute("type", None),
+            Some("result")
+        );
+        assert!(unchanged.element.children.is_empty());
+
+        let outdated = answer(&get(""), &store, &router, &juliet(), None)
+            .await
+            .unwrap();
+        assert_eq!(items(&outdated).len(), 1);
+        let query = outdated
+            .element
+            .get_child("query", Some(namespaces::ROSTER))
+            .unwrap();
+        assert_eq!(query.get_attribute("ver", None), Some(version.as_str()));
+    }
+
+    #[tokio::test]
+    async fn removing_an_unknown_item_is_item_not_found() {
+        let store = StoreHandle::new(FakeStoreBackend::default());
+        let router = RouterHandle::new(store.clone());
+        let remove = request(
+            "<iq xmlns='jabber:client' type='set' id='remove1'>\
+                <query xmlns='jabber:iq:roster'>\
+                    <item jid='nurse@example.com' subscription='remove'/>\
+                </query>\
+            </iq>",
+        );
+
+        let reply = answer(&remove, &store, &router, &juliet(), None)
+            .await
+            .unwrap();
+
+        assert_eq!(reply.element.get_attribute("type", None), Some("error"));
+        assert!(reply
+            .element
+            .path(&[
+                ("error", Some(namespaces::XMPP_CLIENT)),
+                ("item-not-found", Some(namespaces::XMPP_STANZAS)),
+            ])
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn other_iqs_are_not_roster_requests() {
+        let store = StoreHandle::new(FakeStoreBackend::default());
+        let router = RouterHandle::new(store.clone());
+        let ping = request(
+            "<iq xmlns='jabber:client' type='get' id='ping1'>\
+                <ping xmlns='urn:xmpp:ping'/></iq>",
+        );
+
+        assert!(answer(&ping, &store, &router, &juliet(), None)
+            .await
+            .is_none());
+    }
+}
@@ -5,22 +5,30 @@ use std::{
     str::FromStr,
 };
 
-use anyhow::{bail, Error};
+use anyhow::{anyhow, bail, Error};
 use base64::prelude::*;
+use serde::Deserialize;
 use tokio_stream::StreamExt;
 
 use crate::{
-    services::store::{self, StoreHandle},
+    services::{
+        metrics,
+        store::{self, StoreHandle},
+    },
+    settings::PasswordHashing,
     xml::{namespaces, stream_parser::Frame, Element, Node},
     xmpp::{
         jid::Jid,
         stream::{Connection, XmppStream},
+        stream_error::StreamError,
     },
 };
 
 pub use self::plain::StoredPasswordArgon2;
 pub use self::scram::StoredPasswordScram;
 
+mod anonymous;
+mod external;
 mod plain;
 mod scram;
 
@@ -33,21 +41,32 @@ pub struct SaslNegotiator {
 }
 
 impl SaslNegotiator {
-    pub fn advertise_feature(secure: bool, authenticated: bool) -> Element {
-        let mut available_mechanisms = Vec::new();
-
-        if Self::mechanism_available(&Mechanism::External, secure, authenticated) {
-            available_mechanisms.push(Node::Element(Mechanism::External.to_element()));
-        }
-        if Self::mechanism_available(&Mechanism::ScramSha1, secure, authenticated) {
-            available_mechanisms.push(Node::Element(Mechanism::ScramSha1.to_element()));
-        }
-        if Self::mechanism_available(&Mechanism::Plain, secure, authenticated) {
-            available_mechanisms.push(Node::Element(Mechanism::Plain.to_element()));
-        }
+    // Nothing is advertised when no mechanism is acceptable on the connection as it is, e.g.
+    // before TLS has been negotiated.
+    pub fn advertise_feature(
+        context: &SecurityContext,
+        mechanisms: &SaslMechanisms,
+    ) -> Option<Element> {
+        let available_mechanisms: Vec<_> = [
+            Mechanism::External,
+            Mechanism::ScramSha256Plus,
+            Mechanism::ScramSha1Plus,
+            Mechanism::ScramSha256,
+            Mechanism::ScramSha1,
+            Mechanism::Plain,
+            Mechanism::Anonymous,
+        ]
+        .into_iter()
+        .filter(|mechanism| {
+            mechanisms
+                .required_level(mechanism)
+                .is_satisfied_by(context)
+        })
+        .map(|mechanism| Node::Element(mechanism.to_element()))
+        .collect();
 
         if available_mechanisms.is_empty() {
-            todo!("make sure at least one mechanism is available");
+            return None;
         }
 
         let mut attributes = HashMap::new();
@@ -56,33 +75,143 @@ impl SaslNegotiator {
             namespaces::XMPP_SASL.to_string(),
         );
 
-        Element {
+        Some(Element {
             name: "mechanisms".to_string(),
             namespace: Some(namespaces::XMPP_SASL.to_string()),
             attributes,
             children: available_mechanisms,
+        })
+    }
+
+    pub fn advertise_channel_binding(cb_name: &str) -> Element {
+        let channel_binding = Element {
+            name: "channel-binding".to_string(),
+            namespace: Some(namespaces::SASL_CHANNEL_BINDING.to_string()),
+            attributes: vec![(("type".to_string(), None), cb_name.to_string())]
+                .into_iter()
+                .collect(),
+            children: vec![],
+        };
+
+        Element {
+            name: "sasl-channel-binding".to_string(),
+            namespace: Some(namespaces::SASL_CHANNEL_BINDING.to_string()),
+            attributes: vec![(
+                ("xmlns".to_string(), None),
+                namespaces::SASL_CHANNEL_BINDING.to_string(),
+            )]
+            .into_iter()
+            .collect(),
+            children: vec![Node::Element(channel_binding)],
         }
     }
 
+    // A failed attempt may be retried with a new `<auth/>` (RFC 6120, section 6.4.5), but only
+    // `max_retries` times before the stream is closed, so a single connection cannot be used to
+    // guess passwords indefinitely. Accounts are looked up in `domain`, the one the stream is for.
     pub async fn negotiate_feature<C>(
         stream: &mut XmppStream<C>,
         element: &Element,
         store: StoreHandle,
-    ) -> Result<Jid, Error>
+        domain: &Jid,
+        mechanisms: &SaslMechanisms,
+        max_retries: usize,
+    ) -> Result<(Jid, SecurityContext), Error>
     where
         C: Connection,
     {
-        if element.name != "auth" || element.namespace != Some(namespaces::XMPP_SASL.to_string()) {
-            bail!("expected auth element");
+        let mut auth = element.clone();
+        let mut failed_attempts = 0;
+
+        loop {
+            if auth.name != "auth" || auth.namespace != Some(namespaces::XMPP_SASL.to_string()) {
+                bail!("expected auth element");
+            }
+
+            let attempt = Self::authenticate(stream, &auth, store.clone(), domain, mechanisms);
+            if let Some(authenticated) = attempt.await? {
+                return Ok(authenticated);
+            }
+
+            failed_attempts += 1;
+            if failed_attempts > max_retries {
+                let err = anyhow!("{failed_attempts} failed authentication attempts");
+                return Err(err.context(StreamError::PolicyViolation));
+            }
+
+            auth = next_element(stream).await?;
         }
+    }
+
+    // Runs a single attempt, returning `None` once the peer has been told it failed.
+    async fn authenticate<C>(
+        stream: &mut XmppStream<C>,
+        auth: &Element,
+        store: StoreHandle,
+        domain: &Jid,
+        mechanisms: &SaslMechanisms,
+    ) -> Result<Option<(Jid, SecurityContext)>, Error>
+    where
+        C: Connection,
+    {
+        let Some(mechanism) = auth.get_attribute("mechanism", None) else {
+            bail!("auth element is missing mechanism attribute");
+        };
+
+        // the peer may pick another mechanism from those advertised
+        let context = SecurityContext::of(stream);
+        let mechanism = Mechanism::try_from(mechanism).and_then(|mechanism| {
+            if !mechanisms
+                .required_level(&mechanism)
+                .is_satisfied_by(&context)
+            {
+                bail!(SaslError::UnavailableMechanism(mechanism.to_string()));
+            }
+            Ok(mechanism)
+        });
+        let Ok(mechanism) = mechanism else {
+            stream
+                .writer()
+                .write_xml_element(&failure_element("invalid-mechanism"))
+                .await?;
+            return Ok(None);
+        };
 
-        let mechanism = match element.get_attribute("mechanism", None) {
-            Some(mechanism) => Mechanism::try_from(mechanism).unwrap(),
-            None => bail!("auth element is missing mechanism attribute"),
+        let mut negotiator = mechanism.negotiator(store, stream, domain)?;
+        let Some(jid) = Self::exchange(stream, auth, &mut negotiator).await? else {
+            metrics::get().auth_failed(&mechanism.to_string());
+            return Ok(None);
         };
 
-        let mut negotiator = mechanism.negotiator(store)?;
-        let mut response_payload = BASE64_STANDARD.decode(element.get_text()).unwrap();
+        metrics::get().auth_succeeded(&mechanism.to_string());
+        let context = SecurityContext {
+            mechanism: Some(mechanism),
+            ..context
+        };
+        Ok(Some((jid, context)))
+    }
+
+    // The challenges and responses of an attempt once its mechanism is settled.
+    async fn exchange<C>(
+        stream: &mut XmppStream<C>,
+        auth: &Element,
+        negotiator: &mut AnyMechanismNegotiator,
+    ) -> Result<Option<Jid>, Error>
+    where
+        C: Connection,
+    {
+        // `=` stands for an empty initial response (RFC 6120, section 6.4.2)
+        let initial_response = match auth.get_text().as_str() {
+            "=" => Ok(vec![]),
+            text => BASE64_STANDARD.decode(text),
+        };
+        let Ok(mut response_payload) = initial_response else {
+            stream
+                .writer()
+                .write_xml_element(&failure_element("incorrect-encoding"))
+                .await?;
+            return Ok(None);
+        };
 
         loop {
             let result = negotiator.process(response_payload).await;
@@ -122,40 +251,35 @@ impl SaslNegotiator {
                         children,
                     };
                     stream.writer().write_xml_element(&xml).await?;
-                    return Ok(jid);
+                    return Ok(Some(jid));
                 }
-                MechanismNegotiatorResult::Failure(_err) => {
-                    let reason = Element {
-                        name: "not-authorized".to_string(),
-                        namespace: Some(namespaces::XMPP_SASL.to_string()),
-                        attributes: HashMap::new(),
-                        children: vec![],
-                    };
-                    let xml = Element {
-                        name: "failure".to_string(),
-                        namespace: Some(namespaces::XMPP_SASL.to_string()),
-                        attributes: vec![(
-                            ("xmlns".to_string(), None),
-                            namespaces::XMPP_SASL.to_string(),
-                        )]
-                        .into_iter()
-                        .collect(),
-                        children: vec![Node::Element(reason)],
-                    };
-                    stream.writer().write_xml_element(&xml).await?;
+                MechanismNegotiatorResult::Failure(err) => {
+                    stream
+                        .writer()
+                        .write_xml_element(&failure_element(err.condition()))
+                        .await?;
+                    return Ok(None);
                 }
             }
 
-            let Some(Ok(Frame::XmlFragment(response))) = stream.reader().next().await else {
-                bail!("expected xml fragment");
-            };
-
+            let response = next_element(stream).await?;
             match response.name.as_str() {
-                "response" => {
-                    response_payload = BASE64_STANDARD.decode(response.get_text()).unwrap();
-                }
+                "response" => match BASE64_STANDARD.decode(response.get_text()) {
+                    Ok(payload) => response_payload = payload,
+                    Err(_) => {
+                        stream
+                            .writer()
+                            .write_xml_element(&failure_element("incorrect-encoding"))
+                            .await?;
+                        return Ok(None);
+                    }
+                },
                 "abort" => {
-                    bail!("authentication aborted");
+                    stream
+                        .writer()
+                        .write_xml_element(&failure_element("aborted"))
+                        .await?;
+                    return Ok(None);
                 }
                 _ => {
                     bail!("unexpected element");
@@ -163,12 +287,127 @@ impl SaslNegotiator {
             }
         }
     }
+}
+
+async fn next_element<C: Connection>(stream: &mut XmppStream<C>) -> Result<Element, Error> {
+    loop {
+        match stream.reader().next().await {
+            Some(Ok(Frame::XmlFragment(element))) => return Ok(element),
+            Some(Ok(Frame::Whitespace)) => continue,
+            _ => bail!("expected xml fragment"),
+        }
+    }
+}
+
+fn failure_element(condition: &str) -> Element {
+    let reason = Element {
+        name: condition.to_string(),
+        namespace: Some(namespaces::XMPP_SASL.to_string()),
+        attributes: HashMap::new(),
+        children: vec![],
+    };
+
+    Element {
+        name: "failure".to_string(),
+        namespace: Some(namespaces::XMPP_SASL.to_string()),
+        attributes: vec![(
+            ("xmlns".to_string(), None),
+            namespaces::XMPP_SASL.to_string(),
+        )]
+        .into_iter()
+        .collect(),
+        children: vec![Node::Element(reason)],
+    }
+}
+
+// What the connection offers when deciding which mechanisms may be used on it, and how the peer
+// authenticated once it has.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SecurityContext {
+    pub secure: bool,
+    pub channel_binding: bool,
+    // the peer presented a certificate that was verified
+    pub peer_cert: bool,
+    pub mechanism: Option<Mechanism>,
+}
+
+impl SecurityContext {
+    // Whether the peer logged in as a guest without an account (XEP-0175).
+    pub fn is_guest(&self) -> bool {
+        self.mechanism == Some(Mechanism::Anonymous)
+    }
+
+    fn of<C: Connection>(stream: &XmppStream<C>) -> Self {
+        SecurityContext {
+            secure: stream.is_secure(),
+            channel_binding: stream.channel_binding().is_some(),
+            peer_cert: stream.is_authenticated(),
+            mechanism: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SecurityLevel {
+    Disabled,
+    None,
+    Tls,
+    TlsWithChannelBinding,
+    AuthenticatedTls,
+}
+
+impl SecurityLevel {
+    fn is_satisfied_by(&self, context: &SecurityContext) -> bool {
+        match self {
+            SecurityLevel::Disabled => false,
+            SecurityLevel::None => true,
+            SecurityLevel::Tls => context.secure,
+            SecurityLevel::TlsWithChannelBinding => context.secure && context.channel_binding,
+            SecurityLevel::AuthenticatedTls => context.secure && context.peer_cert,
+        }
+    }
+}
+
+// The security each mechanism requires before it is advertised or accepted.
+#[derive(Debug, Deserialize)]
+pub struct SaslMechanisms {
+    pub external: SecurityLevel,
+    pub plain: SecurityLevel,
+    pub scram_sha1: SecurityLevel,
+    pub scram_sha1_plus: SecurityLevel,
+    pub scram_sha256: SecurityLevel,
+    pub scram_sha256_plus: SecurityLevel,
+    // ANONYMOUS hands out throwaway identities, so it is opt-in rather than a security level
+    #[serde(default)]
+    pub allow_anonymous: bool,
+}
+
+impl Default for SaslMechanisms {
+    fn default() -> Self {
+        SaslMechanisms {
+            external: SecurityLevel::AuthenticatedTls,
+            plain: SecurityLevel::Tls,
+            scram_sha1: SecurityLevel::None,
+            scram_sha1_plus: SecurityLevel::TlsWithChannelBinding,
+            scram_sha256: SecurityLevel::None,
+            scram_sha256_plus: SecurityLevel::TlsWithChannelBinding,
+            allow_anonymous: false,
+        }
+    }
+}
 
-    fn mechanism_available(mechanism: &Mechanism, secure: bool, authenticated: bool) -> bool {
+impl SaslMechanisms {
+    fn required_level(&self, mechanism: &Mechanism) -> SecurityLevel {
         match mechanism {
-            Mechanism::External => secure && authenticated,
-            Mechanism::Plain => secure,
-            Mechanism::ScramSha1 => true,
+            Mechanism::External => self.external,
+            Mechanism::Plain => self.plain,
+            Mechanism::ScramSha1 => self.scram_sha1,
+            Mechanism::ScramSha1Plus => self.scram_sha1_plus,
+            Mechanism::ScramSha256 => self.scram_sha256,
+            Mechanism::ScramSha256Plus => self.scram_sha256_plus,
+            Mechanism::Anonymous if self.allow_anonymous => SecurityLevel::None,
+            Mechanism::Anonymous => SecurityLevel::Disabled,
         }
     }
 }
@@ -177,12 +416,50 @@ impl SaslNegotiator {
 pub enum SaslError {
     #[error("the SASL mechanism `{0}` is not supported")]
     UnsupportedMechanism(String),
+    #[error("the SASL mechanism `{0}` is not available on this connection")]
+    UnavailableMechanism(String),
+}
+
+// Why an attempt failed, as far as the peer gets to know (RFC 6120, section 6.5).
+#[derive(thiserror::Error, Debug)]
+pub enum AuthError {
+    #[error("no such user")]
+    NoSuchUser,
+    #[error("incorrect password")]
+    PasswordIncorrect,
+    #[error("not allowed to authorize as the requested identity")]
+    AuthzBad,
+    #[error("malformed request: {0}")]
+    Malformed(Error),
+    #[error("credentials rejected: {0}")]
+    Rejected(Error),
+    #[error("credentials could not be looked up: {0}")]
+    Temporary(Error),
+}
+
+impl AuthError {
+    fn condition(&self) -> &'static str {
+        match self {
+            // whether the account exists is nobody's business
+            AuthError::NoSuchUser | AuthError::PasswordIncorrect | AuthError::Rejected(_) => {
+                "not-authorized"
+            }
+            AuthError::AuthzBad => "invalid-authzid",
+            AuthError::Malformed(_) => "malformed-request",
+            AuthError::Temporary(_) => "temporary-auth-failure",
+        }
+    }
 }
 
-enum Mechanism {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mechanism {
+    Anonymous,
     External,
     Plain,
     ScramSha1,
+    ScramSha1Plus,
+    ScramSha256,
+    ScramSha256Plus,
 }
 
 impl Mechanism {
@@ -195,11 +472,44 @@ impl Mechanism {
         }
     }
 
-    fn negotiator(&self, store: StoreHandle) -> Result<impl MechanismNegotiator, Error> {
+    fn negotiator<C: Connection>(
+        &self,
+        store: StoreHandle,
+        stream: &XmppStream<C>,
+        domain: &Jid,
+    ) -> Result<AnyMechanismNegotiator, Error> {
+        let resolved_domain = domain.domain().to_string();
         match self {
-            Mechanism::External => todo!(),
-            Mechanism::Plain => todo!(),
-            Mechanism::ScramSha1 => scram::ScramSha1Negotiator::new("localhost".to_string(), store),
+            Mechanism::Anonymous => Ok(AnyMechanismNegotiator::Anonymous(
+                anonymous::AnonymousNegotiator::new(resolved_domain, store)?,
+            )),
+            Mechanism::External => Ok(AnyMechanismNegotiator::External(
+                external::ExternalNegotiator::new(resolved_domain, stream.peer_certificate()),
+            )),
+            Mechanism::Plain => Ok(AnyMechanismNegotiator::Plain(plain::PlainNegotiator::new(
+                resolved_domain,
+                store,
+            )?)),
+            Mechanism::ScramSha1 => Ok(AnyMechanismNegotiator::ScramSha1(
+                scram::ScramSha1Negotiator::new(resolved_domain, store)?,
+            )),
+            Mechanism::ScramSha1Plus => Ok(AnyMechanismNegotiator::ScramSha1(
+                scram::ScramSha1Negotiator::new_plus(
+                    resolved_domain,
+                    store,
+                    stream.channel_binding().cloned(),
+                )?,
+            )),
+            Mechanism::ScramSha256 => Ok(AnyMechanismNegotiator::ScramSha256(
+                scram::ScramSha256Negotiator::new(resolved_domain, store)?,
+            )),
+            Mechanism::ScramSha256Plus => Ok(AnyMechanismNegotiator::ScramSha256(
+                scram::ScramSha256Negotiator::new_plus(
+                    resolved_domain,
+                    store,
+                    stream.channel_binding().cloned(),
+                )?,
+            )),
         }
     }
 }
@@ -209,9 +519,13 @@ impl TryFrom<&str> for Mechanism {
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
         match value {
+            "ANONYMOUS" => Ok(Mechanism::Anonymous),
             "EXTERNAL" => Ok(Mechanism::External),
             "PLAIN" => Ok(Mechanism::Plain),
             "SCRAM-SHA-1" => Ok(Mechanism::ScramSha1),
+            "SCRAM-SHA-1-PLUS" => Ok(Mechanism::ScramSha1Plus),
+            "SCRAM-SHA-256" => Ok(Mechanism::ScramSha256),
+            "SCRAM-SHA-256-PLUS" => Ok(Mechanism::ScramSha256Plus),
             _ => bail!(SaslError::UnsupportedMechanism(value.into())),
         }
     }
@@ -220,18 +534,22 @@ impl TryFrom<&str> for Mechanism {
 impl Display for Mechanism {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
+            Mechanism::Anonymous => write!(f, "ANONYMOUS"),
             Mechanism::External => write!(f, "EXTERNAL"),
             Mechanism::Plain => write!(f, "PLAIN"),
             Mechanism::ScramSha1 => write!(f, "SCRAM-SHA-1"),
+            Mechanism::ScramSha1Plus => write!(f, "SCRAM-SHA-1-PLUS"),
+            Mechanism::ScramSha256 => write!(f, "SCRAM-SHA-256"),
+            Mechanism::ScramSha256Plus => write!(f, "SCRAM-SHA-256-PLUS"),
         }
     }
 }
 
 pub trait StoredPassword: FromStr + Display {
-    fn new(plaintext: &str) -> Result<Self, Error>;
+    fn new(plaintext: &str, hashing: &PasswordHashing) -> Result<Self, Error>;
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum StoredPasswordKind {
     Argon2,
     ScramSha1,
@@ -241,7 +559,7 @@ pub enum StoredPasswordKind {
 enum MechanismNegotiatorResult {
     Challenge(Vec<u8>),
     Success(Jid, Option<Vec<u8>>),
-    Failure(Error),
+    Failure(AuthError),
 }
 
 trait MechanismNegotiator {
@@ -253,3 +571,498 @@ trait MechanismNegotiator {
         payload: Vec<u8>,
     ) -> impl Future<Output = MechanismNegotiatorResult> + Send;
 }
+
+enum AnyMechanismNegotiator {
+    Anonymous(anonymous::AnonymousNegotiator),
+    External(external::ExternalNegotiator),
+    Plain(plain::PlainNegotiator),
+    ScramSha1(scram::ScramSha1Negotiator),
+    ScramSha256(scram::ScramSha256Negotiator),
+}
+
+impl AnyMechanismNegotiator {
+    async fn process(&mut self, payload: Vec<u8>) -> MechanismNegotiatorResult {
+        match self {
+            Self::Anonymous(negotiator) => negotiator.process(payload).await,
+            Self::External(negotiator) => negotiator.process(payload),
+            Self::Plain(negotiator) => negotiator.process(payload).await,
+            Self::ScramSha1(negotiator) => negotiator.process(payload).await,
+            Self::ScramSha256(negotiator) => negotiator.process(payload).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hmac::{Hmac, Mac};
+    use scram_rs::ScramSha1Ring;
+    use sha1::{Digest, Sha1};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    use crate::inbound::connection::fake::FakeConnection;
+    use crate::services::store::fake::FakeStoreBackend;
+    use crate::settings::PasswordHashing;
+    use crate::xml::stream_parser::{ElementLimits, ParserConfig, ParserKind};
+
+    use super::*;
+
+    fn advertised(context: SecurityContext, mechanisms: &SaslMechanisms) -> Vec<String> {
+        let Some(advertised) = SaslNegotiator::advertise_feature(&context, mechanisms) else {
+            return vec![];
+        };
+
+        advertised.children().map(Element::get_text).collect()
+    }
+
+    #[test]
+    fn plain_is_suppressed_without_tls() {
+        let mechanisms = SaslMechanisms::default();
+
+        let plaintext = SecurityContext::default();
+        assert_eq!(
+            advertised(plaintext, &mechanisms),
+            vec!["SCRAM-SHA-256", "SCRAM-SHA-1"]
+        );
+
+        let tls = SecurityContext {
+            secure: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            advertised(tls, &mechanisms),
+            vec!["SCRAM-SHA-256", "SCRAM-SHA-1", "PLAIN"]
+        );
+    }
+
+    #[test]
+    fn external_requires_a_client_certificate() {
+        let mechanisms = SaslMechanisms {
+            external: SecurityLevel::AuthenticatedTls,
+            plain: SecurityLevel::Disabled,
+            scram_sha1: SecurityLevel::Disabled,
+            scram_sha1_plus: SecurityLevel::Disabled,
+            scram_sha256: SecurityLevel::Disabled,
+            scram_sha256_plus: SecurityLevel::Disabled,
+            allow_anonymous: false,
+        };
+
+        let tls = SecurityContext {
+            secure: true,
+            channel_binding: true,
+            peer_cert: false,
+            mechanism: None,
+        };
+        assert!(advertised(tls, &mechanisms).is_empty());
+
+        let client_certificate = SecurityContext {
+            peer_cert: true,
+            ..tls
+        };
+        assert_eq!(
+            advertised(client_certificate, &mechanisms),
+            vec!["EXTERNAL"]
+        );
+    }
+
+    #[test]
+    fn scram_plus_is_only_advertised_with_channel_binding() {
+        let mechanisms = SaslMechanisms::default();
+
+        let plaintext = SecurityContext::default();
+        assert!(!advertised(plaintext, &mechanisms).contains(&"SCRAM-SHA-1-PLUS".to_string()));
+
+        let tls = SecurityContext {
+            secure: true,
+            ..Default::default()
+        };
+        assert!(!advertised(tls, &mechanisms).contains(&"SCRAM-SHA-1-PLUS".to_string()));
+
+        let channel_binding = SecurityContext {
+            channel_binding: true,
+            ..tls
+        };
+        assert_eq!(
+            advertised(channel_binding, &mechanisms),
+            vec![
+                "SCRAM-SHA-256-PLUS",
+                "SCRAM-SHA-1-PLUS",
+                "SCRAM-SHA-256",
+                "SCRAM-SHA-1",
+                "PLAIN"
+            ]
+        );
+    }
+
+    #[test]
+    fn anonymous_is_only_advertised_when_allowed() {
+        let mut mechanisms = SaslMechanisms::default();
+        let tls = SecurityContext {
+            secure: true,
+            ..Default::default()
+        };
+        assert!(!advertised(tls, &mechanisms).contains(&"ANONYMOUS".to_string()));
+
+        mechanisms.allow_anonymous = true;
+        assert_eq!(
+            advertised(SecurityContext::default(), &mechanisms),
+            vec!["SCRAM-SHA-256", "SCRAM-SHA-1", "ANONYMOUS"]
+        );
+    }
+
+    fn auth(mechanism: &str, payload: &str) -> Element {
+        Element {
+            name: "auth".to_string(),
+            namespace: Some(namespaces::XMPP_SASL.to_string()),
+            attributes: vec![(("mechanism".to_string(), None), mechanism.to_string())]
+                .into_iter()
+                .collect(),
+            children: vec![Node::Text(payload.to_string())],
+        }
+    }
+
+    async fn negotiate(
+        auth: Element,
+        input: &str,
+        mechanisms: &SaslMechanisms,
+        max_retries: usize,
+    ) -> (Result<Jid, Error>, String) {
+        let domain = "localhost".parse().unwrap();
+        negotiate_for(&domain, auth, input, mechanisms, max_retries).await
+    }
+
+    // Runs a negotiation starting with `auth`, with `input` being all the peer sends after it.
+    // Returns the outcome along with everything written to the peer.
+    async fn negotiate_for(
+        domain: &Jid,
+        auth: Element,
+        input: &str,
+        mechanisms: &SaslMechanisms,
+        max_retries: usize,
+    ) -> (Result<Jid, Error>, String) {
+        let (connection, peer) = tokio::io::duplex(4096);
+        let connection = FakeConnection::new(connection);
+        let (result, output) = negotiate_over(
+            connection,
+            peer,
+            domain,
+            auth,
+            input,
+            mechanisms,
+            max_retries,
+        )
+        .await;
+        (result.map(|(jid, _)| jid), output)
+    }
+
+    fn parser_config() -> ParserConfig {
+        ParserConfig {
+            kind: ParserKind::RustyXml,
+            limits: ElementLimits::default(),
+        }
+    }
+
+    async fn negotiate_over(
+        connection: FakeConnection,
+        mut peer: DuplexStream,
+        domain: &Jid,
+        auth: Element,
+        input: &str,
+        mechanisms: &SaslMechanisms,
+        max_retries: usize,
+    ) -> (Result<(Jid, SecurityContext), Error>, String) {
+        let mut stream = XmppStream::new(connection, parser_config());
+        peer.write_all(input.as_bytes()).await.unwrap();
+        peer.shutdown().await.unwrap();
+
+        let store = StoreHandle::new(FakeStoreBackend::default());
+        let result = SaslNegotiator::negotiate_feature(
+            &mut stream,
+            &auth,
+            store,
+            domain,
+            mechanisms,
+            max_retries,
+        )
+        .await;
+
+        drop(stream);
+        let mut output = String::new();
+        peer.read_to_string(&mut output).await.unwrap();
+        (result, output)
+    }
+
+    #[tokio::test]
+    async fn fourth_failed_attempt_terminates_the_stream() {
+        // without a client certificate every EXTERNAL attempt fails
+        let mechanisms = SaslMechanisms {
+            external: SecurityLevel::None,
+            ..Default::default()
+        };
+        let retry = "<auth xmlns='urn:ietf:params:xml:ns:xmpp-sasl' mechanism='EXTERNAL'>=</auth>";
+
+        let (result, output) =
+            negotiate(auth("EXTERNAL", "="), &retry.repeat(3), &mechanisms, 3).await;
+
+        assert_eq!(
+            result.unwrap_err().downcast_ref::<StreamError>(),
+            Some(&StreamError::PolicyViolation)
+        );
+        assert_eq!(output.matches("<not-authorized").count(), 4);
+    }
+
+    #[tokio::test]
+    async fn failed_attempts_are_counted() {
+        let mechanisms = SaslMechanisms {
+            external: SecurityLevel::None,
+            ..Default::default()
+        };
+        // other tests fail with EXTERNAL too, so this can only tell it went up by at least one
+        let failures = metrics::get().auth_failures("EXTERNAL");
+
+        let (result, _) = negotiate(auth("EXTERNAL", "="), "", &mechanisms, 3).await;
+
+        assert!(result.is_err());
+        assert!(metrics::get().auth_failures("EXTERNAL") > failures);
+    }
+
+    #[tokio::test]
+    async fn malformed_base64_is_incorrect_encoding() {
+        let mechanisms = SaslMechanisms {
+            external: SecurityLevel::None,
+            ..Default::default()
+        };
+
+        let (result, output) =
+            negotiate(auth("EXTERNAL", "!!!notbase64!!!"), "", &mechanisms, 3).await;
+
+        assert!(result.is_err());
+        assert!(output.contains("<incorrect-encoding/>"));
+    }
+
+    #[tokio::test]
+    async fn unsupported_mechanism_can_be_retried() {
+        let mechanisms = SaslMechanisms {
+            allow_anonymous: true,
+            ..Default::default()
+        };
+        let retry = "<auth xmlns='urn:ietf:params:xml:ns:xmpp-sasl' mechanism='ANONYMOUS'>=</auth>";
+
+        let (result, output) = negotiate(auth("DIGEST-MD5", "="), retry, &mechanisms, 3).await;
+
+        assert_eq!(result.unwrap().domain(), "localhost");
+        assert!(output.contains("<invalid-mechanism/>"));
+        assert!(output.contains("<success"));
+    }
+
+    #[tokio::test]
+    async fn authenticated_jid_is_in_the_stream_domain() {
+        let mechanisms = SaslMechanisms {
+            allow_anonymous: true,
+            ..Default::default()
+        };
+        let domain = "example.com".parse().unwrap();
+
+        let (result, output) =
+            negotiate_for(&domain, auth("ANONYMOUS", "="), "", &mechanisms, 3).await;
+
+        assert_eq!(result.unwrap().domain(), "example.com");
+        assert!(output.contains("<success"));
+    }
+
+    // The decoded payload of the first challenge written to the peer.
+    fn challenge_in(output: &str) -> String {
+        let start = output.find("<challenge").unwrap();
+        let challenge = &output[output[start..].find('>').unwrap() + start + 1..];
+        let challenge = &challenge[..challenge.find("</challenge>").unwrap()];
+        String::from_utf8(BASE64_STANDARD.decode(challenge).unwrap()).unwrap()
+    }
+
+    fn hmac_sha1(key: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut mac = Hmac::<Sha1>::new_from_slice(key).unwrap();
+        mac.update(data);
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    // Answers the server-first message with the proof for `password` (RFC 5802, section 3).
+    async fn answer_scram_sha1_challenge(
+        peer: &mut DuplexStream,
+        client_first_bare: &str,
+        password: &str,
+    ) {
+        let mut output = String::new();
+        let mut buffer = [0u8; 4096];
+        while !output.contains("</challenge>") {
+            let read = peer.read(&mut buffer).await.unwrap();
+            assert!(read > 0, "no challenge in {output:?}");
+            output.push_str(std::str::from_utf8(&buffer[..read]).unwrap());
+        }
+        let server_first = challenge_in(&output);
+
+        let attribute = |name: &str| {
+            server_first
+                .split(',')
+                .find_map(|attribute| attribute.strip_prefix(name))
+                .unwrap()
+        };
+        let salt = BASE64_STANDARD.decode(attribute("s=")).unwrap();
+        let iterations = attribute("i=").parse().unwrap();
+        let client_final_bare = format!("c=biws,r={}", attribute("r="));
+
+        let mut salted_password = [0u8; 20];
+        pbkdf2::pbkdf2_hmac::<Sha1>(password.as_bytes(), &salt, iterations, &mut salted_password);
+        let client_key = hmac_sha1(&salted_password, b"Client Key");
+        let auth_message = format!("{client_first_bare},{server_first},{client_final_bare}");
+        let signature = hmac_sha1(&Sha1::digest(&client_key), auth_message.as_bytes());
+        let proof: Vec<u8> = client_key
+            .iter()
+            .zip(signature)
+            .map(|(a, b)| a ^ b)
+            .collect();
+
+        let client_final = format!("{client_final_bare},p={}", BASE64_STANDARD.encode(proof));
+        let response = format!(
+            "<response xmlns='urn:ietf:params:xml:ns:xmpp-sasl'>{}</response>",
+            BASE64_STANDARD.encode(client_final)
+        );
+        peer.write_all(response.as_bytes()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn scram_sha256_is_negotiated() {
+        // "n,,n=juliet,r=fyko+d2lbbFgONRv9qkxdawL"
+        let client_first = "biwsbj1qdWxpZXQscj1meWtvK2QybGJiRmdPTlJ2OXFreGRhd0w=";
+
+        let (result, output) = negotiate(
+            auth("SCRAM-SHA-256", client_first),
+            "",
+            &SaslMechanisms::default(),
+            3,
+        )
+        .await;
+
+        assert!(result.is_err());
+        let server_first = challenge_in(&output);
+        assert!(server_first.starts_with("r=fyko+d2lbbFgONRv9qkxdawL"));
+        assert!(server_first.contains(",s="));
+    }
+
+    #[tokio::test]
+    async fn context_records_the_stream_and_the_mechanism() {
+        let mechanisms = &SaslMechanisms {
+            allow_anonymous: true,
+            ..Default::default()
+        };
+        let domain = &"localhost".parse().unwrap();
+        let context = |secure: bool| async move {
+            let (connection, peer) = tokio::io::duplex(4096);
+            let connection = FakeConnection {
+                secure,
+                ..FakeConnection::new(connection)
+            };
+            let (result, _) = negotiate_over(
+                connection,
+                peer,
+                domain,
+                auth("ANONYMOUS", "="),
+                "",
+                mechanisms,
+                3,
+            )
+            .await;
+            result.unwrap().1
+        };
+
+        let over_tls = context(true).await;
+        let cleartext = context(false).await;
+
+        let store = StoreHandle::new(FakeStoreBackend {
+            stored_password_scram_sha1: Some(
+                StoredPasswordScram::<ScramSha1Ring>::new("password", &PasswordHashing::default())
+                    .unwrap()
+                    .to_string(),
+            ),
+            ..Default::default()
+        });
+        let (connection, mut peer) = tokio::io::duplex(4096);
+        let connection = FakeConnection {
+            secure: true,
+            ..FakeConnection::new(connection)
+        };
+        let mut stream = XmppStream::new(connection, parser_config());
+        // "n,,n=juliet,r=fyko+d2lbbFgONRv9qkxdawL"
+        let client_first = "biwsbj1qdWxpZXQscj1meWtvK2QybGJiRmdPTlJ2OXFreGRhd0w=";
+        let (result, ()) = tokio::join!(
+            SaslNegotiator::negotiate_feature(
+                &mut stream,
+                &auth("SCRAM-SHA-1", client_first),
+                store,
+                domain,
+                mechanisms,
+                3,
+            ),
+            answer_scram_sha1_challenge(
+                &mut peer,
+                "n=juliet,r=fyko+d2lbbFgONRv9qkxdawL",
+                "password"
+            ),
+        );
+        let (jid, over_scram) = result.unwrap();
+
+        assert_eq!(
+            over_tls,
+            SecurityContext {
+                secure: true,
+                channel_binding: false,
+                peer_cert: false,
+                mechanism: Some(Mechanism::Anonymous),
+            }
+        );
+        assert!(!cleartext.secure);
+        assert_eq!(cleartext.mechanism, Some(Mechanism::Anonymous));
+        assert_ne!(over_tls, cleartext);
+
+        // the password authenticates the account, not the TLS peer, which showed no certificate
+        assert_eq!(jid.to_string(), "juliet@localhost");
+        assert!(over_scram.secure);
+        assert!(!over_scram.peer_cert);
+        assert_eq!(over_scram.mechanism, Some(Mechanism::ScramSha1));
+        assert_ne!(over_scram, over_tls);
+    }
+
+    #[test]
+    fn failure_condition_matches_the_cause() {
+        let condition = |err: AuthError| {
+            let failure = failure_element(err.condition());
+            failure.children().next().unwrap().name.clone()
+        };
+
+        assert_eq!(condition(AuthError::NoSuchUser), "not-authorized");
+        assert_eq!(condition(AuthError::PasswordIncorrect), "not-authorized");
+        assert_eq!(condition(AuthError::AuthzBad), "invalid-authzid");
+        assert_eq!(
+            condition(AuthError::Malformed(anyhow!("no separators"))),
+            "malformed-request"
+        );
+        assert_eq!(
+            condition(AuthError::Temporary(anyhow!("database is locked"))),
+            "temporary-auth-failure"
+        );
+    }
+
+    #[test]
+    fn channel_binding_level_requires_channel_binding() {
+        let context = SecurityContext {
+            secure: true,
+            channel_binding: false,
+            peer_cert: false,
+            mechanism: None,
+        };
+        assert!(!SecurityLevel::TlsWithChannelBinding.is_satisfied_by(&context));
+
+        let context = SecurityContext {
+            channel_binding: true,
+            ..context
+        };
+        assert!(SecurityLevel::TlsWithChannelBinding.is_satisfied_by(&context));
+    }
+}
@@ -0,0 +1,52 @@
+use anyhow::Error;
+
+use crate::services::store::StoreHandle;
+use crate::xmpp::jid::Jid;
+
+use super::{MechanismNegotiator, MechanismNegotiatorResult};
+
+// Guests get a fresh identity that no account can ever have (RFC 4505). The trace information
+// a client may send is ignored.
+pub struct AnonymousNegotiator {
+    resolved_domain: String,
+}
+
+impl MechanismNegotiator for AnonymousNegotiator {
+    fn new(resolved_domain: String, _store: StoreHandle) -> Result<Self, Error> {
+        Ok(Self { resolved_domain })
+    }
+
+    async fn process(&mut self, _payload: Vec<u8>) -> MechanismNegotiatorResult {
+        let local = uuid::Uuid::new_v4().to_string();
+        let jid = Jid::new(Some(local), self.resolved_domain.clone(), None);
+
+        MechanismNegotiatorResult::Success(jid, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::services::store::fake::FakeStoreBackend;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn guests_get_distinct_identities_in_the_domain() {
+        let store = StoreHandle::new(FakeStoreBackend::default());
+        let mut negotiator = AnonymousNegotiator::new("localhost".to_string(), store).unwrap();
+
+        let MechanismNegotiatorResult::Success(first, None) = negotiator.process(vec![]).await
+        else {
+            panic!("expected success");
+        };
+        let MechanismNegotiatorResult::Success(second, None) =
+            negotiator.process(b"guest".to_vec()).await
+        else {
+            panic!("expected success");
+        };
+
+        assert_eq!(first.domain(), "localhost");
+        assert_eq!(first.to_bare(), first);
+        assert_ne!(first, second);
+    }
+}
@@ -0,0 +1,207 @@
+use anyhow::{anyhow, Error};
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use x509_parser::{extensions::GeneralName, parse_x509_certificate};
+
+use crate::xmpp::jid::Jid;
+
+use super::{AuthError, MechanismNegotiatorResult};
+
+const ID_ON_XMPP_ADDR: &str = "1.3.6.1.5.5.7.8.5";
+
+// The content of a DER value with a single byte tag.
+fn der_content(value: &[u8]) -> Option<&[u8]> {
+    let (&length, rest) = value.get(1..)?.split_first()?;
+    let (length, rest) = if length < 0x80 {
+        (length as usize, rest)
+    } else {
+        let count = (length & 0x7f) as usize;
+        let length_bytes = rest.get(..count)?;
+        let length = length_bytes
+            .iter()
+            .fold(0, |length, byte| (length << 8) | *byte as usize);
+        (length, &rest[count..])
+    };
+
+    rest.get(..length)
+}
+
+// An xmppAddr is a UTF8String, possibly still wrapped in the explicit tag of `otherName`.
+fn xmpp_addr(value: &[u8]) -> Option<&str> {
+    let value = match value {
+        [0xa0, ..] => der_content(value)?,
+        _ => value,
+    };
+
+    match value {
+        [0x0c, ..] => std::str::from_utf8(der_content(value)?).ok(),
+        _ => None,
+    }
+}
+
+// Certificates name an XMPP address in an xmppAddr subject alternative name (RFC 6120, section
+// 13.7.1.4). The common name is only used if there is none.
+pub fn certificate_identity(certificate: &[u8]) -> Result<Jid, Error> {
+    let (_, certificate) = parse_x509_certificate(certificate)
+        .map_err(|err| anyhow!("could not parse peer certificate: {err}"))?;
+
+    let xmpp_addr = certificate
+        .subject_alternative_name()?
+        .and_then(|extension| {
+            extension
+                .value
+                .general_names
+                .iter()
+                .find_map(|name| match name {
+                    GeneralName::OtherName(oid, value) if oid.to_id_string() == ID_ON_XMPP_ADDR => {
+                        xmpp_addr(*value)
+                    }
+                    _ => None,
+                })
+        });
+    let identity = match xmpp_addr {
+        Some(xmpp_addr) => xmpp_addr,
+        None => certificate
+            .subject()
+            .iter_common_name()
+            .next()
+            .and_then(|common_name| common_name.as_str().ok())
+            .ok_or(anyhow!("peer certificate does not name an XMPP address"))?,
+    };
+
+    identity.parse()
+}
+
+pub struct ExternalNegotiator {
+    resolved_domain: String,
+    identity: Option<Jid>,
+}
+
+impl ExternalNegotiator {
+    pub fn new(resolved_domain: String, peer_certificate: Option<&CertificateDer>) -> Self {
+        let identity = peer_certificate
+            .and_then(|certificate| certificate_identity(certificate).ok())
+            .map(|identity| identity.to_bare());
+
+        Self {
+            resolved_domain,
+            identity,
+        }
+    }
+
+    fn authenticate(&self, payload: &[u8]) -> Result<Jid, AuthError> {
+        let Some(identity) = &self.identity else {
+            return Err(AuthError::Rejected(anyhow!(
+                "peer did not present a certificate naming an XMPP address"
+            )));
+        };
+
+        if identity.domain() != self.resolved_domain {
+            return Err(AuthError::Rejected(anyhow!(
+                "`{identity}` is not an account on `{}`",
+                self.resolved_domain
+            )));
+        }
+
+        // an empty authorization identity asks for the one in the certificate
+        let authzid =
+            std::str::from_utf8(payload).map_err(|err| AuthError::Malformed(err.into()))?;
+        if !authzid.is_empty() && authzid.parse::<Jid>().ok().as_ref() != Some(identity) {
+            return Err(AuthError::AuthzBad);
+        }
+
+        Ok(identity.clone())
+    }
+
+    pub fn process(&mut self, payload: Vec<u8>) -> MechanismNegotiatorResult {
+        match self.authenticate(&payload) {
+            Ok(jid) => MechanismNegotiatorResult::Success(jid, None),
+            Err(err) => MechanismNegotiatorResult::Failure(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::prelude::*;
+
+    use super::*;
+
+    // CN=romeo@localhost with an xmppAddr of juliet@localhost
+    const XMPP_ADDR_CERTIFICATE: &str = "\
+        MIIBtjCCAVygAwIBAgIUepJwXBXPQp9ORJJeiQfFYApNNM4wCgYIKoZIzj0EAwIwGjEYMBYGA1UEAwwPcm9tZW9A\
+        bG9jYWxob3N0MCAXDTI2MTAxNjEyMzA0MVoYDzIxMjYwOTIyMTIzMDQxWjAaMRgwFgYDVQQDDA9yb21lb0Bsb2Nh\
+        bGhvc3QwWTATBgcqhkjOPQIBBggqhkjOPQMBBwNCAARitfMTkc2DfvIDXD5ykcFSpM5EhqVKVoUpShv0g/l1If+1\
+        5wJO8qN5CJT0hvfwZCERs50qOscMlpP2s+wHgHMCo34wfDAdBgNVHQ4EFgQUQpjH+YK9Crc+VvgZA+rAmdXfoLcw\
+        HwYDVR0jBBgwFoAUQpjH+YK9Crc+VvgZA+rAmdXfoLcwDwYDVR0TAQH/BAUwAwEB/zApBgNVHREEIjAgoB4GCCsG\
+        AQUFBwgFoBIMEGp1bGlldEBsb2NhbGhvc3QwCgYIKoZIzj0EAwIDSAAwRQIhAKcg/gMm4DZ/xQ5UpRbCapGI7gBW\
+        /eRzZh1KDRJfC1C6AiAjbtIylvyeYHN/28uHPERZr+ktgC2wluB2yuDm/BXa+w==";
+
+    // CN=romeo@localhost without any subject alternative name
+    const COMMON_NAME_CERTIFICATE: &str = "\
+        MIIBjDCCATGgAwIBAgIUMrZHbvQeZtJHtmLI7XxmICe9hzMwCgYIKoZIzj0EAwIwGjEYMBYGA1UEAwwPcm9tZW9A\
+        bG9jYWxob3N0MCAXDTI2MTAxNjEyMzA0MVoYDzIxMjYwOTIyMTIzMDQxWjAaMRgwFgYDVQQDDA9yb21lb0Bsb2Nh\
+        bGhvc3QwWTATBgcqhkjOPQIBBggqhkjOPQMBBwNCAATSIJgc0pCXt3NMSaEg8smgtstf4+rDJL/pd4xldQ48n62Y\
+        k69OJg3hIsp6vHAujYfkeSitFtlUXuEWaZah2dn1o1MwUTAdBgNVHQ4EFgQULwe1bewjyNcMZJKoXfhQW3usZTMw\
+        HwYDVR0jBBgwFoAULwe1bewjyNcMZJKoXfhQW3usZTMwDwYDVR0TAQH/BAUwAwEB/zAKBggqhkjOPQQDAgNJADBG\
+        AiEA1h6ffJpLTMs1nBC07Q5g1xcL9vqIAviljzD3aa4nuWkCIQDqNkOqlB2uEeuXnsNrgOZrs6v6t5yiDBANrmB7\
+        IQ2YYA==";
+
+    fn certificate(base64: &str) -> CertificateDer<'static> {
+        CertificateDer::from(BASE64_STANDARD.decode(base64).unwrap())
+    }
+
+    #[test]
+    fn xmpp_addr_is_preferred_over_common_name() {
+        let identity = certificate_identity(&certificate(XMPP_ADDR_CERTIFICATE)).unwrap();
+        assert_eq!(identity.to_string(), "juliet@localhost");
+    }
+
+    #[test]
+    fn common_name_is_used_without_xmpp_addr() {
+        let identity = certificate_identity(&certificate(COMMON_NAME_CERTIFICATE)).unwrap();
+        assert_eq!(identity.to_string(), "romeo@localhost");
+    }
+
+    #[test]
+    fn empty_authzid_uses_certificate_identity() {
+        let certificate = certificate(XMPP_ADDR_CERTIFICATE);
+        let mut negotiator = ExternalNegotiator::new("localhost".to_string(), Some(&certificate));
+
+        let MechanismNegotiatorResult::Success(jid, None) = negotiator.process(vec![]) else {
+            panic!("expected success");
+        };
+        assert_eq!(jid.to_string(), "juliet@localhost");
+    }
+
+    #[test]
+    fn matching_authzid_is_accepted() {
+        let certificate = certificate(XMPP_ADDR_CERTIFICATE);
+        let mut negotiator = ExternalNegotiator::new("localhost".to_string(), Some(&certificate));
+
+        let result = negotiator.process(b"juliet@localhost".to_vec());
+
+        assert!(matches!(result, MechanismNegotiatorResult::Success(..)));
+    }
+
+    #[test]
+    fn mismatched_authzid_is_rejected() {
+        let certificate = certificate(XMPP_ADDR_CERTIFICATE);
+        let mut negotiator = ExternalNegotiator::new("localhost".to_string(), Some(&certificate));
+
+        let result = negotiator.process(b"romeo@localhost".to_vec());
+
+        assert!(matches!(
+            result,
+            MechanismNegotiatorResult::Failure(AuthError::AuthzBad)
+        ));
+    }
+
+    #[test]
+    fn missing_certificate_is_rejected() {
+        let mut negotiator = ExternalNegotiator::new("localhost".to_string(), None);
+
+        let result = negotiator.process(vec![]);
+
+        assert!(matches!(result, MechanismNegotiatorResult::Failure(_)));
+    }
+}
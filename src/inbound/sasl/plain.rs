@@ -1,25 +1,138 @@
-use std::{fmt::Display, str::FromStr};
+use std::{fmt::Display, str::FromStr, sync::OnceLock};
 
-use anyhow::Error;
+use anyhow::{anyhow, Error};
 use argon2::{
     password_hash::{self, rand_core::OsRng, PasswordHashString, PasswordHasher, SaltString},
-    Argon2,
+    Algorithm, Argon2, KeyId, Params, ParamsBuilder, PasswordVerifier, Version,
 };
+use tracing::warn;
 
-use super::StoredPassword;
+use crate::services::store::StoreHandle;
+use crate::settings::{get_settings, PasswordHashing, PasswordPepper};
+use crate::utils::blocking;
+use crate::xmpp::jid::Jid;
+
+use super::{
+    AuthError, MechanismNegotiator, MechanismNegotiatorResult, StoredPassword, StoredPasswordKind,
+};
 
 #[derive(Debug)]
 pub struct StoredPasswordArgon2 {
     pub hash: PasswordHashString,
 }
 
-impl StoredPassword for StoredPasswordArgon2 {
-    fn new(plaintext: &str) -> Result<Self, Error> {
+// Stands in for the stored password of accounts that do not exist, so turning those away takes
+// as long as a wrong password does.
+static DUMMY_PASSWORD: OnceLock<StoredPasswordArgon2> = OnceLock::new();
+
+fn dummy_password(hashing: &PasswordHashing) -> Result<&'static StoredPasswordArgon2, Error> {
+    if let Some(dummy) = DUMMY_PASSWORD.get() {
+        return Ok(dummy);
+    }
+    let dummy = StoredPasswordArgon2::new_with_pepper("", None, hashing)?;
+
+    Ok(DUMMY_PASSWORD.get_or_init(|| dummy))
+}
+
+fn argon2_params(hashing: &PasswordHashing) -> Result<ParamsBuilder, Error> {
+    let mut params = ParamsBuilder::new();
+    params
+        .m_cost(hashing.argon2_memory)
+        .t_cost(hashing.argon2_time)
+        .p_cost(hashing.argon2_parallelism);
+    // rejects costs argon2 cannot work with right away, rather than on first use
+    params.build()?;
+
+    Ok(params)
+}
+
+impl StoredPasswordArgon2 {
+    // The pepper id is recorded as the `keyid` parameter of the hash, so hashes made with a
+    // retired pepper can still be verified after rotation.
+    pub fn new_with_pepper(
+        plaintext: &str,
+        pepper: Option<&PasswordPepper>,
+        hashing: &PasswordHashing,
+    ) -> Result<Self, Error> {
         let salt = SaltString::generate(&mut OsRng);
-        let argon2 = Argon2::default();
+        let mut params = argon2_params(hashing)?;
+        let argon2 = match pepper {
+            Some(pepper) => {
+                let params = params.keyid(KeyId::new(pepper.id.as_bytes())?).build()?;
+                Argon2::new_with_secret(
+                    pepper.secret.as_bytes(),
+                    Algorithm::default(),
+                    Version::default(),
+                    params,
+                )?
+            }
+            None => Argon2::new(Algorithm::default(), Version::default(), params.build()?),
+        };
         let hash = argon2.hash_password(plaintext.as_bytes(), &salt)?.into();
         Ok(Self { hash })
     }
+
+    pub fn verify(&self, plaintext: &str) -> Result<bool, Error> {
+        let settings = get_settings();
+        let peppers = settings
+            .password_pepper
+            .iter()
+            .chain(&settings.retired_password_peppers);
+        self.verify_with_peppers(plaintext, peppers)
+    }
+
+    pub fn verify_with_peppers<'p>(
+        &self,
+        plaintext: &str,
+        peppers: impl IntoIterator<Item = &'p PasswordPepper>,
+    ) -> Result<bool, Error> {
+        let hash = self.hash.password_hash();
+        let params = Params::try_from(&hash)?;
+
+        let argon2 = if params.keyid().is_empty() {
+            Argon2::default()
+        } else {
+            let pepper = peppers
+                .into_iter()
+                .find(|pepper| pepper.id.as_bytes() == params.keyid())
+                .ok_or(anyhow!("No password pepper configured for stored hash"))?;
+            Argon2::new_with_secret(
+                pepper.secret.as_bytes(),
+                Algorithm::default(),
+                Version::default(),
+                params,
+            )?
+        };
+
+        Ok(argon2.verify_password(plaintext.as_bytes(), &hash).is_ok())
+    }
+
+    // Hashes made with weaker parameters than configured, or with anything but the current
+    // pepper, are replaced the next time the plaintext is at hand.
+    pub fn needs_rehash(
+        &self,
+        pepper: Option<&PasswordPepper>,
+        hashing: &PasswordHashing,
+    ) -> Result<bool, Error> {
+        let hash = self.hash.password_hash();
+        let params = Params::try_from(&hash)?;
+        let current = argon2_params(hashing)?.build()?;
+
+        let outdated = hash.algorithm != Algorithm::default().ident()
+            || hash.version != Some(Version::default().into())
+            || params.m_cost() < current.m_cost()
+            || params.t_cost() < current.t_cost()
+            || params.p_cost() < current.p_cost();
+        let pepper_id = pepper.map_or(&[][..], |pepper| pepper.id.as_bytes());
+
+        Ok(outdated || params.keyid() != pepper_id)
+    }
+}
+
+impl StoredPassword for StoredPasswordArgon2 {
+    fn new(plaintext: &str, hashing: &PasswordHashing) -> Result<Self, Error> {
+        Self::new_with_pepper(plaintext, get_settings().password_pepper.as_ref(), hashing)
+    }
 }
 
 impl FromStr for StoredPasswordArgon2 {
@@ -36,3 +149,394 @@ impl Display for StoredPasswordArgon2 {
         write!(f, "{}", self.hash)
     }
 }
+
+pub struct PlainNegotiator {
+    resolved_domain: String,
+    store: StoreHandle,
+    pepper: Option<PasswordPepper>,
+    peppers: Vec<PasswordPepper>,
+    hashing: PasswordHashing,
+}
+
+impl PlainNegotiator {
+    fn new_with_peppers(
+        resolved_domain: String,
+        store: StoreHandle,
+        pepper: Option<PasswordPepper>,
+        retired_peppers: Vec<PasswordPepper>,
+        hashing: PasswordHashing,
+    ) -> Self {
+        let peppers = pepper.iter().cloned().chain(retired_peppers).collect();
+
+        Self {
+            resolved_domain,
+            store,
+            pepper,
+            peppers,
+            hashing,
+        }
+    }
+
+    async fn authenticate(&self, payload: &[u8]) -> Result<Jid, AuthError> {
+        let payload =
+            std::str::from_utf8(payload).map_err(|err| AuthError::Malformed(err.into()))?;
+        let [authzid, authcid, password] = payload.split('\0').collect::<Vec<_>>()[..] else {
+            return Err(AuthError::Malformed(anyhow!(
+                "PLAIN payload must consist of authzid, authcid and password"
+            )));
+        };
+
+        // a username that is not a valid localpart cannot belong to anybody
+        let jid = Jid::from_parts(Some(authcid), &self.resolved_domain, None)
+            .map_err(|_| AuthError::NoSuchUser)?;
+        // authorizing as anybody else is not supported
+        if !authzid.is_empty() && authzid.parse::<Jid>().ok() != Some(jid.clone()) {
+            return Err(AuthError::AuthzBad);
+        }
+
+        if !self
+            .store
+            .user_exists(jid.clone())
+            .await
+            .map_err(AuthError::Temporary)?
+        {
+            let password = password.to_string();
+            let hashing = self.hashing;
+            blocking::run(move || dummy_password(&hashing)?.verify_with_peppers(&password, []))
+                .await
+                .and_then(|verified| verified)
+                .map_err(AuthError::Temporary)?;
+            return Err(AuthError::NoSuchUser);
+        }
+        let stored_password = self
+            .store
+            .get_stored_password(jid.clone(), StoredPasswordKind::Argon2)
+            .await
+            .and_then(|stored_password| stored_password.parse::<StoredPasswordArgon2>())
+            .map_err(AuthError::Temporary)?;
+        let password = password.to_string();
+        let peppers = self.peppers.clone();
+        let pepper = self.pepper.clone();
+        let hashing = self.hashing;
+        let (verified, stored_password) = blocking::run(move || -> Result<_, Error> {
+            if !stored_password.verify_with_peppers(&password, &peppers)? {
+                return Ok((false, None));
+            }
+            if !stored_password.needs_rehash(pepper.as_ref(), &hashing)? {
+                return Ok((true, None));
+            }
+            let upgraded =
+                StoredPasswordArgon2::new_with_pepper(&password, pepper.as_ref(), &hashing)?;
+            Ok((true, Some(upgraded)))
+        })
+        .await
+        .and_then(|verified| verified)
+        .map_err(AuthError::Temporary)?;
+        if !verified {
+            return Err(AuthError::PasswordIncorrect);
+        }
+
+        if let Some(stored_password) = stored_password {
+            self.upgrade_stored_password(&jid, stored_password).await;
+        }
+
+        Ok(jid)
+    }
+
+    // failing to upgrade is no reason to turn the user away, the old hash still works
+    async fn upgrade_stored_password(&self, jid: &Jid, stored_password: StoredPasswordArgon2) {
+        let result = self
+            .store
+            .set_stored_password(
+                jid.clone(),
+                StoredPasswordKind::Argon2,
+                stored_password.to_string(),
+            )
+            .await;
+        if let Err(err) = result {
+            warn!("Failed to upgrade stored password for {}: {}", jid, err);
+        }
+    }
+}
+
+impl MechanismNegotiator for PlainNegotiator {
+    fn new(resolved_domain: String, store: StoreHandle) -> Result<Self, Error> {
+        let settings = get_settings();
+
+        Ok(Self::new_with_peppers(
+            resolved_domain,
+            store,
+            settings.password_pepper.clone(),
+            settings.retired_password_peppers.clone(),
+            settings.password_hashing,
+        ))
+    }
+
+    async fn process(&mut self, payload: Vec<u8>) -> MechanismNegotiatorResult {
+        match self.authenticate(&payload).await {
+            Ok(jid) => MechanismNegotiatorResult::Success(jid, None),
+            Err(err) => MechanismNegotiatorResult::Failure(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::services::store::fake::FakeStoreBackend;
+
+    use super::*;
+
+    fn pepper(id: &str, secret: &str) -> PasswordPepper {
+        PasswordPepper {
+            id: id.to_string(),
+            secret: secret.to_string(),
+        }
+    }
+
+    #[test]
+    fn unpeppered_hash_verifies() {
+        let stored_password =
+            StoredPasswordArgon2::new_with_pepper("password", None, &PasswordHashing::default())
+                .unwrap();
+
+        assert!(stored_password.verify_with_peppers("password", []).unwrap());
+        assert!(!stored_password.verify_with_peppers("wrong", []).unwrap());
+    }
+
+    #[test]
+    fn peppered_hash_verifies_only_with_correct_pepper() {
+        let current = pepper("2024", "correct horse battery staple");
+        let stored_password = StoredPasswordArgon2::new_with_pepper(
+            "password",
+            Some(&current),
+            &PasswordHashing::default(),
+        )
+        .unwrap();
+
+        assert!(stored_password
+            .verify_with_peppers("password", [&current])
+            .unwrap());
+        assert!(!stored_password
+            .verify_with_peppers("password", [&pepper("2024", "not the secret")])
+            .unwrap());
+        assert!(stored_password
+            .verify_with_peppers(
+                "password",
+                [&pepper("2025", "correct horse battery staple")]
+            )
+            .is_err());
+        assert!(Argon2::default()
+            .verify_password("password".as_bytes(), &stored_password.hash.password_hash())
+            .is_err());
+    }
+
+    #[test]
+    fn peppered_hash_verifies_with_retired_pepper() {
+        let retired = pepper("2023", "old secret");
+        let stored_password = StoredPasswordArgon2::new_with_pepper(
+            "password",
+            Some(&retired),
+            &PasswordHashing::default(),
+        )
+        .unwrap();
+
+        let current = pepper("2024", "new secret");
+        assert!(stored_password
+            .verify_with_peppers("password", [&current, &retired])
+            .unwrap());
+    }
+
+    #[test]
+    fn weak_or_differently_peppered_hash_needs_rehash() {
+        let current = pepper("2024", "secret");
+        let stored_password = StoredPasswordArgon2::new_with_pepper(
+            "password",
+            Some(&current),
+            &PasswordHashing::default(),
+        )
+        .unwrap();
+        assert!(!stored_password
+            .needs_rehash(Some(&current), &PasswordHashing::default())
+            .unwrap());
+        assert!(stored_password
+            .needs_rehash(None, &PasswordHashing::default())
+            .unwrap());
+        assert!(stored_password
+            .needs_rehash(Some(&pepper("2025", "secret")), &PasswordHashing::default())
+            .unwrap());
+
+        let weak = weak_hash("password");
+        assert!(weak
+            .needs_rehash(None, &PasswordHashing::default())
+            .unwrap());
+    }
+
+    #[test]
+    fn configured_costs_are_used() {
+        let hashing = PasswordHashing {
+            argon2_memory: 1024,
+            argon2_time: 1,
+            argon2_parallelism: 1,
+            ..Default::default()
+        };
+        let stored_password =
+            StoredPasswordArgon2::new_with_pepper("password", None, &hashing).unwrap();
+
+        let params = Params::try_from(&stored_password.hash.password_hash()).unwrap();
+        assert_eq!(
+            (params.m_cost(), params.t_cost(), params.p_cost()),
+            (1024, 1, 1)
+        );
+        assert!(stored_password.verify_with_peppers("password", []).unwrap());
+        assert!(!stored_password.needs_rehash(None, &hashing).unwrap());
+        assert!(stored_password
+            .needs_rehash(None, &PasswordHashing::default())
+            .unwrap());
+    }
+
+    fn weak_hash(plaintext: &str) -> StoredPasswordArgon2 {
+        let params = Params::new(Params::MIN_M_COST, 1, 1, None).unwrap();
+        let argon2 = Argon2::new(Algorithm::default(), Version::default(), params);
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = argon2.hash_password(plaintext.as_bytes(), &salt).unwrap();
+
+        StoredPasswordArgon2 { hash: hash.into() }
+    }
+
+    async fn negotiator() -> PlainNegotiator {
+        let store = StoreHandle::new(FakeStoreBackend::default());
+        let stored_password =
+            StoredPasswordArgon2::new_with_pepper("password", None, &PasswordHashing::default())
+                .unwrap();
+        store
+            .set_stored_password(
+                "juliet@localhost".parse().unwrap(),
+                StoredPasswordKind::Argon2,
+                stored_password.to_string(),
+            )
+            .await
+            .unwrap();
+
+        PlainNegotiator::new_with_peppers(
+            "localhost".to_string(),
+            store,
+            None,
+            vec![],
+            PasswordHashing::default(),
+        )
+    }
+
+    #[tokio::test]
+    async fn plain_accepts_correct_password() {
+        let mut negotiator = negotiator().await;
+
+        let result = negotiator.process(b"\0juliet\0password".to_vec()).await;
+
+        let MechanismNegotiatorResult::Success(jid, None) = result else {
+            panic!("expected success");
+        };
+        assert_eq!(jid.to_string(), "juliet@localhost");
+    }
+
+    #[tokio::test]
+    async fn plain_upgrades_weak_hash() {
+        let store = StoreHandle::new(FakeStoreBackend::default());
+        let jid = "juliet@localhost".parse::<Jid>().unwrap();
+        store
+            .set_stored_password(
+                jid.clone(),
+                StoredPasswordKind::Argon2,
+                weak_hash("password").to_string(),
+            )
+            .await
+            .unwrap();
+        let mut negotiator = PlainNegotiator::new_with_peppers(
+            "localhost".to_string(),
+            store.clone(),
+            None,
+            vec![],
+            PasswordHashing::default(),
+        );
+
+        let result = negotiator.process(b"\0juliet\0password".to_vec()).await;
+        assert!(matches!(
+            result,
+            MechanismNegotiatorResult::Success(_, None)
+        ));
+
+        let stored_password = store
+            .get_stored_password(jid, StoredPasswordKind::Argon2)
+            .await
+            .unwrap()
+            .parse::<StoredPasswordArgon2>()
+            .unwrap();
+        assert!(!stored_password
+            .needs_rehash(None, &PasswordHashing::default())
+            .unwrap());
+        assert!(stored_password.verify_with_peppers("password", []).unwrap());
+    }
+
+    #[tokio::test]
+    async fn plain_rejects_wrong_password() {
+        let mut negotiator = negotiator().await;
+
+        let result = negotiator.process(b"\0juliet\0wrong".to_vec()).await;
+
+        assert!(matches!(
+            result,
+            MechanismNegotiatorResult::Failure(AuthError::PasswordIncorrect)
+        ));
+    }
+
+    #[tokio::test]
+    async fn plain_rejects_payload_without_separators() {
+        let mut negotiator = negotiator().await;
+
+        let result = negotiator.process(b"julietpassword".to_vec()).await;
+        assert!(matches!(
+            result,
+            MechanismNegotiatorResult::Failure(AuthError::Malformed(_))
+        ));
+
+        let result = negotiator.process(b"juliet\0password".to_vec()).await;
+        assert!(matches!(
+            result,
+            MechanismNegotiatorResult::Failure(AuthError::Malformed(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn plain_rejects_foreign_authzid() {
+        let mut negotiator = negotiator().await;
+
+        let result = negotiator
+            .process(b"romeo@localhost\0juliet\0password".to_vec())
+            .await;
+
+        assert!(matches!(
+            result,
+            MechanismNegotiatorResult::Failure(AuthError::AuthzBad)
+        ));
+    }
+
+    #[tokio::test]
+    async fn plain_rejects_unknown_user() {
+        let store = StoreHandle::new(FakeStoreBackend::default());
+        let mut negotiator = PlainNegotiator::new_with_peppers(
+            "localhost".to_string(),
+            store,
+            None,
+            vec![],
+            PasswordHashing::default(),
+        );
+
+        let result = negotiator.process(b"\0juliet\0password".to_vec()).await;
+
+        assert!(matches!(
+            result,
+            MechanismNegotiatorResult::Failure(AuthError::NoSuchUser)
+        ));
+        // the password was checked all the same, against the stand-in
+        assert!(DUMMY_PASSWORD.get().is_some());
+    }
+}
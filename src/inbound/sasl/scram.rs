@@ -8,22 +8,45 @@ use anyhow::{anyhow, bail, Error};
 use base64::prelude::*;
 use password_hash::{rand_core::OsRng, SaltString};
 use scram_rs::{
-    async_trait, scram_async::AsyncScramServer, AsyncScramAuthServer, AsyncScramCbHelper,
-    ScramCommon, ScramHashing, ScramKey, ScramNonce, ScramPassword, ScramResult, ScramResultServer,
-    ScramSha1Ring, SCRAM_TYPES,
+    async_trait, scram_async::AsyncScramServer, scram_error, AsyncScramAuthServer,
+    AsyncScramCbHelper, ScramCommon, ScramErrorCode, ScramHashing, ScramKey, ScramNonce,
+    ScramPassword, ScramResult, ScramResultServer, ScramSha1Ring, ScramSha256Ring, SCRAM_TYPES,
 };
 
 use crate::{
     services::store::{self, StoreHandle},
-    xmpp::jid::Jid,
+    settings::PasswordHashing,
+    utils::{blocking, random},
+    xmpp::{jid::Jid, stream::ChannelBinding},
 };
 
-use super::{MechanismNegotiator, MechanismNegotiatorResult, StoredPassword, StoredPasswordKind};
+use super::{
+    AuthError, MechanismNegotiator, MechanismNegotiatorResult, StoredPassword, StoredPasswordKind,
+};
+
+// The hash functions SCRAM is offered with, each with a stored password of its own.
+pub trait ScramHash: ScramHashing + Send + Sync + 'static {
+    const MECHANISM: &'static str;
+    const STORED_PASSWORD_KIND: StoredPasswordKind;
+}
+
+impl ScramHash for ScramSha1Ring {
+    const MECHANISM: &'static str = "SCRAM-SHA-1";
+    const STORED_PASSWORD_KIND: StoredPasswordKind = StoredPasswordKind::ScramSha1;
+}
+
+impl ScramHash for ScramSha256Ring {
+    const MECHANISM: &'static str = "SCRAM-SHA-256";
+    const STORED_PASSWORD_KIND: StoredPasswordKind = StoredPasswordKind::ScramSha256;
+}
+
+pub type ScramSha1Negotiator = ScramNegotiator<ScramSha1Ring>;
+pub type ScramSha256Negotiator = ScramNegotiator<ScramSha256Ring>;
 
 #[derive(Debug)]
 pub struct StoredPasswordScram<H>
 where
-    H: ScramHashing,
+    H: ScramHash,
 {
     stored_password: ScramPassword,
     _hash_type: std::marker::PhantomData<H>,
@@ -31,12 +54,11 @@ where
 
 impl<H> StoredPassword for StoredPasswordScram<H>
 where
-    H: ScramHashing,
+    H: ScramHash,
 {
-    fn new(plaintext: &str) -> Result<Self, Error> {
-        let iterations = NonZero::new(4096).expect("Iterations must be positive");
+    fn new(plaintext: &str, hashing: &PasswordHashing) -> Result<Self, Error> {
+        let iterations = hashing.scram_iterations;
         let salt = SaltString::generate(&mut OsRng);
-        dbg!(&salt);
         let stored_password = ScramPassword::salt_password_with_params::<&str, H>(
             plaintext,
             Some(salt.as_str().as_bytes().to_vec()),
@@ -54,7 +76,7 @@ where
 
 impl<H> FromStr for StoredPasswordScram<H>
 where
-    H: ScramHashing,
+    H: ScramHash,
 {
     type Err = Error;
 
@@ -65,10 +87,8 @@ where
             bail!("Invalid SCRAM password format");
         }
 
-        dbg!(&parts);
         let iterations = parts[2].parse::<NonZero<u32>>()?;
         let salt_base64 = parts[3].to_string();
-        dbg!(&salt_base64);
         let salted_hashed_password = BASE64_STANDARD.decode(parts[4])?;
         let client_key = BASE64_STANDARD.decode(parts[5])?;
         let server_key = BASE64_STANDARD.decode(parts[6])?;
@@ -90,7 +110,7 @@ where
 
 impl<H> Display for StoredPasswordScram<H>
 where
-    H: ScramHashing,
+    H: ScramHash,
 {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         let salted_hashed_password = self.stored_password.get_salted_hashed_password();
@@ -104,41 +124,88 @@ where
 
         write!(
             f,
-            "$SCRAM-SHA-1${}${}${}${}${}",
-            iterations, salt_base64, salted_hashed_password, client_key, server_key
+            "${}${}${}${}${}${}",
+            H::MECHANISM,
+            iterations,
+            salt_base64,
+            salted_hashed_password,
+            client_key,
+            server_key
         )
     }
 }
 
-pub struct ScramSha1Negotiator {
+pub struct ScramNegotiator<H>
+where
+    H: ScramHash,
+{
     resolved_domain: String,
-    server: AsyncScramServer<ScramSha1Ring, ScramAuthHelper, ScramAuthHelper>,
+    server: AsyncScramServer<H, ScramAuthHelper, ScramAuthHelper>,
 }
 
-impl MechanismNegotiator for ScramSha1Negotiator {
-    fn new(resolved_domain: String, store: StoreHandle) -> Result<Self, Error> {
+impl<H> ScramNegotiator<H>
+where
+    H: ScramHash,
+{
+    // The channel binding data of the connection is what the client has to prove it sees, too.
+    pub fn new_plus(
+        resolved_domain: String,
+        store: StoreHandle,
+        channel_binding: Option<ChannelBinding>,
+    ) -> Result<Self, Error> {
+        let scram_type = format!("{}-PLUS", H::MECHANISM);
+        if channel_binding.is_none() {
+            bail!("{scram_type} requires channel binding");
+        }
+
+        Self::with_scram_type(resolved_domain, store, &scram_type, channel_binding)
+    }
+
+    fn with_scram_type(
+        resolved_domain: String,
+        store: StoreHandle,
+        scram_type: &str,
+        channel_binding: Option<ChannelBinding>,
+    ) -> Result<Self, Error> {
         let helper = ScramAuthHelper {
             resolved_domain: resolved_domain.clone(),
             store,
+            channel_binding,
         };
 
-        let scram_type = SCRAM_TYPES.get_scramtype("SCRAM-SHA-1").unwrap();
-        let server = AsyncScramServer::new(helper.clone(), helper, ScramNonce::none(), scram_type)
-            .map_err(|_err| anyhow!("Could not initialize SCRAM server"))?;
+        let mut nonce_raw = [0u8; 24];
+        random::fill_bytes(&mut nonce_raw);
+        let nonce = BASE64_STANDARD.encode(nonce_raw);
+
+        let scram_type = SCRAM_TYPES.get_scramtype(scram_type).unwrap();
+        let server = AsyncScramServer::new(
+            helper.clone(),
+            helper,
+            ScramNonce::Base64(&nonce),
+            scram_type,
+        )
+        .map_err(|_err| anyhow!("Could not initialize SCRAM server"))?;
 
         Ok(Self {
             resolved_domain,
             server,
         })
     }
+}
+
+impl<H> MechanismNegotiator for ScramNegotiator<H>
+where
+    H: ScramHash,
+{
+    fn new(resolved_domain: String, store: StoreHandle) -> Result<Self, Error> {
+        Self::with_scram_type(resolved_domain, store, H::MECHANISM, None)
+    }
 
     async fn process(&mut self, payload: Vec<u8>) -> MechanismNegotiatorResult {
         let payload = match std::str::from_utf8(&payload) {
             Ok(payload) => payload,
-            Err(_) => {
-                return MechanismNegotiatorResult::Failure(anyhow!(
-                    "Could not parse payload as UTF-8"
-                ))
+            Err(err) => {
+                return MechanismNegotiatorResult::Failure(AuthError::Malformed(err.into()))
             }
         };
         let step_result = self.server.parse_response(payload).await;
@@ -147,12 +214,17 @@ impl MechanismNegotiator for ScramSha1Negotiator {
             ScramResultServer::Data(challenge) => {
                 MechanismNegotiatorResult::Challenge(challenge.into_bytes())
             }
-            ScramResultServer::Error(err) => {
-                MechanismNegotiatorResult::Failure(anyhow!(err.message.clone()).context(err))
-            }
+            ScramResultServer::Error(err) => MechanismNegotiatorResult::Failure(
+                AuthError::Rejected(anyhow!(err.message.clone()).context(err)),
+            ),
             ScramResultServer::Final(additional_data) => {
                 let username = self.server.get_auth_username().cloned().unwrap();
-                let jid = Jid::new(Some(username), self.resolved_domain.clone(), None);
+                let jid = match Jid::from_parts(Some(&username), &self.resolved_domain, None) {
+                    Ok(jid) => jid,
+                    Err(err) => {
+                        return MechanismNegotiatorResult::Failure(AuthError::Rejected(err))
+                    }
+                };
                 let additional_data = if additional_data.is_empty() {
                     None
                 } else {
@@ -168,45 +240,100 @@ impl MechanismNegotiator for ScramSha1Negotiator {
 struct ScramAuthHelper {
     resolved_domain: String,
     store: StoreHandle,
+    channel_binding: Option<ChannelBinding>,
 }
 
 #[async_trait]
-impl AsyncScramCbHelper for ScramAuthHelper {}
+impl AsyncScramCbHelper for ScramAuthHelper {
+    async fn get_tls_server_endpoint(&self) -> ScramResult<Vec<u8>> {
+        match &self.channel_binding {
+            Some(ChannelBinding::TlsServerEndPoint(data)) => Ok(data.clone()),
+            _ => scram_error!(
+                ScramErrorCode::ChanBindNotImplemented,
+                "tls-server-end-point is not available on this connection"
+            ),
+        }
+    }
+
+    async fn get_tls_exporter(&self) -> ScramResult<Vec<u8>> {
+        match &self.channel_binding {
+            Some(ChannelBinding::TlsExporter(data)) => Ok(data.clone()),
+            _ => scram_error!(
+                ScramErrorCode::ChanBindNotImplemented,
+                "tls-exporter is not available on this connection"
+            ),
+        }
+    }
+}
 
 #[async_trait]
-impl AsyncScramAuthServer<ScramSha1Ring> for ScramAuthHelper {
+impl<H> AsyncScramAuthServer<H> for ScramAuthHelper
+where
+    H: ScramHash,
+{
     async fn get_password_for_user(&self, username: &str) -> ScramResult<ScramPassword> {
-        let jid = Jid::new(
-            Some(username.to_string()),
-            self.resolved_domain.clone(),
-            None,
-        );
-        dbg!(&jid);
-        let stored_password = self
-            .store
-            .get_stored_password(jid, StoredPasswordKind::ScramSha1)
-            .await;
-        dbg!(&stored_password);
-
-        let stored_password = stored_password
-            .and_then(|password| password.parse::<StoredPasswordScram<ScramSha1Ring>>());
-
-        match stored_password {
-            Ok(stored_password) => match stored_password.stored_password {
+        // a username that is not a valid localpart is treated like an unknown one
+        let stored_password = match Jid::from_parts(Some(username), &self.resolved_domain, None) {
+            Ok(jid) => {
+                self.store
+                    .get_stored_password(jid, H::STORED_PASSWORD_KIND)
+                    .await
+            }
+            Err(err) => Err(err),
+        };
+
+        let stored_password =
+            stored_password.and_then(|password| password.parse::<StoredPasswordScram<H>>());
+
+        if let Ok(StoredPasswordScram {
+            stored_password:
                 ScramPassword::UserPasswordData {
                     salted_hashed_password,
                     salt_b64,
                     iterations,
                     scram_keys,
-                } => Ok(ScramPassword::found_secret_password(
-                    salted_hashed_password,
-                    salt_b64,
-                    iterations,
-                    Some(scram_keys),
-                )),
-                _ => ScramPassword::not_found::<ScramSha1Ring>(),
-            },
-            Err(_) => ScramPassword::not_found::<ScramSha1Ring>(),
+                },
+            ..
+        }) = stored_password
+        {
+            return Ok(ScramPassword::found_secret_password(
+                salted_hashed_password,
+                salt_b64,
+                iterations,
+                Some(scram_keys),
+            ));
         }
+
+        // unknown users get a freshly salted password, which costs as much as a real one
+        match blocking::run(ScramPassword::not_found::<H>).await {
+            Ok(not_found) => not_found,
+            Err(err) => scram_error!(ScramErrorCode::ExternalError, "{}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU32;
+
+    use super::*;
+
+    #[test]
+    fn configured_iterations_are_stored() {
+        let hashing = PasswordHashing {
+            scram_iterations: NonZeroU32::new(12_345).unwrap(),
+            ..Default::default()
+        };
+
+        let stored_password = StoredPasswordScram::<ScramSha256Ring>::new("password", &hashing)
+            .unwrap()
+            .to_string();
+
+        assert_eq!(stored_password.split('$').nth(1), Some("SCRAM-SHA-256"));
+        assert_eq!(stored_password.split('$').nth(2), Some("12345"));
+        let parsed = stored_password
+            .parse::<StoredPasswordScram<ScramSha256Ring>>()
+            .unwrap();
+        assert_eq!(parsed.stored_password.get_iterations().get(), 12_345);
     }
 }
@@ -13,6 +13,12 @@ use crate::{
     xmpp::stream::{Connection, XmppStream},
 };
 
+// The peer has been told that TLS cannot be negotiated and the stream is closed already
+// (RFC 6120, section 5.4.2.2), so there is no stream error to send.
+#[derive(thiserror::Error, Debug)]
+#[error("STARTTLS cannot proceed")]
+pub struct StarttlsFailure;
+
 pub(super) struct StarttlsNegotiator {
     _private: (),
 }
@@ -40,11 +46,12 @@ impl StarttlsNegotiator {
     where
         C: Connection,
     {
-        if element.name != "starttls"
-            || element.namespace != Some(namespaces::XMPP_STARTTLS.to_string())
-        {
+        if !Self::is_request(element) {
             bail!("expected starttls element");
         }
+        if !stream.is_starttls_allowed() {
+            return Self::refuse(stream).await;
+        }
 
         let starttls_proceed = Element {
             name: "proceed".to_string(),
@@ -63,4 +70,66 @@ impl StarttlsNegotiator {
 
         Ok(())
     }
+
+    pub fn is_request(element: &Element) -> bool {
+        element.name == "starttls"
+            && element.namespace.as_deref() == Some(namespaces::XMPP_STARTTLS)
+    }
+
+    pub async fn refuse<C>(stream: &mut XmppStream<C>) -> Result<(), Error>
+    where
+        C: Connection,
+    {
+        let starttls_failure = Element {
+            name: "failure".to_string(),
+            namespace: Some(namespaces::XMPP_STARTTLS.to_string()),
+            attributes: vec![(
+                ("xmlns".to_string(), None),
+                namespaces::XMPP_STARTTLS.to_string(),
+            )]
+            .into_iter()
+            .collect(),
+            children: vec![],
+        };
+
+        stream.writer().write_xml_element(&starttls_failure).await?;
+        stream.writer().write_stream_close().await?;
+
+        Err(StarttlsFailure.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use crate::inbound::connection::fake::FakeConnection;
+    use crate::xml::stream_parser::{ElementLimits, ParserConfig, ParserKind};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn secure_connection_cannot_starttls_again() {
+        let (connection, mut peer) = tokio::io::duplex(4096);
+        let mut connection = FakeConnection::new(connection);
+        connection.secure = true;
+        let parser_config = ParserConfig {
+            kind: ParserKind::RustyXml,
+            limits: ElementLimits::default(),
+        };
+        let mut stream = XmppStream::new(connection, parser_config);
+
+        let request = StarttlsNegotiator::advertise_feature();
+        let result = StarttlsNegotiator::negotiate_feature(&mut stream, &request).await;
+
+        assert!(result
+            .unwrap_err()
+            .downcast_ref::<StarttlsFailure>()
+            .is_some());
+        drop(stream);
+        let mut output = String::new();
+        peer.read_to_string(&mut output).await.unwrap();
+        assert!(output.contains(r#"<failure xmlns="urn:ietf:params:xml:ns:xmpp-tls"/>"#));
+        assert!(!output.contains("<proceed"));
+    }
 }
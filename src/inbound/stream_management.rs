@@ -0,0 +1,721 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Error};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::oneshot;
+
+use crate::{
+    xml::{namespaces, Element, Node},
+    xmpp::{
+        jid::Jid,
+        stanza::Stanza,
+        stream::{Connection, XmppStream},
+        stream_error::StreamError,
+    },
+};
+
+fn sm_element(name: &str, attributes: Vec<(&str, String)>, children: Vec<Node>) -> Element {
+    let mut attributes = attributes
+        .into_iter()
+        .map(|(name, value)| ((name.to_string(), None), value))
+        .collect::<HashMap<_, _>>();
+    attributes.insert(
+        ("xmlns".to_string(), None),
+        namespaces::STREAM_MANAGEMENT.to_string(),
+    );
+
+    Element {
+        name: name.to_string(),
+        namespace: Some(namespaces::STREAM_MANAGEMENT.to_string()),
+        attributes,
+        children,
+    }
+}
+
+fn failed(condition: &str) -> Element {
+    let condition = Element {
+        name: condition.to_string(),
+        namespace: Some(namespaces::XMPP_STANZAS.to_string()),
+        attributes: vec![(
+            ("xmlns".to_string(), None),
+            namespaces::XMPP_STANZAS.to_string(),
+        )]
+        .into_iter()
+        .collect(),
+        children: vec![],
+    };
+
+    sm_element("failed", vec![], vec![Node::Element(condition)])
+}
+
+pub fn is_nonza(element: &Element) -> bool {
+    element.namespace.as_deref() == Some(namespaces::STREAM_MANAGEMENT)
+}
+
+fn parse_count(element: &Element) -> Result<u32, Error> {
+    element
+        .get_attribute("h", None)
+        .and_then(|h| h.parse::<u32>().ok())
+        .ok_or_else(|| anyhow!("missing or invalid count").context(StreamError::InvalidXml))
+}
+
+// Identifies a resumable session, see `ResumableSessions`.
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+pub struct SmId(String);
+
+impl SmId {
+    fn new() -> Self {
+        SmId(uuid::Uuid::new_v4().to_string())
+    }
+}
+
+impl Display for SmId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+pub enum Negotiated {
+    Enabled { resumption_id: Option<SmId> },
+    Resumed(ResumableState),
+    Failed,
+}
+
+pub struct StreamManagementNegotiator {
+    _private: (),
+}
+
+impl StreamManagementNegotiator {
+    pub fn advertise_feature() -> Element {
+        sm_element("sm", vec![], vec![])
+    }
+
+    // Acks only make sense for stanzas that can be routed, so enabling before a resource is bound
+    // fails (XEP-0198, section 3), leaving the peer free to try again later. Resuming takes the
+    // place of binding, so it fails once a resource is bound.
+    pub async fn negotiate_feature<C>(
+        stream: &mut XmppStream<C>,
+        element: &Element,
+        peer_jid: Option<&Jid>,
+        bound: bool,
+        sessions: &ResumableSessions,
+    ) -> Result<Negotiated, Error>
+    where
+        C: Connection,
+    {
+        if !is_nonza(element) {
+            bail!("expected stream management element");
+        }
+
+        match (element.name.as_str(), bound) {
+            ("enable", true) => Self::enable(stream, element, sessions).await,
+            ("resume", false) => Self::resume(stream, element, peer_jid, sessions).await,
+            ("enable" | "resume", _) => {
+                let failed = failed("unexpected-request");
+                stream.writer().write_xml_element(&failed).await?;
+                Ok(Negotiated::Failed)
+            }
+            _ => bail!("expected enable or resume element"),
+        }
+    }
+
+    async fn enable<C>(
+        stream: &mut XmppStream<C>,
+        element: &Element,
+        sessions: &ResumableSessions,
+    ) -> Result<Negotiated, Error>
+    where
+        C: Connection,
+    {
+        let resumable = matches!(element.get_attribute("resume", None), Some("true" | "1"));
+        let resumption_id = resumable.then(SmId::new);
+
+        let mut attributes = vec![];
+        if let Some(resumption_id) = &resumption_id {
+            attributes.push(("id", resumption_id.to_string()));
+            attributes.push(("resume", "true".to_string()));
+            attributes.push(("max", sessions.timeout.as_secs().to_string()));
+        }
+        let enabled = sm_element("enabled", attributes, vec![]);
+        stream.writer().write_xml_element(&enabled).await?;
+
+        Ok(Negotiated::Enabled { resumption_id })
+    }
+
+    // Whatever the client did not get before the old stream went away is sent again, in order.
+    // The counters carry on from where they were.
+    async fn resume<C>(
+        stream: &mut XmppStream<C>,
+        element: &Element,
+        peer_jid: Option<&Jid>,
+        sessions: &ResumableSessions,
+    ) -> Result<Negotiated, Error>
+    where
+        C: Connection,
+    {
+        let h = parse_count(element)?;
+        let previd = element
+            .get_attribute("previd", None)
+            .map(|previd| SmId(previd.to_string()));
+        let state = match (&previd, peer_jid) {
+            (Some(previd), Some(peer_jid)) => sessions.take(previd, peer_jid).await,
+            _ => None,
+        };
+        let (Some(previd), Some(mut state)) = (previd, state) else {
+            let failed = failed("item-not-found");
+            stream.writer().write_xml_element(&failed).await?;
+            return Ok(Negotiated::Failed);
+        };
+
+        state.stream_management.acknowledge(h)?;
+        state.stream_management.last_request = Instant::now();
+        let attributes = vec![
+            ("previd", previd.to_string()),
+            ("h", state.stream_management.handled.to_string()),
+        ];
+        let resumed = sm_element("resumed", attributes, vec![]);
+        stream.writer().write_xml_element(&resumed).await?;
+        for stanza in &state.stream_management.unacked {
+            stream.writer().write_stanza(stanza).await?;
+        }
+
+        Ok(Negotiated::Resumed(state))
+    }
+}
+
+// The counters of XEP-0198, section 4. Both wrap around at 2^32, so they are only ever compared
+// by their difference. Stanzas sent to the peer are held on to until it acknowledges them.
+pub struct StreamManagement {
+    resumption_id: Option<SmId>,
+    handled: u32,
+    acknowledged: u32,
+    unacked: VecDeque<Stanza>,
+    max_unacked: usize,
+    request_interval: Duration,
+    last_request: Instant,
+}
+
+impl StreamManagement {
+    pub fn new(
+        resumption_id: Option<SmId>,
+        request_interval: Duration,
+        max_unacked: usize,
+        now: Instant,
+    ) -> Self {
+        StreamManagement {
+            resumption_id,
+            handled: 0,
+            acknowledged: 0,
+            unacked: VecDeque::new(),
+            max_unacked,
+            request_interval,
+            last_request: now,
+        }
+    }
+
+    pub fn resumption_id(&self) -> Option<&SmId> {
+        self.resumption_id.as_ref()
+    }
+
+    pub fn stanza_received(&mut self) {
+        self.handled = self.handled.wrapping_add(1);
+    }
+
+    // A peer that never acknowledges anything must not make us hold on to its stanzas forever.
+    pub fn stanza_sent(&mut self, stanza: &Stanza) -> Result<(), Error> {
+        if self.unacked.len() >= self.max_unacked {
+            let err = anyhow!("too many stanzas not acknowledged by peer");
+            return Err(err.context(StreamError::ResourceConstraint));
+        }
+
+        self.unacked.push_back(stanza.clone());
+        Ok(())
+    }
+
+    // Handles a stream management element once enabled, returning what to answer with, if
+    // anything.
+    pub fn process(&mut self, element: &Element) -> Result<Option<Element>, Error> {
+        match element.name.as_str() {
+            "r" => Ok(Some(self.answer())),
+            "a" => {
+                self.acknowledge(parse_count(element)?)?;
+                Ok(None)
+            }
+            "enable" | "resume" => Ok(Some(failed("unexpected-request"))),
+            _ => {
+                let err = anyhow!("unexpected stream management element");
+                Err(err.context(StreamError::UnsupportedStanzaType))
+            }
+        }
+    }
+
+    fn acknowledge(&mut self, h: u32) -> Result<(), Error> {
+        let newly_acknowledged = h.wrapping_sub(self.acknowledged) as usize;
+        if newly_acknowledged > self.unacked.len() {
+            let err = anyhow!("peer acknowledged more stanzas than were sent");
+            return Err(err.context(StreamError::UndefinedCondition));
+        }
+
+        self.unacked.drain(..newly_acknowledged);
+        self.acknowledged = h;
+        Ok(())
+    }
+
+    fn answer(&self) -> Element {
+        sm_element("a", vec![("h", self.handled.to_string())], vec![])
+    }
+
+    pub fn deadline(&self) -> Instant {
+        self.last_request + self.request_interval
+    }
+
+    // Asks for an ack every `request_interval`, as long as there is anything to acknowledge.
+    pub fn poll(&mut self, now: Instant) -> Option<Element> {
+        if now < self.deadline() {
+            return None;
+        }
+
+        self.last_request = now;
+        if self.unacked.is_empty() {
+            return None;
+        }
+
+        Some(sm_element("r", vec![], vec![]))
+    }
+}
+
+// Everything a client needs to pick its session up again on a new stream. The router keeps
+// delivering to the session's channel in the meantime (see `CloseReason::Resumable`), so nothing
+// sent to the client while it was away is lost, and it all arrives after the stanzas that are
+// sent again.
+pub struct ResumableState {
+    pub jid: Jid,
+    pub stream_management: StreamManagement,
+    pub stanza_tx: Sender<Stanza>,
+    pub stanza_rx: Receiver<Stanza>,
+}
+
+// Asks a stream that is still connected to give up its session, see `ResumableSessions::take`.
+pub type Takeover = oneshot::Sender<ResumableState>;
+
+enum ResumableSession {
+    // still on its stream, which hands it over when asked to
+    Live {
+        jid: Jid,
+        takeover_tx: Sender<Takeover>,
+    },
+    Parked {
+        state: ResumableState,
+        parked_at: Instant,
+    },
+}
+
+impl ResumableSession {
+    fn jid(&self) -> &Jid {
+        match self {
+            ResumableSession::Live { jid, .. } => jid,
+            ResumableSession::Parked { state, .. } => &state.jid,
+        }
+    }
+
+    fn parked_at(&self) -> Option<Instant> {
+        match self {
+            ResumableSession::Live { .. } => None,
+            ResumableSession::Parked { parked_at, .. } => Some(*parked_at),
+        }
+    }
+
+    // a live session whose stream is gone without parking it will never be handed over
+    fn is_abandoned(&self) -> bool {
+        matches!(self, ResumableSession::Live { takeover_tx, .. } if takeover_tx.is_closed())
+    }
+}
+
+// Sessions that may be resumed. Those whose stream went away are each kept for `timeout`, which
+// is as long as the router keeps their JID registered.
+#[derive(Clone)]
+pub struct ResumableSessions {
+    timeout: Duration,
+    sessions: Arc<Mutex<HashMap<SmId, ResumableSession>>>,
+}
+
+impl ResumableSessions {
+    pub fn new(timeout: Duration) -> Self {
+        ResumableSessions {
+            timeout,
+            sessions: Default::default(),
+        }
+    }
+
+    // Makes the session of a connected stream resumable. The stream has to hand it over once a
+    // takeover arrives on the returned channel.
+    pub fn watch(&self, id: SmId, jid: Jid) -> Receiver<Takeover> {
+        let (takeover_tx, takeover_rx) = mpsc::channel(1);
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, session| !session.is_abandoned());
+        sessions.insert(id, ResumableSession::Live { jid, takeover_tx });
+
+        takeover_rx
+    }
+
+    pub fn park(&self, id: SmId, state: ResumableState) {
+        let parked_at = Instant::now();
+        let parked = ResumableSession::Parked { state, parked_at };
+        self.sessions.lock().unwrap().insert(id.clone(), parked);
+
+        let sessions = self.sessions.clone();
+        let timeout = self.timeout;
+        tokio::spawn(async move {
+            tokio::time::sleep(timeout).await;
+            let mut sessions = sessions.lock().unwrap();
+            // the session may have been resumed and parked again since
+            if sessions.get(&id).and_then(ResumableSession::parked_at) == Some(parked_at) {
+                sessions.remove(&id);
+            }
+        });
+    }
+
+    // Only the account that enabled resumption can pick the session up again. A session still on
+    // its old stream is taken over from it, since the client evidently lost that stream before
+    // the server noticed (XEP-0198, section 5).
+    async fn take(&self, id: &SmId, peer_jid: &Jid) -> Option<ResumableState> {
+        let takeover_tx = match self.remove(id, peer_jid)? {
+            ResumableSession::Parked { state, .. } => return Some(state),
+            ResumableSession::Live { takeover_tx, .. } => takeover_tx,
+        };
+
+        let (state_tx, state_rx) = oneshot::channel();
+        if takeover_tx.send(state_tx).await.is_ok() {
+            if let Ok(state) = state_rx.await {
+                return Some(state);
+            }
+        }
+
+        // the old stream went away in the meantime, parking the session on its way out
+        match self.remove(id, peer_jid)? {
+            ResumableSession::Parked { state, .. } => Some(state),
+            ResumableSession::Live { .. } => None,
+        }
+    }
+
+    fn remove(&self, id: &SmId, peer_jid: &Jid) -> Option<ResumableSession> {
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.get(id)?.jid().to_bare() != peer_jid.to_bare() {
+            return None;
+        }
+
+        sessions.remove(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+    use tokio::sync::mpsc;
+
+    use crate::inbound::connection::fake::FakeConnection;
+    use crate::xml::stream_parser::{ElementLimits, ParserConfig, ParserKind};
+
+    use super::*;
+
+    fn message(body: &str) -> Stanza {
+        Stanza {
+            element: Element::parse(&format!(
+                "<message xmlns='jabber:client'><body>{body}</body></message>"
+            ))
+            .unwrap(),
+        }
+    }
+
+    fn nonza(xml: &str) -> Element {
+        Element::parse(xml).unwrap()
+    }
+
+    fn juliet() -> Jid {
+        Jid::new(
+            Some("juliet".to_string()),
+            "localhost".to_string(),
+            Some("balcony".to_string()),
+        )
+    }
+
+    // Returns the outcome of negotiating as `juliet` along with everything written to the client.
+    async fn negotiate(
+        element: &str,
+        bound: bool,
+        sessions: &ResumableSessions,
+    ) -> (Negotiated, String) {
+        let (connection, mut peer) = tokio::io::duplex(4096);
+        let parser_config = ParserConfig {
+            kind: ParserKind::RustyXml,
+            limits: ElementLimits::default(),
+        };
+        let mut stream = XmppStream::new(FakeConnection::new(connection), parser_config);
+
+        let negotiated = StreamManagementNegotiator::negotiate_feature(
+            &mut stream,
+            &nonza(element),
+            Some(&juliet().to_bare()),
+            bound,
+            sessions,
+        )
+        .await
+        .unwrap();
+
+        drop(stream);
+        let mut output = String::new();
+        peer.read_to_string(&mut output).await.unwrap();
+        (negotiated, output)
+    }
+
+    #[tokio::test]
+    async fn enable_is_answered_with_enabled() {
+        let sessions = ResumableSessions::new(Duration::from_secs(300));
+        let (negotiated, output) =
+            negotiate("<enable xmlns='urn:xmpp:sm:3'/>", true, &sessions).await;
+
+        assert!(matches!(
+            negotiated,
+            Negotiated::Enabled {
+                resumption_id: None
+            }
+        ));
+        assert!(output.contains("<enabled"));
+        assert!(output.contains("urn:xmpp:sm:3"));
+        assert!(!output.contains("resume="));
+    }
+
+    #[tokio::test]
+    async fn enable_before_binding_fails() {
+        let sessions = ResumableSessions::new(Duration::from_secs(300));
+        let (negotiated, output) =
+            negotiate("<enable xmlns='urn:xmpp:sm:3'/>", false, &sessions).await;
+
+        assert!(matches!(negotiated, Negotiated::Failed));
+        assert!(output.contains("<failed"));
+        assert!(output.contains("<unexpected-request"));
+    }
+
+    fn park(sessions: &ResumableSessions, jid: Jid, bodies: &[&str]) -> SmId {
+        let (id, state) = resumable_state(jid, bodies);
+        sessions.park(id.clone(), state);
+        id
+    }
+
+    fn resumable_state(jid: Jid, bodies: &[&str]) -> (SmId, ResumableState) {
+        let (stanza_tx, stanza_rx) = mpsc::channel(8);
+        let id = SmId::new();
+        let mut stream_management = StreamManagement::new(
+            Some(id.clone()),
+            Duration::from_secs(30),
+            10,
+            Instant::now(),
+        );
+        stream_management.stanza_received();
+        for body in bodies {
+            stream_management.stanza_sent(&message(body)).unwrap();
+        }
+
+        let state = ResumableState {
+            jid,
+            stream_management,
+            stanza_tx,
+            stanza_rx,
+        };
+        (id, state)
+    }
+
+    #[tokio::test]
+    async fn resuming_replays_unacknowledged_stanzas() {
+        let sessions = ResumableSessions::new(Duration::from_secs(300));
+        let (Negotiated::Enabled { resumption_id }, output) = negotiate(
+            "<enable xmlns='urn:xmpp:sm:3' resume='true'/>",
+            true,
+            &sessions,
+        )
+        .await
+        else {
+            panic!("expected stream management to be enabled");
+        };
+        assert!(output.contains(&format!("id=\"{}\"", resumption_id.unwrap())));
+
+        let id = park(&sessions, juliet(), &["one", "two", "three"]);
+        let resume = format!("<resume xmlns='urn:xmpp:sm:3' previd='{id}' h='1'/>");
+        let (negotiated, output) = negotiate(&resume, false, &sessions).await;
+
+        let Negotiated::Resumed(state) = negotiated else {
+            panic!("expected the session to be resumed");
+        };
+        assert_eq!(state.jid, juliet());
+        assert_eq!(state.stream_management.unacked.len(), 2);
+        assert!(output.contains("<resumed"));
+        assert!(output.contains("h=\"1\""));
+        assert!(!output.contains("<body>one</body>"));
+        let two = output.find("<body>two</body>").unwrap();
+        let three = output.find("<body>three</body>").unwrap();
+        assert!(two < three);
+
+        // a session can only be resumed once
+        let (negotiated, output) = negotiate(&resume, false, &sessions).await;
+        assert!(matches!(negotiated, Negotiated::Failed));
+        assert!(output.contains("<item-not-found"));
+    }
+
+    #[tokio::test]
+    async fn session_is_taken_over_from_its_connected_stream() {
+        let sessions = ResumableSessions::new(Duration::from_secs(300));
+        let (id, state) = resumable_state(juliet(), &["one", "two"]);
+        let mut takeover_rx = sessions.watch(id.clone(), juliet());
+        // the old stream hands its session over as soon as it is asked to
+        let old_stream = tokio::spawn(async move {
+            let takeover = takeover_rx.recv().await.unwrap();
+            assert!(takeover.send(state).is_ok());
+        });
+
+        let resume = format!("<resume xmlns='urn:xmpp:sm:3' previd='{id}' h='1'/>");
+        let (negotiated, output) = negotiate(&resume, false, &sessions).await;
+
+        old_stream.await.unwrap();
+        let Negotiated::Resumed(state) = negotiated else {
+            panic!("expected the session to be resumed");
+        };
+        assert_eq!(state.jid, juliet());
+        assert!(output.contains("<resumed"));
+        assert!(!output.contains("<body>one</body>"));
+        assert!(output.contains("<body>two</body>"));
+    }
+
+    #[tokio::test]
+    async fn unknown_session_cannot_be_resumed() {
+        let sessions = ResumableSessions::new(Duration::from_secs(300));
+        park(&sessions, juliet(), &["one"]);
+
+        let resume = format!(
+            "<resume xmlns='urn:xmpp:sm:3' previd='{}' h='0'/>",
+            SmId::new()
+        );
+        let (negotiated, output) = negotiate(&resume, false, &sessions).await;
+
+        assert!(matches!(negotiated, Negotiated::Failed));
+        assert!(output.contains("<item-not-found"));
+        assert!(!output.contains("<body>one</body>"));
+    }
+
+    #[tokio::test]
+    async fn abandoned_session_cannot_be_resumed() {
+        let sessions = ResumableSessions::new(Duration::from_secs(300));
+        let id = SmId::new();
+        drop(sessions.watch(id.clone(), juliet()));
+
+        let resume = format!("<resume xmlns='urn:xmpp:sm:3' previd='{id}' h='0'/>");
+        let (negotiated, output) = negotiate(&resume, false, &sessions).await;
+
+        assert!(matches!(negotiated, Negotiated::Failed));
+        assert!(output.contains("<item-not-found"));
+    }
+
+    #[tokio::test]
+    async fn sessions_of_other_accounts_cannot_be_resumed() {
+        let sessions = ResumableSessions::new(Duration::from_secs(300));
+        let romeo = Jid::new(Some("romeo".to_string()), "localhost".to_string(), None);
+        let id = park(&sessions, romeo.bind("orchard".to_string()), &["one"]);
+
+        let resume = format!("<resume xmlns='urn:xmpp:sm:3' previd='{id}' h='0'/>");
+        let (negotiated, output) = negotiate(&resume, false, &sessions).await;
+
+        assert!(matches!(negotiated, Negotiated::Failed));
+        assert!(output.contains("<item-not-found"));
+    }
+
+    #[tokio::test]
+    async fn parked_sessions_expire() {
+        let sessions = ResumableSessions::new(Duration::from_millis(10));
+        let id = park(&sessions, juliet(), &["one"]);
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let resume = format!("<resume xmlns='urn:xmpp:sm:3' previd='{id}' h='0'/>");
+        let (negotiated, _) = negotiate(&resume, false, &sessions).await;
+        assert!(matches!(negotiated, Negotiated::Failed));
+    }
+
+    #[test]
+    fn ack_reports_the_handled_count() {
+        let mut stream_management =
+            StreamManagement::new(None, Duration::from_secs(30), 10, Instant::now());
+        for _ in 0..3 {
+            stream_management.stanza_received();
+        }
+
+        let answer = stream_management
+            .process(&nonza("<r xmlns='urn:xmpp:sm:3'/>"))
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(answer.name, "a");
+        assert_eq!(answer.get_attribute("h", None), Some("3"));
+    }
+
+    #[test]
+    fn acks_release_unacknowledged_stanzas() {
+        let mut stream_management =
+            StreamManagement::new(None, Duration::from_secs(30), 10, Instant::now());
+        for body in ["one", "two", "three"] {
+            stream_management.stanza_sent(&message(body)).unwrap();
+        }
+
+        let ack = nonza("<a xmlns='urn:xmpp:sm:3' h='2'/>");
+        assert!(stream_management.process(&ack).unwrap().is_none());
+        assert_eq!(stream_management.unacked.len(), 1);
+
+        // acks are cumulative, so repeating one changes nothing
+        assert!(stream_management.process(&ack).unwrap().is_none());
+        assert_eq!(stream_management.unacked.len(), 1);
+
+        let err = stream_management
+            .process(&nonza("<a xmlns='urn:xmpp:sm:3' h='4'/>"))
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<StreamError>(),
+            Some(&StreamError::UndefinedCondition)
+        );
+    }
+
+    #[test]
+    fn counts_wrap_around() {
+        let mut stream_management =
+            StreamManagement::new(None, Duration::from_secs(30), 10, Instant::now());
+        stream_management.acknowledged = u32::MAX;
+        stream_management.stanza_sent(&message("one")).unwrap();
+        stream_management.stanza_sent(&message("two")).unwrap();
+
+        let ack = nonza("<a xmlns='urn:xmpp:sm:3' h='1'/>");
+        assert!(stream_management.process(&ack).unwrap().is_none());
+        assert!(stream_management.unacked.is_empty());
+    }
+
+    #[test]
+    fn acks_are_only_requested_for_outstanding_stanzas() {
+        let start = Instant::now();
+        let mut stream_management = StreamManagement::new(None, Duration::from_secs(30), 1, start);
+
+        assert!(stream_management
+            .poll(start + Duration::from_secs(30))
+            .is_none());
+        stream_management.stanza_sent(&message("one")).unwrap();
+        assert!(stream_management
+            .poll(start + Duration::from_secs(45))
+            .is_none());
+        let request = stream_management.poll(start + Duration::from_secs(60));
+        assert_eq!(request.map(|request| request.name), Some("r".to_string()));
+
+        let err = stream_management.stanza_sent(&message("two")).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<StreamError>(),
+            Some(&StreamError::ResourceConstraint)
+        );
+    }
+}
@@ -0,0 +1,114 @@
+use std::time::SystemTime;
+
+use futures::future::{self, BoxFuture, FutureExt};
+
+use crate::xml::{namespaces, Element, Node};
+use crate::xmpp::delay::timestamp;
+use crate::xmpp::stanza::Stanza;
+
+use super::iq::{IqContext, IqHandler};
+
+// The result for an entity time query (XEP-0202) addressed to the server, or `None` if the
+// stanza is something else. The offset is configured rather than taken from the system, which
+// is usually running on UTC anyway.
+pub fn answer(stanza: &Stanza, offset_minutes: i16, now: SystemTime) -> Option<Stanza> {
+    if stanza.element.name != "iq" || stanza.element.get_attribute("type", None) != Some("get") {
+        return None;
+    }
+    stanza.element.get_child("time", Some(namespaces::TIME))?;
+
+    let children = [("tzo", tzo(offset_minutes)), ("utc", timestamp(now))]
+        .into_iter()
+        .map(|(name, value)| {
+            Node::Element(Element {
+                name: name.to_string(),
+                namespace: Some(namespaces::TIME.to_string()),
+                attributes: Default::default(),
+                children: vec![Node::Text(value)],
+            })
+        })
+        .collect();
+
+    let time = Element {
+        name: "time".to_string(),
+        namespace: Some(namespaces::TIME.to_string()),
+        attributes: vec![(("xmlns".to_string(), None), namespaces::TIME.to_string())]
+            .into_iter()
+            .collect(),
+        children,
+    };
+    Some(stanza.result_reply(vec![Node::Element(time)]))
+}
+
+pub struct TimeHandler {
+    offset_minutes: i16,
+}
+
+impl TimeHandler {
+    pub fn new(offset_minutes: i16) -> Self {
+        TimeHandler { offset_minutes }
+    }
+}
+
+impl IqHandler for TimeHandler {
+    fn namespace(&self) -> &'static str {
+        namespaces::TIME
+    }
+
+    fn handle<'a>(
+        &'a self,
+        iq: &'a Stanza,
+        _context: &'a IqContext<'a>,
+    ) -> BoxFuture<'a, Option<Stanza>> {
+        future::ready(answer(iq, self.offset_minutes, SystemTime::now())).boxed()
+    }
+}
+
+// XEP-0082 TZD, e.g. `-06:00`, with UTC as `+00:00` rather than `Z`.
+fn tzo(offset_minutes: i16) -> String {
+    let sign = if offset_minutes < 0 { '-' } else { '+' };
+    let minutes = offset_minutes.unsigned_abs();
+    format!("{sign}{:02}:{:02}", minutes / 60, minutes % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use regex::Regex;
+
+    use super::*;
+
+    fn query() -> Stanza {
+        Stanza {
+            element: Element::parse(
+                "<iq xmlns='jabber:client' type='get' id='time1' to='localhost'>\
+                    <time xmlns='urn:xmpp:time'/></iq>",
+            )
+            .unwrap(),
+        }
+    }
+
+    fn field(reply: &Stanza, name: &str) -> Option<String> {
+        reply.element.path_text(&[
+            ("time", Some(namespaces::TIME)),
+            (name, Some(namespaces::TIME)),
+        ])
+    }
+
+    #[test]
+    fn time_is_utc_with_the_configured_offset() {
+        let reply = answer(&query(), -360, SystemTime::now()).unwrap();
+
+        assert_eq!(reply.element.get_attribute("type", None), Some("result"));
+        assert_eq!(field(&reply, "tzo"), Some("-06:00".to_string()));
+        let utc = field(&reply, "utc").unwrap();
+        let rfc3339 = Regex::new(r"^\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2}(\.\d+)?Z$").unwrap();
+        assert!(rfc3339.is_match(&utc), "{utc}");
+    }
+
+    #[test]
+    fn offsets_are_hours_and_minutes() {
+        assert_eq!(tzo(0), "+00:00");
+        assert_eq!(tzo(330), "+05:30");
+        assert_eq!(tzo(-570), "-09:30");
+    }
+}
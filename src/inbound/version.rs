@@ -0,0 +1,116 @@
+use futures::future::{self, BoxFuture, FutureExt};
+
+use crate::xml::{namespaces, Element, Node};
+use crate::xmpp::stanza::Stanza;
+
+use super::iq::{IqContext, IqHandler};
+
+const NAME: &str = "confidante";
+
+// The result for a software version query (XEP-0092) addressed to the server, or `None` if the
+// stanza is something else. The operating system is only named if `disclose_os` is set, since
+// it helps anybody looking for a known vulnerability.
+pub fn answer(stanza: &Stanza, disclose_os: bool) -> Option<Stanza> {
+    if stanza.element.name != "iq" || stanza.element.get_attribute("type", None) != Some("get") {
+        return None;
+    }
+    stanza
+        .element
+        .get_child("query", Some(namespaces::VERSION))?;
+
+    let mut fields = vec![("name", NAME), ("version", env!("CARGO_PKG_VERSION"))];
+    if disclose_os {
+        fields.push(("os", std::env::consts::OS));
+    }
+    let children = fields
+        .into_iter()
+        .map(|(name, value)| {
+            Node::Element(Element {
+                name: name.to_string(),
+                namespace: Some(namespaces::VERSION.to_string()),
+                attributes: Default::default(),
+                children: vec![Node::Text(value.to_string())],
+            })
+        })
+        .collect();
+
+    let query = Element {
+        name: "query".to_string(),
+        namespace: Some(namespaces::VERSION.to_string()),
+        attributes: vec![(("xmlns".to_string(), None), namespaces::VERSION.to_string())]
+            .into_iter()
+            .collect(),
+        children,
+    };
+    Some(stanza.result_reply(vec![Node::Element(query)]))
+}
+
+pub struct VersionHandler {
+    disclose_os: bool,
+}
+
+impl VersionHandler {
+    pub fn new(disclose_os: bool) -> Self {
+        VersionHandler { disclose_os }
+    }
+}
+
+impl IqHandler for VersionHandler {
+    fn namespace(&self) -> &'static str {
+        namespaces::VERSION
+    }
+
+    fn handle<'a>(
+        &'a self,
+        iq: &'a Stanza,
+        _context: &'a IqContext<'a>,
+    ) -> BoxFuture<'a, Option<Stanza>> {
+        future::ready(answer(iq, self.disclose_os)).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query() -> Stanza {
+        Stanza {
+            element: Element::parse(
+                "<iq xmlns='jabber:client' type='get' id='version1' to='localhost'>\
+                    <query xmlns='jabber:iq:version'/></iq>",
+            )
+            .unwrap(),
+        }
+    }
+
+    #[test]
+    fn version_is_the_crate_version() {
+        let reply = answer(&query(), false).unwrap();
+
+        assert_eq!(reply.element.get_attribute("type", None), Some("result"));
+        assert_eq!(reply.element.get_attribute("id", None), Some("version1"));
+        let field = |name| {
+            reply.element.path_text(&[
+                ("query", Some(namespaces::VERSION)),
+                (name, Some(namespaces::VERSION)),
+            ])
+        };
+        assert_eq!(field("name"), Some("confidante".to_string()));
+        assert_eq!(
+            field("version"),
+            Some(env!("CARGO_PKG_VERSION").to_string())
+        );
+        assert_eq!(field("os"), None);
+    }
+
+    #[test]
+    fn os_is_only_named_when_disclosed() {
+        let reply = answer(&query(), true).unwrap();
+
+        let os = reply.element.path_text(&[
+            ("query", Some(namespaces::VERSION)),
+            ("os", Some(namespaces::VERSION)),
+        ]);
+        assert_eq!(os, Some(std::env::consts::OS.to_string()));
+    }
+}
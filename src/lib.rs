@@ -0,0 +1,12 @@
+mod inbound;
+mod server;
+mod services;
+mod settings;
+mod types;
+mod utils;
+mod xml;
+mod xmpp;
+
+pub use server::{run, Error};
+// public for the benchmarks
+pub use xml::stream_parser::{AnyStreamParser, ElementLimits, Frame, ParserConfig, ParserKind};
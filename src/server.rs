@@ -0,0 +1,260 @@
+use std::sync::Arc;
+
+use clap::{Parser, Subcommand};
+use scram_rs::{ScramSha1Ring, ScramSha256Ring};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{watch, OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinSet;
+use tracing::{info, warn};
+use tracing_subscriber::EnvFilter;
+
+use crate::inbound::connection::builder::ConnectionBuilder;
+use crate::inbound::connection::tcp::TcpConnection;
+use crate::inbound::{
+    InboundStream, IqHandlers, StoredPassword, StoredPasswordArgon2, StoredPasswordScram,
+};
+use crate::services::metrics;
+use crate::services::router::RouterHandle;
+use crate::services::store::{SqliteStoreBackend, StoreHandle};
+use crate::settings::{get_settings, Settings};
+use crate::utils::blocking;
+use crate::xmpp::jid::Jid;
+
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Parser)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Commands>,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    AddUser { bare_jid: String, password: String },
+    RemoveUser { bare_jid: String },
+    ListUsers,
+}
+
+pub async fn run() -> Result<(), Error> {
+    // RUST_LOG=debug shows every frame, RUST_LOG=trace their contents as well
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    tracing_subscriber::fmt().with_env_filter(filter).init();
+
+    Settings::init()?;
+
+    let store_backend = SqliteStoreBackend::new().await?;
+    let store = StoreHandle::new(store_backend);
+
+    let cli = Cli::parse();
+    match cli.command {
+        Some(Commands::AddUser { bare_jid, password }) => {
+            let bare_jid = bare_jid.parse::<Jid>()?.to_bare();
+            if store.user_exists(bare_jid.clone()).await? {
+                return Err(format!("User {} already exists", bare_jid).into());
+            }
+
+            let hashing = get_settings().password_hashing;
+            let (stored_password_argon2, stored_password_scram_sha1, stored_password_scram_sha256) =
+                blocking::run(move || -> Result<_, anyhow::Error> {
+                    Ok((
+                        StoredPasswordArgon2::new(&password, &hashing)?.to_string(),
+                        StoredPasswordScram::<ScramSha1Ring>::new(&password, &hashing)?.to_string(),
+                        StoredPasswordScram::<ScramSha256Ring>::new(&password, &hashing)?
+                            .to_string(),
+                    ))
+                })
+                .await??;
+            let added = store
+                .add_user(
+                    bare_jid.clone(),
+                    stored_password_argon2,
+                    stored_password_scram_sha1,
+                    stored_password_scram_sha256,
+                )
+                .await?;
+            if !added {
+                return Err(format!("User {} already exists", bare_jid).into());
+            }
+        }
+        Some(Commands::RemoveUser { bare_jid }) => {
+            let bare_jid = bare_jid.parse::<Jid>()?.to_bare();
+            store.remove_user(bare_jid).await?;
+        }
+        Some(Commands::ListUsers) => {
+            for bare_jid in store.list_users().await? {
+                println!("{}", bare_jid);
+            }
+        }
+        None => {
+            let settings = get_settings();
+            IqHandlers::init(settings)?;
+            let router = RouterHandle::new(store.clone());
+            let connection_builder = Arc::new(ConnectionBuilder::from_settings(settings));
+            let direct_tls_builder = Arc::new(
+                ConnectionBuilder::from_settings(settings)
+                    .implicit_tls(Some(settings.tls.server_config.config.clone())),
+            );
+
+            // bind everything first, so a bad address is reported before anybody connects
+            let mut listeners = Vec::new();
+            for address in &settings.connection.c2s_bind {
+                listeners.push((TcpListener::bind(address).await?, &connection_builder));
+            }
+            for address in &settings.connection.direct_tls_bind {
+                listeners.push((TcpListener::bind(address).await?, &direct_tls_builder));
+            }
+            let metrics_listener = match settings.connection.metrics_bind {
+                Some(address) => Some(TcpListener::bind(address).await?),
+                None => None,
+            };
+
+            let connection_limit = Arc::new(Semaphore::new(settings.connection.max_connections));
+            let (shutdown_tx, shutdown_rx) = watch::channel(false);
+            let mut servers = JoinSet::new();
+            for (listener, connection_builder) in listeners {
+                info!("Listening on {}", listener.local_addr()?);
+                servers.spawn(serve(
+                    listener,
+                    connection_limit.clone(),
+                    shutdown_rx.clone(),
+                    router.clone(),
+                    store.clone(),
+                    connection_builder.clone(),
+                ));
+            }
+            if let Some(listener) = metrics_listener {
+                info!("Serving metrics on {}", listener.local_addr()?);
+                servers.spawn(async move { Ok(metrics::serve(listener).await?) });
+            }
+            tokio::select! {
+                Some(result) = servers.join_next() => result??,
+                signal = shutdown_signal() => signal?,
+            }
+
+            info!("Shutting down");
+            // dropping the listeners stops accepting, then the open streams are told to close
+            servers.shutdown().await;
+            let _ = shutdown_tx.send(true);
+            // every connection holds a permit until it is closed
+            let all_connections = settings.connection.max_connections as u32;
+            let closed = tokio::time::timeout(
+                settings.connection.shutdown_grace_period,
+                connection_limit.acquire_many(all_connections),
+            )
+            .await;
+            if closed.is_err() {
+                warn!("Cutting off connections still open after the grace period");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn serve(
+    listener: TcpListener,
+    connection_limit: Arc<Semaphore>,
+    shutdown: watch::Receiver<bool>,
+    router: RouterHandle,
+    store: StoreHandle,
+    connection_builder: Arc<ConnectionBuilder>,
+) -> Result<(), Error> {
+    loop {
+        let (connection, permit) = accept(&listener, &connection_limit).await?;
+
+        let shutdown = shutdown.clone();
+        let router = router.clone();
+        let store = store.clone();
+        let connection_builder = connection_builder.clone();
+
+        tokio::spawn(async move {
+            // held until the connection is closed
+            let _permit = permit;
+            let connection = TcpConnection::new(connection, true);
+            let connection = match connection_builder.build(connection).await {
+                Ok(connection) => connection,
+                Err(err) => {
+                    warn!("Failed to set up connection: {}", err);
+                    return;
+                }
+            };
+            let counters = connection.counters();
+            info!(
+                "New connection: {} from {}",
+                connection
+                    .recording_id()
+                    .map_or("unrecorded".to_string(), |uuid| uuid.to_string()),
+                connection
+                    .source_address()
+                    .map_or("direct peer".to_string(), |address| address.to_string()),
+            );
+
+            let mut stream = InboundStream::new(connection, router, store, shutdown);
+            stream.handle().await;
+            info!(
+                "Connection closed: {} bytes in, {} bytes out",
+                counters.bytes_read(),
+                counters.bytes_written()
+            );
+        });
+    }
+}
+
+async fn shutdown_signal() -> Result<(), Error> {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result?,
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+
+    Ok(())
+}
+
+// Nothing is accepted while the limit is reached, so further peers wait in the listen backlog
+// instead of each costing a task and a file descriptor.
+async fn accept(
+    listener: &TcpListener,
+    connection_limit: &Arc<Semaphore>,
+) -> Result<(TcpStream, OwnedSemaphorePermit), Error> {
+    let permit = connection_limit.clone().acquire_owned().await?;
+    let (connection, _) = listener.accept().await?;
+
+    Ok((connection, permit))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn connections_beyond_the_limit_wait_to_be_accepted() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let connection_limit = Arc::new(Semaphore::new(1));
+        let _first = TcpStream::connect(address).await.unwrap();
+        let _second = TcpStream::connect(address).await.unwrap();
+
+        let (_connection, permit) = accept(&listener, &connection_limit).await.unwrap();
+        let waiting = tokio::time::timeout(
+            Duration::from_millis(50),
+            accept(&listener, &connection_limit),
+        )
+        .await;
+        assert!(waiting.is_err());
+
+        drop(permit);
+        let accepted =
+            tokio::time::timeout(Duration::from_secs(5), accept(&listener, &connection_limit))
+                .await;
+        assert!(accepted.unwrap().is_ok());
+    }
+}
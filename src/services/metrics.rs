@@ -0,0 +1,233 @@
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use anyhow::Error;
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::body::Incoming;
+use hyper::header::CONTENT_TYPE;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use prometheus::{IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder, TEXT_FORMAT};
+use tokio::net::TcpListener;
+use tracing::debug;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+pub fn get() -> &'static Metrics {
+    METRICS.get_or_init(|| Metrics::new().expect("metrics should be valid"))
+}
+
+// Counters and gauges about the running server, in a registry of their own.
+pub struct Metrics {
+    registry: Registry,
+    connections_active: IntGauge,
+    connections_total: IntCounter,
+    tls_upgrades_total: IntCounter,
+    // by stanza kind, which the router only ever sees as `message`, `presence` or `iq`
+    stanzas_routed_total: IntCounterVec,
+    // by mechanism and outcome
+    auth_attempts_total: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> Result<Self, Error> {
+        let registry = Registry::new_custom(Some("confidante".to_string()), None)?;
+        let connections_active =
+            IntGauge::new("connections_active", "Connections currently open.")?;
+        let connections_total = IntCounter::new(
+            "connections_total",
+            "Connections accepted since the server started.",
+        )?;
+        let tls_upgrades_total = IntCounter::new(
+            "tls_upgrades_total",
+            "Streams upgraded to TLS with STARTTLS.",
+        )?;
+        let stanzas_routed_total = IntCounterVec::new(
+            Opts::new(
+                "stanzas_routed_total",
+                "Stanzas handed to the router, by kind.",
+            ),
+            &["kind"],
+        )?;
+        let auth_attempts_total = IntCounterVec::new(
+            Opts::new(
+                "auth_attempts_total",
+                "SASL authentication attempts, by mechanism and outcome.",
+            ),
+            &["mechanism", "outcome"],
+        )?;
+
+        registry.register(Box::new(connections_active.clone()))?;
+        registry.register(Box::new(connections_total.clone()))?;
+        registry.register(Box::new(tls_upgrades_total.clone()))?;
+        registry.register(Box::new(stanzas_routed_total.clone()))?;
+        registry.register(Box::new(auth_attempts_total.clone()))?;
+
+        Ok(Metrics {
+            registry,
+            connections_active,
+            connections_total,
+            tls_upgrades_total,
+            stanzas_routed_total,
+            auth_attempts_total,
+        })
+    }
+
+    // The connection counts as active until the returned guard is dropped.
+    pub fn connection_opened(&self) -> OpenConnection {
+        self.connections_total.inc();
+        self.connections_active.inc();
+        OpenConnection {
+            connections_active: self.connections_active.clone(),
+        }
+    }
+
+    pub fn tls_upgraded(&self) {
+        self.tls_upgrades_total.inc();
+    }
+
+    pub fn stanza_routed(&self, kind: &str) {
+        self.stanzas_routed_total.with_label_values(&[kind]).inc();
+    }
+
+    pub fn auth_succeeded(&self, mechanism: &str) {
+        self.count_auth_attempt(mechanism, "success");
+    }
+
+    pub fn auth_failed(&self, mechanism: &str) {
+        self.count_auth_attempt(mechanism, "failure");
+    }
+
+    #[cfg(test)]
+    pub fn auth_failures(&self, mechanism: &str) -> u64 {
+        self.auth_attempts_total
+            .with_label_values(&[mechanism, "failure"])
+            .get()
+    }
+
+    fn count_auth_attempt(&self, mechanism: &str, outcome: &str) {
+        self.auth_attempts_total
+            .with_label_values(&[mechanism, outcome])
+            .inc();
+    }
+
+    // All metrics in the Prometheus text format.
+    pub fn render(&self) -> Result<String, Error> {
+        Ok(TextEncoder::new().encode_to_string(&self.registry.gather())?)
+    }
+}
+
+pub struct OpenConnection {
+    connections_active: IntGauge,
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.connections_active.dec();
+    }
+}
+
+// Answers `GET /metrics` for a Prometheus scraper. Anything else is not found, and there is no
+// keep-alive, since a scrape is a single request every so often.
+pub async fn serve(listener: TcpListener) -> Result<(), Error> {
+    loop {
+        let (connection, address) = listener.accept().await?;
+        tokio::spawn(async move {
+            let connection = http1::Builder::new()
+                .keep_alive(false)
+                .serve_connection(TokioIo::new(connection), service_fn(answer));
+            let answered = tokio::time::timeout(REQUEST_TIMEOUT, connection).await;
+            if !matches!(answered, Ok(Ok(()))) {
+                debug!("Failed to answer metrics request from {}", address);
+            }
+        });
+    }
+}
+
+async fn answer(request: Request<Incoming>) -> Result<Response<Full<Bytes>>, Error> {
+    if request.method() != Method::GET || request.uri().path() != "/metrics" {
+        let response = Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Full::default())?;
+        return Ok(response);
+    }
+
+    let response = Response::builder()
+        .header(CONTENT_TYPE, TEXT_FORMAT)
+        .body(Full::new(Bytes::from(get().render()?)))?;
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    use super::*;
+
+    #[test]
+    fn metrics_are_rendered_with_their_labels() {
+        let metrics = Metrics::new().unwrap();
+        let connection = metrics.connection_opened();
+        metrics.connection_opened();
+        metrics.stanza_routed("message");
+        metrics.stanza_routed("message");
+        metrics.auth_failed("PLAIN");
+
+        let rendered = metrics.render().unwrap();
+
+        assert!(rendered.contains("# TYPE confidante_connections_active gauge\n"));
+        assert!(rendered.contains("\nconfidante_connections_active 1\n"));
+        assert!(rendered.contains("\nconfidante_connections_total 2\n"));
+        assert!(rendered.contains("\nconfidante_stanzas_routed_total{kind=\"message\"} 2\n"));
+        assert!(rendered.contains(
+            "\nconfidante_auth_attempts_total{mechanism=\"PLAIN\",outcome=\"failure\"} 1\n"
+        ));
+        assert_eq!(metrics.auth_failures("PLAIN"), 1);
+        drop(connection);
+        assert!(metrics
+            .render()
+            .unwrap()
+            .contains("\nconfidante_connections_active 0\n"));
+    }
+
+    #[tokio::test]
+    async fn metrics_are_served_over_http() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener));
+
+        let mut scraper = TcpStream::connect(address).await.unwrap();
+        scraper
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        scraper.read_to_string(&mut response).await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("# TYPE confidante_connections_total counter\n"));
+    }
+
+    #[tokio::test]
+    async fn other_paths_are_not_found() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener));
+
+        let mut scraper = TcpStream::connect(address).await.unwrap();
+        scraper
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        scraper.read_to_string(&mut response).await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
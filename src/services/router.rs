@@ -1,77 +1,1215 @@
 use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime};
 
-use tokio::{select, sync::mpsc};
+use tokio::{
+    select,
+    sync::{mpsc, oneshot},
+};
+use tracing::error;
 
+use crate::inbound::SecurityContext;
+use crate::services::metrics;
+use crate::services::store::{OfflineMessage, StoreHandle};
+use crate::xml::{namespaces, Element};
 use crate::xmpp::{jid::Jid, stanza::Stanza};
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryOutcome {
+    Delivered,
+    // Kept until the recipient's account has an available resource again
+    Stored,
+    // The recipient is there, but too far behind to take anything more for now
+    RecipientOverloaded,
+    // The recipient's account already holds as many messages as it may keep (XEP-0160)
+    StorageFull,
+    NoSuchRecipient,
+    RouterUnavailable,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum RouterError {
+    #[error("stanza has no recipient")]
+    MissingRecipient,
+    #[error("stanza recipient `{0}` is not a valid JID")]
+    InvalidRecipient(String),
+}
+
+struct Delivery {
+    stanza: Stanza,
+    result_tx: oneshot::Sender<DeliveryOutcome>,
+}
+
+// Why a stream gives up its registration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    // The stream is done, so its resource becomes unavailable right away
+    Closed,
+    // The stream was lost, but its session may be resumed on another one for this long. Until
+    // then the resource stays available and stanzas for it wait in its channel.
+    Resumable(Duration),
+}
+
 #[derive(Debug)]
 pub enum ManagementCommand {
-    Register(Jid, mpsc::Sender<Stanza>),
-    Unregister(Jid),
+    Register(Jid, SecurityContext, mpsc::Sender<Stanza>),
+    Unregister(Jid, CloseReason),
+    // Ends a session that was not resumed by the given time
+    Expire(Jid, Instant),
+    // A broadcast presence, without `to`, sent by the given resource
+    UpdatePresence(Jid, Stanza),
+    IsRegistered(Jid, oneshot::Sender<bool>),
+    // Delivers a copy to every resource of the account, addressed to each of them
+    DeliverToResources(Jid, Stanza),
+}
+
+// A stream registered under a JID, with its last presence while it is available.
+struct Session {
+    jid: Jid,
+    presence: Option<Stanza>,
+    tx: mpsc::Sender<Stanza>,
+    // how the stream is secured, for decisions about what the session may do
+    security: SecurityContext,
+    // Once `tx` has been full, everything else for the session queues up behind what did not
+    // fit, so that stanzas arrive in the order they were routed (RFC 6120, section 10.1).
+    overflow: Option<mpsc::Sender<Stanza>>,
+    // set while the stream is gone but the session may still be resumed
+    resumable_until: Option<Instant>,
+}
+
+impl Session {
+    fn priority(&self) -> Option<i8> {
+        self.presence.as_ref().and_then(Stanza::presence_priority)
+    }
+}
+
+const OVERFLOW_BUFFER_SIZE: usize = 256;
+
+// Feeds the session's channel from its overflow queue until the session goes away.
+fn forward_overflow(tx: mpsc::Sender<Stanza>) -> mpsc::Sender<Stanza> {
+    let (overflow_tx, mut overflow_rx) = mpsc::channel(OVERFLOW_BUFFER_SIZE);
+    tokio::spawn(async move {
+        while let Some(stanza) = overflow_rx.recv().await {
+            if tx.send(stanza).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    overflow_tx
+}
+
+fn unavailable_presence() -> Stanza {
+    Stanza {
+        element: Element {
+            name: "presence".to_string(),
+            namespace: Some(namespaces::XMPP_CLIENT.to_string()),
+            attributes: vec![(("type".to_string(), None), "unavailable".to_string())]
+                .into_iter()
+                .collect(),
+            children: vec![],
+        },
+    }
 }
 
 struct Router {
-    stanzas: mpsc::Receiver<Stanza>,
+    deliveries: mpsc::Receiver<Delivery>,
     management: mpsc::Receiver<ManagementCommand>,
-    entities: HashMap<Jid, mpsc::Sender<Stanza>>,
+    // keyed by bare JID, so all resources of an account are found in one place
+    entities: HashMap<Jid, Vec<Session>>,
+    store: StoreHandle,
+    // to expire suspended sessions with, without keeping the router alive
+    management_tx: mpsc::WeakSender<ManagementCommand>,
 }
 
 impl Router {
     async fn run(&mut self) {
         loop {
+            // registrations take effect before any stanza sent after them is routed
             select! {
-                Some(stanza) = self.stanzas.recv() => {
-                    self.route_stanza(stanza).await;
-                }
+                biased;
                 Some(command) = self.management.recv() => {
                     self.handle_management_command(command).await;
                 }
+                Some(Delivery { stanza, result_tx }) = self.deliveries.recv() => {
+                    metrics::get().stanza_routed(&stanza.element.name);
+                    match self.route_stanza(&stanza) {
+                        DeliveryOutcome::NoSuchRecipient
+                            if is_storable(&stanza) && !self.is_from_guest(&stanza) =>
+                        {
+                            self.store_offline(stanza, result_tx);
+                        }
+                        outcome => {
+                            let _ = result_tx.send(outcome);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn route_stanza(&mut self, stanza: &Stanza) -> DeliveryOutcome {
+        // the sender already made sure the recipient is a valid JID
+        let Ok(Some(to)) = stanza.to() else {
+            return DeliveryOutcome::NoSuchRecipient;
+        };
+
+        let recipients = self.recipients(&to, &stanza.element.name);
+        let mut outcome = DeliveryOutcome::NoSuchRecipient;
+        for recipient in recipients {
+            match self.deliver(&recipient, stanza.clone()) {
+                DeliveryOutcome::Delivered => outcome = DeliveryOutcome::Delivered,
+                // an account that is online is not one to keep messages for
+                DeliveryOutcome::RecipientOverloaded if outcome != DeliveryOutcome::Delivered => {
+                    outcome = DeliveryOutcome::RecipientOverloaded;
+                }
+                _ => {}
+            }
+        }
+
+        outcome
+    }
+
+    // Storing takes a trip to the database, which the other deliveries do not wait for.
+    fn store_offline(&self, stanza: Stanza, result_tx: oneshot::Sender<DeliveryOutcome>) {
+        let Ok(Some(to)) = stanza.to() else {
+            let _ = result_tx.send(DeliveryOutcome::NoSuchRecipient);
+            return;
+        };
+
+        let store = self.store.clone();
+        tokio::spawn(async move {
+            let message = OfflineMessage {
+                stanza,
+                stored_at: SystemTime::now(),
+            };
+            let outcome = match store.store_offline_message(to.to_bare(), message).await {
+                Ok(true) => DeliveryOutcome::Stored,
+                // only worth telling apart from an unknown account once nothing was stored
+                Ok(false) if store.user_exists(to.to_bare()).await.unwrap_or(false) => {
+                    DeliveryOutcome::StorageFull
+                }
+                Ok(false) => DeliveryOutcome::NoSuchRecipient,
+                Err(err) => {
+                    error!(
+                        "Failed to store offline message for {}: {}",
+                        to.to_bare(),
+                        err
+                    );
+                    DeliveryOutcome::NoSuchRecipient
+                }
+            };
+            let _ = result_tx.send(outcome);
+        });
+    }
+
+    // A resource becoming available with a non-negative priority gets whatever was stored while
+    // there was none (XEP-0160).
+    fn deliver_offline_messages(&self, recipient: &Jid) {
+        let Some(session) = self.session(recipient) else {
+            return;
+        };
+        if !session.priority().is_some_and(|priority| priority >= 0) {
+            return;
+        }
+
+        let tx = session.tx.clone();
+        let store = self.store.clone();
+        let recipient = recipient.clone();
+        tokio::spawn(async move {
+            let messages = match store.take_offline_messages(recipient.to_bare()).await {
+                Ok(messages) => messages,
+                Err(err) => {
+                    error!("Failed to take offline messages for {}: {}", recipient, err);
+                    return;
+                }
+            };
+
+            let mut messages = messages.into_iter();
+            let undelivered = loop {
+                let Some(message) = messages.next() else {
+                    return;
+                };
+                let mut stanza = message.stanza.with_to(&recipient);
+                stanza.add_delay(message.stored_at, &recipient.domain_jid());
+                if tx.send(stanza).await.is_err() {
+                    break message;
+                }
+            };
+
+            // the stream went away in the meantime, so the rest waits for the next one
+            for message in std::iter::once(undelivered).chain(messages) {
+                if let Err(err) = store
+                    .store_offline_message(recipient.to_bare(), message)
+                    .await
+                {
+                    error!("Lost offline message for {}: {}", recipient, err);
+                }
+            }
+        });
+    }
+
+    fn session(&self, jid: &Jid) -> Option<&Session> {
+        self.entities
+            .get(&jid.to_bare())?
+            .iter()
+            .find(|session| session.jid == *jid)
+    }
+
+    // Guests come and go without an account of their own, so they do not get to fill up the
+    // offline storage of accounts (XEP-0175).
+    fn is_from_guest(&self, stanza: &Stanza) -> bool {
+        let Ok(Some(from)) = stanza.from() else {
+            return false;
+        };
+
+        self.session(&from)
+            .is_some_and(|session| session.security.is_guest())
+    }
+
+    fn session_mut(&mut self, jid: &Jid) -> Option<&mut Session> {
+        self.entities
+            .get_mut(&jid.to_bare())?
+            .iter_mut()
+            .find(|session| session.jid == *jid)
+    }
+
+    fn recipients(&self, to: &Jid, stanza_name: &str) -> Vec<Jid> {
+        if self.session(to).is_some() {
+            return vec![to.clone()];
+        }
+
+        let Some(sessions) = self.entities.get(&to.to_bare()) else {
+            return vec![];
+        };
+        match stanza_name {
+            "presence" => return sessions.iter().map(|session| session.jid.clone()).collect(),
+            "message" => {}
+            // the server answers IQs to the bare JID on behalf of the account, and whatever it
+            // does not handle must not reach an arbitrary resource (RFC 6121, section 8.5.2.1.3)
+            _ => return vec![],
+        }
+
+        // Messages to the bare JID go to the available resources with the highest
+        // non-negative priority (RFC 6121, section 8.5.2.1.1)
+        let available = sessions
+            .iter()
+            .filter(|session| session.priority().is_some_and(|priority| priority >= 0));
+        let Some(highest) = available.clone().filter_map(Session::priority).max() else {
+            return vec![];
+        };
+
+        available
+            .filter(|session| session.priority() == Some(highest))
+            .map(|session| session.jid.clone())
+            .collect()
+    }
+
+    fn deliver(&mut self, recipient: &Jid, stanza: Stanza) -> DeliveryOutcome {
+        let Some(session) = self.session_mut(recipient) else {
+            return DeliveryOutcome::NoSuchRecipient;
+        };
+
+        let stanza = match &session.overflow {
+            Some(_) => stanza,
+            None => match session.tx.try_send(stanza) {
+                Ok(()) => return DeliveryOutcome::Delivered,
+                Err(mpsc::error::TrySendError::Full(stanza)) => {
+                    // waiting here could deadlock with a recipient that is routing a stanza itself
+                    session.overflow = Some(forward_overflow(session.tx.clone()));
+                    stanza
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => {
+                    self.remove(recipient);
+                    return DeliveryOutcome::NoSuchRecipient;
+                }
+            },
+        };
+
+        let overflow = session.overflow.as_ref().unwrap();
+        match overflow.try_send(stanza) {
+            Ok(()) => DeliveryOutcome::Delivered,
+            // a recipient this far behind gets nothing more until it catches up
+            Err(mpsc::error::TrySendError::Full(_)) => DeliveryOutcome::RecipientOverloaded,
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                self.remove(recipient);
+                DeliveryOutcome::NoSuchRecipient
             }
         }
     }
 
-    async fn route_stanza(&mut self, stanza: Stanza) {
-        dbg!(stanza);
+    // Until there are rosters, the only entities subscribed to a resource's presence are the
+    // other available resources of the same account (RFC 6121, section 4.2.2).
+    fn broadcast_presence(&mut self, from: &Jid, presence: &Stanza) {
+        let recipients = self
+            .entities
+            .get(&from.to_bare())
+            .into_iter()
+            .flatten()
+            .filter(|session| session.jid != *from && session.presence.is_some())
+            .map(|session| session.jid.clone())
+            .collect::<Vec<_>>();
+
+        for recipient in recipients {
+            let presence = presence.with_from(from).with_to(&recipient);
+            self.deliver(&recipient, presence);
+        }
+    }
+
+    // A resource that just became available learns about the other available resources.
+    fn send_presences_to(&mut self, recipient: &Jid) {
+        let presences = self
+            .entities
+            .get(&recipient.to_bare())
+            .into_iter()
+            .flatten()
+            .filter(|session| session.jid != *recipient)
+            .filter_map(|session| {
+                let presence = session.presence.as_ref()?;
+                Some(presence.with_from(&session.jid).with_to(recipient))
+            })
+            .collect::<Vec<_>>();
+
+        for presence in presences {
+            self.deliver(recipient, presence);
+        }
+    }
+
+    fn update_presence(&mut self, jid: Jid, presence: Stanza) {
+        let available = presence.presence_priority().is_some();
+        let Some(session) = self
+            .entities
+            .get_mut(&jid.to_bare())
+            .and_then(|sessions| sessions.iter_mut().find(|session| session.jid == jid))
+        else {
+            return;
+        };
+
+        // unavailable presence is only broadcast if the resource was available to begin with
+        let was_available = session.presence.is_some();
+        session.presence = available.then(|| presence.clone());
+        if !available && !was_available {
+            return;
+        }
+
+        self.broadcast_presence(&jid, &presence);
+        if available && !was_available {
+            self.send_presences_to(&jid);
+            self.deliver_offline_messages(&jid);
+        }
+    }
+
+    fn suspend(&mut self, jid: Jid, timeout: Duration) {
+        let Some(session) = self
+            .entities
+            .get_mut(&jid.to_bare())
+            .and_then(|sessions| sessions.iter_mut().find(|session| session.jid == jid))
+        else {
+            return;
+        };
+        let resumable_until = Instant::now() + timeout;
+        session.resumable_until = Some(resumable_until);
+
+        let management_tx = self.management_tx.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep_until(resumable_until.into()).await;
+            if let Some(management_tx) = management_tx.upgrade() {
+                let command = ManagementCommand::Expire(jid, resumable_until);
+                let _ = management_tx.send(command).await;
+            }
+        });
+    }
+
+    fn remove(&mut self, jid: &Jid) {
+        let bare = jid.to_bare();
+        if let Some(sessions) = self.entities.get_mut(&bare) {
+            sessions.retain(|session| session.jid != *jid);
+            if sessions.is_empty() {
+                self.entities.remove(&bare);
+            }
+        }
     }
 
     async fn handle_management_command(&mut self, command: ManagementCommand) {
         match command {
-            ManagementCommand::Register(jid, tx) => {
-                self.entities.insert(jid, tx);
+            ManagementCommand::Register(jid, security, tx) => {
+                // a resumed session is as available as it was before its stream went away, and
+                // what still waits for it keeps its place in line
+                let (presence, overflow) = self
+                    .session_mut(&jid)
+                    .filter(|session| session.resumable_until.is_some())
+                    .map(|session| (session.presence.clone(), session.overflow.take()))
+                    .unwrap_or_default();
+                self.remove(&jid);
+                let session = Session {
+                    jid: jid.clone(),
+                    presence,
+                    tx,
+                    security,
+                    overflow,
+                    resumable_until: None,
+                };
+                self.entities
+                    .entry(jid.to_bare())
+                    .or_default()
+                    .push(session);
+            }
+            ManagementCommand::Unregister(jid, CloseReason::Closed) => {
+                // a stream going away makes its resource unavailable
+                self.update_presence(jid.clone(), unavailable_presence());
+                self.remove(&jid);
+            }
+            ManagementCommand::Unregister(jid, CloseReason::Resumable(timeout)) => {
+                self.suspend(jid, timeout);
+            }
+            ManagementCommand::Expire(jid, resumable_until) => {
+                // the session may have been resumed, or even suspended again, since
+                let expired = self
+                    .session(&jid)
+                    .is_some_and(|session| session.resumable_until == Some(resumable_until));
+                if expired {
+                    self.update_presence(jid.clone(), unavailable_presence());
+                    self.remove(&jid);
+                }
             }
-            ManagementCommand::Unregister(jid) => {
-                self.entities.remove(&jid);
+            ManagementCommand::UpdatePresence(jid, presence) => {
+                self.update_presence(jid, presence);
             }
+            ManagementCommand::IsRegistered(jid, result_tx) => {
+                // a stream that went away without unregistering does not hold on to its JID
+                let registered = self
+                    .session(&jid)
+                    .is_some_and(|session| !session.tx.is_closed());
+                let _ = result_tx.send(registered);
+            }
+            ManagementCommand::DeliverToResources(jid, stanza) => {
+                let resources = self
+                    .entities
+                    .get(&jid.to_bare())
+                    .into_iter()
+                    .flatten()
+                    .map(|session| session.jid.clone())
+                    .collect::<Vec<_>>();
+                for resource in resources {
+                    self.deliver(&resource, stanza.with_to(&resource));
+                }
+            }
+        }
+    }
+}
+
+// Messages that would be delivered to an available resource are kept for later, unless they only
+// matter right now (XEP-0160, section 3).
+fn is_storable(stanza: &Stanza) -> bool {
+    stanza.element.name == "message"
+        && matches!(
+            stanza.element.get_attribute("type", None),
+            None | Some("normal" | "chat")
+        )
+}
+
+// Keeps a JID registered for as long as it is held, so a stream that goes away for whatever
+// reason stops being a recipient. Dropping it is a normal close, see `close` for others.
+pub struct Registration {
+    jid: Jid,
+    management: mpsc::Sender<ManagementCommand>,
+    reason: CloseReason,
+}
+
+impl Registration {
+    pub fn close(mut self, reason: CloseReason) {
+        self.reason = reason;
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let command = ManagementCommand::Unregister(self.jid.clone(), self.reason);
+        if let Err(mpsc::error::TrySendError::Full(command)) = self.management.try_send(command) {
+            let management = self.management.clone();
+            tokio::spawn(async move {
+                let _ = management.send(command).await;
+            });
         }
     }
 }
 
 #[derive(Clone)]
 pub struct RouterHandle {
-    pub stanzas: mpsc::Sender<Stanza>,
+    deliveries: mpsc::Sender<Delivery>,
     pub management: mpsc::Sender<ManagementCommand>,
 }
 
 impl RouterHandle {
-    pub fn new() -> Self {
-        let (stanzas_tx, stanzas_rx) = mpsc::channel(8);
+    pub fn new(store: StoreHandle) -> Self {
+        let (handle, mut router) = Self::unstarted(store);
+        tokio::spawn(async move {
+            router.run().await;
+        });
+
+        handle
+    }
+
+    fn unstarted(store: StoreHandle) -> (Self, Router) {
+        let (deliveries_tx, deliveries_rx) = mpsc::channel(8);
         let (management_tx, management_rx) = mpsc::channel(8);
-        let mut router = Router {
-            stanzas: stanzas_rx,
+        let router = Router {
+            deliveries: deliveries_rx,
             management: management_rx,
             entities: HashMap::new(),
+            store,
+            management_tx: management_tx.downgrade(),
         };
-        tokio::spawn(async move {
-            router.run().await;
+        let handle = RouterHandle {
+            deliveries: deliveries_tx,
+            management: management_tx,
+        };
+
+        (handle, router)
+    }
+
+    pub async fn register(
+        &self,
+        jid: Jid,
+        security: SecurityContext,
+        tx: mpsc::Sender<Stanza>,
+    ) -> Registration {
+        let command = ManagementCommand::Register(jid.clone(), security, tx);
+        let _ = self.management.send(command).await;
+
+        Registration {
+            jid,
+            management: self.management.clone(),
+            reason: CloseReason::Closed,
+        }
+    }
+
+    pub async fn is_registered(&self, jid: Jid) -> bool {
+        let (result_tx, result_rx) = oneshot::channel();
+        let command = ManagementCommand::IsRegistered(jid, result_tx);
+        if self.management.send(command).await.is_err() {
+            return false;
+        }
+
+        result_rx.await.unwrap_or(false)
+    }
+
+    pub async fn route(&self, stanza: Stanza) -> Result<DeliveryOutcome, RouterError> {
+        let to = stanza
+            .element
+            .get_attribute("to", None)
+            .ok_or(RouterError::MissingRecipient)?;
+        if to.parse::<Jid>().is_err() {
+            return Err(RouterError::InvalidRecipient(to.to_string()));
+        }
+
+        let (result_tx, result_rx) = oneshot::channel();
+        let delivery = Delivery { stanza, result_tx };
+        if self.deliveries.send(delivery).await.is_err() {
+            return Ok(DeliveryOutcome::RouterUnavailable);
+        }
+
+        Ok(result_rx
+            .await
+            .unwrap_or(DeliveryOutcome::RouterUnavailable))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::services::store::fake::FakeStoreBackend;
+    use crate::xml::Node;
+
+    use super::*;
+
+    fn router() -> RouterHandle {
+        RouterHandle::new(StoreHandle::new(FakeStoreBackend::default()))
+    }
+
+    fn message(to: Option<&str>) -> Stanza {
+        let attributes = to
+            .map(|to| (("to".to_string(), None), to.to_string()))
+            .into_iter()
+            .collect();
+
+        Stanza {
+            element: Element {
+                name: "message".to_string(),
+                namespace: Some("jabber:client".to_string()),
+                attributes,
+                children: vec![],
+            },
+        }
+    }
+
+    fn presence(priority: Option<i8>) -> Stanza {
+        let Some(priority) = priority else {
+            return unavailable_presence();
+        };
+
+        let priority = Element {
+            name: "priority".to_string(),
+            namespace: Some("jabber:client".to_string()),
+            attributes: HashMap::new(),
+            children: vec![Node::Text(priority.to_string())],
+        };
+        Stanza {
+            element: Element {
+                name: "presence".to_string(),
+                namespace: Some("jabber:client".to_string()),
+                attributes: HashMap::new(),
+                children: vec![Node::Element(priority)],
+            },
+        }
+    }
+
+    // Skips the presence the resources of an account exchange among themselves.
+    fn try_recv_message(rx: &mut mpsc::Receiver<Stanza>) -> Option<Stanza> {
+        while let Ok(stanza) = rx.try_recv() {
+            if stanza.element.name != "presence" {
+                return Some(stanza);
+            }
+        }
+
+        None
+    }
+
+    fn juliet(resource: &str) -> Jid {
+        Jid::new(
+            Some("juliet".to_string()),
+            "localhost".to_string(),
+            Some(resource.to_string()),
+        )
+    }
+
+    async fn register(router: &RouterHandle, jid: &str) -> mpsc::Receiver<Stanza> {
+        register_jid(router, jid.parse().unwrap()).await
+    }
+
+    async fn register_jid(router: &RouterHandle, jid: Jid) -> mpsc::Receiver<Stanza> {
+        let (tx, rx) = mpsc::channel(8);
+        let command = ManagementCommand::Register(jid, SecurityContext::default(), tx);
+        router.management.send(command).await.unwrap();
+        rx
+    }
+
+    async fn available_resource(
+        router: &RouterHandle,
+        resource: &str,
+        priority: i8,
+    ) -> mpsc::Receiver<Stanza> {
+        let jid = Jid::new(
+            Some("juliet".to_string()),
+            "localhost".to_string(),
+            Some(resource.to_string()),
+        );
+        let rx = register_jid(router, jid.clone()).await;
+        let command = ManagementCommand::UpdatePresence(jid, presence(Some(priority)));
+        router.management.send(command).await.unwrap();
+        rx
+    }
+
+    #[tokio::test]
+    async fn registered_recipient_gets_the_stanza() {
+        let router = router();
+        let mut rx = register(&router, "juliet@localhost").await;
+
+        let outcome = router.route(message(Some("juliet@localhost"))).await;
+        assert_eq!(outcome, Ok(DeliveryOutcome::Delivered));
+        assert!(rx.recv().await.is_some());
+    }
+
+    #[tokio::test]
+    async fn registered_entities_exchange_messages() {
+        let router = router();
+        let mut juliet = register(&router, "juliet@localhost").await;
+        let mut romeo = register(&router, "romeo@localhost").await;
+
+        let outcome = router.route(message(Some("romeo@localhost"))).await;
+        assert_eq!(outcome, Ok(DeliveryOutcome::Delivered));
+        let received = romeo.recv().await.unwrap();
+        assert_eq!(received.to(), Ok(Some("romeo@localhost".parse().unwrap())));
+
+        let reply = message(Some("juliet@localhost"));
+        assert_eq!(router.route(reply).await, Ok(DeliveryOutcome::Delivered));
+        assert!(juliet.recv().await.is_some());
+        assert!(romeo.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn presence_to_bare_jid_goes_to_all_resources() {
+        let router = router();
+        let mut first = available_resource(&router, "first", 5).await;
+        let mut second = available_resource(&router, "second", -1).await;
+
+        let mut presence = message(Some("juliet@localhost"));
+        presence.element.name = "presence".to_string();
+        let outcome = router.route(presence).await;
+
+        assert_eq!(outcome, Ok(DeliveryOutcome::Delivered));
+        assert!(first.try_recv().is_ok());
+        assert!(second.try_recv().is_ok());
+    }
+
+    #[tokio::test]
+    async fn unknown_recipient_is_reported() {
+        let router = router();
+        let _rx = register(&router, "juliet@localhost").await;
+
+        let outcome = router.route(message(Some("romeo@localhost"))).await;
+        assert_eq!(outcome, Ok(DeliveryOutcome::NoSuchRecipient));
+    }
+
+    #[tokio::test]
+    async fn full_channel_keeps_stanzas_in_order() {
+        let router = router();
+        let mut rx = register(&router, "juliet@localhost/garden").await;
+
+        for id in 0..20 {
+            let mut stanza = message(Some("juliet@localhost/garden"));
+            let key = ("id".to_string(), None);
+            stanza.element.attributes.insert(key, id.to_string());
+            let outcome = router.route(stanza).await;
+            assert_eq!(outcome, Ok(DeliveryOutcome::Delivered));
+        }
+
+        for id in 0..20 {
+            let stanza = rx.recv().await.unwrap();
+            let expected = id.to_string();
+            assert_eq!(
+                stanza.element.get_attribute("id", None),
+                Some(expected.as_str())
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn stanzas_for_a_stalled_recipient_are_bounded() {
+        let router = router();
+        let _rx = register(&router, "juliet@localhost/garden").await;
+
+        let mut outcomes = Vec::new();
+        for _ in 0..OVERFLOW_BUFFER_SIZE * 2 {
+            let outcome = router.route(message(Some("juliet@localhost/garden"))).await;
+            outcomes.push(outcome.unwrap());
+        }
+
+        assert_eq!(outcomes.first(), Some(&DeliveryOutcome::Delivered));
+        assert_eq!(outcomes.last(), Some(&DeliveryOutcome::RecipientOverloaded));
+    }
+
+    #[tokio::test]
+    async fn messages_for_an_overloaded_account_are_not_stored() {
+        let juliet_bare = "juliet@localhost".parse::<Jid>().unwrap();
+        let store = StoreHandle::new(FakeStoreBackend {
+            users: vec![juliet_bare.clone()],
+            ..Default::default()
         });
+        let router = RouterHandle::new(store.clone());
+        let _balcony = available_resource(&router, "balcony", 0).await;
 
-        RouterHandle {
-            stanzas: stanzas_tx,
-            management: management_tx,
+        let mut outcomes = Vec::new();
+        for _ in 0..OVERFLOW_BUFFER_SIZE * 2 {
+            let outcome = router.route(message(Some("juliet@localhost"))).await;
+            outcomes.push(outcome.unwrap());
         }
+
+        assert_eq!(outcomes.last(), Some(&DeliveryOutcome::RecipientOverloaded));
+        assert!(!outcomes.contains(&DeliveryOutcome::Stored));
+        let stored = store.take_offline_messages(juliet_bare).await.unwrap();
+        assert!(stored.is_empty());
+    }
+
+    #[tokio::test]
+    async fn message_to_bare_jid_goes_to_highest_priority() {
+        let router = router();
+        let mut low = available_resource(&router, "low", 1).await;
+        let mut high = available_resource(&router, "high", 5).await;
+        let mut negative = available_resource(&router, "negative", -1).await;
+
+        let outcome = router.route(message(Some("juliet@localhost"))).await;
+
+        assert_eq!(outcome, Ok(DeliveryOutcome::Delivered));
+        assert!(try_recv_message(&mut high).is_some());
+        assert!(try_recv_message(&mut low).is_none());
+        assert!(try_recv_message(&mut negative).is_none());
     }
 
-    pub async fn send_stanza(&mut self, stanza: Stanza) {
-        self.stanzas.send(stanza).await.unwrap();
+    #[tokio::test]
+    async fn iq_to_bare_jid_goes_to_no_resource() {
+        let router = router();
+        let mut balcony = available_resource(&router, "balcony", 0).await;
+        let mut iq = message(Some("juliet@localhost"));
+        iq.element.name = "iq".to_string();
+
+        let outcome = router.route(iq).await;
+        assert_eq!(outcome, Ok(DeliveryOutcome::NoSuchRecipient));
+        assert!(try_recv_message(&mut balcony).is_none());
+    }
+
+    #[tokio::test]
+    async fn message_to_bare_jid_goes_to_all_tied_resources() {
+        let router = router();
+        let mut first = available_resource(&router, "first", 5).await;
+        let mut second = available_resource(&router, "second", 5).await;
+        let mut low = available_resource(&router, "low", 1).await;
+
+        let outcome = router.route(message(Some("juliet@localhost"))).await;
+
+        assert_eq!(outcome, Ok(DeliveryOutcome::Delivered));
+        assert!(try_recv_message(&mut first).is_some());
+        assert!(try_recv_message(&mut second).is_some());
+        assert!(try_recv_message(&mut low).is_none());
+    }
+
+    #[tokio::test]
+    async fn message_to_bare_jid_is_not_delivered_to_negative_priorities() {
+        let router = router();
+        let mut negative = available_resource(&router, "negative", -1).await;
+
+        let outcome = router.route(message(Some("juliet@localhost"))).await;
+
+        assert_eq!(outcome, Ok(DeliveryOutcome::NoSuchRecipient));
+        assert!(negative.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn message_to_bare_jid_skips_resources_without_presence() {
+        let router = router();
+        let unavailable = Jid::new(
+            Some("juliet".to_string()),
+            "localhost".to_string(),
+            Some("unavailable".to_string()),
+        );
+        let mut unavailable = register_jid(&router, unavailable).await;
+        let mut available = available_resource(&router, "available", 0).await;
+
+        let outcome = router.route(message(Some("juliet@localhost"))).await;
+
+        assert_eq!(outcome, Ok(DeliveryOutcome::Delivered));
+        assert!(try_recv_message(&mut available).is_some());
+        assert!(try_recv_message(&mut unavailable).is_none());
+    }
+
+    #[tokio::test]
+    async fn unavailable_resource_no_longer_gets_bare_messages() {
+        let router = router();
+        let mut high = available_resource(&router, "high", 10).await;
+        let mut low = available_resource(&router, "low", 1).await;
+        let high_jid = Jid::new(
+            Some("juliet".to_string()),
+            "localhost".to_string(),
+            Some("high".to_string()),
+        );
+        let command = ManagementCommand::UpdatePresence(high_jid, presence(None));
+        router.management.send(command).await.unwrap();
+
+        let outcome = router.route(message(Some("juliet@localhost"))).await;
+
+        assert_eq!(outcome, Ok(DeliveryOutcome::Delivered));
+        assert!(try_recv_message(&mut high).is_none());
+        assert!(try_recv_message(&mut low).is_some());
+    }
+
+    #[tokio::test]
+    async fn available_presence_goes_to_other_resources() {
+        let router = router();
+        let mut balcony = available_resource(&router, "balcony", 0).await;
+        let mut garden = register_jid(&router, juliet("garden")).await;
+
+        let command = ManagementCommand::UpdatePresence(juliet("garden"), presence(Some(1)));
+        router.management.send(command).await.unwrap();
+
+        let received = balcony.recv().await.unwrap();
+        assert_eq!(received.element.name, "presence");
+        assert_eq!(received.from(), Ok(Some(juliet("garden"))));
+        assert_eq!(received.to(), Ok(Some(juliet("balcony"))));
+        assert_eq!(received.presence_priority(), Some(1));
+
+        // the new resource learns about the one that was there already
+        let received = garden.recv().await.unwrap();
+        assert_eq!(received.from(), Ok(Some(juliet("balcony"))));
+        assert_eq!(received.to(), Ok(Some(juliet("garden"))));
+    }
+
+    #[tokio::test]
+    async fn teardown_broadcasts_unavailable_presence() {
+        let router = router();
+        let mut balcony = available_resource(&router, "balcony", 0).await;
+        let (tx, _rx) = mpsc::channel(8);
+        let registration = router
+            .register(juliet("garden"), SecurityContext::default(), tx)
+            .await;
+        let command = ManagementCommand::UpdatePresence(juliet("garden"), presence(Some(0)));
+        router.management.send(command).await.unwrap();
+        let available = balcony.recv().await.unwrap();
+        assert_eq!(available.presence_priority(), Some(0));
+
+        drop(registration);
+
+        let unavailable = balcony.recv().await.unwrap();
+        assert_eq!(unavailable.from(), Ok(Some(juliet("garden"))));
+        assert_eq!(
+            unavailable.element.get_attribute("type", None),
+            Some("unavailable")
+        );
+    }
+
+    #[tokio::test]
+    async fn resources_without_presence_are_not_broadcast_to() {
+        let router = router();
+        let mut silent = register_jid(&router, juliet("silent")).await;
+        let (tx, _rx) = mpsc::channel(8);
+        let registration = router
+            .register(juliet("garden"), SecurityContext::default(), tx)
+            .await;
+        let command = ManagementCommand::UpdatePresence(juliet("garden"), presence(Some(0)));
+        router.management.send(command).await.unwrap();
+        drop(registration);
+
+        assert!(!router.is_registered(juliet("garden")).await);
+        assert!(silent.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn every_resource_gets_a_copy() {
+        let router = router();
+        let mut balcony = register_jid(&router, juliet("balcony")).await;
+        let mut garden = register_jid(&router, juliet("garden")).await;
+        let mut romeo = register(&router, "romeo@localhost/orchard").await;
+
+        let command = ManagementCommand::DeliverToResources(
+            "juliet@localhost".parse().unwrap(),
+            message(None),
+        );
+        router.management.send(command).await.unwrap();
+
+        let received = balcony.recv().await.unwrap();
+        assert_eq!(received.to(), Ok(Some(juliet("balcony"))));
+        let received = garden.recv().await.unwrap();
+        assert_eq!(received.to(), Ok(Some(juliet("garden"))));
+        assert!(romeo.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn closed_recipient_is_reported() {
+        let router = router();
+        drop(register(&router, "juliet@localhost").await);
+
+        let outcome = router.route(message(Some("juliet@localhost"))).await;
+        assert_eq!(outcome, Ok(DeliveryOutcome::NoSuchRecipient));
+    }
+
+    #[tokio::test]
+    async fn only_open_streams_are_registered() {
+        let router = router();
+        let resource = |resource: &str| {
+            Jid::new(
+                Some("juliet".to_string()),
+                "localhost".to_string(),
+                Some(resource.to_string()),
+            )
+        };
+        let _open = register_jid(&router, resource("balcony")).await;
+        drop(register_jid(&router, resource("garden")).await);
+
+        assert!(router.is_registered(resource("balcony")).await);
+        assert!(!router.is_registered(resource("garden")).await);
+        assert!(!router.is_registered(resource("orchard")).await);
+    }
+
+    #[tokio::test]
+    async fn dropped_registration_is_unregistered() {
+        let router = router();
+        let jid = "juliet@localhost".parse::<Jid>().unwrap();
+        let (tx, _rx) = mpsc::channel(8);
+
+        let registration = router
+            .register(jid.clone(), SecurityContext::default(), tx)
+            .await;
+        assert!(router.is_registered(jid.clone()).await);
+
+        drop(registration);
+        assert!(!router.is_registered(jid).await);
+    }
+
+    #[tokio::test]
+    async fn closed_registration_is_unregistered_at_once() {
+        let router = router();
+        let mut balcony = available_resource(&router, "balcony", 0).await;
+        let (tx, _rx) = mpsc::channel(8);
+        let registration = router
+            .register(juliet("garden"), SecurityContext::default(), tx)
+            .await;
+        let command = ManagementCommand::UpdatePresence(juliet("garden"), presence(Some(0)));
+        router.management.send(command).await.unwrap();
+        assert!(balcony.recv().await.is_some());
+
+        registration.close(CloseReason::Closed);
+
+        assert!(!router.is_registered(juliet("garden")).await);
+        let unavailable = balcony.try_recv().unwrap();
+        assert_eq!(
+            unavailable.element.get_attribute("type", None),
+            Some("unavailable")
+        );
+    }
+
+    #[tokio::test]
+    async fn resumable_registration_stays_until_it_expires() {
+        let router = router();
+        let mut balcony = available_resource(&router, "balcony", 0).await;
+        let (tx, mut garden) = mpsc::channel(8);
+        let registration = router
+            .register(juliet("garden"), SecurityContext::default(), tx)
+            .await;
+        let command = ManagementCommand::UpdatePresence(juliet("garden"), presence(Some(0)));
+        router.management.send(command).await.unwrap();
+        assert!(balcony.recv().await.is_some());
+
+        registration.close(CloseReason::Resumable(Duration::from_millis(50)));
+
+        assert!(router.is_registered(juliet("garden")).await);
+        assert!(balcony.try_recv().is_err());
+        let outcome = router.route(message(Some("juliet@localhost/garden"))).await;
+        assert_eq!(outcome, Ok(DeliveryOutcome::Delivered));
+        assert!(try_recv_message(&mut garden).is_some());
+
+        let unavailable = balcony.recv().await.unwrap();
+        assert_eq!(unavailable.from(), Ok(Some(juliet("garden"))));
+        assert_eq!(
+            unavailable.element.get_attribute("type", None),
+            Some("unavailable")
+        );
+        assert!(!router.is_registered(juliet("garden")).await);
+    }
+
+    #[tokio::test]
+    async fn resumed_registration_does_not_expire() {
+        let router = router();
+        let mut balcony = available_resource(&router, "balcony", 0).await;
+        let (tx, _rx) = mpsc::channel(8);
+        let registration = router
+            .register(juliet("garden"), SecurityContext::default(), tx.clone())
+            .await;
+        let command = ManagementCommand::UpdatePresence(juliet("garden"), presence(Some(0)));
+        router.management.send(command).await.unwrap();
+        assert!(balcony.recv().await.is_some());
+
+        registration.close(CloseReason::Resumable(Duration::from_millis(50)));
+        let _registration = router
+            .register(juliet("garden"), SecurityContext::default(), tx)
+            .await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        assert!(router.is_registered(juliet("garden")).await);
+        assert!(balcony.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn messages_from_guests_are_not_stored() {
+        let store = StoreHandle::new(FakeStoreBackend {
+            users: vec!["juliet@localhost".parse().unwrap()],
+            ..Default::default()
+        });
+        let router = RouterHandle::new(store);
+        let anonymous = SecurityContext {
+            mechanism: "ANONYMOUS".try_into().ok(),
+            ..SecurityContext::default()
+        };
+        let plain = SecurityContext {
+            mechanism: "PLAIN".try_into().ok(),
+            ..anonymous
+        };
+        let (guest_tx, _guest_rx) = mpsc::channel(8);
+        let guest: Jid = "guest@localhost/x".parse().unwrap();
+        let _guest = router.register(guest.clone(), anonymous, guest_tx).await;
+        let (romeo_tx, _romeo_rx) = mpsc::channel(8);
+        let romeo: Jid = "romeo@localhost/orchard".parse().unwrap();
+        let _romeo = router.register(romeo.clone(), plain, romeo_tx).await;
+
+        let outcome = router
+            .route(message(Some("juliet@localhost")).with_from(&guest))
+            .await;
+        assert_eq!(outcome, Ok(DeliveryOutcome::NoSuchRecipient));
+        let outcome = router
+            .route(message(Some("juliet@localhost")).with_from(&romeo))
+            .await;
+        assert_eq!(outcome, Ok(DeliveryOutcome::Stored));
+    }
+
+    #[tokio::test]
+    async fn message_for_full_offline_storage_is_refused() {
+        let store = StoreHandle::new(FakeStoreBackend {
+            users: vec!["juliet@localhost".parse().unwrap()],
+            max_offline_messages: Some(1),
+            ..Default::default()
+        });
+        let router = RouterHandle::new(store);
+
+        let outcome = router.route(message(Some("juliet@localhost"))).await;
+        assert_eq!(outcome, Ok(DeliveryOutcome::Stored));
+        let outcome = router.route(message(Some("juliet@localhost"))).await;
+        assert_eq!(outcome, Ok(DeliveryOutcome::StorageFull));
+        let outcome = router.route(message(Some("romeo@localhost"))).await;
+        assert_eq!(outcome, Ok(DeliveryOutcome::NoSuchRecipient));
+    }
+
+    #[tokio::test]
+    async fn stopped_router_is_unavailable() {
+        let (router, stopped) =
+            RouterHandle::unstarted(StoreHandle::new(FakeStoreBackend::default()));
+        drop(stopped);
+
+        let outcome = router.route(message(Some("juliet@localhost"))).await;
+        assert_eq!(outcome, Ok(DeliveryOutcome::RouterUnavailable));
+    }
+
+    #[tokio::test]
+    async fn stanza_without_recipient_is_rejected() {
+        let router = router();
+
+        let outcome = router.route(message(None)).await;
+        assert_eq!(outcome, Err(RouterError::MissingRecipient));
+    }
+
+    #[tokio::test]
+    async fn message_for_unavailable_account_is_stored_until_it_is_available() {
+        let store = StoreHandle::new(FakeStoreBackend {
+            users: vec!["juliet@localhost".parse().unwrap()],
+            ..Default::default()
+        });
+        let router = RouterHandle::new(store);
+        let mut silent = register_jid(&router, juliet("silent")).await;
+
+        let outcome = router.route(message(Some("juliet@localhost"))).await;
+        assert_eq!(outcome, Ok(DeliveryOutcome::Stored));
+        let mut headline = message(Some("juliet@localhost"));
+        headline
+            .element
+            .attributes
+            .insert(("type".to_string(), None), "headline".to_string());
+        let outcome = router.route(headline).await;
+        assert_eq!(outcome, Ok(DeliveryOutcome::NoSuchRecipient));
+        assert!(silent.try_recv().is_err());
+
+        let mut balcony = available_resource(&router, "balcony", 0).await;
+        let received = balcony.recv().await.unwrap();
+        assert_eq!(received.element.name, "message");
+        assert_eq!(received.to(), Ok(Some(juliet("balcony"))));
+        let delay = received
+            .element
+            .get_child("delay", Some(namespaces::DELAY))
+            .unwrap();
+        assert_eq!(delay.get_attribute("from", None), Some("localhost"));
+        let stamp = delay.get_attribute("stamp", None).unwrap();
+        assert_eq!(stamp.len(), "2002-09-10T23:08:25.000Z".len());
+        assert_eq!(&stamp[10..11], "T");
+        assert!(stamp.ends_with('Z'));
+
+        // they are only delivered once
+        let mut garden = available_resource(&router, "garden", 0).await;
+        assert_eq!(garden.recv().await.unwrap().element.name, "presence");
+        assert!(router.is_registered(juliet("garden")).await);
+        assert!(try_recv_message(&mut garden).is_none());
     }
 }
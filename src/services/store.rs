@@ -1,4 +1,6 @@
 use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
 use anyhow::Error;
 use tokio::{
@@ -8,18 +10,44 @@ use tokio::{
 
 use crate::inbound::StoredPasswordKind;
 use crate::xmpp::jid::Jid;
+use crate::xmpp::roster::{Roster, RosterItem};
+use crate::xmpp::stanza::Stanza;
 
+use self::cache::PasswordCache;
 pub use self::sqlite::SqliteStoreBackend;
 
-mod fake;
+mod cache;
+#[cfg(test)]
+pub mod fake;
 mod sqlite;
 
+const PASSWORD_CACHE_CAPACITY: usize = 1024;
+const PASSWORD_CACHE_TTL: Duration = Duration::from_secs(60);
+
+// A message kept for an account while none of its resources is available.
+#[derive(Debug, Clone)]
+pub struct OfflineMessage {
+    pub stanza: Stanza,
+    pub stored_at: SystemTime,
+}
+
 enum Query {
     GetStoredPassword {
         jid: Jid,
         kind: StoredPasswordKind,
         result_tx: oneshot::Sender<Result<String, Error>>,
     },
+    UserExists {
+        jid: Jid,
+        result_tx: oneshot::Sender<Result<bool, Error>>,
+    },
+    ListUsers {
+        result_tx: oneshot::Sender<Result<Vec<Jid>, Error>>,
+    },
+    GetRoster {
+        owner: Jid,
+        result_tx: oneshot::Sender<Result<Roster, Error>>,
+    },
 }
 
 enum Command {
@@ -28,7 +56,7 @@ enum Command {
         stored_password_argon2: String,
         stored_password_scram_sha1: String,
         stored_password_scram_sha256: String,
-        result_tx: oneshot::Sender<Result<(), Error>>,
+        result_tx: oneshot::Sender<Result<bool, Error>>,
     },
     RemoveUser {
         jid: Jid,
@@ -40,6 +68,32 @@ enum Command {
         stored_password: String,
         result_tx: oneshot::Sender<Result<(), Error>>,
     },
+    SetStoredPasswords {
+        jid: Jid,
+        stored_password_argon2: String,
+        stored_password_scram_sha1: String,
+        stored_password_scram_sha256: String,
+        result_tx: oneshot::Sender<Result<(), Error>>,
+    },
+    SetRosterItem {
+        owner: Jid,
+        item: RosterItem,
+        result_tx: oneshot::Sender<Result<(RosterItem, u64), Error>>,
+    },
+    RemoveRosterItem {
+        owner: Jid,
+        contact: Jid,
+        result_tx: oneshot::Sender<Result<Option<u64>, Error>>,
+    },
+    StoreOfflineMessage {
+        recipient: Jid,
+        message: OfflineMessage,
+        result_tx: oneshot::Sender<Result<bool, Error>>,
+    },
+    TakeOfflineMessages {
+        recipient: Jid,
+        result_tx: oneshot::Sender<Result<Vec<OfflineMessage>, Error>>,
+    },
 }
 
 struct Store<B>
@@ -79,6 +133,18 @@ where
                 let result = self.backend.get_stored_password(jid, kind).await;
                 result_tx.send(result).unwrap();
             }
+            Query::UserExists { jid, result_tx } => {
+                let result = self.backend.user_exists(jid).await;
+                result_tx.send(result).unwrap();
+            }
+            Query::ListUsers { result_tx } => {
+                let result = self.backend.list_users().await;
+                result_tx.send(result).unwrap();
+            }
+            Query::GetRoster { owner, result_tx } => {
+                let result = self.backend.get_roster(owner).await;
+                result_tx.send(result).unwrap();
+            }
         }
     }
 
@@ -118,6 +184,55 @@ where
                     .await;
                 result_tx.send(result).unwrap();
             }
+            Command::SetStoredPasswords {
+                jid,
+                stored_password_argon2,
+                stored_password_scram_sha1,
+                stored_password_scram_sha256,
+                result_tx,
+            } => {
+                let result = self
+                    .backend
+                    .set_stored_passwords(
+                        jid,
+                        stored_password_argon2,
+                        stored_password_scram_sha1,
+                        stored_password_scram_sha256,
+                    )
+                    .await;
+                result_tx.send(result).unwrap();
+            }
+            Command::SetRosterItem {
+                owner,
+                item,
+                result_tx,
+            } => {
+                let result = self.backend.set_roster_item(owner, item).await;
+                result_tx.send(result).unwrap();
+            }
+            Command::RemoveRosterItem {
+                owner,
+                contact,
+                result_tx,
+            } => {
+                let result = self.backend.remove_roster_item(owner, contact).await;
+                result_tx.send(result).unwrap();
+            }
+            Command::StoreOfflineMessage {
+                recipient,
+                message,
+                result_tx,
+            } => {
+                let result = self.backend.store_offline_message(recipient, message).await;
+                result_tx.send(result).unwrap();
+            }
+            Command::TakeOfflineMessages {
+                recipient,
+                result_tx,
+            } => {
+                let result = self.backend.take_offline_messages(recipient).await;
+                result_tx.send(result).unwrap();
+            }
         }
     }
 }
@@ -126,6 +241,7 @@ where
 pub struct StoreHandle {
     queries: mpsc::Sender<Query>,
     commands: mpsc::Sender<Command>,
+    password_cache: Arc<Mutex<PasswordCache>>,
 }
 
 impl StoreHandle {
@@ -144,37 +260,68 @@ impl StoreHandle {
             store.run().await;
         });
 
+        let password_cache = PasswordCache::new(PASSWORD_CACHE_CAPACITY, PASSWORD_CACHE_TTL);
         StoreHandle {
             queries: queries_tx,
             commands: commands_tx,
+            password_cache: Arc::new(Mutex::new(password_cache)),
         }
     }
 
+    // Whether the account was added, which it is not when it exists already, however recently it
+    // was created.
     pub async fn add_user(
         &self,
         jid: Jid,
         stored_password_argon2: String,
         stored_password_scram_sha1: String,
         stored_password_scram_sha256: String,
-    ) -> Result<(), Error> {
+    ) -> Result<bool, Error> {
         let (result_tx, result_rx) = oneshot::channel();
         let msg = Command::AddUser {
-            jid,
+            jid: jid.clone(),
             stored_password_argon2,
             stored_password_scram_sha1,
             stored_password_scram_sha256,
             result_tx,
         };
 
+        self.invalidate_cached_passwords(&jid);
         let _ = self.commands.send(msg).await;
-        result_rx.await.expect("Store is gone")
+        let result = result_rx.await.expect("Store is gone");
+        self.invalidate_cached_passwords(&jid);
+
+        result
     }
 
     pub async fn remove_user(&self, jid: Jid) -> Result<(), Error> {
         let (result_tx, result_rx) = oneshot::channel();
-        let msg = Command::RemoveUser { jid, result_tx };
+        let msg = Command::RemoveUser {
+            jid: jid.clone(),
+            result_tx,
+        };
 
+        self.invalidate_cached_passwords(&jid);
         let _ = self.commands.send(msg).await;
+        let result = result_rx.await.expect("Store is gone");
+        self.invalidate_cached_passwords(&jid);
+
+        result
+    }
+
+    pub async fn user_exists(&self, jid: Jid) -> Result<bool, Error> {
+        let (result_tx, result_rx) = oneshot::channel();
+        let msg = Query::UserExists { jid, result_tx };
+
+        let _ = self.queries.send(msg).await;
+        result_rx.await.expect("Store is gone")
+    }
+
+    pub async fn list_users(&self) -> Result<Vec<Jid>, Error> {
+        let (result_tx, result_rx) = oneshot::channel();
+        let msg = Query::ListUsers { result_tx };
+
+        let _ = self.queries.send(msg).await;
         result_rx.await.expect("Store is gone")
     }
 
@@ -183,15 +330,35 @@ impl StoreHandle {
         jid: Jid,
         kind: StoredPasswordKind,
     ) -> Result<String, Error> {
+        let (cached, generation) = {
+            let mut password_cache = self.password_cache.lock().unwrap();
+            let cached = password_cache.get(&jid, kind, Instant::now());
+            (cached, password_cache.generation(&jid))
+        };
+        if let Some(stored_password) = cached {
+            return Ok(stored_password);
+        }
+
         let (result_tx, result_rx) = oneshot::channel();
         let msg = Query::GetStoredPassword {
-            jid,
+            jid: jid.clone(),
             kind,
             result_tx,
         };
 
         let _ = self.queries.send(msg).await;
-        result_rx.await.expect("Store is gone")
+        let stored_password = result_rx.await.expect("Store is gone")?;
+        // unknown users are not cached, so they can be added while the server is running, and
+        // neither is a password that was changed while it was being looked up
+        self.password_cache.lock().unwrap().insert_if_current(
+            &jid,
+            kind,
+            stored_password.clone(),
+            Instant::now(),
+            generation,
+        );
+
+        Ok(stored_password)
     }
 
     pub async fn set_stored_password(
@@ -202,12 +369,121 @@ impl StoreHandle {
     ) -> Result<(), Error> {
         let (result_tx, result_rx) = oneshot::channel();
         let msg = Command::SetStoredPassword {
-            jid,
+            jid: jid.clone(),
             kind,
             stored_password,
             result_tx,
         };
 
+        self.invalidate_cached_passwords(&jid);
+        let _ = self.commands.send(msg).await;
+        let result = result_rx.await.expect("Store is gone");
+        self.invalidate_cached_passwords(&jid);
+
+        result
+    }
+
+    // Replaces all of the account's passwords at once, so no login ever sees some of them changed
+    // and others not.
+    pub async fn set_stored_passwords(
+        &self,
+        jid: Jid,
+        stored_password_argon2: String,
+        stored_password_scram_sha1: String,
+        stored_password_scram_sha256: String,
+    ) -> Result<(), Error> {
+        let (result_tx, result_rx) = oneshot::channel();
+        let msg = Command::SetStoredPasswords {
+            jid: jid.clone(),
+            stored_password_argon2,
+            stored_password_scram_sha1,
+            stored_password_scram_sha256,
+            result_tx,
+        };
+
+        self.invalidate_cached_passwords(&jid);
+        let _ = self.commands.send(msg).await;
+        let result = result_rx.await.expect("Store is gone");
+        self.invalidate_cached_passwords(&jid);
+
+        result
+    }
+
+    // Before a write, so lookups already underway are not cached, and again after it, for those
+    // that started while it was pending and may have read either password.
+    fn invalidate_cached_passwords(&self, jid: &Jid) {
+        self.password_cache.lock().unwrap().invalidate(jid);
+    }
+
+    pub async fn get_roster(&self, owner: Jid) -> Result<Roster, Error> {
+        let (result_tx, result_rx) = oneshot::channel();
+        let msg = Query::GetRoster { owner, result_tx };
+
+        let _ = self.queries.send(msg).await;
+        result_rx.await.expect("Store is gone")
+    }
+
+    // Adds the item or updates its name and groups, returning it as stored along with the new
+    // version of the roster. The subscription state is not the client's to set, so it is kept as
+    // it is.
+    pub async fn set_roster_item(
+        &self,
+        owner: Jid,
+        item: RosterItem,
+    ) -> Result<(RosterItem, u64), Error> {
+        let (result_tx, result_rx) = oneshot::channel();
+        let msg = Command::SetRosterItem {
+            owner,
+            item,
+            result_tx,
+        };
+
+        let _ = self.commands.send(msg).await;
+        result_rx.await.expect("Store is gone")
+    }
+
+    // The new version of the roster, or `None` if there was no item to remove.
+    pub async fn remove_roster_item(&self, owner: Jid, contact: Jid) -> Result<Option<u64>, Error> {
+        let (result_tx, result_rx) = oneshot::channel();
+        let msg = Command::RemoveRosterItem {
+            owner,
+            contact,
+            result_tx,
+        };
+
+        let _ = self.commands.send(msg).await;
+        result_rx.await.expect("Store is gone")
+    }
+
+    // Whether the message was stored, which it is not for an unknown account or one that already
+    // has as many as it may keep.
+    pub async fn store_offline_message(
+        &self,
+        recipient: Jid,
+        message: OfflineMessage,
+    ) -> Result<bool, Error> {
+        let (result_tx, result_rx) = oneshot::channel();
+        let msg = Command::StoreOfflineMessage {
+            recipient,
+            message,
+            result_tx,
+        };
+
+        let _ = self.commands.send(msg).await;
+        result_rx.await.expect("Store is gone")
+    }
+
+    // Removes the account's stored messages and returns them, oldest first.
+    pub async fn take_offline_messages(
+        &self,
+        recipient: Jid,
+    ) -> Result<Vec<OfflineMessage>, Error> {
+        let (result_tx, result_rx) = oneshot::channel();
+        let msg = Command::TakeOfflineMessages {
+            recipient,
+            result_tx,
+        };
+
         let _ = self.commands.send(msg).await;
         result_rx.await.expect("Store is gone")
     }
@@ -220,10 +496,14 @@ trait StoreBackend {
         stored_password_argon2: String,
         stored_password_scram_sha1: String,
         stored_password_scram_sha256: String,
-    ) -> impl Future<Output = Result<(), Error>> + Send;
+    ) -> impl Future<Output = Result<bool, Error>> + Send;
 
     fn remove_user(&mut self, jid: Jid) -> impl Future<Output = Result<(), Error>> + Send;
 
+    fn user_exists(&self, jid: Jid) -> impl Future<Output = Result<bool, Error>> + Send;
+
+    fn list_users(&self) -> impl Future<Output = Result<Vec<Jid>, Error>> + Send;
+
     fn get_stored_password(
         &self,
         jid: Jid,
@@ -236,16 +516,54 @@ trait StoreBackend {
         kind: StoredPasswordKind,
         stored_password: String,
     ) -> impl Future<Output = Result<(), Error>> + Send;
+
+    fn set_stored_passwords(
+        &mut self,
+        jid: Jid,
+        stored_password_argon2: String,
+        stored_password_scram_sha1: String,
+        stored_password_scram_sha256: String,
+    ) -> impl Future<Output = Result<(), Error>> + Send;
+
+    fn get_roster(&self, owner: Jid) -> impl Future<Output = Result<Roster, Error>> + Send;
+
+    fn set_roster_item(
+        &mut self,
+        owner: Jid,
+        item: RosterItem,
+    ) -> impl Future<Output = Result<(RosterItem, u64), Error>> + Send;
+
+    fn remove_roster_item(
+        &mut self,
+        owner: Jid,
+        contact: Jid,
+    ) -> impl Future<Output = Result<Option<u64>, Error>> + Send;
+
+    fn store_offline_message(
+        &mut self,
+        recipient: Jid,
+        message: OfflineMessage,
+    ) -> impl Future<Output = Result<bool, Error>> + Send;
+
+    fn take_offline_messages(
+        &mut self,
+        recipient: Jid,
+    ) -> impl Future<Output = Result<Vec<OfflineMessage>, Error>> + Send;
 }
 
 #[cfg(test)]
 mod test {
     use std::default::Default;
+    use std::pin::pin;
+    use std::sync::atomic::Ordering;
 
     use argon2::{Argon2, PasswordVerifier};
+    use futures::poll;
 
-    use crate::inbound::StoredPassword;
     use crate::inbound::StoredPasswordArgon2;
+    use crate::settings::PasswordHashing;
+    use crate::xml::Element;
+    use crate::xmpp::roster::Subscription;
 
     use self::fake::FakeStoreBackend;
 
@@ -255,7 +573,13 @@ mod test {
     async fn test_store_query() {
         let mut store = StoreHandle::new(FakeStoreBackend {
             stored_password_argon2: Some(
-                StoredPasswordArgon2::new("password").unwrap().to_string(),
+                StoredPasswordArgon2::new_with_pepper(
+                    "password",
+                    None,
+                    &PasswordHashing::default(),
+                )
+                .unwrap()
+                .to_string(),
             ),
             ..Default::default()
         });
@@ -270,4 +594,340 @@ mod test {
             .verify_password("password".as_bytes(), &stored_assword.hash.password_hash())
             .is_ok());
     }
+
+    #[tokio::test]
+    async fn cached_password_avoids_backend_lookup() {
+        let backend = FakeStoreBackend {
+            stored_password_argon2: Some("hash".to_string()),
+            ..Default::default()
+        };
+        let lookups = backend.password_lookups.clone();
+        let store = StoreHandle::new(backend);
+        let jid = "user@localhost".parse::<Jid>().unwrap();
+
+        for _ in 0..3 {
+            let stored_password = store
+                .get_stored_password(jid.clone(), StoredPasswordKind::Argon2)
+                .await
+                .unwrap();
+            assert_eq!(stored_password, "hash");
+        }
+        assert_eq!(lookups.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn setting_a_password_invalidates_the_cache() {
+        let backend = FakeStoreBackend {
+            stored_password_argon2: Some("old".to_string()),
+            ..Default::default()
+        };
+        let lookups = backend.password_lookups.clone();
+        let store = StoreHandle::new(backend);
+        let jid = "user@localhost".parse::<Jid>().unwrap();
+
+        store
+            .get_stored_password(jid.clone(), StoredPasswordKind::Argon2)
+            .await
+            .unwrap();
+        store
+            .set_stored_password(jid.clone(), StoredPasswordKind::Argon2, "new".to_string())
+            .await
+            .unwrap();
+        let stored_password = store
+            .get_stored_password(jid.clone(), StoredPasswordKind::Argon2)
+            .await
+            .unwrap();
+
+        assert_eq!(stored_password, "new");
+        assert_eq!(lookups.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn setting_all_passwords_invalidates_the_cache() {
+        let backend = FakeStoreBackend {
+            stored_password_argon2: Some("old".to_string()),
+            stored_password_scram_sha1: Some("old".to_string()),
+            stored_password_scram_sha256: Some("old".to_string()),
+            ..Default::default()
+        };
+        let store = StoreHandle::new(backend);
+        let jid = "user@localhost".parse::<Jid>().unwrap();
+        let kinds = [
+            StoredPasswordKind::Argon2,
+            StoredPasswordKind::ScramSha1,
+            StoredPasswordKind::ScramSha256,
+        ];
+
+        for kind in kinds {
+            store.get_stored_password(jid.clone(), kind).await.unwrap();
+        }
+        store
+            .set_stored_passwords(
+                jid.clone(),
+                "new".to_string(),
+                "new".to_string(),
+                "new".to_string(),
+            )
+            .await
+            .unwrap();
+
+        for kind in kinds {
+            let stored_password = store.get_stored_password(jid.clone(), kind).await.unwrap();
+            assert_eq!(stored_password, "new");
+        }
+    }
+
+    #[tokio::test]
+    async fn lookup_answered_before_a_password_change_is_not_cached() {
+        let backend = FakeStoreBackend {
+            stored_password_argon2: Some("old".to_string()),
+            ..Default::default()
+        };
+        let store = StoreHandle::new(backend);
+        let jid = "user@localhost".parse::<Jid>().unwrap();
+
+        // the lookup is queued before the change but only gets its answer after it
+        let mut lookup = pin!(store.get_stored_password(jid.clone(), StoredPasswordKind::Argon2));
+        assert!(poll!(&mut lookup).is_pending());
+        tokio::task::yield_now().await;
+        store
+            .set_stored_password(jid.clone(), StoredPasswordKind::Argon2, "new".to_string())
+            .await
+            .unwrap();
+        assert_eq!(lookup.await.unwrap(), "old");
+
+        let stored_password = store
+            .get_stored_password(jid, StoredPasswordKind::Argon2)
+            .await
+            .unwrap();
+        assert_eq!(stored_password, "new");
+    }
+
+    #[tokio::test]
+    async fn removing_a_user_invalidates_the_cache() {
+        let backend = FakeStoreBackend {
+            stored_password_argon2: Some("hash".to_string()),
+            ..Default::default()
+        };
+        let store = StoreHandle::new(backend);
+        let jid = "user@localhost".parse::<Jid>().unwrap();
+
+        store
+            .get_stored_password(jid.clone(), StoredPasswordKind::Argon2)
+            .await
+            .unwrap();
+        store.remove_user(jid.clone()).await.unwrap();
+
+        assert!(store
+            .get_stored_password(jid, StoredPasswordKind::Argon2)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_user_exists() {
+        let store = StoreHandle::new(FakeStoreBackend {
+            stored_password_argon2: Some(
+                StoredPasswordArgon2::new_with_pepper(
+                    "password",
+                    None,
+                    &PasswordHashing::default(),
+                )
+                .unwrap()
+                .to_string(),
+            ),
+            ..Default::default()
+        });
+        let jid = "user@localhost".parse::<Jid>().unwrap();
+        assert!(store.user_exists(jid).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_user_does_not_exist() {
+        let store = StoreHandle::new(FakeStoreBackend::default());
+        let jid = "user@localhost".parse::<Jid>().unwrap();
+        assert!(!store.user_exists(jid).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn list_users_returns_added_users() {
+        let store = StoreHandle::new(FakeStoreBackend::default());
+        let juliet = "juliet@localhost".parse::<Jid>().unwrap();
+        let romeo = "romeo@localhost/orchard".parse::<Jid>().unwrap();
+        for jid in [&juliet, &romeo] {
+            store
+                .add_user(jid.clone(), "".into(), "".into(), "".into())
+                .await
+                .unwrap();
+        }
+        store.remove_user(juliet.clone()).await.unwrap();
+
+        let users = store.list_users().await.unwrap();
+
+        assert_eq!(users, vec![romeo.to_bare()]);
+        assert!(!store.user_exists(juliet).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn existing_user_is_not_added_again() {
+        let store = StoreHandle::new(FakeStoreBackend::default());
+        let jid = "juliet@localhost".parse::<Jid>().unwrap();
+
+        let first = store
+            .add_user(jid.clone(), "first".into(), "".into(), "".into())
+            .await
+            .unwrap();
+        let second = store
+            .add_user(jid.clone(), "second".into(), "".into(), "".into())
+            .await
+            .unwrap();
+
+        assert!(first);
+        assert!(!second);
+        let stored_password = store
+            .get_stored_password(jid, StoredPasswordKind::Argon2)
+            .await
+            .unwrap();
+        assert_eq!(stored_password, "first");
+    }
+
+    #[tokio::test]
+    async fn added_roster_item_is_returned() {
+        let store = StoreHandle::new(FakeStoreBackend::default());
+        let juliet = "juliet@localhost".parse::<Jid>().unwrap();
+        let item = RosterItem {
+            jid: "romeo@localhost".parse().unwrap(),
+            name: Some("Romeo".to_string()),
+            subscription: Subscription::Both,
+            groups: vec!["Friends".to_string()],
+        };
+
+        let (stored, version) = store
+            .set_roster_item(juliet.clone(), item.clone())
+            .await
+            .unwrap();
+
+        // a new item has no subscription, whatever the client asked for
+        assert_eq!(stored.subscription, Subscription::None);
+        assert_eq!(stored.name, item.name);
+        assert_eq!(
+            store.get_roster(juliet.clone()).await.unwrap(),
+            Roster {
+                version,
+                items: vec![stored],
+            }
+        );
+        assert_eq!(
+            store
+                .get_roster("romeo@localhost".parse().unwrap())
+                .await
+                .unwrap(),
+            Roster::default()
+        );
+
+        let removed = store
+            .remove_roster_item(juliet.clone(), item.jid.clone())
+            .await
+            .unwrap();
+        assert!(removed.is_some_and(|removed| removed > version));
+        assert_eq!(
+            store
+                .remove_roster_item(juliet.clone(), item.jid)
+                .await
+                .unwrap(),
+            None
+        );
+        let roster = store.get_roster(juliet).await.unwrap();
+        assert!(roster.items.is_empty());
+        assert_eq!(Some(roster.version), removed);
+    }
+
+    #[tokio::test]
+    async fn offline_messages_are_limited_per_account() {
+        let juliet = "juliet@localhost".parse::<Jid>().unwrap();
+        let romeo = "romeo@localhost".parse::<Jid>().unwrap();
+        let store = StoreHandle::new(FakeStoreBackend {
+            users: vec![juliet.clone(), romeo.clone()],
+            max_offline_messages: Some(2),
+            ..Default::default()
+        });
+        let message = || OfflineMessage {
+            stanza: Stanza {
+                element: Element::parse("<message xmlns='jabber:client'/>").unwrap(),
+            },
+            stored_at: SystemTime::now(),
+        };
+
+        for _ in 0..2 {
+            assert!(store
+                .store_offline_message(juliet.clone(), message())
+                .await
+                .unwrap());
+        }
+        assert!(!store
+            .store_offline_message(juliet.clone(), message())
+            .await
+            .unwrap());
+        assert!(store.store_offline_message(romeo, message()).await.unwrap());
+
+        // taking them makes room again
+        store.take_offline_messages(juliet.clone()).await.unwrap();
+        assert!(store
+            .store_offline_message(juliet, message())
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn offline_messages_are_taken_once() {
+        let juliet = "juliet@localhost".parse::<Jid>().unwrap();
+        let store = StoreHandle::new(FakeStoreBackend {
+            users: vec![juliet.clone()],
+            ..Default::default()
+        });
+        let message = |body: &str| {
+            OfflineMessage {
+            stanza: Stanza {
+                element: Element::parse(&format!(
+                    "<message xmlns='jabber:client' to='juliet@localhost'><body>{body}</body></message>"
+                ))
+                .unwrap(),
+            },
+            stored_at: SystemTime::now(),
+        }
+        };
+
+        for body in ["first", "second"] {
+            assert!(store
+                .store_offline_message(juliet.clone(), message(body))
+                .await
+                .unwrap());
+        }
+        assert!(!store
+            .store_offline_message("romeo@localhost".parse().unwrap(), message("lost"))
+            .await
+            .unwrap());
+
+        let bodies = store
+            .take_offline_messages(juliet.clone())
+            .await
+            .unwrap()
+            .iter()
+            .map(|message| {
+                message
+                    .stanza
+                    .element
+                    .path_text(&[("body", Some("jabber:client"))])
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            bodies,
+            vec![Some("first".to_string()), Some("second".to_string())]
+        );
+        assert!(store
+            .take_offline_messages(juliet)
+            .await
+            .unwrap()
+            .is_empty());
+    }
 }
@@ -0,0 +1,241 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::inbound::StoredPasswordKind;
+use crate::xmpp::jid::Jid;
+
+struct Entry {
+    stored_password: String,
+    expires: Instant,
+    last_used: u64,
+}
+
+// Stored passwords looked up recently, so repeated logins and multi-step SCRAM exchanges do not
+// each go through the store. Entries expire after `ttl` in case the database is changed by
+// something other than this process, and the least recently used entry makes room for new
+// ones.
+pub struct PasswordCache {
+    capacity: usize,
+    ttl: Duration,
+    entries: HashMap<(Jid, StoredPasswordKind), Entry>,
+    uses: u64,
+    // how often each account's passwords have been invalidated, so a lookup that was answered
+    // before a write but arrives after it does not bring the old password back
+    generations: HashMap<Jid, u64>,
+}
+
+impl PasswordCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity,
+            ttl,
+            entries: HashMap::new(),
+            uses: 0,
+            generations: HashMap::new(),
+        }
+    }
+
+    // To be taken before looking a password up, and handed to `insert_if_current` with it.
+    pub fn generation(&self, jid: &Jid) -> u64 {
+        self.generations
+            .get(&jid.to_bare())
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn get(&mut self, jid: &Jid, kind: StoredPasswordKind, now: Instant) -> Option<String> {
+        let key = (jid.to_bare(), kind);
+        let entry = self.entries.get_mut(&key)?;
+        if entry.expires <= now {
+            self.entries.remove(&key);
+            return None;
+        }
+
+        self.uses += 1;
+        entry.last_used = self.uses;
+        Some(entry.stored_password.clone())
+    }
+
+    pub fn insert(
+        &mut self,
+        jid: &Jid,
+        kind: StoredPasswordKind,
+        stored_password: String,
+        now: Instant,
+    ) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let key = (jid.to_bare(), kind);
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.capacity {
+            self.evict(now);
+        }
+
+        self.uses += 1;
+        let entry = Entry {
+            stored_password,
+            expires: now + self.ttl,
+            last_used: self.uses,
+        };
+        self.entries.insert(key, entry);
+    }
+
+    // Caches the password unless the account was invalidated since `generation` was taken.
+    pub fn insert_if_current(
+        &mut self,
+        jid: &Jid,
+        kind: StoredPasswordKind,
+        stored_password: String,
+        now: Instant,
+        generation: u64,
+    ) {
+        if self.generation(jid) == generation {
+            self.insert(jid, kind, stored_password, now);
+        }
+    }
+
+    pub fn invalidate(&mut self, jid: &Jid) {
+        let jid = jid.to_bare();
+        self.entries.retain(|(cached, _), _| *cached != jid);
+        *self.generations.entry(jid).or_default() += 1;
+    }
+
+    fn evict(&mut self, now: Instant) {
+        self.entries.retain(|_, entry| entry.expires > now);
+        if self.entries.len() < self.capacity {
+            return;
+        }
+
+        let least_recently_used = self
+            .entries
+            .iter()
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(key, _)| key.clone());
+        if let Some(key) = least_recently_used {
+            self.entries.remove(&key);
+        }
+    }
+}
+
+// keeps the stored passwords out of debug output
+impl fmt::Debug for PasswordCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PasswordCache")
+            .field("capacity", &self.capacity)
+            .field("ttl", &self.ttl)
+            .field("entries", &self.entries.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jid(local: &str) -> Jid {
+        Jid::new(Some(local.to_string()), "localhost".to_string(), None)
+    }
+
+    #[test]
+    fn entries_expire() {
+        let start = Instant::now();
+        let mut cache = PasswordCache::new(8, Duration::from_secs(60));
+        cache.insert(
+            &jid("juliet"),
+            StoredPasswordKind::Argon2,
+            "hash".into(),
+            start,
+        );
+
+        let later = start + Duration::from_secs(59);
+        assert_eq!(
+            cache.get(&jid("juliet"), StoredPasswordKind::Argon2, later),
+            Some("hash".to_string())
+        );
+        let expired = start + Duration::from_secs(60);
+        assert_eq!(
+            cache.get(&jid("juliet"), StoredPasswordKind::Argon2, expired),
+            None
+        );
+    }
+
+    #[test]
+    fn least_recently_used_entry_is_evicted() {
+        let now = Instant::now();
+        let mut cache = PasswordCache::new(2, Duration::from_secs(60));
+        cache.insert(&jid("juliet"), StoredPasswordKind::Argon2, "1".into(), now);
+        cache.insert(&jid("romeo"), StoredPasswordKind::Argon2, "2".into(), now);
+        cache.get(&jid("juliet"), StoredPasswordKind::Argon2, now);
+        cache.insert(&jid("nurse"), StoredPasswordKind::Argon2, "3".into(), now);
+
+        assert!(cache
+            .get(&jid("juliet"), StoredPasswordKind::Argon2, now)
+            .is_some());
+        assert!(cache
+            .get(&jid("romeo"), StoredPasswordKind::Argon2, now)
+            .is_none());
+        assert!(cache
+            .get(&jid("nurse"), StoredPasswordKind::Argon2, now)
+            .is_some());
+    }
+
+    #[test]
+    fn invalidation_covers_every_kind() {
+        let now = Instant::now();
+        let mut cache = PasswordCache::new(8, Duration::from_secs(60));
+        cache.insert(&jid("juliet"), StoredPasswordKind::Argon2, "1".into(), now);
+        cache.insert(
+            &jid("juliet"),
+            StoredPasswordKind::ScramSha1,
+            "2".into(),
+            now,
+        );
+        cache.insert(&jid("romeo"), StoredPasswordKind::Argon2, "3".into(), now);
+
+        cache.invalidate(&jid("juliet"));
+
+        assert!(cache
+            .get(&jid("juliet"), StoredPasswordKind::Argon2, now)
+            .is_none());
+        assert!(cache
+            .get(&jid("juliet"), StoredPasswordKind::ScramSha1, now)
+            .is_none());
+        assert!(cache
+            .get(&jid("romeo"), StoredPasswordKind::Argon2, now)
+            .is_some());
+    }
+
+    #[test]
+    fn lookup_from_before_an_invalidation_is_not_cached() {
+        let now = Instant::now();
+        let mut cache = PasswordCache::new(8, Duration::from_secs(60));
+        let generation = cache.generation(&jid("juliet"));
+
+        cache.invalidate(&jid("juliet"));
+        cache.insert_if_current(
+            &jid("juliet"),
+            StoredPasswordKind::Argon2,
+            "old".into(),
+            now,
+            generation,
+        );
+
+        assert!(cache
+            .get(&jid("juliet"), StoredPasswordKind::Argon2, now)
+            .is_none());
+        let generation = cache.generation(&jid("juliet"));
+        cache.insert_if_current(
+            &jid("juliet"),
+            StoredPasswordKind::Argon2,
+            "new".into(),
+            now,
+            generation,
+        );
+        assert_eq!(
+            cache.get(&jid("juliet"), StoredPasswordKind::Argon2, now),
+            Some("new".to_string())
+        );
+    }
+}
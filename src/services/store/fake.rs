@@ -1,35 +1,51 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
 use anyhow::{anyhow, Error};
 
 use crate::inbound::StoredPasswordKind;
 use crate::xmpp::jid::Jid;
+use crate::xmpp::roster::{Roster, RosterItem, Subscription};
 
-use super::StoreBackend;
+use super::{OfflineMessage, StoreBackend};
 
-#[cfg(test)]
 #[derive(Default)]
 pub struct FakeStoreBackend {
     pub stored_password_argon2: Option<String>,
     pub stored_password_scram_sha1: Option<String>,
     pub stored_password_scram_sha256: Option<String>,
+    pub password_lookups: Arc<AtomicUsize>,
+    pub users: Vec<Jid>,
+    pub rosters: HashMap<Jid, Roster>,
+    pub offline_messages: HashMap<Jid, Vec<OfflineMessage>>,
+    // no limit unless set
+    pub max_offline_messages: Option<usize>,
 }
 
-#[cfg(test)]
 impl StoreBackend for FakeStoreBackend {
     async fn add_user(
         &mut self,
-        _jid: Jid,
+        jid: Jid,
         stored_password_argon2: String,
         stored_password_scram_sha1: String,
         stored_password_scram_sha256: String,
-    ) -> Result<(), Error> {
+    ) -> Result<bool, Error> {
+        if self.users.contains(&jid.to_bare()) {
+            return Ok(false);
+        }
+
+        self.users.push(jid.to_bare());
         self.stored_password_argon2 = Some(stored_password_argon2);
         self.stored_password_scram_sha1 = Some(stored_password_scram_sha1);
         self.stored_password_scram_sha256 = Some(stored_password_scram_sha256);
 
-        Ok(())
+        Ok(true)
     }
 
-    async fn remove_user(&mut self, _jid: Jid) -> Result<(), Error> {
+    async fn remove_user(&mut self, jid: Jid) -> Result<(), Error> {
+        let jid = jid.to_bare();
+        self.users.retain(|user| *user != jid);
         self.stored_password_argon2 = None;
         self.stored_password_scram_sha1 = None;
         self.stored_password_scram_sha256 = None;
@@ -37,11 +53,22 @@ impl StoreBackend for FakeStoreBackend {
         Ok(())
     }
 
+    async fn user_exists(&self, _jid: Jid) -> Result<bool, Error> {
+        Ok(self.stored_password_argon2.is_some()
+            || self.stored_password_scram_sha1.is_some()
+            || self.stored_password_scram_sha256.is_some())
+    }
+
+    async fn list_users(&self) -> Result<Vec<Jid>, Error> {
+        Ok(self.users.clone())
+    }
+
     async fn get_stored_password(
         &self,
         _jid: Jid,
         kind: StoredPasswordKind,
     ) -> Result<String, Error> {
+        self.password_lookups.fetch_add(1, Ordering::SeqCst);
         match kind {
             StoredPasswordKind::Argon2 => self
                 .stored_password_argon2
@@ -78,4 +105,94 @@ impl StoreBackend for FakeStoreBackend {
 
         Ok(())
     }
+
+    async fn set_stored_passwords(
+        &mut self,
+        _jid: Jid,
+        stored_password_argon2: String,
+        stored_password_scram_sha1: String,
+        stored_password_scram_sha256: String,
+    ) -> Result<(), Error> {
+        self.stored_password_argon2 = Some(stored_password_argon2);
+        self.stored_password_scram_sha1 = Some(stored_password_scram_sha1);
+        self.stored_password_scram_sha256 = Some(stored_password_scram_sha256);
+
+        Ok(())
+    }
+
+    async fn get_roster(&self, owner: Jid) -> Result<Roster, Error> {
+        Ok(self
+            .rosters
+            .get(&owner.to_bare())
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn set_roster_item(
+        &mut self,
+        owner: Jid,
+        item: RosterItem,
+    ) -> Result<(RosterItem, u64), Error> {
+        let roster = self.rosters.entry(owner.to_bare()).or_default();
+        let subscription = roster
+            .items
+            .iter()
+            .find(|existing| existing.jid == item.jid)
+            .map_or(Subscription::None, |existing| existing.subscription);
+        let item = RosterItem {
+            subscription,
+            ..item
+        };
+        roster.items.retain(|existing| existing.jid != item.jid);
+        roster.items.push(item.clone());
+        roster.version += 1;
+
+        Ok((item, roster.version))
+    }
+
+    async fn remove_roster_item(&mut self, owner: Jid, contact: Jid) -> Result<Option<u64>, Error> {
+        let Some(roster) = self.rosters.get_mut(&owner.to_bare()) else {
+            return Ok(None);
+        };
+        let length = roster.items.len();
+        roster.items.retain(|existing| existing.jid != contact);
+        if roster.items.len() == length {
+            return Ok(None);
+        }
+
+        roster.version += 1;
+        Ok(Some(roster.version))
+    }
+
+    async fn store_offline_message(
+        &mut self,
+        recipient: Jid,
+        message: OfflineMessage,
+    ) -> Result<bool, Error> {
+        let recipient = recipient.to_bare();
+        if !self.users.contains(&recipient) {
+            return Ok(false);
+        }
+
+        let messages = self.offline_messages.entry(recipient).or_default();
+        if self
+            .max_offline_messages
+            .is_some_and(|max| messages.len() >= max)
+        {
+            return Ok(false);
+        }
+
+        messages.push(message);
+        Ok(true)
+    }
+
+    async fn take_offline_messages(
+        &mut self,
+        recipient: Jid,
+    ) -> Result<Vec<OfflineMessage>, Error> {
+        Ok(self
+            .offline_messages
+            .remove(&recipient.to_bare())
+            .unwrap_or_default())
+    }
 }
@@ -1,25 +1,83 @@
-use anyhow::Error;
-use sqlx::{migrate, sqlite::SqlitePoolOptions, Pool, Sqlite};
+use std::fs::OpenOptions;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Context, Error};
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    Pool, Sqlite, Transaction,
+};
 
 use crate::inbound::StoredPasswordKind;
 use crate::settings::get_settings;
+use crate::xml::Element;
 use crate::xmpp::jid::Jid;
+use crate::xmpp::roster::{Roster, RosterItem, Subscription};
+use crate::xmpp::stanza::Stanza;
 
-use super::StoreBackend;
+use super::{OfflineMessage, StoreBackend};
 
 pub struct SqliteStoreBackend {
     pool: Pool<Sqlite>,
+    max_offline_messages: usize,
 }
 
 impl SqliteStoreBackend {
     pub async fn new() -> Result<Self, Error> {
+        let settings = get_settings();
+        Self::connect(&settings.database_url, settings.max_offline_messages).await
+    }
+
+    async fn connect(database_url: &str, max_offline_messages: usize) -> Result<Self, Error> {
+        let options = SqliteConnectOptions::from_str(database_url)?;
+        let in_memory = database_url.contains(":memory:") || database_url.contains("mode=memory");
+        if !in_memory {
+            prepare_database_file(options.get_filename(), database_url.contains("mode=rwc"))?;
+        }
+
         let pool = SqlitePoolOptions::new()
             .max_connections(5)
-            .connect(&get_settings().database_url)
-            .await?;
+            .connect_with(options)
+            .await
+            .with_context(|| format!("could not open database `{database_url}`"))?;
+
+        Ok(Self {
+            pool,
+            max_offline_messages,
+        })
+    }
+}
 
-        Ok(Self { pool })
+// SQLite creates the database file itself, but neither its parent directory nor with
+// permissions restrictive enough for password hashes.
+fn prepare_database_file(path: &Path, create: bool) -> Result<(), Error> {
+    if path.exists() {
+        return Ok(());
     }
+
+    if !create {
+        bail!(
+            "database file `{}` does not exist (add `?mode=rwc` to the database URL to create it)",
+            path.display()
+        );
+    }
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(|err| {
+            anyhow!(err).context(format!("cannot create directory `{}`", parent.display()))
+        })?;
+    }
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path).map_err(|err| {
+        anyhow!(err).context(format!("cannot create database file `{}`", path.display()))
+    })?;
+
+    Ok(())
 }
 
 impl StoreBackend for SqliteStoreBackend {
@@ -29,11 +87,13 @@ impl StoreBackend for SqliteStoreBackend {
         stored_password_argon2: String,
         stored_password_scram_sha1: String,
         stored_password_scram_sha256: String,
-    ) -> Result<(), Error> {
-        sqlx::query(
+    ) -> Result<bool, Error> {
+        // an account created since the caller checked is left alone rather than failing
+        let result = sqlx::query(
                 r#"
                 INSERT INTO users (bare_jid, stored_password_argon2, stored_password_scram_sha1, stored_password_scram_sha256)
                 VALUES (?, ?, ?, ?)
+                ON CONFLICT (bare_jid) DO NOTHING
                 "#,
             )
             .bind(jid.to_bare().to_string())
@@ -43,7 +103,7 @@ impl StoreBackend for SqliteStoreBackend {
             .execute(&self.pool)
             .await?;
 
-        Ok(())
+        Ok(result.rows_affected() > 0)
     }
 
     async fn remove_user(&mut self, jid: Jid) -> Result<(), Error> {
@@ -60,6 +120,38 @@ impl StoreBackend for SqliteStoreBackend {
         Ok(())
     }
 
+    async fn user_exists(&self, jid: Jid) -> Result<bool, Error> {
+        let row = sqlx::query(
+            r#"
+            SELECT 1
+            FROM users
+            WHERE bare_jid = ?
+            "#,
+        )
+        .bind(jid.to_bare().to_string())
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.is_some())
+    }
+
+    async fn list_users(&self) -> Result<Vec<Jid>, Error> {
+        let bare_jids = sqlx::query_scalar::<_, String>(
+            r#"
+            SELECT bare_jid
+            FROM users
+            ORDER BY bare_jid
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        bare_jids
+            .into_iter()
+            .map(|bare_jid| bare_jid.parse::<Jid>())
+            .collect()
+    }
+
     async fn get_stored_password(
         &self,
         jid: Jid,
@@ -121,6 +213,253 @@ impl StoreBackend for SqliteStoreBackend {
 
         Ok(())
     }
+
+    async fn set_stored_passwords(
+        &mut self,
+        jid: Jid,
+        stored_password_argon2: String,
+        stored_password_scram_sha1: String,
+        stored_password_scram_sha256: String,
+    ) -> Result<(), Error> {
+        sqlx::query(
+            r#"
+            UPDATE users
+            SET stored_password_argon2 = ?, stored_password_scram_sha1 = ?, stored_password_scram_sha256 = ?
+            WHERE bare_jid = ?
+            "#,
+        )
+        .bind(stored_password_argon2)
+        .bind(stored_password_scram_sha1)
+        .bind(stored_password_scram_sha256)
+        .bind(jid.to_bare().to_string())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_roster(&self, owner: Jid) -> Result<Roster, Error> {
+        let mut transaction = self.pool.begin().await?;
+
+        // a roster that never changed has no row
+        let version = sqlx::query_scalar::<_, i64>(
+            r#"
+            SELECT version
+            FROM roster_versions
+            WHERE owner = ?
+            "#,
+        )
+        .bind(owner.to_bare().to_string())
+        .fetch_optional(&mut *transaction)
+        .await?
+        .unwrap_or(0);
+
+        // one row per group, or a single one for items without any
+        let rows = sqlx::query_as::<_, RosterRow>(
+            r#"
+            SELECT roster.id, roster.contact, roster.name, roster.subscription, roster_groups.name AS group_name
+            FROM roster
+            LEFT JOIN roster_groups ON roster_groups.roster_id = roster.id
+            WHERE roster.owner = ?
+            ORDER BY roster.contact, roster_groups.name
+            "#,
+        )
+        .bind(owner.to_bare().to_string())
+        .fetch_all(&mut *transaction)
+        .await?;
+        transaction.commit().await?;
+
+        let mut items: Vec<(i64, RosterItem)> = Vec::new();
+        for row in rows {
+            let group = row.group_name;
+            match items.last_mut() {
+                Some((id, item)) if *id == row.id => item.groups.extend(group),
+                _ => {
+                    let item = RosterItem {
+                        jid: row.contact.parse()?,
+                        name: row.name,
+                        subscription: row.subscription.parse()?,
+                        groups: group.into_iter().collect(),
+                    };
+                    items.push((row.id, item));
+                }
+            }
+        }
+
+        Ok(Roster {
+            version: version as u64,
+            items: items.into_iter().map(|(_, item)| item).collect(),
+        })
+    }
+
+    async fn set_roster_item(
+        &mut self,
+        owner: Jid,
+        item: RosterItem,
+    ) -> Result<(RosterItem, u64), Error> {
+        let mut transaction = self.pool.begin().await?;
+
+        let (id, subscription) = sqlx::query_as::<_, (i64, String)>(
+            r#"
+            INSERT INTO roster (owner, contact, name)
+            VALUES (?, ?, ?)
+            ON CONFLICT (owner, contact) DO UPDATE SET name = excluded.name
+            RETURNING id, subscription
+            "#,
+        )
+        .bind(owner.to_bare().to_string())
+        .bind(item.jid.to_bare().to_string())
+        .bind(item.name.as_deref())
+        .fetch_one(&mut *transaction)
+        .await?;
+
+        sqlx::query(
+            r#"
+            DELETE FROM roster_groups
+            WHERE roster_id = ?
+            "#,
+        )
+        .bind(id)
+        .execute(&mut *transaction)
+        .await?;
+        for group in &item.groups {
+            sqlx::query(
+                r#"
+                INSERT INTO roster_groups (roster_id, name)
+                VALUES (?, ?)
+                "#,
+            )
+            .bind(id)
+            .bind(group)
+            .execute(&mut *transaction)
+            .await?;
+        }
+
+        let version = bump_roster_version(&mut transaction, &owner).await?;
+
+        transaction.commit().await?;
+
+        let item = RosterItem {
+            subscription: subscription.parse::<Subscription>()?,
+            ..item
+        };
+        Ok((item, version))
+    }
+
+    async fn remove_roster_item(&mut self, owner: Jid, contact: Jid) -> Result<Option<u64>, Error> {
+        let mut transaction = self.pool.begin().await?;
+
+        // the item's groups go with it
+        let result = sqlx::query(
+            r#"
+            DELETE FROM roster
+            WHERE owner = ? AND contact = ?
+            "#,
+        )
+        .bind(owner.to_bare().to_string())
+        .bind(contact.to_bare().to_string())
+        .execute(&mut *transaction)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Ok(None);
+        }
+
+        let version = bump_roster_version(&mut transaction, &owner).await?;
+        transaction.commit().await?;
+
+        Ok(Some(version))
+    }
+
+    async fn store_offline_message(
+        &mut self,
+        recipient: Jid,
+        message: OfflineMessage,
+    ) -> Result<bool, Error> {
+        let stored_at = message.stored_at.duration_since(UNIX_EPOCH)?.as_millis() as i64;
+        // nothing is inserted for an unknown account, nor for one that has all it may keep, and
+        // counting in the same statement leaves no room for another message to slip in between
+        let result = sqlx::query(
+            r#"
+            INSERT INTO offline_messages (recipient, stanza, stored_at)
+            SELECT bare_jid, ?, ?
+            FROM users
+            WHERE bare_jid = ?
+            AND (SELECT COUNT(*) FROM offline_messages WHERE recipient = users.bare_jid) < ?
+            "#,
+        )
+        .bind(message.stanza.element.to_string())
+        .bind(stored_at)
+        .bind(recipient.to_bare().to_string())
+        .bind(i64::try_from(self.max_offline_messages).unwrap_or(i64::MAX))
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn take_offline_messages(
+        &mut self,
+        recipient: Jid,
+    ) -> Result<Vec<OfflineMessage>, Error> {
+        let mut rows = sqlx::query_as::<_, OfflineMessageRow>(
+            r#"
+            DELETE FROM offline_messages
+            WHERE recipient = ?
+            RETURNING id, stanza, stored_at
+            "#,
+        )
+        .bind(recipient.to_bare().to_string())
+        .fetch_all(&self.pool)
+        .await?;
+        // RETURNING comes in no particular order
+        rows.sort_by_key(|row| row.id);
+
+        rows.into_iter()
+            .map(|row| {
+                Ok(OfflineMessage {
+                    stanza: Stanza {
+                        element: Element::parse(&row.stanza)?,
+                    },
+                    stored_at: UNIX_EPOCH + Duration::from_millis(row.stored_at as u64),
+                })
+            })
+            .collect()
+    }
+}
+
+async fn bump_roster_version(
+    transaction: &mut Transaction<'_, Sqlite>,
+    owner: &Jid,
+) -> Result<u64, Error> {
+    let version = sqlx::query_scalar::<_, i64>(
+        r#"
+        INSERT INTO roster_versions (owner, version)
+        VALUES (?, 1)
+        ON CONFLICT (owner) DO UPDATE SET version = version + 1
+        RETURNING version
+        "#,
+    )
+    .bind(owner.to_bare().to_string())
+    .fetch_one(&mut **transaction)
+    .await?;
+
+    Ok(version as u64)
+}
+
+#[derive(sqlx::FromRow)]
+struct OfflineMessageRow {
+    id: i64,
+    stanza: String,
+    stored_at: i64,
+}
+
+#[derive(sqlx::FromRow)]
+struct RosterRow {
+    id: i64,
+    contact: String,
+    name: Option<String>,
+    subscription: String,
+    group_name: Option<String>,
 }
 
 #[derive(sqlx::FromRow)]
@@ -130,3 +469,45 @@ struct User {
     stored_password_scram_sha1: String,
     stored_password_scram_sha256: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir()
+            .join(uuid::Uuid::new_v4().to_string())
+            .join("nested")
+            .join(name)
+    }
+
+    #[tokio::test]
+    async fn fresh_database_is_created_with_restrictive_permissions() {
+        let path = temp_path("db.sqlite3");
+
+        SqliteStoreBackend::connect(&format!("sqlite://{}?mode=rwc", path.display()), 100)
+            .await
+            .unwrap();
+
+        assert!(path.exists());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+
+    #[tokio::test]
+    async fn missing_database_is_not_created_without_rwc() {
+        let path = temp_path("db.sqlite3");
+
+        let result =
+            SqliteStoreBackend::connect(&format!("sqlite://{}", path.display()), 100).await;
+
+        let error = result.err().unwrap().to_string();
+        assert!(error.contains("does not exist"));
+        assert!(!path.exists());
+    }
+}
@@ -1,4 +1,9 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::num::NonZeroU32;
+use std::path::PathBuf;
 use std::sync::{Arc, OnceLock};
+use std::time::Duration;
 use std::{fs::File, io::BufReader};
 
 use anyhow::{anyhow, Error};
@@ -10,31 +15,194 @@ use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
 use tokio_rustls::rustls::server::WebPkiClientVerifier;
 use tokio_rustls::rustls::{RootCertStore, ServerConfig};
 
+use crate::inbound::SaslMechanisms;
+use crate::utils::certificates::{certified_key, CertificateResolver};
+use crate::xml::stream_parser::ParserConfig;
 use crate::xmpp::jid::Jid;
 
 static SETTINGS: OnceLock<Settings> = OnceLock::new();
 
 #[derive(Debug, Deserialize)]
-
-struct TlsConfig {
+struct TlsCertificate {
     #[serde(deserialize_with = "load_certificate_chain")]
     certificate_chain: Vec<CertificateDer<'static>>,
     #[serde(deserialize_with = "load_private_key")]
     private_key: PrivateKeyDer<'static>,
 }
 
+#[derive(Debug, Deserialize)]
+struct TlsConfig {
+    #[serde(flatten)]
+    default: TlsCertificate,
+    // Certificates for the names clients ask for via SNI, if not the default one
+    #[serde(default)]
+    domains: HashMap<String, TlsCertificate>,
+}
+
+#[derive(Debug)]
+pub struct TlsServerConfig {
+    pub config: Arc<ServerConfig>,
+    certificate_chain: Vec<CertificateDer<'static>>,
+    domain_certificate_chains: HashMap<String, Vec<CertificateDer<'static>>>,
+}
+
+impl TlsServerConfig {
+    // The chain that was presented to a client asking for `server_name`.
+    pub fn certificate_chain(&self, server_name: Option<&str>) -> &[CertificateDer<'static>] {
+        server_name
+            .and_then(|name| self.domain_certificate_chains.get(&name.to_lowercase()))
+            .unwrap_or(&self.certificate_chain)
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Tls {
     pub required_for_clients: bool,
     pub required_for_servers: bool,
     #[serde(deserialize_with = "init_tls_server_config")]
-    pub server_config: Arc<ServerConfig>,
+    pub server_config: TlsServerConfig,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RateLimits {
+    pub stanzas_per_second: NonZeroU32,
+    pub stanza_burst: u32,
+    pub global_stanzas_per_second: NonZeroU32,
+    pub global_stanza_burst: u32,
+}
+
+// Whether clients have to put their JID in the `from` of their stream header.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HeaderFromPolicy {
+    #[default]
+    Optional,
+    Required,
+    Forbidden,
+}
+
+// What happens when a client asks for a resource that another stream has already bound.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResourceConflictPolicy {
+    #[default]
+    Reject,
+    Generate,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConnectionSettings {
+    // Client connections are accepted on every one of these
+    #[serde(default = "default_c2s_bind")]
+    pub c2s_bind: Vec<SocketAddr>,
+    // ... and on these with TLS from the first byte (XEP-0368), usually port 5223
+    #[serde(default)]
+    pub direct_tls_bind: Vec<SocketAddr>,
+    // Connections served at once across all listeners, further ones wait to be accepted
+    #[serde(default = "default_max_connections")]
+    pub max_connections: usize,
+    // How long open streams get to close on shutdown before they are cut off
+    #[serde(
+        default = "default_shutdown_grace_period",
+        deserialize_with = "deserialize_seconds"
+    )]
+    pub shutdown_grace_period: Duration,
+    pub proxy_protocol: bool,
+    pub implicit_tls: bool,
+    // Streams are only recorded when this is set
+    #[serde(default)]
+    pub recording_directory: Option<PathBuf>,
+    // ... and each recording file is continued in a new one once it grows past this many bytes
+    #[serde(default)]
+    pub recording_rotate_after: Option<u64>,
+    // Prometheus metrics are only served when this is set
+    #[serde(default)]
+    pub metrics_bind: Option<SocketAddr>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InboundStreamSettings {
+    #[serde(deserialize_with = "deserialize_seconds")]
+    pub whitespace_ping_interval: Duration,
+    #[serde(deserialize_with = "deserialize_seconds")]
+    pub idle_timeout: Duration,
+    #[serde(deserialize_with = "deserialize_seconds")]
+    pub close_timeout: Duration,
+    #[serde(deserialize_with = "deserialize_seconds")]
+    pub client_ping_interval: Duration,
+    #[serde(deserialize_with = "deserialize_seconds")]
+    pub client_ping_timeout: Duration,
+    #[serde(deserialize_with = "deserialize_seconds")]
+    pub ack_request_interval: Duration,
+    pub max_unacked_stanzas: usize,
+    #[serde(deserialize_with = "deserialize_seconds")]
+    pub resumption_timeout: Duration,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PasswordPepper {
+    pub id: String,
+    pub secret: String,
+}
+
+// Costs of newly stored passwords. Existing Argon2 hashes below these are upgraded on login.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct PasswordHashing {
+    pub scram_iterations: NonZeroU32,
+    // in KiB
+    pub argon2_memory: u32,
+    pub argon2_time: u32,
+    pub argon2_parallelism: u32,
+}
+
+impl Default for PasswordHashing {
+    fn default() -> Self {
+        PasswordHashing {
+            scram_iterations: NonZeroU32::new(10_000).unwrap(),
+            argon2_memory: argon2::Params::DEFAULT_M_COST,
+            argon2_time: argon2::Params::DEFAULT_T_COST,
+            argon2_parallelism: argon2::Params::DEFAULT_P_COST,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Settings {
     pub database_url: String,
+    #[serde(deserialize_with = "deserialize_domain")]
     pub domain: Jid,
+    // Further domains served here, chosen by the `to` of a client's stream header
+    #[serde(default, deserialize_with = "deserialize_domains")]
+    pub virtual_hosts: Vec<Jid>,
+    pub require_from_match: bool,
+    #[serde(default)]
+    pub client_header_from: HeaderFromPolicy,
+    pub cache_stream_features: bool,
+    pub max_pre_auth_elements: usize,
+    pub max_auth_retries: usize,
+    // Messages kept for an account while none of its resources is available
+    pub max_offline_messages: usize,
+    #[serde(default)]
+    pub allow_registration: bool,
+    #[serde(default)]
+    pub resource_conflict: ResourceConflictPolicy,
+    #[serde(default)]
+    pub disclose_os: bool,
+    // Minutes east of UTC, reported in entity time queries
+    #[serde(default)]
+    pub time_zone_offset: i16,
+    pub xml_parser: ParserConfig,
+    pub password_pepper: Option<PasswordPepper>,
+    #[serde(default)]
+    pub password_hashing: PasswordHashing,
+    #[serde(default)]
+    pub retired_password_peppers: Vec<PasswordPepper>,
+    #[serde(default)]
+    pub sasl_mechanisms: SaslMechanisms,
+    pub rate_limits: RateLimits,
+    pub connection: ConnectionSettings,
+    pub inbound_stream: InboundStreamSettings,
     pub tls: Tls,
 }
 
@@ -54,10 +222,125 @@ impl Settings {
     }
 }
 
+#[cfg(test)]
+impl Settings {
+    // What tests driving a whole stream run with: a plaintext client stream that may log in with
+    // ANONYMOUS, and no certificate to offer TLS with.
+    pub fn init_for_tests() -> &'static Settings {
+        use tokio_rustls::rustls::server::ResolvesServerCertUsingSni;
+
+        use crate::xml::stream_parser::{ElementLimits, ParserKind};
+
+        SETTINGS.get_or_init(|| Settings {
+            database_url: "sqlite::memory:".to_string(),
+            domain: "localhost".parse().unwrap(),
+            virtual_hosts: vec![],
+            require_from_match: true,
+            client_header_from: HeaderFromPolicy::Optional,
+            cache_stream_features: false,
+            max_pre_auth_elements: 10,
+            max_auth_retries: 3,
+            max_offline_messages: 100,
+            allow_registration: false,
+            resource_conflict: ResourceConflictPolicy::Reject,
+            disclose_os: false,
+            time_zone_offset: 0,
+            xml_parser: ParserConfig {
+                kind: ParserKind::RustyXml,
+                limits: ElementLimits::default(),
+            },
+            password_pepper: None,
+            password_hashing: PasswordHashing::default(),
+            retired_password_peppers: vec![],
+            sasl_mechanisms: SaslMechanisms {
+                allow_anonymous: true,
+                ..Default::default()
+            },
+            rate_limits: RateLimits {
+                stanzas_per_second: NonZeroU32::new(1000).unwrap(),
+                stanza_burst: 1000,
+                global_stanzas_per_second: NonZeroU32::new(100_000).unwrap(),
+                global_stanza_burst: 100_000,
+            },
+            connection: ConnectionSettings {
+                c2s_bind: vec![],
+                direct_tls_bind: vec![],
+                max_connections: 16,
+                shutdown_grace_period: Duration::from_secs(1),
+                proxy_protocol: false,
+                implicit_tls: false,
+                recording_directory: None,
+                recording_rotate_after: None,
+                metrics_bind: None,
+            },
+            inbound_stream: InboundStreamSettings {
+                whitespace_ping_interval: Duration::from_secs(60),
+                idle_timeout: Duration::from_secs(300),
+                close_timeout: Duration::from_secs(1),
+                client_ping_interval: Duration::from_secs(600),
+                client_ping_timeout: Duration::from_secs(60),
+                ack_request_interval: Duration::from_secs(30),
+                max_unacked_stanzas: 1000,
+                resumption_timeout: Duration::from_secs(300),
+            },
+            tls: Tls {
+                required_for_clients: false,
+                required_for_servers: false,
+                server_config: TlsServerConfig {
+                    config: Arc::new(
+                        ServerConfig::builder()
+                            .with_no_client_auth()
+                            .with_cert_resolver(Arc::new(ResolvesServerCertUsingSni::new())),
+                    ),
+                    certificate_chain: vec![],
+                    domain_certificate_chains: HashMap::new(),
+                },
+            },
+        })
+    }
+}
+
 pub fn get_settings() -> &'static Settings {
     SETTINGS.get().expect("Settings not initialized")
 }
 
+fn deserialize_domain<'d, D: Deserializer<'d>>(deserializer: D) -> Result<Jid, D::Error> {
+    check_domain(Jid::deserialize(deserializer)?)
+}
+
+fn deserialize_domains<'d, D: Deserializer<'d>>(deserializer: D) -> Result<Vec<Jid>, D::Error> {
+    Vec::<Jid>::deserialize(deserializer)?
+        .into_iter()
+        .map(check_domain)
+        .collect()
+}
+
+fn check_domain<E: serde::de::Error>(domain: Jid) -> Result<Jid, E> {
+    if !domain.is_domain() {
+        return Err(E::custom(format!(
+            "domain `{domain}` must not contain a local or resource part"
+        )));
+    }
+
+    Ok(domain)
+}
+
+fn default_c2s_bind() -> Vec<SocketAddr> {
+    vec![SocketAddr::from(([127, 0, 0, 1], 5222))]
+}
+
+fn default_max_connections() -> usize {
+    1024
+}
+
+fn default_shutdown_grace_period() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn deserialize_seconds<'d, D: Deserializer<'d>>(deserializer: D) -> Result<Duration, D::Error> {
+    u64::deserialize(deserializer).map(Duration::from_secs)
+}
+
 fn load_certificate_chain<'d, D: Deserializer<'d>>(
     deserializer: D,
 ) -> Result<Vec<CertificateDer<'static>>, D::Error> {
@@ -83,7 +366,7 @@ fn load_private_key<'d, D: Deserializer<'d>>(
 
 fn init_tls_server_config<'d, D: Deserializer<'d>>(
     deserializer: D,
-) -> Result<Arc<ServerConfig>, D::Error> {
+) -> Result<TlsServerConfig, D::Error> {
     let config = TlsConfig::deserialize(deserializer)?;
 
     let mut root_cert_store = RootCertStore::empty();
@@ -96,10 +379,142 @@ fn init_tls_server_config<'d, D: Deserializer<'d>>(
         .allow_unauthenticated()
         .build()
         .map_err(serde::de::Error::custom)?;
+
+    let default = config.default;
+    let mut resolver = CertificateResolver::new(
+        certified_key(default.certificate_chain.clone(), &default.private_key)
+            .map_err(serde::de::Error::custom)?,
+    );
+    let mut domain_certificate_chains = HashMap::new();
+    for (domain, certificate) in config.domains {
+        let key = certified_key(
+            certificate.certificate_chain.clone(),
+            &certificate.private_key,
+        )
+        .map_err(serde::de::Error::custom)?;
+        resolver.add(&domain, key);
+        domain_certificate_chains.insert(domain.to_lowercase(), certificate.certificate_chain);
+    }
+
     let config = ServerConfig::builder()
         .with_client_cert_verifier(client_cert_verifier)
-        .with_single_cert(config.certificate_chain, config.private_key)
-        .map_err(serde::de::Error::custom)?;
+        .with_cert_resolver(Arc::new(resolver));
+
+    Ok(TlsServerConfig {
+        config: Arc::new(config),
+        certificate_chain: default.certificate_chain,
+        domain_certificate_chains,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    struct DomainSettings {
+        #[serde(deserialize_with = "deserialize_domain")]
+        domain: Jid,
+        #[serde(default, deserialize_with = "deserialize_domains")]
+        virtual_hosts: Vec<Jid>,
+    }
+
+    fn deserialize(source: &str) -> Result<DomainSettings, config::ConfigError> {
+        config::Config::builder()
+            .add_source(config::File::from_str(source, config::FileFormat::Yaml))
+            .build()?
+            .try_deserialize()
+    }
+
+    fn deserialize_rate_limits(source: &str) -> Result<RateLimits, config::ConfigError> {
+        config::Config::builder()
+            .add_source(config::File::from_str(source, config::FileFormat::Yaml))
+            .build()?
+            .try_deserialize()
+    }
+
+    fn deserialize_connection(source: &str) -> Result<ConnectionSettings, config::ConfigError> {
+        config::Config::builder()
+            .add_source(config::File::from_str(source, config::FileFormat::Yaml))
+            .build()?
+            .try_deserialize()
+    }
+
+    #[test]
+    fn c2s_bind_accepts_a_list_of_addresses() {
+        let settings = deserialize_connection(
+            "c2s_bind: ['0.0.0.0:5222', '[::]:5222', '192.0.2.1:15222']\n\
+            proxy_protocol: false\n\
+            implicit_tls: false",
+        )
+        .unwrap();
+
+        let expected: Vec<SocketAddr> = ["0.0.0.0:5222", "[::]:5222", "192.0.2.1:15222"]
+            .into_iter()
+            .map(|address| address.parse().unwrap())
+            .collect();
+        assert_eq!(settings.c2s_bind, expected);
+    }
+
+    #[test]
+    fn c2s_bind_defaults_to_localhost() {
+        let settings =
+            deserialize_connection("proxy_protocol: false\nimplicit_tls: false").unwrap();
+        assert_eq!(settings.c2s_bind, default_c2s_bind());
+        assert!(settings.direct_tls_bind.is_empty());
+        assert_eq!(settings.max_connections, default_max_connections());
+        assert_eq!(
+            settings.shutdown_grace_period,
+            default_shutdown_grace_period()
+        );
+        assert_eq!(settings.recording_directory, None);
+        assert_eq!(settings.recording_rotate_after, None);
+    }
+
+    #[test]
+    fn domain_only_jid_is_accepted() {
+        let settings = deserialize("domain: example.com").unwrap();
+        assert_eq!(settings.domain.domain(), "example.com");
+    }
+
+    #[test]
+    fn virtual_hosts_are_optional() {
+        let settings = deserialize("domain: example.com").unwrap();
+        assert!(settings.virtual_hosts.is_empty());
+
+        let settings =
+            deserialize("domain: example.com\nvirtual_hosts: [example.net, example.org]").unwrap();
+        let domains: Vec<&str> = settings.virtual_hosts.iter().map(Jid::domain).collect();
+        assert_eq!(domains, ["example.net", "example.org"]);
+    }
+
+    #[test]
+    fn full_jid_virtual_host_is_rejected() {
+        let error =
+            deserialize("domain: example.com\nvirtual_hosts: [admin@example.net]").unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("must not contain a local or resource part"));
+    }
 
-    Ok(Arc::new(config))
+    #[test]
+    fn full_jid_domain_is_rejected() {
+        let error = deserialize("domain: admin@example.com").unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("must not contain a local or resource part"));
+    }
+
+    #[test]
+    fn zero_stanza_rates_are_rejected() {
+        let limits = "stanza_burst: 10\nglobal_stanza_burst: 10";
+        let valid = format!("stanzas_per_second: 5\nglobal_stanzas_per_second: 5\n{limits}");
+        assert!(deserialize_rate_limits(&valid).is_ok());
+
+        let per_stream = format!("stanzas_per_second: 0\nglobal_stanzas_per_second: 5\n{limits}");
+        assert!(deserialize_rate_limits(&per_stream).is_err());
+
+        let global = format!("stanzas_per_second: 5\nglobal_stanzas_per_second: 0\n{limits}");
+        assert!(deserialize_rate_limits(&global).is_err());
+    }
 }
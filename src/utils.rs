@@ -1 +1,5 @@
+pub mod blocking;
+pub mod certificates;
+pub mod random;
+pub mod rate_limiter;
 pub mod recorder;
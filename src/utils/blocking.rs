@@ -0,0 +1,42 @@
+use anyhow::Error;
+
+// Password hashing is slow on purpose. Running it on the blocking pool keeps it from stalling
+// every other stream scheduled on the same worker.
+pub async fn run<F, T>(f: F) -> Result<T, Error>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    Ok(tokio::task::spawn_blocking(f).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn other_tasks_progress_while_hashing() {
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let ticker = tokio::spawn({
+            let ticks = ticks.clone();
+            async move {
+                loop {
+                    ticks.fetch_add(1, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                }
+            }
+        });
+
+        // the test runtime has a single worker, which a hash on it would keep to itself
+        run(|| std::thread::sleep(Duration::from_millis(100)))
+            .await
+            .unwrap();
+        ticker.abort();
+
+        assert!(ticks.load(Ordering::SeqCst) > 1);
+    }
+}
@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Error};
+use tokio_rustls::rustls::crypto::aws_lc_rs::sign::any_supported_type;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+
+// Picks the certificate for the name a client asked for via SNI. Clients that sent no name, or
+// one without a certificate of its own, get the default certificate.
+#[derive(Debug)]
+pub struct CertificateResolver {
+    default: Arc<CertifiedKey>,
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl CertificateResolver {
+    pub fn new(default: CertifiedKey) -> Self {
+        Self {
+            default: Arc::new(default),
+            by_name: HashMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, name: &str, key: CertifiedKey) {
+        self.by_name.insert(name.to_lowercase(), Arc::new(key));
+    }
+
+    fn select(&self, server_name: Option<&str>) -> Arc<CertifiedKey> {
+        server_name
+            .and_then(|name| self.by_name.get(&name.to_lowercase()))
+            .unwrap_or(&self.default)
+            .clone()
+    }
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.select(client_hello.server_name()))
+    }
+}
+
+pub fn certified_key(
+    certificate_chain: Vec<CertificateDer<'static>>,
+    private_key: &PrivateKeyDer<'static>,
+) -> Result<CertifiedKey, Error> {
+    let signing_key = any_supported_type(private_key).map_err(|err| anyhow!(err))?;
+    Ok(CertifiedKey::new(certificate_chain, signing_key))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_rustls::rustls::sign::{Signer, SigningKey};
+    use tokio_rustls::rustls::{SignatureAlgorithm, SignatureScheme};
+
+    use super::*;
+
+    #[derive(Debug)]
+    struct UnusableKey;
+
+    impl SigningKey for UnusableKey {
+        fn choose_scheme(&self, _offered: &[SignatureScheme]) -> Option<Box<dyn Signer>> {
+            None
+        }
+
+        fn algorithm(&self) -> SignatureAlgorithm {
+            SignatureAlgorithm::ED25519
+        }
+    }
+
+    // The certificate is only there to tell the keys apart
+    fn key(tag: u8) -> CertifiedKey {
+        CertifiedKey::new(vec![CertificateDer::from(vec![tag])], Arc::new(UnusableKey))
+    }
+
+    fn tag(key: &CertifiedKey) -> u8 {
+        key.cert[0].as_ref()[0]
+    }
+
+    fn resolver() -> CertificateResolver {
+        let mut resolver = CertificateResolver::new(key(0));
+        resolver.add("example.com", key(1));
+        resolver.add("Example.NET", key(2));
+        resolver
+    }
+
+    #[test]
+    fn configured_names_get_their_certificate() {
+        let resolver = resolver();
+        assert_eq!(tag(&resolver.select(Some("example.com"))), 1);
+        assert_eq!(tag(&resolver.select(Some("example.net"))), 2);
+        assert_eq!(tag(&resolver.select(Some("EXAMPLE.com"))), 1);
+    }
+
+    #[test]
+    fn other_names_get_the_default_certificate() {
+        let resolver = resolver();
+        assert_eq!(tag(&resolver.select(Some("example.org"))), 0);
+        assert_eq!(tag(&resolver.select(None)), 0);
+    }
+}
@@ -0,0 +1,40 @@
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+
+#[cfg(test)]
+use std::cell::RefCell;
+
+// Tests can seed the generator for the current thread to make stream ids and nonces
+// reproducible. Everything else draws from OS entropy.
+#[cfg(test)]
+thread_local! {
+    static SEEDED_RNG: RefCell<Option<ChaCha20Rng>> = const { RefCell::new(None) };
+}
+
+pub fn fill_bytes(dest: &mut [u8]) {
+    #[cfg(test)]
+    {
+        let seeded = SEEDED_RNG.with(|rng| match rng.borrow_mut().as_mut() {
+            Some(rng) => {
+                rng.fill_bytes(dest);
+                true
+            }
+            None => false,
+        });
+        if seeded {
+            return;
+        }
+    }
+
+    ChaCha20Rng::from_entropy().fill_bytes(dest);
+}
+
+#[cfg(test)]
+pub fn seed(seed: u64) {
+    SEEDED_RNG.with(|rng| *rng.borrow_mut() = Some(ChaCha20Rng::seed_from_u64(seed)));
+}
+
+#[cfg(test)]
+pub fn unseed() {
+    SEEDED_RNG.with(|rng| *rng.borrow_mut() = None);
+}
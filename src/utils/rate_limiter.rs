@@ -0,0 +1,114 @@
+use std::num::NonZeroU32;
+use std::time::{Duration, Instant};
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+#[error("rate limit exceeded")]
+pub struct RateLimitExceeded;
+
+// Tokens may go into debt down to `-burst`, which is paid back by delaying the caller.
+// Only once the debt would exceed that is the limit considered violated.
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    // A rate of zero would never pay back any debt.
+    pub fn new(rate: NonZeroU32, burst: u32, now: Instant) -> Self {
+        TokenBucket {
+            rate: rate.get() as f64,
+            burst: burst as f64,
+            tokens: burst as f64,
+            last_refill: now,
+        }
+    }
+
+    pub fn acquire(&mut self, now: Instant) -> Result<Duration, RateLimitExceeded> {
+        self.refill(now);
+        if self.tokens - 1.0 < -self.burst {
+            return Err(RateLimitExceeded);
+        }
+
+        Ok(self.take())
+    }
+
+    // Takes a token however deep in debt that goes, for limits that are shared by many callers
+    // and so may only slow them down, never single one of them out.
+    pub fn delay(&mut self, now: Instant) -> Duration {
+        self.refill(now);
+        self.take()
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.burst);
+        self.last_refill = now;
+    }
+
+    fn take(&mut self) -> Duration {
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rate(rate: u32) -> NonZeroU32 {
+        NonZeroU32::new(rate).unwrap()
+    }
+
+    #[test]
+    fn burst_is_not_throttled() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(rate(1), 5, now);
+
+        for _ in 0..5 {
+            assert_eq!(bucket.acquire(now), Ok(Duration::ZERO));
+        }
+    }
+
+    #[test]
+    fn throttling_engages_when_sending_faster_than_rate() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(rate(2), 2, now);
+
+        assert_eq!(bucket.acquire(now), Ok(Duration::ZERO));
+        assert_eq!(bucket.acquire(now), Ok(Duration::ZERO));
+        assert_eq!(bucket.acquire(now), Ok(Duration::from_millis(500)));
+        assert_eq!(bucket.acquire(now), Ok(Duration::from_secs(1)));
+        assert_eq!(bucket.acquire(now), Err(RateLimitExceeded));
+    }
+
+    #[test]
+    fn tokens_refill_over_time() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(rate(2), 2, now);
+
+        for _ in 0..4 {
+            bucket.acquire(now).unwrap();
+        }
+        assert_eq!(bucket.acquire(now), Err(RateLimitExceeded));
+
+        let later = now + Duration::from_secs(3);
+        assert_eq!(bucket.acquire(later), Ok(Duration::ZERO));
+    }
+
+    #[test]
+    fn delay_never_refuses() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(rate(2), 1, now);
+
+        assert_eq!(bucket.delay(now), Duration::ZERO);
+        assert_eq!(bucket.delay(now), Duration::from_millis(500));
+        assert_eq!(bucket.delay(now), Duration::from_secs(1));
+        assert_eq!(bucket.delay(now), Duration::from_millis(1500));
+    }
+}
@@ -1,69 +1,170 @@
 use std::{
+    future::Future,
+    path::{Path, PathBuf},
     pin::Pin,
-    task::{ready, Poll},
+    task::{ready, Context, Poll},
 };
 
-use bytes::BufMut;
 use tokio::{
     fs::{File, OpenOptions},
     io::{AsyncRead, AsyncWrite, ReadBuf},
 };
 use uuid::Uuid;
 
+use self::redact::Redactor;
+
+mod redact;
+
 const BUFFER_SIZE: usize = 1024;
 
+type OpenFile = Pin<Box<dyn Future<Output = std::io::Result<(File, u64)>> + Send>>;
+
+// Recordings contain everything sent in the clear, so only the owner may read them.
+async fn open_recording(path: PathBuf) -> std::io::Result<(File, u64)> {
+    let mut options = OpenOptions::new();
+    options.create(true).append(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    // the file may have been started before a STARTTLS upgrade
+    let file = options.open(path).await?;
+    let length = file.metadata().await?.len();
+
+    Ok((file, length))
+}
+
+// One direction of a recorded stream. What passes through is redacted and queued, and the queue
+// is written out before the stream moves on in that direction, so a slow disk holds the stream
+// back rather than piling up recordings in memory.
+struct Recording {
+    directory: PathBuf,
+    name: String,
+    part: usize,
+    file: File,
+    opening: Option<OpenFile>,
+    file_length: u64,
+    rotate_after: Option<u64>,
+    redactor: Redactor,
+    pending: Vec<u8>,
+    needs_flush: bool,
+    done: bool,
+}
+
+impl Recording {
+    async fn open(directory: &Path, name: String) -> std::io::Result<Self> {
+        let (file, file_length) = open_recording(directory.join(format!("{name}.xml"))).await?;
+
+        Ok(Recording {
+            directory: directory.to_path_buf(),
+            name,
+            part: 0,
+            file,
+            opening: None,
+            file_length,
+            rotate_after: None,
+            redactor: Redactor::default(),
+            pending: Vec::with_capacity(BUFFER_SIZE),
+            needs_flush: false,
+            done: false,
+        })
+    }
+
+    fn record(&mut self, bytes: &[u8]) {
+        self.redactor.redact(bytes, &mut self.pending);
+    }
+
+    fn poll_write_pending(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        loop {
+            if let Some(opening) = &mut self.opening {
+                let (file, file_length) = ready!(opening.as_mut().poll(cx))?;
+                self.file = file;
+                self.file_length = file_length;
+                self.opening = None;
+            }
+
+            if self.pending.is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+
+            // a file is only rotated between writes, so it may end up a little past the limit
+            if self
+                .rotate_after
+                .is_some_and(|rotate_after| self.file_length >= rotate_after)
+            {
+                ready!(self.poll_flush_file(cx))?;
+                self.part += 1;
+                let path = self
+                    .directory
+                    .join(format!("{}.{}.xml", self.name, self.part));
+                self.opening = Some(Box::pin(open_recording(path)));
+                continue;
+            }
+
+            let bytes_written = ready!(Pin::new(&mut self.file).poll_write(cx, &self.pending))?;
+            self.pending.drain(..bytes_written);
+            self.file_length += bytes_written as u64;
+            self.needs_flush = true;
+        }
+    }
+
+    fn poll_flush_file(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        if self.needs_flush {
+            ready!(Pin::new(&mut self.file).poll_flush(cx))?;
+            self.needs_flush = false;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        ready!(self.poll_write_pending(cx))?;
+        self.poll_flush_file(cx)
+    }
+
+    fn poll_shutdown(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        ready!(self.poll_flush(cx))?;
+        if !self.done {
+            ready!(Pin::new(&mut self.file).poll_shutdown(cx))?;
+            self.done = true;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
 pub struct StreamRecorder<S> {
     inner_stream: S,
-    read_done: bool,
     write_done: bool,
-    input_recording: File,
-    output_recording: File,
-    input_buffer: Box<[u8]>,
-    input_buffer_read: usize,
-    input_buffer_written: usize,
-    output_buffer: Box<[u8]>,
-    output_bytes_written: usize,
-    output_bytes_recorded: usize,
-    inner_stream_needs_flush: bool,
-    input_recording_needs_flush: bool,
-    output_recording_needs_flush: bool,
-    input_recording_done: bool,
-    output_recording_done: bool,
+    input_recording: Recording,
+    output_recording: Recording,
 }
 
 impl<S> StreamRecorder<S> {
-    pub async fn try_new(wrapped_stream: S, uuid: Uuid) -> std::io::Result<Self> {
-        let input_recording = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&format!("log/{uuid}.in.xml"))
-            .await?;
-        let output_recording = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&format!("log/{uuid}.out.xml"))
-            .await?;
+    pub async fn try_new_in(
+        wrapped_stream: S,
+        uuid: Uuid,
+        directory: &Path,
+    ) -> std::io::Result<Self> {
+        tokio::fs::create_dir_all(directory).await?;
+        let input_recording = Recording::open(directory, format!("{uuid}.in")).await?;
+        let output_recording = Recording::open(directory, format!("{uuid}.out")).await?;
 
         Ok(Self {
             inner_stream: wrapped_stream,
-            read_done: false,
             write_done: false,
             input_recording,
             output_recording,
-            input_buffer: vec![0; BUFFER_SIZE].into_boxed_slice(),
-            input_buffer_read: 0,
-            input_buffer_written: 0,
-            output_buffer: vec![0; BUFFER_SIZE].into_boxed_slice(),
-            output_bytes_written: 0,
-            output_bytes_recorded: 0,
-            inner_stream_needs_flush: false,
-            input_recording_needs_flush: false,
-            output_recording_needs_flush: false,
-            input_recording_done: false,
-            output_recording_done: false,
         })
     }
 
+    // Once a recording file has grown to `max_bytes`, it is continued in {uuid}.in.1.xml,
+    // {uuid}.in.2.xml and so on.
+    pub fn rotate_after(mut self, max_bytes: Option<u64>) -> Self {
+        self.input_recording.rotate_after = max_bytes;
+        self.output_recording.rotate_after = max_bytes;
+        self
+    }
+
     pub fn get_ref(&self) -> &S {
         &self.inner_stream
     }
@@ -78,58 +179,19 @@ where
     S: AsyncRead + Unpin,
 {
     fn poll_read(
-        mut self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-        buf: &mut tokio::io::ReadBuf<'_>,
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
     ) -> Poll<std::io::Result<()>> {
         let me = &mut *self;
 
-        loop {
-            if me.input_buffer_written == me.input_buffer_read && !me.read_done {
-                me.input_buffer_read = 0;
-                me.input_buffer_written = 0;
-                let mut input_buffer = ReadBuf::new(me.input_buffer.as_mut());
-
-                match Pin::new(&mut me.inner_stream).poll_read(cx, &mut input_buffer) {
-                    Poll::Ready(Ok(_)) => {
-                        let filled_len = input_buffer.filled().len();
-                        me.read_done = filled_len == me.input_buffer_read;
-                        me.input_buffer_read = filled_len;
-                    }
-                    Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
-                    Poll::Pending => return Poll::Pending,
-                }
-            }
+        ready!(me.input_recording.poll_write_pending(cx))?;
 
-            if me.input_buffer_written < me.input_buffer_read {
-                let num_bytes_to_write = std::cmp::min(
-                    buf.remaining(),
-                    me.input_buffer_read - me.input_buffer_written,
-                );
-
-                match Pin::new(&mut me.input_recording).poll_write(
-                    cx,
-                    &me.input_buffer
-                        [me.input_buffer_written..(me.input_buffer_written + num_bytes_to_write)],
-                ) {
-                    Poll::Ready(Ok(num_bytes_written)) => {
-                        buf.put_slice(
-                            &me.input_buffer[me.input_buffer_written
-                                ..(me.input_buffer_written + num_bytes_written)],
-                        );
-                        me.input_buffer_written += num_bytes_written;
-                        me.input_recording_needs_flush = true;
-                        return Poll::Ready(Ok(()));
-                    }
-                    Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
-                    Poll::Pending => return Poll::Pending,
-                }
-            }
+        let filled_before = buf.filled().len();
+        ready!(Pin::new(&mut me.inner_stream).poll_read(cx, buf))?;
+        me.input_recording.record(&buf.filled()[filled_before..]);
 
-            if me.input_buffer_written == me.input_buffer_read && me.read_done {
-                return Poll::Ready(Ok(()));
-            }
-        }
+        Poll::Ready(Ok(()))
     }
 }
 
@@ -139,96 +201,38 @@ where
 {
     fn poll_write(
         mut self: Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
+        cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<std::io::Result<usize>> {
         let me = &mut *self;
 
-        if me.output_bytes_written == me.output_bytes_recorded {
-            me.output_bytes_written = 0;
-            me.output_bytes_recorded = 0;
-
-            let num_bytes_to_write = std::cmp::min(buf.len(), me.output_buffer.len());
-            let num_bytes_written =
-                ready!(Pin::new(&mut me.inner_stream).poll_write(cx, &buf[..num_bytes_to_write]))?;
+        ready!(me.output_recording.poll_write_pending(cx))?;
 
-            me.output_buffer
-                .as_mut()
-                .put_slice(&buf[..num_bytes_written]);
-            me.output_bytes_written += num_bytes_written;
-            me.inner_stream_needs_flush = true;
-        }
-
-        debug_assert!(me.output_bytes_recorded < me.output_bytes_written);
+        let bytes_written = ready!(Pin::new(&mut me.inner_stream).poll_write(cx, buf))?;
+        me.output_recording.record(&buf[..bytes_written]);
 
-        let num_bytes_written = ready!(
-            Pin::new(&mut me.output_recording).poll_write(cx, &buf[..me.output_bytes_written])
-        )?;
-        me.output_bytes_recorded += num_bytes_written;
-        me.output_recording_needs_flush = true;
-        Poll::Ready(Ok(num_bytes_written))
+        Poll::Ready(Ok(bytes_written))
     }
 
-    fn poll_flush(
-        mut self: Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-    ) -> Poll<Result<(), std::io::Error>> {
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
         let me = &mut *self;
 
-        if me.output_bytes_recorded < me.output_bytes_written {
-            let num_bytes_written = ready!(Pin::new(&mut me.output_recording)
-                .poll_write(cx, &me.output_buffer[me.output_bytes_recorded..]))?;
-            me.output_bytes_recorded += num_bytes_written;
-            cx.waker().wake_by_ref();
-            Poll::Pending
-        } else {
-            if me.inner_stream_needs_flush {
-                ready!(Pin::new(&mut me.inner_stream).poll_flush(cx))?;
-                me.inner_stream_needs_flush = false;
-            }
-
-            if me.input_recording_needs_flush {
-                ready!(Pin::new(&mut me.input_recording).poll_flush(cx))?;
-                me.input_recording_needs_flush = false;
-            }
+        ready!(Pin::new(&mut me.inner_stream).poll_flush(cx))?;
+        ready!(me.input_recording.poll_flush(cx))?;
+        ready!(me.output_recording.poll_flush(cx))?;
 
-            if me.output_recording_needs_flush {
-                ready!(Pin::new(&mut me.output_recording).poll_flush(cx))?;
-                me.output_recording_needs_flush = false;
-            }
-
-            Poll::Ready(Ok(()))
-        }
+        Poll::Ready(Ok(()))
     }
 
-    fn poll_shutdown(
-        mut self: Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-    ) -> Poll<Result<(), std::io::Error>> {
-        let mut me = &mut *self;
-
-        if me.output_bytes_recorded < me.output_bytes_written
-            || me.inner_stream_needs_flush
-            || me.input_recording_needs_flush
-            || me.output_recording_needs_flush
-        {
-            ready!(Pin::new(&mut me).poll_flush(cx))?;
-        }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let me = &mut *self;
 
         if !me.write_done {
             ready!(Pin::new(&mut me.inner_stream).poll_shutdown(cx))?;
             me.write_done = true;
         }
-
-        if !me.input_recording_done {
-            ready!(Pin::new(&mut me.output_recording).poll_shutdown(cx))?;
-            me.input_recording_done = true;
-        }
-
-        if !me.output_recording_done {
-            ready!(Pin::new(&mut me.output_recording).poll_shutdown(cx))?;
-            me.output_recording_done = true;
-        }
+        ready!(me.input_recording.poll_shutdown(cx))?;
+        ready!(me.output_recording.poll_shutdown(cx))?;
 
         Poll::Ready(Ok(()))
     }
@@ -236,11 +240,98 @@ where
 
 #[cfg(test)]
 mod tests {
+    use std::path::PathBuf;
+
     use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
     use uuid::Uuid;
 
     use super::{StreamRecorder, BUFFER_SIZE};
 
+    fn recording_directory() -> PathBuf {
+        std::env::temp_dir().join(Uuid::new_v4().to_string())
+    }
+
+    #[tokio::test]
+    async fn missing_directory_is_created() {
+        let directory = recording_directory().join("nested").join("recordings");
+        let uuid = Uuid::new_v4();
+
+        let (stream, _peer) = duplex(64);
+        StreamRecorder::try_new_in(stream, uuid, &directory)
+            .await
+            .unwrap();
+
+        let recording = directory.join(format!("{uuid}.in.xml"));
+        assert!(recording.exists());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let mode = std::fs::metadata(&recording).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+
+    #[tokio::test]
+    async fn plain_credentials_are_not_recorded() {
+        let directory = recording_directory();
+        let uuid = Uuid::new_v4();
+        let auth = "<auth xmlns='urn:ietf:params:xml:ns:xmpp-sasl' mechanism='PLAIN'>\
+            AGp1bGlldAByMG0zMG15cjBtMzA=</auth>";
+
+        let (stream, mut peer) = duplex(1024);
+        let mut recorder = StreamRecorder::try_new_in(stream, uuid, &directory)
+            .await
+            .unwrap();
+        peer.write_all(auth.as_bytes()).await.unwrap();
+        let mut received = vec![0u8; auth.len()];
+        recorder.read_exact(&mut received).await.unwrap();
+        recorder.flush().await.unwrap();
+
+        assert_eq!(received, auth.as_bytes());
+        let recorded = std::fs::read_to_string(directory.join(format!("{uuid}.in.xml"))).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert!(!recorded.contains("AGp1bGlldAByMG0zMG15cjBtMzA="));
+        assert!(recorded.contains("[REDACTED]"));
+    }
+
+    #[tokio::test]
+    async fn long_recordings_are_rotated() {
+        let directory = recording_directory();
+        let uuid = Uuid::new_v4();
+        let data = (0..10_000)
+            .map(|i| b'a' + (i % 26) as u8)
+            .collect::<Vec<_>>();
+
+        let (stream, mut peer) = duplex(4096);
+        let mut recorder = StreamRecorder::try_new_in(stream, uuid, &directory)
+            .await
+            .unwrap()
+            .rotate_after(Some(1000));
+        let read = tokio::spawn(async move {
+            let mut received = Vec::new();
+            peer.read_to_end(&mut received).await.unwrap();
+            received
+        });
+        for chunk in data.chunks(300) {
+            recorder.write_all(chunk).await.unwrap();
+        }
+        recorder.shutdown().await.unwrap();
+        assert_eq!(read.await.unwrap(), data);
+
+        let mut recorded = std::fs::read(directory.join(format!("{uuid}.out.xml"))).unwrap();
+        let mut parts = 1;
+        while let Ok(part) = std::fs::read(directory.join(format!("{uuid}.out.{parts}.xml"))) {
+            assert!(part.len() < 1000 + 300);
+            recorded.extend(part);
+            parts += 1;
+        }
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert!(parts > 1);
+        assert_eq!(recorded, data);
+    }
+
     #[tokio::test]
     async fn read_bytes_are_recorded() {
         let original_data = (0..1_000_000)
@@ -251,8 +342,11 @@ mod tests {
 
         let uuid = Uuid::new_v4();
 
+        let directory = recording_directory();
         let (rx, mut tx) = duplex(1000);
-        let mut recorder = StreamRecorder::try_new(rx, uuid).await.unwrap();
+        let mut recorder = StreamRecorder::try_new_in(rx, uuid, &directory)
+            .await
+            .unwrap();
 
         let write = tokio::spawn(async move {
             tx.write_all(&data).await.unwrap();
@@ -274,10 +368,8 @@ mod tests {
         write.await.unwrap();
         read.await.unwrap();
 
-        let recording = format!("log/{uuid}.in.xml");
-        let recorded_data = std::fs::read(&recording).unwrap();
-        std::fs::remove_file(&recording).unwrap();
-        std::fs::remove_file(format!("log/{uuid}.out.xml")).unwrap();
+        let recorded_data = std::fs::read(directory.join(format!("{uuid}.in.xml"))).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
 
         assert_eq!(recorded_data.len(), original_data.len());
         assert!(recorded_data
@@ -299,8 +391,11 @@ mod tests {
 
         let uuid = Uuid::new_v4();
 
+        let directory = recording_directory();
         let (mut rx, tx) = duplex(duplex_buf_size);
-        let mut recorder = StreamRecorder::try_new(tx, uuid).await.unwrap();
+        let mut recorder = StreamRecorder::try_new_in(tx, uuid, &directory)
+            .await
+            .unwrap();
 
         let write = tokio::spawn(async move {
             recorder.write_all(&data).await.unwrap();
@@ -321,10 +416,8 @@ mod tests {
         write.await.unwrap();
         read.await.unwrap();
 
-        let recording = format!("log/{uuid}.out.xml");
-        let recorded_data = std::fs::read(&recording).unwrap();
-        std::fs::remove_file(&recording).unwrap();
-        std::fs::remove_file(format!("log/{uuid}.in.xml")).unwrap();
+        let recorded_data = std::fs::read(directory.join(format!("{uuid}.out.xml"))).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
 
         assert_eq!(recorded_data.len(), original_data.len());
         assert!(recorded_data
@@ -0,0 +1,161 @@
+// SASL elements whose payload may carry a password or something derived from it
+const REDACTED_ELEMENTS: [&[u8]; 3] = [b"auth", b"response", b"challenge"];
+
+const REDACTED: &[u8] = b"[REDACTED]";
+
+#[derive(Debug, Default)]
+enum State {
+    #[default]
+    Text,
+    Tag {
+        name: Vec<u8>,
+        name_done: bool,
+        quote: Option<u8>,
+        previous: u8,
+    },
+    Secret {
+        redacted: bool,
+    },
+}
+
+// Replaces the content of SASL elements with a placeholder. It only looks at tag boundaries,
+// which is all it needs for the flat elements SASL uses, and keeps its state between chunks,
+// since a recorded stream is split at arbitrary points.
+#[derive(Debug, Default)]
+pub struct Redactor {
+    state: State,
+}
+
+impl Redactor {
+    pub fn redact(&mut self, bytes: &[u8], output: &mut Vec<u8>) {
+        for &byte in bytes {
+            self.state = match std::mem::take(&mut self.state) {
+                State::Text => {
+                    output.push(byte);
+                    if byte == b'<' {
+                        State::Tag {
+                            name: Vec::new(),
+                            name_done: false,
+                            quote: None,
+                            previous: byte,
+                        }
+                    } else {
+                        State::Text
+                    }
+                }
+                State::Tag {
+                    mut name,
+                    mut name_done,
+                    quote,
+                    previous,
+                } => {
+                    output.push(byte);
+                    match (quote, byte) {
+                        (Some(quote), byte) if byte == quote => State::Tag {
+                            name,
+                            name_done,
+                            quote: None,
+                            previous: byte,
+                        },
+                        (Some(_), _) => State::Tag {
+                            name,
+                            name_done,
+                            quote,
+                            previous: byte,
+                        },
+                        (None, b'>') if previous != b'/' && is_redacted(&name) => {
+                            State::Secret { redacted: false }
+                        }
+                        (None, b'>') => State::Text,
+                        (None, b'"' | b'\'') => State::Tag {
+                            name,
+                            name_done,
+                            quote: Some(byte),
+                            previous: byte,
+                        },
+                        (None, byte) => {
+                            // a closing tag ends its name right away, so it never matches
+                            if byte.is_ascii_whitespace() || byte == b'/' {
+                                name_done = true;
+                            } else if !name_done {
+                                name.push(byte);
+                            }
+                            State::Tag {
+                                name,
+                                name_done,
+                                quote,
+                                previous: byte,
+                            }
+                        }
+                    }
+                }
+                State::Secret { .. } if byte == b'<' => {
+                    output.push(byte);
+                    State::Tag {
+                        name: Vec::new(),
+                        name_done: false,
+                        quote: None,
+                        previous: byte,
+                    }
+                }
+                State::Secret { redacted } => {
+                    if !redacted {
+                        output.extend_from_slice(REDACTED);
+                    }
+                    State::Secret { redacted: true }
+                }
+            };
+        }
+    }
+}
+
+fn is_redacted(name: &[u8]) -> bool {
+    // the prefix, if any, does not matter
+    let local_name = match name.iter().rposition(|&byte| byte == b':') {
+        Some(colon) => &name[colon + 1..],
+        None => name,
+    };
+
+    REDACTED_ELEMENTS.contains(&local_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redact_in_chunks(input: &str, chunk_size: usize) -> String {
+        let mut redactor = Redactor::default();
+        let mut output = Vec::new();
+        for chunk in input.as_bytes().chunks(chunk_size) {
+            redactor.redact(chunk, &mut output);
+        }
+
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn sasl_payloads_are_redacted() {
+        let input = "<auth xmlns='urn:ietf:params:xml:ns:xmpp-sasl' mechanism='PLAIN'>\
+            AGp1bGlldAByMG0zMG15cjBtMzA=</auth>\
+            <challenge xmlns='urn:ietf:params:xml:ns:xmpp-sasl'>cj1meWtvK2QybGJiRmdPTlJ2OXF</challenge>\
+            <response xmlns='urn:ietf:params:xml:ns:xmpp-sasl'>Yz1iaXdzLHI9ZnlrbytkMmxi</response>\
+            <response xmlns='urn:ietf:params:xml:ns:xmpp-sasl'/>";
+        let expected = "<auth xmlns='urn:ietf:params:xml:ns:xmpp-sasl' mechanism='PLAIN'>\
+            [REDACTED]</auth>\
+            <challenge xmlns='urn:ietf:params:xml:ns:xmpp-sasl'>[REDACTED]</challenge>\
+            <response xmlns='urn:ietf:params:xml:ns:xmpp-sasl'>[REDACTED]</response>\
+            <response xmlns='urn:ietf:params:xml:ns:xmpp-sasl'/>";
+
+        for chunk_size in [1, 3, 7, input.len()] {
+            assert_eq!(redact_in_chunks(input, chunk_size), expected);
+        }
+    }
+
+    #[test]
+    fn everything_else_is_kept() {
+        let input = "<?xml version='1.0'?><stream:stream xmlns='jabber:client' to='a>b'>\
+            <message><body>authenticate me</body></message><sasl:success/>";
+
+        assert_eq!(redact_in_chunks(input, 5), input);
+    }
+}
@@ -1,10 +1,16 @@
 use std::collections::HashMap;
+use std::fmt;
+
+use anyhow::{anyhow, Error};
+
+use self::serializer::Serializer;
 
 pub mod namespaces;
+pub mod serializer;
 pub mod stream_parser;
 pub mod stream_writer;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Node {
     Element(Element),
     Text(String),
@@ -13,7 +19,7 @@ pub enum Node {
     ProcessingInstruction(String),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Element {
     pub name: String,
     pub namespace: Option<String>,
@@ -22,25 +28,55 @@ pub struct Element {
 }
 
 impl Element {
+    // Parses a single standalone element, e.g. a fixture or a static template. Stream headers
+    // get no special treatment here.
+    pub fn parse(xml: &str) -> Result<Element, Error> {
+        let element = xml
+            .parse::<rustyxml::Element>()
+            .map_err(|err| anyhow!(err))?;
+
+        Ok(element.into())
+    }
+
     pub fn get_attribute(&self, name: &str, namespace: Option<&str>) -> Option<&str> {
         self.attributes
             .get(&(name.to_string(), namespace.map(|s| s.to_string())))
             .map(|s| s.as_str())
     }
 
-    pub fn get_child(&self, name: &str, namespace: Option<&str>) -> Option<&Element> {
-        self.children.iter().find_map(|child| match child {
-            Node::Element(element) => {
-                if element.name == name && element.namespace == namespace.map(|s| s.to_string()) {
-                    Some(element)
-                } else {
-                    None
-                }
-            }
+    // Only the element children, skipping text, CDATA, comments and processing instructions
+    pub fn children(&self) -> impl Iterator<Item = &Element> {
+        self.children.iter().filter_map(|child| match child {
+            Node::Element(element) => Some(element),
             _ => None,
         })
     }
 
+    pub fn get_child(&self, name: &str, namespace: Option<&str>) -> Option<&Element> {
+        self.children()
+            .find(|element| element.name == name && element.namespace.as_deref() == namespace)
+    }
+
+    pub fn find_children<'a>(
+        &'a self,
+        name: &'a str,
+        namespace: Option<&'a str>,
+    ) -> impl Iterator<Item = &'a Element> {
+        self.children().filter(move |element| {
+            element.name == name && element.namespace.as_deref() == namespace
+        })
+    }
+
+    pub fn path(&self, path: &[(&str, Option<&str>)]) -> Option<&Element> {
+        path.iter().try_fold(self, |element, (name, namespace)| {
+            element.get_child(name, *namespace)
+        })
+    }
+
+    pub fn path_text(&self, path: &[(&str, Option<&str>)]) -> Option<String> {
+        self.path(path).map(Element::get_text)
+    }
+
     pub fn get_text(&self) -> String {
         let mut text = String::new();
         for child in &self.children {
@@ -54,3 +90,180 @@ impl Element {
         text
     }
 }
+
+// Serializes a standalone element, outside of any stream's namespace declarations.
+impl fmt::Display for Element {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&Serializer::default().build_xml_element(self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn element(name: &str, namespace: Option<&str>, children: Vec<Node>) -> Element {
+        Element {
+            name: name.to_string(),
+            namespace: namespace.map(|s| s.to_string()),
+            attributes: HashMap::new(),
+            children,
+        }
+    }
+
+    fn sample() -> Element {
+        element(
+            "iq",
+            Some(namespaces::XMPP_CLIENT),
+            vec![Node::Element(element(
+                "bind",
+                Some(namespaces::XMPP_BIND),
+                vec![Node::Element(element(
+                    "resource",
+                    Some(namespaces::XMPP_BIND),
+                    vec![Node::Text("balcony".to_string())],
+                ))],
+            ))],
+        )
+    }
+
+    #[test]
+    fn path_descends_nested_children() {
+        let iq = sample();
+
+        let bind = iq.path(&[("bind", Some(namespaces::XMPP_BIND))]).unwrap();
+        assert_eq!(bind.name, "bind");
+
+        let resource = iq
+            .path(&[
+                ("bind", Some(namespaces::XMPP_BIND)),
+                ("resource", Some(namespaces::XMPP_BIND)),
+            ])
+            .unwrap();
+        assert_eq!(resource.name, "resource");
+    }
+
+    #[test]
+    fn path_text_returns_text_of_target() {
+        let iq = sample();
+
+        let text = iq.path_text(&[
+            ("bind", Some(namespaces::XMPP_BIND)),
+            ("resource", Some(namespaces::XMPP_BIND)),
+        ]);
+        assert_eq!(text.as_deref(), Some("balcony"));
+    }
+
+    #[test]
+    fn path_handles_missing_nodes() {
+        let iq = sample();
+
+        assert!(iq
+            .path(&[
+                ("session", Some(namespaces::XMPP_BIND)),
+                ("resource", Some(namespaces::XMPP_BIND)),
+            ])
+            .is_none());
+        assert!(iq
+            .path(&[("bind", Some(namespaces::XMPP_BIND)), ("resource", None),])
+            .is_none());
+        assert!(iq.path_text(&[("bind", None)]).is_none());
+    }
+
+    fn query(children: Vec<Node>) -> Element {
+        element("query", Some(namespaces::ROSTER), children)
+    }
+
+    fn item(name: &str) -> Node {
+        Node::Element(element(name, Some(namespaces::ROSTER), vec![]))
+    }
+
+    #[test]
+    fn children_skips_non_element_nodes() {
+        let query = query(vec![
+            Node::Text("\n".to_string()),
+            item("item"),
+            Node::Comment("note".to_string()),
+            item("group"),
+        ]);
+
+        let names = query.children().map(|child| child.name.as_str());
+        assert_eq!(names.collect::<Vec<_>>(), vec!["item", "group"]);
+    }
+
+    #[test]
+    fn find_children_without_matches() {
+        assert_eq!(
+            query(vec![])
+                .find_children("item", Some(namespaces::ROSTER))
+                .count(),
+            0
+        );
+
+        let query = query(vec![item("item")]);
+        assert_eq!(query.find_children("item", None).count(), 0);
+    }
+
+    #[test]
+    fn find_children_with_single_match() {
+        let query = query(vec![item("group"), item("item")]);
+
+        let items = query.find_children("item", Some(namespaces::ROSTER));
+        assert_eq!(items.count(), 1);
+    }
+
+    #[test]
+    fn find_children_with_multiple_matches() {
+        let query = query(vec![
+            item("item"),
+            item("group"),
+            item("item"),
+            item("item"),
+        ]);
+
+        let items = query.find_children("item", Some(namespaces::ROSTER));
+        assert_eq!(items.count(), 3);
+    }
+
+    #[test]
+    fn parse_resolves_namespaces() {
+        let iq = Element::parse(
+            "<iq xmlns='jabber:client' type='set' id='bind_1'>\
+                <bind xmlns='urn:ietf:params:xml:ns:xmpp-bind'>\
+                    <resource>balcony</resource>\
+                </bind>\
+            </iq>",
+        )
+        .unwrap();
+
+        assert_eq!(iq.name, "iq");
+        assert_eq!(iq.namespace.as_deref(), Some(namespaces::XMPP_CLIENT));
+        assert_eq!(iq.get_attribute("type", None), Some("set"));
+        assert_eq!(iq.get_attribute("id", None), Some("bind_1"));
+
+        let text = iq.path_text(&[
+            ("bind", Some(namespaces::XMPP_BIND)),
+            ("resource", Some(namespaces::XMPP_BIND)),
+        ]);
+        assert_eq!(text.as_deref(), Some("balcony"));
+    }
+
+    #[test]
+    fn parse_resolves_prefixes() {
+        let features =
+            Element::parse("<stream:features xmlns:stream='http://etherx.jabber.org/streams'/>")
+                .unwrap();
+
+        assert_eq!(features.name, "features");
+        assert_eq!(
+            features.namespace.as_deref(),
+            Some(namespaces::XMPP_STREAMS)
+        );
+    }
+
+    #[test]
+    fn parse_rejects_malformed_xml() {
+        assert!(Element::parse("<iq><query></iq>").is_err());
+        assert!(Element::parse("").is_err());
+    }
+}
@@ -4,7 +4,23 @@ pub const XMLNS: &str = "http://www.w3.org/2000/xmlns/";
 pub const XMPP_STREAMS: &str = "http://etherx.jabber.org/streams";
 pub const XMPP_CLIENT: &str = "jabber:client";
 pub const XMPP_SERVER: &str = "jabber:server";
+pub const XMPP_COMPONENT_ACCEPT: &str = "jabber:component:accept";
 pub const XMPP_SASL: &str = "urn:ietf:params:xml:ns:xmpp-sasl";
 pub const XMPP_STREAM_ERRORS: &str = "urn:ietf:params:xml:ns:xmpp-streams";
+pub const XMPP_STANZAS: &str = "urn:ietf:params:xml:ns:xmpp-stanzas";
 pub const XMPP_BIND: &str = "urn:ietf:params:xml:ns:xmpp-bind";
 pub const XMPP_STARTTLS: &str = "urn:ietf:params:xml:ns:xmpp-tls";
+pub const ROSTER: &str = "jabber:iq:roster";
+pub const ROSTER_VERSIONING: &str = "urn:xmpp:features:rosterver";
+pub const PING: &str = "urn:xmpp:ping";
+pub const DISCO_INFO: &str = "http://jabber.org/protocol/disco#info";
+pub const DISCO_ITEMS: &str = "http://jabber.org/protocol/disco#items";
+pub const REGISTER: &str = "jabber:iq:register";
+pub const REGISTER_FEATURE: &str = "http://jabber.org/features/iq-register";
+pub const VERSION: &str = "jabber:iq:version";
+
+pub const STANZA_ID: &str = "urn:xmpp:sid:0";
+pub const SASL_CHANNEL_BINDING: &str = "urn:xmpp:sasl-cb:0";
+pub const STREAM_MANAGEMENT: &str = "urn:xmpp:sm:3";
+pub const DELAY: &str = "urn:xmpp:delay";
+pub const TIME: &str = "urn:xmpp:time";
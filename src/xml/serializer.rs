@@ -0,0 +1,308 @@
+use std::collections::HashMap;
+
+use crate::xml::namespaces;
+use crate::xml::Element;
+use crate::xml::Node;
+
+fn escape_text(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn escape_attribute(value: &str) -> String {
+    escape_text(value).replace('"', "&quot;")
+}
+
+// A CDATA section cannot contain its own terminator, so it is split across two sections.
+fn escape_cdata(cdata: &str) -> String {
+    cdata.replace("]]>", "]]]]><![CDATA[>")
+}
+
+// Turns elements into XML text, keeping track of the namespace declarations in scope. The
+// stream writer keeps one of these for the whole stream, standalone elements get a fresh one.
+pub struct Serializer {
+    namespaces: Vec<HashMap<String, String>>, // stacked namespace to prefix map
+}
+
+impl Default for Serializer {
+    fn default() -> Self {
+        let mut namespaces = HashMap::new();
+        namespaces.insert(namespaces::XML.to_string(), "xml".to_string());
+        namespaces.insert(namespaces::XMLNS.to_string(), "xmlns".to_string());
+        let namespaces = vec![namespaces];
+
+        Self { namespaces }
+    }
+}
+
+impl Serializer {
+    pub fn lookup_namespace_prefix(&self, namespace: &str) -> Option<&str> {
+        for namespaces in self.namespaces.iter().rev() {
+            if let Some(prefix) = namespaces.get(namespace) {
+                return Some(prefix);
+            }
+        }
+
+        None
+    }
+
+    pub fn lookup_default_namespace(&self) -> Option<&str> {
+        for namespaces in self.namespaces.iter().rev() {
+            if let Some((namespace, _)) = namespaces.iter().find(|(_, prefix)| prefix.is_empty()) {
+                return Some(namespace);
+            }
+        }
+
+        None
+    }
+
+    pub fn build_xml_element(&mut self, element: &Element) -> String {
+        let mut xml = String::new();
+
+        if !element.children.is_empty() {
+            xml.push_str(&self.build_opening_tag(element, false));
+            xml.push_str(&self.build_children(element));
+            xml.push_str(&self.build_closing_tag(element));
+        } else {
+            xml.push_str(&self.build_opening_tag(element, true));
+        }
+
+        xml
+    }
+
+    pub fn build_opening_tag(&mut self, element: &Element, self_closing: bool) -> String {
+        let mut xml = String::new();
+
+        // Iterate over attributes and process namespace declarations
+        let mut namespaces = HashMap::new();
+        for ((attribute, namespace), value) in &element.attributes {
+            match namespace {
+                Some(namespace) => {
+                    if namespace == namespaces::XMLNS {
+                        namespaces.insert(value.clone(), attribute.clone()); // prefixed namespace
+                    }
+                }
+                None => {
+                    if attribute == "xmlns" {
+                        namespaces.insert(value.clone(), String::new()); // default namespace
+                    }
+                }
+            }
+        }
+        self.namespaces.push(namespaces);
+
+        // Namespaces without a declaration in scope get a generated prefix declared right here
+        let mut declarations = String::new();
+        let attribute_namespaces = element
+            .attributes
+            .keys()
+            .filter_map(|(_, namespace)| namespace.as_ref());
+        for namespace in element.namespace.iter().chain(attribute_namespaces) {
+            if self.lookup_namespace_prefix(namespace).is_none() {
+                let prefix = self.generate_prefix();
+                declarations.push_str(&format!(
+                    r#" xmlns:{}="{}""#,
+                    prefix,
+                    escape_attribute(namespace)
+                ));
+                self.namespaces
+                    .last_mut()
+                    .unwrap()
+                    .insert(namespace.clone(), prefix);
+            }
+        }
+
+        let prefix = element
+            .namespace
+            .as_deref()
+            .and_then(|namespace| self.lookup_namespace_prefix(namespace));
+        match prefix {
+            Some("") | None => {
+                // Element is in the default namespace
+                xml.push_str(&format!(
+                    "<{}{}{}",
+                    element.name,
+                    declarations,
+                    self.build_attributes(element)
+                ));
+            }
+            Some(prefix) => {
+                // Element is in a prefixed namespace
+                xml.push_str(&format!(
+                    "<{}:{}{}{}",
+                    prefix,
+                    element.name,
+                    declarations,
+                    self.build_attributes(element)
+                ));
+            }
+        }
+
+        if self_closing {
+            self.namespaces.pop();
+
+            xml.push_str("/>");
+        } else {
+            xml.push('>');
+        }
+
+        xml
+    }
+
+    fn generate_prefix(&self) -> String {
+        (0..)
+            .map(|n| format!("ns{n}"))
+            .find(|prefix| {
+                !self
+                    .namespaces
+                    .iter()
+                    .any(|namespaces| namespaces.values().any(|used| used == prefix))
+            })
+            .unwrap()
+    }
+
+    fn build_attributes(&self, element: &Element) -> String {
+        let mut xml = String::new();
+
+        for ((attribute, namespace), value) in &element.attributes {
+            match namespace {
+                Some(namespace) => match self.lookup_namespace_prefix(namespace) {
+                    Some("") => {
+                        debug_assert!(false, "cannot use default namespace for attribute");
+                    }
+                    Some(prefix) => {
+                        xml.push_str(&format!(
+                            r#" {}:{}="{}""#,
+                            prefix,
+                            attribute,
+                            escape_attribute(value),
+                        ));
+                    }
+                    None => {
+                        debug_assert!(false, "namespace not declared");
+                    }
+                },
+                None => {
+                    xml.push_str(&format!(r#" {}="{}""#, attribute, escape_attribute(value)));
+                }
+            }
+        }
+
+        xml
+    }
+
+    fn build_children(&mut self, element: &Element) -> String {
+        let mut xml = String::new();
+
+        for child in &element.children {
+            match child {
+                Node::Element(child_element) => {
+                    xml.push_str(&self.build_xml_element(child_element));
+                }
+                Node::Text(text) => {
+                    xml.push_str(&escape_text(text));
+                }
+                Node::CData(cdata) => {
+                    xml.push_str(&format!("<![CDATA[{}]]>", escape_cdata(cdata)));
+                }
+                Node::Comment(comment) => {
+                    xml.push_str(&format!("<!--{}-->", comment));
+                }
+                Node::ProcessingInstruction(pi) => {
+                    xml.push_str(&format!("<?{}?>", pi));
+                }
+            }
+        }
+
+        xml
+    }
+
+    pub fn build_closing_tag(&mut self, element: &Element) -> String {
+        let mut xml = String::new();
+
+        match &element.namespace {
+            Some(namespace) => match self.lookup_namespace_prefix(namespace) {
+                Some("") => {
+                    // Element is in the default namespace
+                    xml.push_str(&format!("</{}>", element.name));
+                }
+                Some(prefix) => {
+                    // Element is in a prefixed namespace
+                    xml.push_str(&format!("</{}:{}>", prefix, element.name));
+                }
+                None => {
+                    debug_assert!(false, "namespace not declared");
+                }
+            },
+            None => {
+                xml.push_str(&format!("</{}>", element.name));
+            }
+        }
+
+        self.namespaces.pop();
+
+        xml
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UNDECLARED: &str = "urn:example:undeclared";
+
+    #[test]
+    fn undeclared_namespace_gets_a_prefix() {
+        let mut attributes = HashMap::new();
+        attributes.insert(
+            ("level".to_string(), Some(UNDECLARED.to_string())),
+            "3".to_string(),
+        );
+        let element = Element {
+            name: "x".to_string(),
+            namespace: Some(UNDECLARED.to_string()),
+            attributes,
+            children: vec![Node::Element(Element {
+                name: "y".to_string(),
+                namespace: Some(UNDECLARED.to_string()),
+                attributes: HashMap::new(),
+                children: vec![Node::Text("payload".to_string())],
+            })],
+        };
+
+        let xml = Serializer::default().build_xml_element(&element);
+        assert_eq!(
+            xml,
+            r#"<ns0:x xmlns:ns0="urn:example:undeclared" ns0:level="3"><ns0:y>payload</ns0:y></ns0:x>"#
+        );
+
+        let parsed = Element::parse(&xml).unwrap();
+        assert_eq!(parsed.name, "x");
+        assert_eq!(parsed.namespace.as_deref(), Some(UNDECLARED));
+        assert_eq!(parsed.get_attribute("level", Some(UNDECLARED)), Some("3"));
+        assert_eq!(
+            parsed.get_child("y", Some(UNDECLARED)).unwrap().get_text(),
+            "payload"
+        );
+    }
+
+    #[test]
+    fn generated_prefixes_do_not_clash() {
+        let mut attributes = HashMap::new();
+        attributes.insert(
+            ("ns0".to_string(), Some(namespaces::XMLNS.to_string())),
+            "urn:example:declared".to_string(),
+        );
+        let element = Element {
+            name: "x".to_string(),
+            namespace: Some(UNDECLARED.to_string()),
+            attributes,
+            children: vec![],
+        };
+
+        let xml = Serializer::default().build_xml_element(&element);
+
+        assert!(xml.starts_with(r#"<ns1:x xmlns:ns1="urn:example:undeclared""#));
+    }
+}
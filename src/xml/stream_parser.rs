@@ -1,22 +1,567 @@
-use anyhow::Error;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use anyhow::{anyhow, Error};
+use serde::Deserialize;
 use tokio::io::AsyncRead;
 use tokio_stream::Stream;
 
-use crate::xmpp::stream_header::StreamHeader;
+use crate::xml::namespaces::{XML, XMPP_STREAMS};
+use crate::xmpp::stream::StreamId;
+use crate::xmpp::stream_error::StreamError;
+use crate::xmpp::stream_header::{LanguageTag, StreamHeader};
 
 use super::Element;
 
+pub mod quick_xml;
 pub mod rusty_xml;
 
 #[derive(Debug)]
 pub enum Frame {
     StreamStart(StreamHeader),
     XmlFragment(Element),
+    // Whitespace between stanzas, which clients commonly send as a keepalive
+    Whitespace,
 }
 
 pub trait StreamParser: Stream<Item = Result<Frame, Error>> + Unpin {
     type Reader: AsyncRead + Unpin;
 
-    fn new(reader: Self::Reader) -> Self;
+    fn new(reader: Self::Reader, limits: ElementLimits) -> Self;
     fn into_inner(self) -> Self::Reader;
+    // Whether the end of the frames came from the peer's closing tag rather than the end of
+    // the input.
+    fn stream_closed(&self) -> bool;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct ElementLimits {
+    pub max_attributes: usize,
+    pub max_children: usize,
+    pub max_depth: usize,
+    pub max_pending_bytes: usize,
+}
+
+impl Default for ElementLimits {
+    fn default() -> Self {
+        ElementLimits {
+            max_attributes: 64,
+            max_children: 1024,
+            max_depth: 256,
+            max_pending_bytes: 262_144,
+        }
+    }
+}
+
+impl ElementLimits {
+    fn check_attributes(&self, count: usize) -> Result<(), Error> {
+        if count > self.max_attributes {
+            let err = anyhow!("element has more than {} attributes", self.max_attributes);
+            return Err(err.context(StreamError::PolicyViolation));
+        }
+
+        Ok(())
+    }
+
+    fn check_children(&self, count: usize) -> Result<(), Error> {
+        if count > self.max_children {
+            let err = anyhow!("element has more than {} children", self.max_children);
+            return Err(err.context(StreamError::PolicyViolation));
+        }
+
+        Ok(())
+    }
+
+    // Depth counts the elements open within a stanza, including the stanza itself.
+    fn check_depth(&self, depth: usize) -> Result<(), Error> {
+        if depth > self.max_depth {
+            let err = anyhow!("elements are nested more than {} deep", self.max_depth);
+            return Err(err.context(StreamError::PolicyViolation));
+        }
+
+        Ok(())
+    }
+
+    // Pending bytes have been read but do not yet make up a complete tag or text node.
+    fn check_pending(&self, bytes: usize) -> Result<(), Error> {
+        if bytes > self.max_pending_bytes {
+            let err = anyhow!("more than {} bytes pending", self.max_pending_bytes);
+            return Err(err.context(StreamError::PolicyViolation));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ParserKind {
+    RustyXml,
+    QuickXml,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct ParserConfig {
+    pub kind: ParserKind,
+    pub limits: ElementLimits,
+}
+
+pub enum AnyStreamParser<R: AsyncRead + Unpin> {
+    RustyXml(rusty_xml::StreamParser<R>),
+    QuickXml(quick_xml::StreamParser<R>),
+}
+
+impl<R: AsyncRead + Unpin> AnyStreamParser<R> {
+    pub fn new(config: ParserConfig, reader: R) -> Self {
+        match config.kind {
+            ParserKind::RustyXml => {
+                Self::RustyXml(rusty_xml::StreamParser::new(reader, config.limits))
+            }
+            ParserKind::QuickXml => {
+                Self::QuickXml(quick_xml::StreamParser::new(reader, config.limits))
+            }
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        match self {
+            Self::RustyXml(parser) => parser.into_inner(),
+            Self::QuickXml(parser) => parser.into_inner(),
+        }
+    }
+
+    pub fn stream_closed(&self) -> bool {
+        match self {
+            Self::RustyXml(parser) => parser.stream_closed(),
+            Self::QuickXml(parser) => parser.stream_closed(),
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> Stream for AnyStreamParser<R> {
+    type Item = Result<Frame, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame, Error>>> {
+        match self.get_mut() {
+            Self::RustyXml(parser) => Pin::new(parser).poll_next(cx),
+            Self::QuickXml(parser) => Pin::new(parser).poll_next(cx),
+        }
+    }
+}
+
+fn is_whitespace(text: &str) -> bool {
+    text.chars().all(|c| matches!(c, ' ' | '\t' | '\n' | '\r'))
+}
+
+fn valid_stream_tag(name: &str, namespace: Option<&str>) -> bool {
+    name == "stream" && namespace == Some(XMPP_STREAMS)
+}
+
+// The header is taken as is, so the stream can be closed with a proper error if its namespaces
+// or version are wrong.
+fn stream_header(
+    namespace: Option<&str>,
+    attributes: &HashMap<(String, Option<String>), String>,
+) -> StreamHeader {
+    StreamHeader {
+        from: attributes
+            .get(&("from".to_string(), None))
+            .and_then(|jid| jid.parse().ok()),
+        to: attributes
+            .get(&("to".to_string(), None))
+            .and_then(|jid| jid.parse().ok()),
+        id: attributes
+            .get(&("id".to_string(), None))
+            .cloned()
+            .map(StreamId::from),
+        language: attributes
+            .get(&("lang".to_string(), Some(XML.to_string())))
+            .map(|lang| LanguageTag(lang.to_string())),
+        stream_namespace: namespace.map(str::to_string),
+        content_namespace: attributes.get(&("xmlns".to_string(), None)).cloned(),
+        version: attributes.get(&("version".to_string(), None)).cloned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::ReadBuf;
+    use tokio_stream::StreamExt;
+
+    use crate::xml::Node;
+
+    use super::*;
+
+    const PARSERS: [ParserKind; 2] = [ParserKind::RustyXml, ParserKind::QuickXml];
+
+    const STREAM_HEADER: &str = "<stream:stream xmlns='jabber:client' \
+        xmlns:stream='http://etherx.jabber.org/streams' to='localhost' version='1.0'>";
+
+    // Hands out the input a few bytes at a time to exercise parsing across reads.
+    struct ChunkedReader<'a> {
+        input: &'a [u8],
+        chunk_size: usize,
+    }
+
+    impl AsyncRead for ChunkedReader<'_> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            let length = self.chunk_size.min(self.input.len()).min(buf.remaining());
+            let (chunk, rest) = self.input.split_at(length);
+            buf.put_slice(chunk);
+            self.input = rest;
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn describe_element(element: &Element) -> String {
+        let mut attributes = element
+            .attributes
+            .iter()
+            .map(|((name, namespace), value)| format!("{namespace:?}:{name}={value:?}"))
+            .collect::<Vec<_>>();
+        attributes.sort();
+
+        // parsers may split character data differently
+        let mut children = Vec::new();
+        let mut text = String::new();
+        for child in &element.children {
+            match child {
+                Node::Text(s) => text.push_str(s),
+                node => {
+                    if !text.is_empty() {
+                        children.push(format!("{:?}", std::mem::take(&mut text)));
+                    }
+                    children.push(match node {
+                        Node::Element(element) => describe_element(element),
+                        node => format!("{node:?}"),
+                    });
+                }
+            }
+        }
+        if !text.is_empty() {
+            children.push(format!("{text:?}"));
+        }
+
+        format!(
+            "<{:?}:{} {}>{}</>",
+            element.namespace,
+            element.name,
+            attributes.join(" "),
+            children.join("")
+        )
+    }
+
+    async fn parse(kind: ParserKind, input: &[u8], chunk_size: usize) -> Vec<String> {
+        let reader = ChunkedReader { input, chunk_size };
+        let config = ParserConfig {
+            kind,
+            limits: ElementLimits::default(),
+        };
+        let mut parser = AnyStreamParser::new(config, reader);
+
+        let mut frames = Vec::new();
+        while let Some(frame) = parser.next().await {
+            match frame {
+                Ok(Frame::StreamStart(header)) => frames.push(format!(
+                    "stream from={:?} to={:?} language={:?}",
+                    header.from.map(|jid| jid.to_string()),
+                    header.to.map(|jid| jid.to_string()),
+                    header.language.map(|language| language.0),
+                )),
+                Ok(Frame::XmlFragment(element)) => frames.push(describe_element(&element)),
+                Ok(Frame::Whitespace) => frames.push("whitespace".to_string()),
+                Err(err) => {
+                    let condition = err.downcast_ref::<StreamError>().unwrap();
+                    frames.push(format!("error {}", condition.condition()));
+                    break;
+                }
+            }
+        }
+
+        frames
+    }
+
+    async fn assert_parity(input: &str) -> Vec<String> {
+        let expected = parse(ParserKind::RustyXml, input.as_bytes(), 4096).await;
+        for kind in PARSERS {
+            for chunk_size in [4096, 7, 1] {
+                let frames = parse(kind, input.as_bytes(), chunk_size).await;
+                assert_eq!(frames, expected, "{kind:?} with {chunk_size} byte chunks");
+            }
+        }
+
+        expected
+    }
+
+    #[tokio::test]
+    async fn stream_with_stanzas() {
+        let input = format!(
+            "<?xml version='1.0'?>{STREAM_HEADER}\
+            <message to='juliet@example.com' type='chat'><body>Wherefore art thou?</body></message>\
+            \n<presence/></stream:stream>"
+        );
+        let frames = assert_parity(&input).await;
+        assert_eq!(frames.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn whitespace_between_stanzas() {
+        let input = format!("{STREAM_HEADER}<presence/> \n\t<presence/>");
+        let frames = assert_parity(&input).await;
+        assert_eq!(frames.len(), 4);
+        assert_eq!(frames[2], "whitespace");
+    }
+
+    #[tokio::test]
+    async fn whitespace_before_stream_header() {
+        let input = format!("<?xml version='1.0'?>\n{STREAM_HEADER}");
+        let frames = assert_parity(&input).await;
+        assert_eq!(frames.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn namespaced_children() {
+        let input = format!(
+            "{STREAM_HEADER}<iq type='get' id='roster'>\
+            <query xmlns='jabber:iq:roster'><item jid='romeo@example.net'/></query></iq>"
+        );
+        assert_parity(&input).await;
+    }
+
+    #[tokio::test]
+    async fn character_data() {
+        let input = format!(
+            "{STREAM_HEADER}<message><body>a &amp; b &lt;c&gt;</body>\
+            <data><![CDATA[<raw>]]></data><!-- note --></message>"
+        );
+        assert_parity(&input).await;
+    }
+
+    #[tokio::test]
+    async fn improperly_nested_xml() {
+        let input = format!("{STREAM_HEADER}<message><body></message>");
+        let frames = assert_parity(&input).await;
+        assert_eq!(frames.last().unwrap(), "error not-well-formed");
+    }
+
+    #[tokio::test]
+    async fn unbound_prefix() {
+        let input = format!("{STREAM_HEADER}<foo:message/>");
+        let frames = assert_parity(&input).await;
+        assert_eq!(frames.last().unwrap(), "error bad-namespace-prefix");
+    }
+
+    #[tokio::test]
+    async fn unsupported_encoding() {
+        let input = format!("<?xml version='1.0' encoding='ISO-8859-1'?>{STREAM_HEADER}");
+        let frames = assert_parity(&input).await;
+        assert_eq!(frames.last().unwrap(), "error unsupported-encoding");
+    }
+
+    #[tokio::test]
+    async fn stream_header_language() {
+        let input = "<stream:stream xmlns='jabber:client' \
+            xmlns:stream='http://etherx.jabber.org/streams' from='juliet@example.com' \
+            to='example.com' xml:lang='en' version='1.0'>";
+        let frames = assert_parity(input).await;
+        assert_eq!(
+            frames,
+            vec![
+                r#"stream from=Some("juliet@example.com") to=Some("example.com") language=Some("en")"#
+            ]
+        );
+    }
+
+    async fn first_header(kind: ParserKind, input: &str) -> StreamHeader {
+        let config = ParserConfig {
+            kind,
+            limits: ElementLimits::default(),
+        };
+        let mut parser = AnyStreamParser::new(config, input.as_bytes());
+        match parser.next().await {
+            Some(Ok(Frame::StreamStart(header))) => header,
+            other => panic!("expected stream header, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn stream_header_namespaces_and_version() {
+        for kind in PARSERS {
+            let header = first_header(kind, STREAM_HEADER).await;
+            assert_eq!(header.stream_namespace.as_deref(), Some(XMPP_STREAMS));
+            assert_eq!(header.content_namespace.as_deref(), Some("jabber:client"));
+            assert_eq!(header.version.as_deref(), Some("1.0"));
+        }
+    }
+
+    #[tokio::test]
+    async fn stream_header_id() {
+        let input = "<stream:stream xmlns='jabber:server' \
+            xmlns:stream='http://etherx.jabber.org/streams' id='abc' version='1.0'>";
+        for kind in PARSERS {
+            let header = first_header(kind, input).await;
+            assert_eq!(header.id, Some(StreamId::from("abc".to_string())));
+        }
+        for kind in PARSERS {
+            assert_eq!(first_header(kind, STREAM_HEADER).await.id, None);
+        }
+    }
+
+    #[tokio::test]
+    async fn stream_header_in_wrong_namespace_is_passed_on() {
+        let input = "<stream xmlns='jabber:client' to='localhost' version='0.9'>";
+        for kind in PARSERS {
+            let header = first_header(kind, input).await;
+            assert_eq!(header.stream_namespace.as_deref(), Some("jabber:client"));
+            assert_eq!(header.version.as_deref(), Some("0.9"));
+        }
+    }
+
+    #[tokio::test]
+    async fn prefixed_elements_and_attributes() {
+        let input = format!(
+            "{STREAM_HEADER}<foo:bar xmlns:foo='urn:example:foo' foo:baz='1' qux='2'>\
+            <foo:child/><child xmlns='urn:example:default'><grandchild/></child></foo:bar>"
+        );
+        assert_parity(&input).await;
+    }
+
+    #[tokio::test]
+    async fn escaped_attribute_values() {
+        let input = format!("{STREAM_HEADER}<message id='a&amp;b' to=\"&quot;x&quot;\"/>");
+        assert_parity(&input).await;
+    }
+
+    #[tokio::test]
+    async fn whitespace_inside_elements() {
+        let input = format!("{STREAM_HEADER}<message>\n  <body> text </body>\n</message>\n");
+        assert_parity(&input).await;
+    }
+
+    #[tokio::test]
+    async fn invalid_utf8() {
+        let mut input = STREAM_HEADER.as_bytes().to_vec();
+        input.extend_from_slice(b"<message>\xff\xfe</message>");
+        for kind in PARSERS {
+            let frames = parse(kind, &input, 4096).await;
+            assert_eq!(frames.last().unwrap(), "error unsupported-encoding");
+        }
+    }
+
+    #[tokio::test]
+    async fn multibyte_characters_split_across_reads() {
+        let input = format!("{STREAM_HEADER}<message><body>Grüße 😀</body></message>");
+        let frames = assert_parity(&input).await;
+        assert!(frames[1].contains("Grüße 😀"));
+    }
+
+    #[tokio::test]
+    async fn too_many_attributes() {
+        let mut input = format!("{STREAM_HEADER}<message");
+        for i in 0..5000 {
+            input.push_str(&format!(" a{i}='{i}'"));
+        }
+        input.push_str("/>");
+
+        for kind in PARSERS {
+            let frames = parse(kind, input.as_bytes(), 4096).await;
+            assert_eq!(frames.last().unwrap(), "error policy-violation");
+        }
+    }
+
+    #[tokio::test]
+    async fn too_many_children() {
+        let mut input = format!("{STREAM_HEADER}<message>");
+        for _ in 0..5000 {
+            input.push_str("<child/>");
+        }
+        input.push_str("</message>");
+
+        for kind in PARSERS {
+            let frames = parse(kind, input.as_bytes(), 4096).await;
+            assert_eq!(frames.last().unwrap(), "error policy-violation");
+        }
+    }
+
+    #[tokio::test]
+    async fn closing_tag_is_told_apart_from_end_of_input() {
+        for kind in PARSERS {
+            for (input, closed) in [
+                (format!("{STREAM_HEADER}<presence/></stream:stream>"), true),
+                (format!("{STREAM_HEADER}<presence/>"), false),
+            ] {
+                let config = ParserConfig {
+                    kind,
+                    limits: ElementLimits::default(),
+                };
+                let mut parser = AnyStreamParser::new(config, input.as_bytes());
+                while let Some(frame) = parser.next().await {
+                    frame.unwrap();
+                }
+                assert_eq!(parser.stream_closed(), closed, "{kind:?} parsing {input}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn too_deeply_nested() {
+        let mut input = STREAM_HEADER.to_string();
+        input.push_str(&"<a>".repeat(1000));
+        input.push_str(&"</a>".repeat(1000));
+
+        for kind in PARSERS {
+            let frames = parse(kind, input.as_bytes(), 4096).await;
+            assert_eq!(frames.last().unwrap(), "error policy-violation");
+        }
+    }
+
+    #[tokio::test]
+    async fn too_much_pending() {
+        let mut input = format!("{STREAM_HEADER}<message><body>");
+        input.push_str(&"a".repeat(ElementLimits::default().max_pending_bytes + 1));
+
+        for kind in PARSERS {
+            let frames = parse(kind, input.as_bytes(), 4096).await;
+            assert_eq!(frames.last().unwrap(), "error policy-violation");
+        }
+    }
+
+    #[tokio::test]
+    async fn nesting_within_limits() {
+        let depth = ElementLimits::default().max_depth;
+        let mut input = STREAM_HEADER.to_string();
+        input.push_str(&"<a>".repeat(depth - 1));
+        input.push_str("<b/>");
+        input.push_str(&"</a>".repeat(depth - 1));
+
+        for kind in PARSERS {
+            let frames = parse(kind, input.as_bytes(), 4096).await;
+            assert_eq!(frames.len(), 2);
+            assert!(!frames[1].starts_with("error"));
+        }
+    }
+
+    #[tokio::test]
+    async fn elements_within_limits() {
+        let mut input = format!("{STREAM_HEADER}<message");
+        for i in 0..ElementLimits::default().max_attributes - 1 {
+            input.push_str(&format!(" a{i}='{i}'"));
+        }
+        input.push('>');
+        for _ in 0..ElementLimits::default().max_children {
+            input.push_str("<child/>");
+        }
+        input.push_str("</message>");
+
+        for kind in PARSERS {
+            let frames = parse(kind, input.as_bytes(), 4096).await;
+            assert_eq!(frames.len(), 2);
+            assert!(!frames[1].starts_with("error"));
+        }
+    }
 }
@@ -0,0 +1,530 @@
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use anyhow::{anyhow, Error};
+use quick_xml::errors::Error as XmlError;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::parser::{ElementParser, Parser as _, PiParser};
+use quick_xml::Reader;
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio_stream::Stream;
+
+use crate::xml::namespaces::{XML, XMLNS};
+use crate::xml::stream_parser::{
+    is_whitespace, stream_header, valid_stream_tag, ElementLimits, Frame,
+};
+use crate::xml::{Element, Node};
+use crate::xmpp::stream_error::StreamError;
+
+type NamespaceDeclarations = Vec<(Option<String>, String)>;
+
+fn xml_error(err: XmlError) -> Error {
+    let condition = match err {
+        XmlError::NonDecodable(_) => StreamError::UnsupportedEncoding,
+        XmlError::UnknownPrefix(_) => StreamError::BadNamespacePrefix,
+        _ => StreamError::NotWellFormed,
+    };
+
+    anyhow!(err).context(condition)
+}
+
+fn not_well_formed(message: &'static str) -> Error {
+    anyhow!(message).context(StreamError::NotWellFormed)
+}
+
+fn unbound_prefix(prefix: &str) -> Error {
+    anyhow!("unbound namespace prefix `{prefix}`").context(StreamError::BadNamespacePrefix)
+}
+
+fn decode(bytes: &[u8]) -> Result<&str, Error> {
+    std::str::from_utf8(bytes).map_err(|err| anyhow!(err).context(StreamError::UnsupportedEncoding))
+}
+
+fn split_qualified_name(qualified_name: &str) -> (Option<&str>, &str) {
+    match qualified_name.split_once(':') {
+        Some((prefix, name)) => (Some(prefix), name),
+        None => (None, qualified_name),
+    }
+}
+
+struct OpenElement {
+    element: Element,
+    namespaces: NamespaceDeclarations,
+    qualified_name: String,
+}
+
+enum Parsed {
+    Frame(Frame),
+    StreamEnd,
+}
+
+// quick-xml only sees the bytes buffered so far, so namespace scopes and the elements under
+// construction are tracked here rather than by the reader.
+struct ParserState {
+    limits: ElementLimits,
+    stream_namespaces: NamespaceDeclarations,
+    open_elements: Vec<OpenElement>,
+    stream_open: bool,
+}
+
+impl ParserState {
+    fn new(limits: ElementLimits) -> Self {
+        ParserState {
+            limits,
+            stream_namespaces: Vec::new(),
+            open_elements: Vec::new(),
+            stream_open: false,
+        }
+    }
+
+    fn resolve(
+        &self,
+        prefix: Option<&str>,
+        declared: &[(Option<String>, String)],
+    ) -> Option<String> {
+        match prefix {
+            Some("xml") => return Some(XML.to_string()),
+            Some("xmlns") => return Some(XMLNS.to_string()),
+            _ => {}
+        }
+
+        let open_namespaces = self
+            .open_elements
+            .iter()
+            .rev()
+            .flat_map(|open| open.namespaces.iter().rev());
+        declared
+            .iter()
+            .rev()
+            .chain(open_namespaces)
+            .chain(self.stream_namespaces.iter().rev())
+            .find(|(declared_prefix, _)| declared_prefix.as_deref() == prefix)
+            .map(|(_, uri)| uri.clone())
+            .filter(|uri| !uri.is_empty())
+    }
+
+    fn start_element(&self, start: &BytesStart) -> Result<OpenElement, Error> {
+        let qualified_name = decode(start.name().as_ref())?.to_string();
+
+        let mut raw_attributes = Vec::new();
+        for attribute in start.attributes() {
+            let attribute =
+                attribute.map_err(|err| anyhow!(err).context(StreamError::NotWellFormed))?;
+            let name = decode(attribute.key.as_ref())?.to_string();
+            let value = attribute.unescape_value().map_err(xml_error)?.into_owned();
+            raw_attributes.push((name, value));
+            self.limits.check_attributes(raw_attributes.len())?;
+        }
+
+        let namespaces: NamespaceDeclarations = raw_attributes
+            .iter()
+            .filter_map(|(name, value)| match split_qualified_name(name) {
+                (None, "xmlns") => Some((None, value.clone())),
+                (Some("xmlns"), prefix) => Some((Some(prefix.to_string()), value.clone())),
+                _ => None,
+            })
+            .collect();
+
+        let mut attributes = HashMap::new();
+        for (qualified_attribute_name, value) in raw_attributes {
+            let key = match split_qualified_name(&qualified_attribute_name) {
+                (Some(prefix), name) => {
+                    let namespace = self
+                        .resolve(Some(prefix), &namespaces)
+                        .ok_or_else(|| unbound_prefix(prefix))?;
+                    (name.to_string(), Some(namespace))
+                }
+                (None, name) => (name.to_string(), None),
+            };
+            attributes.insert(key, value);
+        }
+
+        let (prefix, name) = split_qualified_name(&qualified_name);
+        let namespace = self.resolve(prefix, &namespaces);
+        if let (Some(prefix), None) = (prefix, &namespace) {
+            return Err(unbound_prefix(prefix));
+        }
+
+        let element = Element {
+            name: name.to_string(),
+            namespace,
+            attributes,
+            children: vec![],
+        };
+
+        Ok(OpenElement {
+            element,
+            namespaces,
+            qualified_name,
+        })
+    }
+
+    fn close_element(&mut self, element: Element) -> Result<Option<Parsed>, Error> {
+        if self.open_elements.is_empty() {
+            return Ok(Some(Parsed::Frame(Frame::XmlFragment(element))));
+        }
+
+        self.push_child(Node::Element(element))?;
+        Ok(None)
+    }
+
+    fn push_child(&mut self, node: Node) -> Result<(), Error> {
+        // anything between top-level elements is insignificant
+        if let Some(open) = self.open_elements.last_mut() {
+            self.limits
+                .check_children(open.element.children.len() + 1)?;
+            open.element.children.push(node);
+        }
+
+        Ok(())
+    }
+
+    fn handle_event(&mut self, event: Event) -> Result<Option<Parsed>, Error> {
+        match event {
+            Event::Decl(declaration) => {
+                if let Some(encoding) = declaration.encoding() {
+                    let encoding = encoding.map_err(xml_error)?;
+                    if !encoding.eq_ignore_ascii_case(b"UTF-8") {
+                        let err = anyhow!("unsupported XML declaration encoding");
+                        return Err(err.context(StreamError::UnsupportedEncoding));
+                    }
+                }
+            }
+            Event::Start(start) => {
+                let open = self.start_element(&start)?;
+                // the first element is the stream header, whatever its namespace
+                if !self.stream_open
+                    && self.open_elements.is_empty()
+                    && open.element.name == "stream"
+                {
+                    let header =
+                        stream_header(open.element.namespace.as_deref(), &open.element.attributes);
+                    self.stream_namespaces = open.namespaces;
+                    self.stream_open = true;
+                    return Ok(Some(Parsed::Frame(Frame::StreamStart(header))));
+                }
+                self.limits.check_depth(self.open_elements.len() + 1)?;
+                self.open_elements.push(open);
+            }
+            Event::Empty(start) => {
+                let open = self.start_element(&start)?;
+                self.limits.check_depth(self.open_elements.len() + 1)?;
+                return self.close_element(open.element);
+            }
+            Event::End(end) => {
+                let end_name = end.name();
+                let qualified_name = decode(end_name.as_ref())?;
+                let Some(open) = self.open_elements.pop() else {
+                    let (prefix, name) = split_qualified_name(qualified_name);
+                    let namespace = self.resolve(prefix, &[]);
+                    if valid_stream_tag(name, namespace.as_deref()) {
+                        return Ok(Some(Parsed::StreamEnd));
+                    }
+                    return Err(not_well_formed("closing tag without matching opening tag"));
+                };
+                if open.qualified_name != qualified_name {
+                    return Err(not_well_formed("improperly nested elements"));
+                }
+                return self.close_element(open.element);
+            }
+            Event::Text(text) => {
+                let text = text.unescape().map_err(xml_error)?.into_owned();
+                if self.stream_open && self.open_elements.is_empty() && is_whitespace(&text) {
+                    return Ok(Some(Parsed::Frame(Frame::Whitespace)));
+                }
+                self.push_child(Node::Text(text))?;
+            }
+            Event::CData(cdata) => {
+                let cdata = decode(&cdata)?.to_string();
+                self.push_child(Node::CData(cdata))?;
+            }
+            Event::Comment(comment) => {
+                let comment = decode(&comment)?.to_string();
+                self.push_child(Node::Comment(comment))?;
+            }
+            Event::PI(instruction) => {
+                let instruction = decode(&instruction)?.to_string();
+                self.push_child(Node::ProcessingInstruction(instruction))?;
+            }
+            Event::DocType(_) => {
+                return Err(not_well_formed(
+                    "document type declarations are not allowed",
+                ));
+            }
+            Event::Eof => {}
+        }
+
+        Ok(None)
+    }
+
+    // Consumes events from `input`, which only ever holds complete tags and text nodes.
+    fn parse(&mut self, input: &[u8], consumed: &mut usize) -> Result<Option<Parsed>, Error> {
+        let mut reader = Reader::from_reader(input);
+        let config = reader.config_mut();
+        config.check_end_names = false;
+        config.allow_unmatched_ends = true;
+
+        loop {
+            let event = match reader.read_event() {
+                Ok(Event::Eof) => return Ok(None),
+                Ok(event) => event,
+                Err(err) => return Err(xml_error(err)),
+            };
+            *consumed = reader.buffer_position() as usize;
+
+            if let Some(parsed) = self.handle_event(event)? {
+                return Ok(Some(parsed));
+            }
+        }
+    }
+}
+
+// What the piece of input after the last complete one turned out to be.
+enum Piece {
+    Text,
+    // a `<` followed by too little to tell what kind of markup it starts
+    Markup,
+    Tag(ElementParser),
+    ProcessingInstruction(PiParser),
+    // comments, CDATA sections and declarations, with the length of their opening and their
+    // terminator
+    Bang(usize, &'static [u8]),
+}
+
+impl Piece {
+    // Tells what kind of markup `markup` starts and how long its opening is, once that is known.
+    fn starting(markup: &[u8]) -> Option<(Piece, usize)> {
+        const COMMENT: &[u8] = b"<!--";
+        const CDATA: &[u8] = b"<![CDATA[";
+
+        match *markup.get(1)? {
+            b'?' => Some((Piece::ProcessingInstruction(PiParser::default()), 2)),
+            b'!' => {
+                for (opening, terminator) in [(COMMENT, b"-->"), (CDATA, b"]]>")] {
+                    if markup.starts_with(opening) {
+                        return Some((Piece::Bang(opening.len(), terminator), opening.len()));
+                    }
+                    if opening.starts_with(markup) {
+                        return None;
+                    }
+                }
+                Some((Piece::Bang(2, b">"), 2))
+            }
+            _ => Some((Piece::Tag(ElementParser::default()), 1)),
+        }
+    }
+}
+
+// Finds where complete tags and text nodes end, so that the reader never sees a partial one.
+// Scanning picks up where it stopped, so every byte is looked at once however it is split
+// across reads.
+#[derive(Default)]
+struct Scanner {
+    piece: Option<Piece>,
+    // the end of the complete pieces found so far
+    complete: usize,
+    // how far into the incomplete piece after them scanning got
+    scanned: usize,
+}
+
+impl Scanner {
+    fn scan(&mut self, input: &[u8]) {
+        while self.scanned < input.len() {
+            let rest = &input[self.scanned..];
+            let end = match &mut self.piece {
+                None => {
+                    self.piece = Some(match rest[0] {
+                        b'<' => {
+                            self.scanned += 1;
+                            Piece::Markup
+                        }
+                        _ => Piece::Text,
+                    });
+                    continue;
+                }
+                // text ends where the next markup starts
+                Some(Piece::Text) => match rest.iter().position(|&byte| byte == b'<') {
+                    Some(position) => {
+                        self.complete = self.scanned + position;
+                        self.scanned = self.complete;
+                        self.piece = None;
+                        continue;
+                    }
+                    None => None,
+                },
+                Some(Piece::Markup) => match Piece::starting(&input[self.complete..]) {
+                    Some((piece, opening)) => {
+                        self.scanned = self.complete + opening;
+                        self.piece = Some(piece);
+                        continue;
+                    }
+                    None => break,
+                },
+                Some(Piece::Tag(parser)) => {
+                    parser.feed(rest).map(|position| self.scanned + position)
+                }
+                Some(Piece::ProcessingInstruction(parser)) => {
+                    parser.feed(rest).map(|position| self.scanned + position)
+                }
+                Some(Piece::Bang(opening, terminator)) => {
+                    // the terminator may have been split across reads
+                    let start = (self.complete + *opening)
+                        .max(self.scanned.saturating_sub(terminator.len() - 1));
+                    input[start..]
+                        .windows(terminator.len())
+                        .position(|window| window == *terminator)
+                        .map(|position| start + position + terminator.len() - 1)
+                }
+            };
+
+            match end {
+                Some(end) => {
+                    self.complete = end + 1;
+                    self.scanned = self.complete;
+                    self.piece = None;
+                }
+                None => self.scanned = input.len(),
+            }
+        }
+    }
+
+    fn incomplete(&self) -> bool {
+        self.piece.is_some()
+    }
+
+    fn discard(&mut self, bytes: usize) {
+        self.complete -= bytes;
+        self.scanned -= bytes;
+    }
+}
+
+pub struct StreamParser<R: AsyncRead + Unpin> {
+    reader: R,
+    buffer: Box<[u8]>,
+    pending: Vec<u8>,
+    // how much of `pending` has been handed to the reader
+    position: usize,
+    scanner: Scanner,
+    state: ParserState,
+    stream_closed: bool,
+}
+
+impl<R: AsyncRead + Unpin> super::StreamParser for StreamParser<R> {
+    type Reader = R;
+
+    fn new(reader: R, limits: ElementLimits) -> Self {
+        let buffer = vec![0; 4096].into_boxed_slice();
+
+        Self {
+            reader,
+            buffer,
+            pending: Vec::new(),
+            position: 0,
+            scanner: Scanner::default(),
+            state: ParserState::new(limits),
+            stream_closed: false,
+        }
+    }
+
+    fn into_inner(self) -> R {
+        self.reader
+    }
+
+    fn stream_closed(&self) -> bool {
+        self.stream_closed
+    }
+}
+
+impl<R: AsyncRead + Unpin> Stream for StreamParser<R> {
+    type Item = Result<Frame, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame, Error>>> {
+        let this = self.get_mut();
+        loop {
+            let mut consumed = 0;
+            let complete = &this.pending[this.position..this.scanner.complete];
+            let result = this.state.parse(complete, &mut consumed);
+            this.position += consumed;
+
+            match result {
+                Ok(Some(Parsed::Frame(frame))) => return Poll::Ready(Some(Ok(frame))),
+                Ok(Some(Parsed::StreamEnd)) => {
+                    this.stream_closed = true;
+                    return Poll::Ready(None);
+                }
+                Ok(None) => this.position = this.scanner.complete,
+                Err(err) => return Poll::Ready(Some(Err(err))),
+            }
+
+            // only compact once everything complete has been handed out
+            this.pending.drain(..this.position);
+            this.scanner.discard(this.position);
+            this.position = 0;
+
+            let mut buffer = ReadBuf::new(&mut this.buffer);
+            ready!(Pin::new(&mut this.reader).poll_read(cx, &mut buffer))?;
+            if buffer.filled().is_empty() {
+                // whitespace trailing the stream is no loss, anything else is cut off
+                if this.scanner.incomplete()
+                    && !std::str::from_utf8(&this.pending).is_ok_and(is_whitespace)
+                {
+                    this.pending.clear();
+                    this.scanner = Scanner::default();
+                    return Poll::Ready(Some(Err(not_well_formed(
+                        "stream ended in the middle of markup or text",
+                    ))));
+                }
+                return Poll::Ready(None);
+            }
+
+            this.pending.extend_from_slice(buffer.filled());
+            this.scanner.scan(&this.pending);
+            if let Err(err) = this
+                .state
+                .limits
+                .check_pending(this.pending.len() - this.scanner.complete)
+            {
+                return Poll::Ready(Some(Err(err)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_stream::StreamExt;
+
+    use crate::xml::stream_parser::{ElementLimits, StreamParser as _};
+    use crate::xmpp::stream_error::StreamError;
+
+    use super::StreamParser;
+
+    const STREAM_HEADER: &str = "<stream:stream xmlns='jabber:client' \
+        xmlns:stream='http://etherx.jabber.org/streams' to='localhost' version='1.0'>";
+
+    async fn first_error(input: Vec<u8>) -> StreamError {
+        let mut parser = StreamParser::new(input.as_slice(), ElementLimits::default());
+        loop {
+            match parser.next().await {
+                Some(Ok(_)) => continue,
+                Some(Err(err)) => return *err.downcast_ref::<StreamError>().unwrap(),
+                None => panic!("stream ended without an error"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn truncated_tag_at_eof_is_not_well_formed() {
+        let input = format!("{STREAM_HEADER}<message><bo");
+        let condition = first_error(input.into_bytes()).await;
+        assert_eq!(condition, StreamError::NotWellFormed);
+    }
+
+    #[tokio::test]
+    async fn truncated_text_at_eof_is_not_well_formed() {
+        let input = format!("{STREAM_HEADER}<message><body>Hello");
+        let condition = first_error(input.into_bytes()).await;
+        assert_eq!(condition, StreamError::NotWellFormed);
+    }
+}
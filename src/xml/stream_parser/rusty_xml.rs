@@ -3,23 +3,52 @@ use std::task::{ready, Context, Poll};
 
 use anyhow::{anyhow, Error};
 use pin_project::pin_project;
-use rustyxml::{Element as RustyXmlElement, ElementBuilder, Event, Parser};
+use regex::Regex;
+use rustyxml::{
+    BuilderError, Element as RustyXmlElement, ElementBuilder, Event, Parser, ParserError,
+};
 use tokio::io::{AsyncRead, ReadBuf};
 use tokio_stream::Stream;
 
-use crate::xml::namespaces::XMPP_STREAMS;
-use crate::xml::stream_parser::{Frame, StreamHeader};
+use crate::xml::stream_parser::{
+    is_whitespace, stream_header, valid_stream_tag, ElementLimits, Frame,
+};
 use crate::xml::{Element, Node};
-use crate::xmpp::stream_header::LanguageTag;
+use crate::xmpp::stream_error::StreamError;
 
-fn valid_stream_tag(name: &String, namespace: &Option<String>) -> bool {
-    if name != "stream" {
-        return false;
+fn valid_xml_declaration(instruction: &str) -> bool {
+    let regex = Regex::new(r#"^xml\s.*encoding\s*=\s*['"](?P<encoding>[^'"]*)['"]"#).unwrap();
+    match regex.captures(instruction) {
+        Some(captures) => captures["encoding"].eq_ignore_ascii_case("UTF-8"),
+        None => true,
     }
+}
+
+fn parser_error(err: ParserError) -> Error {
+    let condition = match err.msg {
+        "Unbound namespace prefix in tag name" | "Unbound namespace prefix in attribute name" => {
+            StreamError::BadNamespacePrefix
+        }
+        _ => StreamError::NotWellFormed,
+    };
+
+    anyhow!(err).context(condition)
+}
 
-    match namespace {
-        Some(uri) => uri == XMPP_STREAMS,
-        None => false,
+fn count_child(child_counts: &mut [usize], limits: &ElementLimits) -> Result<(), Error> {
+    match child_counts.last_mut() {
+        Some(count) => {
+            *count += 1;
+            limits.check_children(*count)
+        }
+        None => Ok(()),
+    }
+}
+
+fn builder_error(err: BuilderError) -> Error {
+    match err {
+        BuilderError::Parser(err) => parser_error(err),
+        err => anyhow!(err).context(StreamError::NotWellFormed),
     }
 }
 
@@ -56,12 +85,19 @@ pub struct StreamParser<R: AsyncRead + Unpin> {
     buffer: Box<[u8]>,
     parser: Parser,
     element_builder: ElementBuilder,
+    limits: ElementLimits,
+    child_counts: Vec<usize>,
+    stream_open: bool,
+    stream_closed: bool,
+    undecoded: Vec<u8>,
+    // bytes fed since the parser last produced an event
+    pending: usize,
 }
 
 impl<R: AsyncRead + Unpin> super::StreamParser for StreamParser<R> {
     type Reader = R;
 
-    fn new(reader: R) -> Self {
+    fn new(reader: R, limits: ElementLimits) -> Self {
         let buffer = vec![0; 4096].into_boxed_slice();
         let parser = Parser::new();
         let element_builder = ElementBuilder::new();
@@ -71,12 +107,22 @@ impl<R: AsyncRead + Unpin> super::StreamParser for StreamParser<R> {
             buffer,
             parser,
             element_builder,
+            limits,
+            child_counts: Vec::new(),
+            stream_open: false,
+            stream_closed: false,
+            undecoded: Vec::new(),
+            pending: 0,
         }
     }
 
     fn into_inner(self) -> R {
         self.reader
     }
+
+    fn stream_closed(&self) -> bool {
+        self.stream_closed
+    }
 }
 
 impl<R: AsyncRead + Unpin> Stream for StreamParser<R> {
@@ -85,39 +131,57 @@ impl<R: AsyncRead + Unpin> Stream for StreamParser<R> {
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame, Error>>> {
         let this = self.project();
         for parser_result in this.parser.by_ref() {
+            *this.pending = 0;
             match parser_result {
-                Ok(Event::ElementStart(tag)) if valid_stream_tag(&tag.name, &tag.ns) => {
-                    dbg!(&tag.ns, &tag.attributes);
-                    let header = StreamHeader {
-                        from: tag
-                            .attributes
-                            .get(&("from".to_string(), None))
-                            .and_then(|jid| jid.parse().ok()),
-                        to: tag
-                            .attributes
-                            .get(&("to".to_string(), None))
-                            .and_then(|jid| jid.parse().ok()),
-                        id: None,
-                        language: tag
-                            .attributes
-                            .get(&("xml:lang".to_string(), None))
-                            .map(|lang| LanguageTag(lang.to_string())),
-                    };
+                // the first element is the stream header, whatever its namespace
+                Ok(Event::ElementStart(tag)) if !*this.stream_open && tag.name == "stream" => {
+                    let header = stream_header(tag.ns.as_deref(), &tag.attributes);
+                    *this.stream_open = true;
                     return Poll::Ready(Some(Ok(Frame::StreamStart(header))));
                 }
-                Ok(Event::ElementEnd(tag)) if valid_stream_tag(&tag.name, &tag.ns) => {
+                Ok(Event::ElementEnd(tag)) if valid_stream_tag(&tag.name, tag.ns.as_deref()) => {
+                    *this.stream_closed = true;
                     return Poll::Ready(None);
                 }
+                Ok(Event::PI(ref instruction)) if !valid_xml_declaration(instruction) => {
+                    let err = anyhow!("unsupported XML declaration: {instruction}");
+                    return Poll::Ready(Some(Err(err.context(StreamError::UnsupportedEncoding))));
+                }
+                Ok(Event::Characters(ref text))
+                    if *this.stream_open && this.child_counts.is_empty() && is_whitespace(text) =>
+                {
+                    return Poll::Ready(Some(Ok(Frame::Whitespace)));
+                }
                 Err(err) => {
-                    return Poll::Ready(Some(Err(anyhow!(err))));
+                    return Poll::Ready(Some(Err(parser_error(err))));
+                }
+                Ok(Event::ElementStart(ref tag)) => {
+                    // the element builder only hands out complete fragments, so limits are
+                    // enforced on the events leading up to them
+                    let checked = this
+                        .limits
+                        .check_attributes(tag.attributes.len())
+                        .and_then(|()| count_child(this.child_counts, this.limits))
+                        .and_then(|()| this.limits.check_depth(this.child_counts.len() + 1));
+                    if let Err(err) = checked {
+                        return Poll::Ready(Some(Err(err)));
+                    }
+                    this.child_counts.push(0);
+                }
+                Ok(Event::ElementEnd(_)) => {
+                    this.child_counts.pop();
+                }
+                Ok(_) => {
+                    if let Err(err) = count_child(this.child_counts, this.limits) {
+                        return Poll::Ready(Some(Err(err)));
+                    }
                 }
-                _ => {}
             }
 
             if let Some(builder_result) = this.element_builder.handle_event(parser_result) {
                 let frame_result = match builder_result {
                     Ok(element) => Some(Ok(Frame::XmlFragment(element.into()))),
-                    Err(err) => Some(Err(anyhow!(err))),
+                    Err(err) => Some(Err(builder_error(err))),
                 };
                 return Poll::Ready(frame_result);
             }
@@ -128,17 +192,34 @@ impl<R: AsyncRead + Unpin> Stream for StreamParser<R> {
         let bytes_read = buffer.filled().len();
 
         if bytes_read == 0 {
+            // a character cut off by the end of the stream will never be completed
+            if !this.undecoded.is_empty() {
+                this.undecoded.clear();
+                let err = anyhow!("stream ended in the middle of a UTF-8 sequence");
+                return Poll::Ready(Some(Err(err.context(StreamError::NotWellFormed))));
+            }
             return Poll::Ready(None);
         }
 
-        match std::str::from_utf8(buffer.filled()) {
-            Ok(str) => {
-                println!("{}", str);
-                this.parser.feed_str(str);
-            }
+        this.undecoded.extend_from_slice(buffer.filled());
+        let valid_up_to = match std::str::from_utf8(this.undecoded.as_slice()) {
+            Ok(str) => str.len(),
+            // a character split across reads is completed by the next one
+            Err(err) if err.error_len().is_none() => err.valid_up_to(),
             Err(err) => {
-                return Poll::Ready(Some(Err(anyhow!(err))));
+                return Poll::Ready(Some(Err(
+                    anyhow!(err).context(StreamError::UnsupportedEncoding)
+                )));
             }
+        };
+        let str = std::str::from_utf8(&this.undecoded[..valid_up_to]).unwrap();
+        this.parser.feed_str(str);
+        this.undecoded.drain(..valid_up_to);
+        // the parser keeps whatever it has not turned into events yet, and this is an upper
+        // bound for that
+        *this.pending += bytes_read;
+        if let Err(err) = this.limits.check_pending(*this.pending) {
+            return Poll::Ready(Some(Err(err)));
         }
 
         buffer.clear();
@@ -147,3 +228,65 @@ impl<R: AsyncRead + Unpin> Stream for StreamParser<R> {
         Poll::Pending
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio_stream::StreamExt;
+
+    use crate::xml::stream_parser::{ElementLimits, StreamParser as _};
+    use crate::xmpp::stream_error::StreamError;
+
+    use super::StreamParser;
+
+    const STREAM_HEADER: &str = "<stream:stream xmlns='jabber:client' \
+        xmlns:stream='http://etherx.jabber.org/streams' to='localhost' version='1.0'>";
+
+    async fn first_error(input: Vec<u8>) -> StreamError {
+        let mut parser = StreamParser::new(input.as_slice(), ElementLimits::default());
+        loop {
+            match parser.next().await {
+                Some(Ok(_)) => continue,
+                Some(Err(err)) => return *err.downcast_ref::<StreamError>().unwrap(),
+                None => panic!("stream ended without an error"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn improperly_nested_xml_is_not_well_formed() {
+        let input = format!("{STREAM_HEADER}<message><body></message>");
+        let condition = first_error(input.into_bytes()).await;
+        assert_eq!(condition, StreamError::NotWellFormed);
+    }
+
+    #[tokio::test]
+    async fn unbound_prefix_is_bad_namespace_prefix() {
+        let input = format!("{STREAM_HEADER}<foo:message/>");
+        let condition = first_error(input.into_bytes()).await;
+        assert_eq!(condition, StreamError::BadNamespacePrefix);
+    }
+
+    #[tokio::test]
+    async fn non_utf8_declaration_is_unsupported_encoding() {
+        let input = format!("<?xml version='1.0' encoding='ISO-8859-1'?>{STREAM_HEADER}");
+        let condition = first_error(input.into_bytes()).await;
+        assert_eq!(condition, StreamError::UnsupportedEncoding);
+    }
+
+    #[tokio::test]
+    async fn invalid_utf8_is_unsupported_encoding() {
+        let mut input = STREAM_HEADER.as_bytes().to_vec();
+        input.extend_from_slice(b"<message>\xff\xfe</message>");
+        let condition = first_error(input).await;
+        assert_eq!(condition, StreamError::UnsupportedEncoding);
+    }
+
+    #[tokio::test]
+    async fn truncated_character_at_eof_is_not_well_formed() {
+        let mut input = STREAM_HEADER.as_bytes().to_vec();
+        // the first two bytes of the three making up "€"
+        input.extend_from_slice(b"<message><body>\xe2\x82");
+        let condition = first_error(input).await;
+        assert_eq!(condition, StreamError::NotWellFormed);
+    }
+}
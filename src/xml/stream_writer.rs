@@ -1,28 +1,29 @@
 use std::collections::HashMap;
 
 use anyhow::{anyhow, bail, Error};
-use base64::prelude::*;
-use rand::{RngCore, SeedableRng};
 use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tracing::{debug, trace};
 
 use crate::xml::namespaces;
+use crate::xml::serializer::Serializer;
 use crate::xml::Element;
 use crate::xml::Node;
+use crate::xmpp::error_text::DEFAULT_LANGUAGE;
+use crate::xmpp::stanza::Stanza;
+use crate::xmpp::stream::StreamId;
 use crate::xmpp::stream_header::StreamHeader;
 
 pub struct StreamWriter<W: AsyncWrite + Unpin> {
     writer: W,
-    namespaces: Vec<HashMap<String, String>>, // stacked namespace to prefix map
+    serializer: Serializer,
 }
 
 impl<W: AsyncWrite + Unpin> StreamWriter<W> {
     pub fn new(writer: W) -> Self {
-        let mut namespaces = HashMap::new();
-        namespaces.insert(namespaces::XML.to_string(), "xml".to_string());
-        namespaces.insert(namespaces::XMLNS.to_string(), "xmlns".to_string());
-        let namespaces = vec![namespaces];
-
-        Self { writer, namespaces }
+        Self {
+            writer,
+            serializer: Serializer::default(),
+        }
     }
 
     pub fn into_inner(self) -> W {
@@ -42,23 +43,27 @@ impl<W: AsyncWrite + Unpin> StreamWriter<W> {
             bail!("`from` field is required in outgoing stream header");
         };
 
-        let mut rng = rand_chacha::ChaCha20Rng::from_entropy();
-        let mut id_raw = [0u8; 16];
-        rng.fill_bytes(&mut id_raw);
-        let id_encoded = BASE64_STANDARD.encode(id_raw);
+        // the stream normally passes the id it allocated, so the peer sees the one it tracks
+        let id = header.id.clone().unwrap_or_else(StreamId::new);
 
         let mut header_attributes = HashMap::new();
         header_attributes.insert(("from".to_string(), None), from.to_string());
-        header_attributes.insert(("id".to_string(), None), id_encoded);
+        header_attributes.insert(("id".to_string(), None), id.to_string());
         header_attributes.insert(("version".to_string(), None), "1.0".to_string());
+        // the language the peer asked for, or ours if it did not (RFC 6120, section 4.7.4)
+        let language = header
+            .language
+            .as_ref()
+            .map_or(DEFAULT_LANGUAGE, |tag| tag.0.as_str());
         header_attributes.insert(
             ("lang".to_string(), Some(namespaces::XML.to_string())),
-            "en".to_string(),
-        );
-        header_attributes.insert(
-            ("xmlns".to_string(), None),
-            namespaces::XMPP_CLIENT.to_string(),
+            language.to_string(),
         );
+        let content_namespace = header
+            .content_namespace
+            .as_deref()
+            .unwrap_or(namespaces::XMPP_CLIENT);
+        header_attributes.insert(("xmlns".to_string(), None), content_namespace.to_string());
         header_attributes.insert(
             ("stream".to_string(), Some(namespaces::XMLNS.to_string())),
             namespaces::XMPP_STREAMS.to_string(),
@@ -71,7 +76,7 @@ impl<W: AsyncWrite + Unpin> StreamWriter<W> {
             children: vec![],
         };
 
-        let opening_tag = self.build_opening_tag(&stream_element, false);
+        let opening_tag = self.serializer.build_opening_tag(&stream_element, false);
         self.write_str(&opening_tag).await
     }
 
@@ -83,15 +88,41 @@ impl<W: AsyncWrite + Unpin> StreamWriter<W> {
             children: vec![],
         };
 
-        let closing_tag = self.build_closing_tag(&stream_element);
+        let closing_tag = self.serializer.build_closing_tag(&stream_element);
         self.write_str(&closing_tag).await
     }
 
     pub async fn write_xml_element(&mut self, element: &Element) -> Result<(), Error> {
-        let xml = self.build_xml_element(element);
+        let xml = self.serializer.build_xml_element(element);
         self.write_str(&xml).await
     }
 
+    // Stanzas are in the stream's default namespace, so they are written without declaring it
+    // again, while payloads still get a declaration for their own namespace.
+    pub async fn write_stanza(&mut self, stanza: &Stanza) -> Result<(), Error> {
+        let default_namespace = self
+            .serializer
+            .lookup_default_namespace()
+            .map(str::to_string);
+        let element = self.normalize_declarations(&stanza.element, default_namespace.as_deref());
+        debug!(name = %element.name, "sending stanza");
+        self.write_xml_element(&element).await
+    }
+
+    pub fn serialize_xml_element(&mut self, element: &Element) -> String {
+        self.serializer.build_xml_element(element)
+    }
+
+    pub async fn write_serialized_xml(&mut self, xml: &str) -> Result<(), Error> {
+        self.write_str(xml).await
+    }
+
+    // Whitespace between top-level elements is insignificant, so it can be sent at any point
+    // where no element is half written.
+    pub async fn write_whitespace(&mut self) -> Result<(), Error> {
+        self.write_str(" ").await
+    }
+
     async fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
         self.writer
             .write_all(bytes)
@@ -102,7 +133,7 @@ impl<W: AsyncWrite + Unpin> StreamWriter<W> {
     }
 
     async fn write_str(&mut self, string: &str) -> Result<(), Error> {
-        dbg!(string);
+        trace!(xml = string, "sending");
         self.write_bytes(string.as_bytes()).await
     }
 
@@ -110,169 +141,355 @@ impl<W: AsyncWrite + Unpin> StreamWriter<W> {
         self.write_str("<?xml version='1.0'?>").await
     }
 
-    fn lookup_namespace_prefix(&self, namespace: &str) -> Option<&str> {
-        for namespaces in self.namespaces.iter().rev() {
-            if let Some(prefix) = namespaces.get(namespace) {
-                return Some(prefix);
+    fn normalize_declarations(
+        &self,
+        element: &Element,
+        default_namespace: Option<&str>,
+    ) -> Element {
+        let mut element = element.clone();
+        let xmlns = ("xmlns".to_string(), None);
+
+        if element.attributes.get(&xmlns).map(String::as_str) == default_namespace {
+            element.attributes.remove(&xmlns);
+        }
+
+        if let Some(namespace) = &element.namespace {
+            let declared = element.attributes.contains_key(&xmlns)
+                || element
+                    .attributes
+                    .iter()
+                    .any(|((_, attribute_namespace), value)| {
+                        attribute_namespace.as_deref() == Some(namespaces::XMLNS)
+                            && value == namespace
+                    });
+            let prefixed_in_scope = matches!(
+                self.serializer.lookup_namespace_prefix(namespace),
+                Some(prefix) if !prefix.is_empty()
+            );
+            if !declared && !prefixed_in_scope && default_namespace != Some(namespace.as_str()) {
+                element.attributes.insert(xmlns.clone(), namespace.clone());
             }
         }
 
-        None
+        let default_namespace = element
+            .attributes
+            .get(&xmlns)
+            .map(String::as_str)
+            .or(default_namespace)
+            .map(str::to_string);
+        element.children = element
+            .children
+            .iter()
+            .map(|child| match child {
+                Node::Element(child) => {
+                    Node::Element(self.normalize_declarations(child, default_namespace.as_deref()))
+                }
+                other => other.clone(),
+            })
+            .collect();
+
+        element
     }
+}
 
-    fn build_xml_element(&mut self, element: &Element) -> String {
-        let mut xml = String::new();
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
 
-        if !element.children.is_empty() {
-            xml.push_str(&self.build_opening_tag(element, false));
-            xml.push_str(&self.build_children(element));
-            xml.push_str(&self.build_closing_tag(element));
-        } else {
-            xml.push_str(&self.build_opening_tag(element, true));
-        }
+    use tracing::Level;
 
-        xml
-    }
+    use crate::xml::namespaces;
+    use crate::xmpp::stream_header::LanguageTag;
 
-    fn build_opening_tag(&mut self, element: &Element, self_closing: bool) -> String {
-        let mut xml = String::new();
-
-        // Iterate over attributes and process namespace declarations
-        let mut namespaces = HashMap::new();
-        for ((attribute, namespace), value) in &element.attributes {
-            match namespace {
-                Some(namespace) => {
-                    if namespace == namespaces::XMLNS {
-                        namespaces.insert(value.clone(), attribute.clone()); // prefixed namespace
-                    }
-                }
-                None => {
-                    if attribute == "xmlns" {
-                        namespaces.insert(value.clone(), String::new()); // default namespace
-                    }
-                }
-            }
+    use super::*;
+
+    fn element(name: &str, namespace: &str, declare: bool, children: Vec<Node>) -> Element {
+        let mut attributes = HashMap::new();
+        if declare {
+            attributes.insert(("xmlns".to_string(), None), namespace.to_string());
         }
-        self.namespaces.push(namespaces);
-
-        match &element.namespace {
-            Some(namespace) => match self.lookup_namespace_prefix(namespace) {
-                Some("") => {
-                    // Element is in the default namespace
-                    xml.push_str(&format!(
-                        "<{}{}",
-                        element.name,
-                        self.build_attributes(element)
-                    ));
-                }
-                Some(prefix) => {
-                    // Element is in a prefixed namespace
-                    xml.push_str(&format!(
-                        "<{}:{}{}",
-                        prefix,
-                        element.name,
-                        self.build_attributes(element)
-                    ));
-                }
-                None => {
-                    debug_assert!(false, "namespace not declared");
-                }
-            },
-            None => {
-                xml.push_str(&format!(
-                    "<{}{}",
-                    element.name,
-                    self.build_attributes(element)
-                ));
-            }
+
+        Element {
+            name: name.to_string(),
+            namespace: Some(namespace.to_string()),
+            attributes,
+            children,
         }
+    }
 
-        if self_closing {
-            self.namespaces.pop();
+    async fn write_stanza(stanza: Stanza) -> String {
+        let header = StreamHeader {
+            from: Some("localhost".parse().unwrap()),
+            to: None,
+            id: None,
+            language: None,
+            stream_namespace: None,
+            content_namespace: None,
+            version: None,
+        };
 
-            xml.push_str("/>");
-        } else {
-            xml.push('>');
-        }
+        let mut writer = StreamWriter::new(Vec::new());
+        writer.write_stream_header(&header, false).await.unwrap();
+        let header_length = writer.writer.len();
+        writer.write_stanza(&stanza).await.unwrap();
 
-        xml
+        String::from_utf8(writer.into_inner()[header_length..].to_vec()).unwrap()
     }
 
-    fn build_attributes(&self, element: &Element) -> String {
-        let mut xml = String::new();
-
-        for ((attribute, namespace), value) in &element.attributes {
-            match namespace {
-                Some(namespace) => match self.lookup_namespace_prefix(namespace) {
-                    Some("") => {
-                        debug_assert!(false, "cannot use default namespace for attribute");
-                    }
-                    Some(prefix) => {
-                        xml.push_str(&format!(r#" {}:{}="{}""#, prefix, attribute, value,));
-                    }
-                    None => {
-                        debug_assert!(false, "namespace not declared");
-                    }
-                },
-                None => {
-                    xml.push_str(&format!(r#" {}="{}""#, attribute, value,));
-                }
-            }
+    #[tokio::test]
+    async fn stanza_does_not_redeclare_default_namespace() {
+        let payload = element("x", "jabber:x:oob", false, vec![]);
+        let message = element(
+            "message",
+            namespaces::XMPP_CLIENT,
+            true,
+            vec![Node::Element(payload)],
+        );
+
+        let xml = write_stanza(Stanza { element: message }).await;
+
+        assert_eq!(xml, r#"<message><x xmlns="jabber:x:oob"/></message>"#);
+    }
+
+    #[tokio::test]
+    async fn payload_children_inherit_payload_namespace() {
+        let jid = element("jid", namespaces::XMPP_BIND, false, vec![]);
+        let bind = element(
+            "bind",
+            namespaces::XMPP_BIND,
+            true,
+            vec![Node::Element(jid)],
+        );
+        let iq = element(
+            "iq",
+            namespaces::XMPP_CLIENT,
+            false,
+            vec![Node::Element(bind)],
+        );
+
+        let xml = write_stanza(Stanza { element: iq }).await;
+
+        assert_eq!(
+            xml,
+            r#"<iq><bind xmlns="urn:ietf:params:xml:ns:xmpp-bind"><jid/></bind></iq>"#
+        );
+    }
+
+    async fn write_element(element: &Element) -> String {
+        let mut writer = StreamWriter::new(Vec::new());
+        writer.write_xml_element(element).await.unwrap();
+        String::from_utf8(writer.into_inner()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn display_matches_written_element() {
+        let jid = element("jid", namespaces::XMPP_BIND, false, vec![]);
+        let bind = element(
+            "bind",
+            namespaces::XMPP_BIND,
+            true,
+            vec![Node::Element(jid)],
+        );
+        let body = element(
+            "body",
+            namespaces::XMPP_CLIENT,
+            false,
+            vec![Node::Text("Wherefore art thou?".to_string())],
+        );
+        let mut features = element("features", namespaces::XMPP_STREAMS, false, vec![]);
+        features.attributes.insert(
+            ("stream".to_string(), Some(namespaces::XMLNS.to_string())),
+            namespaces::XMPP_STREAMS.to_string(),
+        );
+        features.children = vec![Node::Element(bind.clone())];
+
+        for fixture in [
+            bind,
+            element(
+                "iq",
+                namespaces::XMPP_CLIENT,
+                true,
+                vec![Node::Element(body)],
+            ),
+            features,
+        ] {
+            assert_eq!(fixture.to_string(), write_element(&fixture).await);
         }
+    }
 
-        xml
+    #[tokio::test]
+    async fn whitespace_leaves_the_stream_well_formed() {
+        let mut writer = StreamWriter::new(Vec::new());
+        let message = element("message", namespaces::XMPP_CLIENT, true, vec![]);
+        writer.write_xml_element(&message).await.unwrap();
+        writer.write_whitespace().await.unwrap();
+        writer.write_xml_element(&message).await.unwrap();
+
+        let xml = String::from_utf8(writer.into_inner()).unwrap();
+        assert_eq!(
+            xml,
+            r#"<message xmlns="jabber:client"/> <message xmlns="jabber:client"/>"#
+        );
     }
 
-    fn build_children(&mut self, element: &Element) -> String {
-        let mut xml = String::new();
+    #[derive(Clone, Default)]
+    struct CapturedLog(Arc<Mutex<Vec<u8>>>);
 
-        for child in &element.children {
-            match child {
-                Node::Element(child_element) => {
-                    xml.push_str(&self.build_xml_element(child_element));
-                }
-                Node::Text(text) => {
-                    xml.push_str(text);
-                }
-                Node::CData(cdata) => {
-                    xml.push_str(&format!("<![CDATA[{}]]>", cdata));
-                }
-                Node::Comment(comment) => {
-                    xml.push_str(&format!("<!--{}-->", comment));
-                }
-                Node::ProcessingInstruction(pi) => {
-                    xml.push_str(&format!("<?{}?>", pi));
-                }
-            }
+    impl std::io::Write for CapturedLog {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
         }
 
-        xml
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
     }
 
-    fn build_closing_tag(&mut self, element: &Element) -> String {
-        let mut xml = String::new();
+    async fn log_of_written_stanza(level: Level, stanza: Stanza) -> String {
+        let log = CapturedLog::default();
+        let writer = log.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(level)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+
+        let guard = tracing::subscriber::set_default(subscriber);
+        write_stanza(stanza).await;
+        drop(guard);
+
+        let log = log.0.lock().unwrap();
+        String::from_utf8(log.clone()).unwrap()
+    }
 
-        match &element.namespace {
-            Some(namespace) => match self.lookup_namespace_prefix(namespace) {
-                Some("") => {
-                    // Element is in the default namespace
-                    xml.push_str(&format!("</{}>", element.name));
-                }
-                Some(prefix) => {
-                    // Element is in a prefixed namespace
-                    xml.push_str(&format!("</{}:{}>", prefix, element.name));
-                }
-                None => {
-                    debug_assert!(false, "namespace not declared");
-                }
+    #[tokio::test]
+    async fn stanza_contents_are_only_logged_at_trace_level() {
+        let body = element(
+            "body",
+            namespaces::XMPP_CLIENT,
+            false,
+            vec![Node::Text("Wherefore art thou?".to_string())],
+        );
+        let message = element(
+            "message",
+            namespaces::XMPP_CLIENT,
+            true,
+            vec![Node::Element(body)],
+        );
+
+        let debug_log = log_of_written_stanza(
+            Level::DEBUG,
+            Stanza {
+                element: message.clone(),
             },
-            None => {
-                xml.push_str(&format!("</{}>", element.name));
-            }
-        }
+        )
+        .await;
+        assert!(debug_log.contains("sending stanza"));
+        assert!(!debug_log.contains("Wherefore"));
+
+        let trace_log = log_of_written_stanza(Level::TRACE, Stanza { element: message }).await;
+        assert!(trace_log.contains("Wherefore"));
+    }
+
+    #[tokio::test]
+    async fn header_carries_the_given_stream_id() {
+        let id = StreamId::new();
+        let header = StreamHeader {
+            from: Some("localhost".parse().unwrap()),
+            to: None,
+            id: Some(id.clone()),
+            language: None,
+            stream_namespace: None,
+            content_namespace: None,
+            version: None,
+        };
+
+        let mut writer = StreamWriter::new(Vec::new());
+        writer.write_stream_header(&header, false).await.unwrap();
+
+        let xml = String::from_utf8(writer.into_inner()).unwrap();
+        assert!(xml.contains(&format!(r#"id="{id}""#)));
+    }
+
+    #[tokio::test]
+    async fn header_is_in_the_given_language() {
+        let header = |language: Option<&str>| StreamHeader {
+            from: Some("localhost".parse().unwrap()),
+            to: None,
+            id: None,
+            language: language.map(|language| LanguageTag(language.to_string())),
+            stream_namespace: None,
+            content_namespace: None,
+            version: None,
+        };
+        let written = |header: StreamHeader| async move {
+            let mut writer = StreamWriter::new(Vec::new());
+            writer.write_stream_header(&header, false).await.unwrap();
+            String::from_utf8(writer.into_inner()).unwrap()
+        };
+
+        assert!(written(header(Some("de-CH")))
+            .await
+            .contains(r#"xml:lang="de-CH""#));
+        assert!(written(header(None)).await.contains(r#"xml:lang="en""#));
+    }
+
+    #[tokio::test]
+    async fn header_declares_the_given_content_namespace() {
+        let header = StreamHeader {
+            from: Some("localhost".parse().unwrap()),
+            to: None,
+            id: None,
+            language: None,
+            stream_namespace: None,
+            content_namespace: Some(namespaces::XMPP_COMPONENT_ACCEPT.to_string()),
+            version: None,
+        };
+
+        let mut writer = StreamWriter::new(Vec::new());
+        writer.write_stream_header(&header, false).await.unwrap();
+
+        let xml = String::from_utf8(writer.into_inner()).unwrap();
+        assert!(xml.contains(r#"xmlns="jabber:component:accept""#));
+        assert!(!xml.contains(namespaces::XMPP_CLIENT));
+    }
+
+    #[tokio::test]
+    async fn header_without_stream_id_gets_a_fresh_one() {
+        let header = StreamHeader {
+            from: Some("localhost".parse().unwrap()),
+            to: None,
+            id: None,
+            language: None,
+            stream_namespace: None,
+            content_namespace: None,
+            version: None,
+        };
+
+        let mut writer = StreamWriter::new(Vec::new());
+        writer.write_stream_header(&header, false).await.unwrap();
+
+        let xml = String::from_utf8(writer.into_inner()).unwrap();
+        let id = regex::Regex::new(r#" id="([^"]+)""#)
+            .unwrap()
+            .captures(&xml)
+            .map(|captures| captures[1].to_string());
+        assert!(id.is_some_and(|id| id.len() == 24));
+    }
+
+    #[test]
+    fn special_characters_survive_a_round_trip() {
+        let text = r#"<script>&""#;
+        let mut body = element("body", namespaces::XMPP_CLIENT, true, vec![]);
+        body.attributes
+            .insert(("id".to_string(), None), text.to_string());
+        body.children = vec![Node::Text(text.to_string()), Node::CData("]]>".to_string())];
 
-        self.namespaces.pop();
+        let xml = StreamWriter::new(Vec::new()).serialize_xml_element(&body);
+        let parsed = Element::parse(&xml).unwrap();
 
-        xml
+        assert_eq!(parsed.get_attribute("id", None), Some(text));
+        assert_eq!(parsed.get_text(), format!("{text}]]>"));
     }
 }
@@ -1,4 +1,10 @@
+pub mod delay;
+pub mod error_text;
 pub mod jid;
+pub mod roster;
 pub mod stanza;
+pub mod stanza_error;
 pub mod stream;
+pub mod stream_error;
+pub mod stream_features;
 pub mod stream_header;
@@ -0,0 +1,77 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::xml::{namespaces, Element};
+use crate::xmpp::jid::Jid;
+
+// XEP-0203: when and by whom a stanza that was not delivered right away was first received.
+pub fn delay_element(stamp: SystemTime, from: &Jid) -> Element {
+    Element {
+        name: "delay".to_string(),
+        namespace: Some(namespaces::DELAY.to_string()),
+        attributes: vec![
+            (("xmlns".to_string(), None), namespaces::DELAY.to_string()),
+            (("stamp".to_string(), None), timestamp(stamp)),
+            (("from".to_string(), None), from.to_string()),
+        ]
+        .into_iter()
+        .collect(),
+        children: vec![],
+    }
+}
+
+// An XEP-0082 DateTime in UTC with millisecond precision, e.g. `2002-09-10T23:08:25.000Z`.
+pub fn timestamp(time: SystemTime) -> String {
+    // times before the epoch do not occur in anything the server stores
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((seconds / 86_400) as i64);
+    let seconds_of_day = seconds % 86_400;
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        seconds_of_day / 3600,
+        seconds_of_day % 3600 / 60,
+        seconds_of_day % 60,
+        since_epoch.subsec_millis(),
+    )
+}
+
+// The proleptic Gregorian date of a day counted from 1970-01-01, after Howard Hinnant's
+// `civil_from_days`.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    // months counted from March, so the leap day comes last
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = (if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    }) as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn timestamps_are_utc_date_times() {
+        let at = |millis: u64| timestamp(UNIX_EPOCH + Duration::from_millis(millis));
+
+        assert_eq!(at(0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(at(1_031_699_305_042), "2002-09-10T23:08:25.042Z");
+        // the leap day of a century divisible by 400
+        assert_eq!(at(951_782_400_000), "2000-02-29T00:00:00.000Z");
+        assert_eq!(at(951_868_799_999), "2000-02-29T23:59:59.999Z");
+    }
+}
@@ -0,0 +1,124 @@
+use crate::xml::{namespaces, Element, Node};
+
+pub const DEFAULT_LANGUAGE: &str = "en";
+
+// Human-readable descriptions for error conditions, keyed by language and condition.
+fn lookup(language: &str, condition: &str) -> Option<&'static str> {
+    let text = match (language, condition) {
+        ("en", "bad-format") => "The XML sent could not be processed.",
+        ("en", "bad-namespace-prefix") => "The namespace prefix sent is not supported.",
+        ("en", "bad-request") => "The stanza is malformed or cannot be processed.",
+        ("en", "conflict") => "The resource is already in use by another session.",
+        ("en", "connection-timeout") => "No data has been received for too long.",
+        ("en", "host-unknown") => "The requested host is not served here.",
+        ("en", "internal-server-error") => "The server has experienced an internal error.",
+        ("en", "invalid-from") => "The sender address is not authorized on this stream.",
+        ("en", "jid-malformed") => "The address is malformed.",
+        ("en", "not-acceptable") => "The request lacks information the server requires.",
+        ("en", "not-authorized") => "Authentication is required before sending data.",
+        ("en", "not-well-formed") => "The XML sent is not well-formed.",
+        ("en", "policy-violation") => "A local service policy has been violated.",
+        ("en", "resource-constraint") => "The server is too busy to serve this stream.",
+        ("en", "service-unavailable") => "The requested service is not available.",
+        ("en", "system-shutdown") => "The server is shutting down.",
+        ("en", "unsupported-encoding") => "The stream encoding is not supported.",
+        ("de", "bad-format") => "Das gesendete XML konnte nicht verarbeitet werden.",
+        ("de", "bad-namespace-prefix") => "Das gesendete Namensraum-Präfix wird nicht unterstützt.",
+        ("de", "bad-request") => "Das Stanza ist fehlerhaft oder kann nicht verarbeitet werden.",
+        ("de", "conflict") => "Die Ressource wird bereits von einer anderen Sitzung verwendet.",
+        ("de", "connection-timeout") => "Es wurden zu lange keine Daten empfangen.",
+        ("de", "host-unknown") => "Der angefragte Host wird hier nicht bedient.",
+        ("de", "internal-server-error") => "Im Server ist ein interner Fehler aufgetreten.",
+        ("de", "invalid-from") => "Die Absenderadresse ist für diesen Stream nicht zulässig.",
+        ("de", "jid-malformed") => "Die Adresse ist fehlerhaft.",
+        ("de", "not-acceptable") => "Der Anfrage fehlen Angaben, die der Server benötigt.",
+        ("de", "not-authorized") => "Vor dem Senden von Daten ist eine Anmeldung erforderlich.",
+        ("de", "not-well-formed") => "Das gesendete XML ist nicht wohlgeformt.",
+        ("de", "policy-violation") => "Eine Richtlinie des Dienstes wurde verletzt.",
+        ("de", "resource-constraint") => "Der Server ist zu ausgelastet für diesen Stream.",
+        ("de", "service-unavailable") => "Der angefragte Dienst ist nicht verfügbar.",
+        ("de", "system-shutdown") => "Der Server wird heruntergefahren.",
+        ("de", "unsupported-encoding") => "Die Kodierung des Streams wird nicht unterstützt.",
+        _ => return None,
+    };
+
+    Some(text)
+}
+
+// Picks the text for the peer's language, matching on the primary subtag only and falling
+// back to English.
+pub fn localized_text(
+    condition: &str,
+    language: Option<&str>,
+) -> Option<(&'static str, &'static str)> {
+    let primary = language
+        .and_then(|language| language.split('-').next())
+        .map(str::to_ascii_lowercase);
+    let language = match primary.as_deref() {
+        Some("de") => "de",
+        _ => DEFAULT_LANGUAGE,
+    };
+
+    match lookup(language, condition) {
+        Some(text) => Some((language, text)),
+        None => lookup(DEFAULT_LANGUAGE, condition).map(|text| (DEFAULT_LANGUAGE, text)),
+    }
+}
+
+// The `<text/>` child of stream and stanza errors, qualified by the namespace of the condition.
+pub fn text_element(condition: &str, language: Option<&str>, namespace: &str) -> Option<Element> {
+    let (language, text) = localized_text(condition, language)?;
+
+    Some(custom_text_element(text, language, namespace))
+}
+
+// A `<text/>` child for a description that is more specific than the condition.
+pub fn custom_text_element(text: &str, language: &str, namespace: &str) -> Element {
+    Element {
+        name: "text".to_string(),
+        namespace: Some(namespace.to_string()),
+        attributes: vec![
+            (("xmlns".to_string(), None), namespace.to_string()),
+            (
+                ("lang".to_string(), Some(namespaces::XML.to_string())),
+                language.to_string(),
+            ),
+        ]
+        .into_iter()
+        .collect(),
+        children: vec![Node::Text(text.to_string())],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn english_is_the_default() {
+        assert_eq!(
+            localized_text("not-well-formed", None),
+            Some(("en", "The XML sent is not well-formed."))
+        );
+        assert_eq!(
+            localized_text("not-well-formed", Some("fr")),
+            Some(("en", "The XML sent is not well-formed."))
+        );
+    }
+
+    #[test]
+    fn language_is_matched_on_primary_subtag() {
+        assert_eq!(
+            localized_text("bad-request", Some("de-AT")),
+            Some((
+                "de",
+                "Das Stanza ist fehlerhaft oder kann nicht verarbeitet werden."
+            ))
+        );
+    }
+
+    #[test]
+    fn unknown_condition_has_no_text() {
+        assert_eq!(localized_text("remote-server-timeout", Some("de")), None);
+    }
+}
@@ -4,8 +4,31 @@ use std::{
 };
 
 use anyhow::{bail, Error};
-use regex::Regex;
 use serde_with::DeserializeFromStr;
+use unicode_normalization::UnicodeNormalization;
+
+const MAX_PART_LENGTH: usize = 1023;
+
+// The characters nodeprep prohibits, and the backslash introducing the escapes (XEP-0106).
+const ESCAPES: [(char, &str); 10] = [
+    (' ', "20"),
+    ('"', "22"),
+    ('&', "26"),
+    ('\'', "27"),
+    ('/', "2f"),
+    (':', "3a"),
+    ('<', "3c"),
+    ('>', "3e"),
+    ('@', "40"),
+    ('\\', "5c"),
+];
+
+fn escaped(sequence: &str) -> Option<char> {
+    ESCAPES
+        .iter()
+        .find(|(_, code)| sequence.eq_ignore_ascii_case(code))
+        .map(|(c, _)| *c)
+}
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 struct DomainPart(String);
@@ -50,6 +73,22 @@ impl Jid {
         }
     }
 
+    pub fn local(&self) -> Option<&str> {
+        self.local.as_ref().map(|local| local.0.as_str())
+    }
+
+    pub fn domain(&self) -> &str {
+        &self.domain.0
+    }
+
+    pub fn resource(&self) -> Option<&str> {
+        self.resource.as_ref().map(|resource| resource.0.as_str())
+    }
+
+    pub fn is_domain(&self) -> bool {
+        self.local.is_none() && self.resource.is_none()
+    }
+
     pub fn to_bare(&self) -> Self {
         Jid {
             local: self.local.clone(),
@@ -58,6 +97,121 @@ impl Jid {
         }
     }
 
+    pub fn domain_jid(&self) -> Self {
+        Jid {
+            local: None,
+            domain: self.domain.clone(),
+            resource: None,
+        }
+    }
+
+    // Prepares a requested resourcepart along the lines of the OpaqueString profile it is
+    // enforced with (RFC 7622, section 3.4): non-ASCII spaces become ASCII ones and the result
+    // is in NFC.
+    pub fn normalize_resource(resource: &str) -> Result<String, Error> {
+        if resource.chars().any(char::is_control) {
+            bail!("resourcepart must not contain control characters");
+        }
+
+        let resource: String = resource
+            .chars()
+            .map(|c| if c.is_whitespace() { ' ' } else { c })
+            .nfc()
+            .collect();
+        if resource.is_empty() {
+            bail!("resourcepart must not be empty");
+        }
+        if resource.len() > MAX_PART_LENGTH {
+            bail!("resourcepart must not be longer than {MAX_PART_LENGTH} bytes");
+        }
+
+        Ok(resource)
+    }
+
+    // Applies nodeprep, IDNA and resourceprep (RFC 6122, appendices A to C), so equivalent
+    // addresses end up equal. The localpart is case-folded and the domainpart lowercased and
+    // turned into U-labels.
+    pub fn from_parts(
+        local: Option<&str>,
+        domain: &str,
+        resource: Option<&str>,
+    ) -> Result<Self, Error> {
+        let local = local
+            .map(|local| match stringprep::nodeprep(local) {
+                Ok(local) => check_length("localpart", local.into_owned()),
+                Err(err) => bail!("invalid localpart: {err}"),
+            })
+            .transpose()?;
+
+        let (domain, result) = idna::domain_to_unicode(domain.strip_suffix('.').unwrap_or(domain));
+        if let Err(err) = result {
+            bail!("invalid domainpart: {err:?}");
+        }
+        if domain.chars().any(char::is_control) {
+            bail!("domainpart must not contain control characters");
+        }
+        let domain = check_length("domainpart", domain)?;
+
+        let resource = resource
+            .map(|resource| match stringprep::resourceprep(resource) {
+                Ok(resource) => check_length("resourcepart", resource.into_owned()),
+                Err(err) => bail!("invalid resourcepart: {err}"),
+            })
+            .transpose()?;
+
+        Ok(Jid {
+            local: local.map(LocalPart),
+            domain: DomainPart(domain),
+            resource: resource.map(ResourcePart),
+        })
+    }
+
+    // Turns a localpart with characters that are not allowed in JIDs into its escaped form
+    // (XEP-0106, section 4.2). A backslash is only escaped where it would otherwise start an
+    // escape sequence.
+    pub fn escape_localpart(local: &str) -> Result<String, Error> {
+        if local.starts_with(' ') || local.ends_with(' ') {
+            bail!("localpart must not start or end with a space");
+        }
+
+        let mut escaped_local = String::with_capacity(local.len());
+        for (i, c) in local.char_indices() {
+            let starts_sequence = local.get(i + 1..i + 3).and_then(escaped).is_some();
+            match ESCAPES.iter().find(|(escapable, _)| *escapable == c) {
+                Some(('\\', _)) if !starts_sequence => escaped_local.push(c),
+                Some((_, code)) => {
+                    escaped_local.push('\\');
+                    escaped_local.push_str(code);
+                }
+                None => escaped_local.push(c),
+            }
+        }
+
+        Ok(escaped_local)
+    }
+
+    // Anything that looks like an escape but is not one is left as it is (XEP-0106, section
+    // 4.3).
+    pub fn unescape_localpart(local: &str) -> String {
+        let mut unescaped = String::with_capacity(local.len());
+        let mut rest = local;
+        while let Some(c) = rest.chars().next() {
+            let sequence = rest.get(1..3).filter(|_| c == '\\').and_then(escaped);
+            match sequence {
+                Some(escaped) => {
+                    unescaped.push(escaped);
+                    rest = &rest[3..];
+                }
+                None => {
+                    unescaped.push(c);
+                    rest = &rest[c.len_utf8()..];
+                }
+            }
+        }
+
+        unescaped
+    }
+
     pub fn bind(&self, resource: String) -> Self {
         Jid {
             local: self.local.clone(),
@@ -70,30 +224,38 @@ impl Jid {
 impl FromStr for Jid {
     type Err = Error;
 
+    // The resourcepart is everything after the first `/`, so it may contain `/` and `@`
+    // itself, and only then is the localpart split off at the first `@` (RFC 7622, section 3.1).
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let regex = Regex::new("(?:(?P<local>.+)@)?(?P<domain>.+)(?:/(?P<resource>.+))?").unwrap();
-        match regex.captures(s) {
-            Some(captures) => {
-                let local = captures
-                    .name("local")
-                    .map(|m| LocalPart(m.as_str().to_string()));
-                let domain = captures
-                    .name("domain")
-                    .map(|m| DomainPart(m.as_str().to_string()))
-                    .unwrap();
-                let resource = captures
-                    .name("resource")
-                    .map(|m| ResourcePart(m.as_str().to_string()));
-
-                Ok(Jid {
-                    local,
-                    domain,
-                    resource,
-                })
-            }
-            None => bail!("Could not parse JID: \"{s}\""),
+        let (rest, resource) = match s.split_once('/') {
+            Some((rest, resource)) => (rest, Some(resource)),
+            None => (s, None),
+        };
+        let (local, domain) = match rest.split_once('@') {
+            Some((local, domain)) => (Some(local), domain),
+            None => (None, rest),
+        };
+
+        if domain.is_empty() || domain.contains('@') {
+            bail!("Could not parse JID: \"{s}\"");
+        }
+        if local == Some("") || resource == Some("") {
+            bail!("Could not parse JID: \"{s}\"");
         }
+
+        Jid::from_parts(local, domain, resource)
+    }
+}
+
+fn check_length(part: &str, value: String) -> Result<String, Error> {
+    if value.is_empty() {
+        bail!("{part} must not be empty");
     }
+    if value.len() > MAX_PART_LENGTH {
+        bail!("{part} must not be longer than {MAX_PART_LENGTH} bytes");
+    }
+
+    Ok(value)
 }
 
 impl Display for Jid {
@@ -112,6 +274,8 @@ impl Display for Jid {
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashSet;
+
     use super::Jid;
 
     #[test]
@@ -119,4 +283,180 @@ mod tests {
         let result = "".parse::<Jid>();
         assert!(result.is_err());
     }
+
+    fn jid(local: Option<&str>, domain: &str, resource: Option<&str>) -> Jid {
+        Jid::new(
+            local.map(str::to_string),
+            domain.to_string(),
+            resource.map(str::to_string),
+        )
+    }
+
+    #[test]
+    fn parse_bare_jid() {
+        let parsed = "juliet@example.com".parse::<Jid>().unwrap();
+        assert_eq!(parsed, jid(Some("juliet"), "example.com", None));
+    }
+
+    #[test]
+    fn parse_full_jid() {
+        let parsed = "juliet@example.com/balcony".parse::<Jid>().unwrap();
+        assert_eq!(parsed, jid(Some("juliet"), "example.com", Some("balcony")));
+    }
+
+    #[test]
+    fn parse_domain_jid() {
+        let parsed = "example.com".parse::<Jid>().unwrap();
+        assert_eq!(parsed, jid(None, "example.com", None));
+
+        let parsed = "example.com/foo".parse::<Jid>().unwrap();
+        assert_eq!(parsed, jid(None, "example.com", Some("foo")));
+    }
+
+    #[test]
+    fn resource_may_contain_separators() {
+        let parsed = "juliet@example.com/foo/bar@baz".parse::<Jid>().unwrap();
+        assert_eq!(
+            parsed,
+            jid(Some("juliet"), "example.com", Some("foo/bar@baz"))
+        );
+        assert_eq!(parsed.to_string(), "juliet@example.com/foo/bar@baz");
+    }
+
+    #[test]
+    fn fail_on_empty_parts() {
+        for s in [
+            "@example.com",
+            "juliet@",
+            "juliet@/balcony",
+            "example.com/",
+            "/balcony",
+        ] {
+            assert!(s.parse::<Jid>().is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn fail_on_second_at_sign() {
+        assert!("juliet@capulet@example.com".parse::<Jid>().is_err());
+    }
+
+    #[test]
+    fn domain_is_case_insensitive() {
+        let upper = "juliet@EXAMPLE.com".parse::<Jid>().unwrap();
+        let lower = "juliet@example.com".parse::<Jid>().unwrap();
+        assert_eq!(upper, lower);
+        assert_eq!(upper.to_string(), "juliet@example.com");
+
+        let jids: HashSet<_> = [upper, lower].into_iter().collect();
+        assert_eq!(jids.len(), 1);
+    }
+
+    #[test]
+    fn localpart_is_case_folded_but_resource_is_not() {
+        let jid = "Juliet@example.com/Balcony".parse::<Jid>().unwrap();
+        assert_eq!(jid.to_string(), "juliet@example.com/Balcony");
+        assert_eq!(jid.to_bare(), "JULIET@Example.COM".parse::<Jid>().unwrap());
+    }
+
+    #[test]
+    fn domain_a_labels_become_u_labels() {
+        let jid = "xn--mnchen-3ya.example".parse::<Jid>().unwrap();
+        assert_eq!(jid, "M\u{fc}nchen.example".parse::<Jid>().unwrap());
+        assert_eq!(jid.domain(), "m\u{fc}nchen.example");
+    }
+
+    #[test]
+    fn fail_on_control_characters() {
+        for s in [
+            "jul\u{7}iet@example.com",
+            "juliet@exam\u{0}ple.com",
+            "juliet@example.com/bal\u{1b}cony",
+        ] {
+            assert!(s.parse::<Jid>().is_err(), "{s:?}");
+        }
+    }
+
+    #[test]
+    fn fail_on_prohibited_localpart_characters() {
+        assert!("jul\"iet@example.com".parse::<Jid>().is_err());
+        assert!("jul:iet@example.com".parse::<Jid>().is_err());
+    }
+
+    // XEP-0106, section 5
+    const ESCAPING_EXAMPLES: [(&str, &str); 12] = [
+        ("space cadet", r"space\20cadet"),
+        (r#"call me "ishmael""#, r"call\20me\20\22ishmael\22"),
+        ("at&t guy", r"at\26t\20guy"),
+        ("d'artagnan", r"d\27artagnan"),
+        ("/.fanboy", r"\2f.fanboy"),
+        ("::foo::", r"\3a\3afoo\3a\3a"),
+        ("<foo>", r"\3cfoo\3e"),
+        ("user@host", r"user\40host"),
+        (r"c:\net", r"c\3a\net"),
+        (r"c:\\net", r"c\3a\\net"),
+        (r"c:\cool stuff", r"c\3a\cool\20stuff"),
+        (r"c:\5commas", r"c\3a\5c5commas"),
+    ];
+
+    #[test]
+    fn escape_localpart_examples() {
+        for (unescaped, escaped) in ESCAPING_EXAMPLES {
+            assert_eq!(Jid::escape_localpart(unescaped).unwrap(), escaped);
+        }
+    }
+
+    #[test]
+    fn unescape_localpart_examples() {
+        for (unescaped, escaped) in ESCAPING_EXAMPLES {
+            assert_eq!(Jid::unescape_localpart(escaped), unescaped);
+        }
+    }
+
+    #[test]
+    fn escaped_localparts_round_trip_through_parsing() {
+        for (_, escaped) in ESCAPING_EXAMPLES {
+            let jid = format!("{escaped}@example.com");
+            assert_eq!(jid.parse::<Jid>().unwrap().to_string(), jid);
+        }
+    }
+
+    #[test]
+    fn uppercase_escapes_are_unescaped() {
+        assert_eq!(Jid::unescape_localpart(r"d\27Artagnan\2F"), "d'Artagnan/");
+    }
+
+    #[test]
+    fn surrounding_spaces_cannot_be_escaped() {
+        assert!(Jid::escape_localpart(" space cadet").is_err());
+        assert!(Jid::escape_localpart("space cadet ").is_err());
+    }
+
+    #[test]
+    fn domain_jid_drops_local_and_resource() {
+        let jid = Jid::new(
+            Some("juliet".to_string()),
+            "example.com".to_string(),
+            Some("balcony".to_string()),
+        );
+
+        let domain_jid = jid.domain_jid();
+
+        assert!(domain_jid.is_domain());
+        assert_eq!(domain_jid.to_string(), "example.com");
+    }
+
+    #[test]
+    fn resource_is_normalized() {
+        let resource = Jid::normalize_resource("Romeo\u{3000}Cafe\u{301}").unwrap();
+        assert_eq!(resource, "Romeo Caf\u{e9}");
+    }
+
+    #[test]
+    fn invalid_resources_are_rejected() {
+        assert!(Jid::normalize_resource("").is_err());
+        assert!(Jid::normalize_resource(&"a".repeat(1024)).is_err());
+        assert!(Jid::normalize_resource("bal\u{0}cony").is_err());
+        assert!(Jid::normalize_resource(&"a".repeat(1023)).is_ok());
+    }
 }
@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error};
+
+use crate::xml::{namespaces, Element, Node};
+use crate::xmpp::jid::Jid;
+use crate::xmpp::stanza_error::StanzaError;
+
+// RFC 6121, section 2.1.2.5. Roster sets may only ask for an item to be removed, everything
+// else follows from subscription requests.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Subscription {
+    #[default]
+    None,
+    To,
+    From,
+    Both,
+}
+
+impl Subscription {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Subscription::None => "none",
+            Subscription::To => "to",
+            Subscription::From => "from",
+            Subscription::Both => "both",
+        }
+    }
+}
+
+impl Display for Subscription {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for Subscription {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Subscription::None),
+            "to" => Ok(Subscription::To),
+            "from" => Ok(Subscription::From),
+            "both" => Ok(Subscription::Both),
+            _ => Err(anyhow!("unknown subscription state `{s}`")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RosterItem {
+    // always a bare JID
+    pub jid: Jid,
+    pub name: Option<String>,
+    pub subscription: Subscription,
+    pub groups: Vec<String>,
+}
+
+impl RosterItem {
+    pub fn to_element(&self) -> Element {
+        let mut attributes = HashMap::new();
+        attributes.insert(("jid".to_string(), None), self.jid.to_string());
+        attributes.insert(
+            ("subscription".to_string(), None),
+            self.subscription.to_string(),
+        );
+        if let Some(name) = &self.name {
+            attributes.insert(("name".to_string(), None), name.clone());
+        }
+
+        let groups = self
+            .groups
+            .iter()
+            .map(|group| {
+                Node::Element(Element {
+                    name: "group".to_string(),
+                    namespace: Some(namespaces::ROSTER.to_string()),
+                    attributes: HashMap::new(),
+                    children: vec![Node::Text(group.clone())],
+                })
+            })
+            .collect();
+
+        Element {
+            name: "item".to_string(),
+            namespace: Some(namespaces::ROSTER.to_string()),
+            attributes,
+            children: groups,
+        }
+    }
+}
+
+// The items of an account's roster, along with its version (XEP-0237), which goes up with every
+// change.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Roster {
+    pub version: u64,
+    pub items: Vec<RosterItem>,
+}
+
+// What a roster set asks for (RFC 6121, section 2.3.2).
+#[derive(Debug, PartialEq, Eq)]
+pub enum RosterSet {
+    Update(RosterItem),
+    Remove(Jid),
+}
+
+impl TryFrom<&Element> for RosterSet {
+    type Error = StanzaError;
+
+    fn try_from(query: &Element) -> Result<Self, Self::Error> {
+        let mut items = query.find_children("item", Some(namespaces::ROSTER));
+        let (Some(item), None) = (items.next(), items.next()) else {
+            return Err(StanzaError::BadRequest);
+        };
+
+        let jid = item
+            .get_attribute("jid", None)
+            .ok_or(StanzaError::BadRequest)?
+            .parse::<Jid>()
+            .map_err(|_| StanzaError::JidMalformed)?
+            .to_bare();
+
+        // any other subscription is the server's to manage, so it is ignored (RFC 6121,
+        // section 2.1.2.5)
+        if item.get_attribute("subscription", None) == Some("remove") {
+            return Ok(RosterSet::Remove(jid));
+        }
+
+        let mut groups: Vec<String> = Vec::new();
+        for group in item.find_children("group", Some(namespaces::ROSTER)) {
+            let group = group.get_text();
+            if group.is_empty() {
+                return Err(StanzaError::NotAcceptable);
+            }
+            if groups.contains(&group) {
+                return Err(StanzaError::BadRequest);
+            }
+            groups.push(group);
+        }
+
+        Ok(RosterSet::Update(RosterItem {
+            jid,
+            name: item
+                .get_attribute("name", None)
+                .filter(|name| !name.is_empty())
+                .map(str::to_string),
+            subscription: Subscription::None,
+            groups,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(xml: &str) -> Result<RosterSet, StanzaError> {
+        RosterSet::try_from(&Element::parse(xml).unwrap())
+    }
+
+    #[test]
+    fn parses_an_update() {
+        let set = parse(
+            "<query xmlns='jabber:iq:roster'>\
+                <item jid='nurse@example.com/chamber' name='Nurse'>\
+                    <group>Servants</group><group>Family</group>\
+                </item>\
+            </query>",
+        );
+
+        assert_eq!(
+            set,
+            Ok(RosterSet::Update(RosterItem {
+                jid: "nurse@example.com".parse().unwrap(),
+                name: Some("Nurse".to_string()),
+                subscription: Subscription::None,
+                groups: vec!["Servants".to_string(), "Family".to_string()],
+            }))
+        );
+    }
+
+    #[test]
+    fn parses_a_removal() {
+        let set = parse(
+            "<query xmlns='jabber:iq:roster'>\
+                <item jid='nurse@example.com' subscription='remove'/>\
+            </query>",
+        );
+
+        assert_eq!(
+            set,
+            Ok(RosterSet::Remove("nurse@example.com".parse().unwrap()))
+        );
+    }
+
+    #[test]
+    fn ignores_subscriptions_other_than_remove() {
+        let set = parse(
+            "<query xmlns='jabber:iq:roster'>\
+                <item jid='nurse@example.com' name='Nurse' subscription='both'/>\
+            </query>",
+        );
+
+        assert_eq!(
+            set,
+            Ok(RosterSet::Update(RosterItem {
+                jid: "nurse@example.com".parse().unwrap(),
+                name: Some("Nurse".to_string()),
+                subscription: Subscription::None,
+                groups: vec![],
+            }))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_sets() {
+        assert_eq!(
+            parse("<query xmlns='jabber:iq:roster'/>"),
+            Err(StanzaError::BadRequest)
+        );
+        assert_eq!(
+            parse(
+                "<query xmlns='jabber:iq:roster'>\
+                    <item jid='nurse@example.com'/><item jid='romeo@example.net'/>\
+                </query>"
+            ),
+            Err(StanzaError::BadRequest)
+        );
+        assert_eq!(
+            parse(
+                "<query xmlns='jabber:iq:roster'>\
+                    <item jid='nurse@example.com'><group>A</group><group>A</group></item>\
+                </query>"
+            ),
+            Err(StanzaError::BadRequest)
+        );
+        assert_eq!(
+            parse(
+                "<query xmlns='jabber:iq:roster'>\
+                    <item jid='nurse@example.com'><group/></item>\
+                </query>"
+            ),
+            Err(StanzaError::NotAcceptable)
+        );
+    }
+}
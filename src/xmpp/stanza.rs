@@ -1,6 +1,541 @@
-use crate::xml::Element;
+use std::collections::HashMap;
+use std::time::SystemTime;
 
-#[derive(Debug)]
+use crate::xml::{namespaces, Element, Node};
+use crate::xmpp::delay::delay_element;
+use crate::xmpp::jid::Jid;
+use crate::xmpp::stanza_error::{StanzaError, StanzaErrorBuilder};
+
+#[derive(Debug, Clone)]
 pub struct Stanza {
     pub element: Element,
 }
+
+impl Stanza {
+    pub fn from(&self) -> Result<Option<Jid>, StanzaError> {
+        self.address("from")
+    }
+
+    pub fn to(&self) -> Result<Option<Jid>, StanzaError> {
+        self.address("to")
+    }
+
+    fn address(&self, attribute: &str) -> Result<Option<Jid>, StanzaError> {
+        self.element
+            .get_attribute(attribute, None)
+            .map(|address| address.parse().map_err(|_| StanzaError::JidMalformed))
+            .transpose()
+    }
+
+    pub fn set_from(&mut self, from: &Jid) {
+        self.element
+            .attributes
+            .insert(("from".to_string(), None), from.to_string());
+    }
+
+    pub fn set_to(&mut self, to: &Jid) {
+        self.element
+            .attributes
+            .insert(("to".to_string(), None), to.to_string());
+    }
+
+    pub fn with_from(&self, from: &Jid) -> Stanza {
+        let mut stanza = self.clone();
+        stanza.set_from(from);
+        stanza
+    }
+
+    pub fn with_to(&self, to: &Jid) -> Stanza {
+        let mut stanza = self.clone();
+        stanza.set_to(to);
+        stanza
+    }
+
+    // A missing `to` addresses the sender's own account (RFC 6120, section 10.3), except for
+    // presence, where it means a broadcast to subscribers.
+    pub fn default_to(&mut self, account: &Jid) {
+        if self.element.name == "presence" || self.element.get_attribute("to", None).is_some() {
+            return;
+        }
+
+        self.set_to(&account.to_bare());
+    }
+
+    // RFC 6120, section 8.2.3 and RFC 6121, sections 4.7.1 and 5.2.2
+    pub fn validate_type(&self) -> Result<(), StanzaError> {
+        let stanza_type = self.element.get_attribute("type", None);
+        let valid = match self.element.name.as_str() {
+            "message" => matches!(
+                stanza_type,
+                None | Some("chat" | "error" | "groupchat" | "headline" | "normal")
+            ),
+            "presence" => matches!(
+                stanza_type,
+                None | Some(
+                    "error"
+                        | "probe"
+                        | "subscribe"
+                        | "subscribed"
+                        | "unavailable"
+                        | "unsubscribe"
+                        | "unsubscribed"
+                )
+            ),
+            "iq" => matches!(stanza_type, Some("get" | "set" | "result" | "error")),
+            _ => false,
+        };
+
+        if !valid {
+            return Err(StanzaError::BadRequest);
+        }
+
+        Ok(())
+    }
+
+    // The priority announced by an available presence (RFC 6121, section 4.7.2.3), or `None`
+    // if the presence makes the resource unavailable.
+    pub fn presence_priority(&self) -> Option<i8> {
+        if self.element.get_attribute("type", None) == Some("unavailable") {
+            return None;
+        }
+
+        let priority = self
+            .element
+            .get_child("priority", self.element.namespace.as_deref())
+            .and_then(|priority| priority.get_text().trim().parse().ok())
+            .unwrap_or(0);
+        Some(priority)
+    }
+
+    // Errors must not be answered with errors (RFC 6120, section 8.3.1), and neither is
+    // anything that is not a stanza in the first place.
+    pub fn error_reply(
+        &self,
+        error: impl Into<StanzaErrorBuilder>,
+        language: Option<&str>,
+    ) -> Option<Stanza> {
+        if !matches!(self.element.name.as_str(), "message" | "presence" | "iq")
+            || self.element.get_attribute("type", None) == Some("error")
+        {
+            return None;
+        }
+
+        let error = error
+            .into()
+            .to_element(self.element.namespace.as_deref(), language);
+        Some(self.reply("error", vec![Node::Element(error)]))
+    }
+
+    // The answer to an IQ request that has been handled.
+    pub fn result_reply(&self, children: Vec<Node>) -> Stanza {
+        self.reply("result", children)
+    }
+
+    fn reply(&self, reply_type: &str, children: Vec<Node>) -> Stanza {
+        let mut attributes = HashMap::new();
+        attributes.insert(("type".to_string(), None), reply_type.to_string());
+        for (from, to) in [("id", "id"), ("from", "to"), ("to", "from")] {
+            if let Some(value) = self.element.get_attribute(from, None) {
+                attributes.insert((to.to_string(), None), value.to_string());
+            }
+        }
+
+        Stanza {
+            element: Element {
+                name: self.element.name.clone(),
+                namespace: self.element.namespace.clone(),
+                attributes,
+                children,
+            },
+        }
+    }
+
+    // IQ requests have to be answered even if nothing handles them (RFC 6120, section 8.2.3).
+    pub fn unhandled_reply(&self, language: Option<&str>) -> Option<Stanza> {
+        if self.element.name != "iq"
+            || !matches!(
+                self.element.get_attribute("type", None),
+                Some("get" | "set")
+            )
+        {
+            return None;
+        }
+
+        self.error_reply(StanzaError::ServiceUnavailable, language)
+    }
+
+    pub fn stamp_stanza_id(&mut self, by: &Jid) {
+        let by = by.to_string();
+
+        // Any stanza id claiming to be assigned by us was not, so it must not be passed on
+        self.element.children.retain(|child| match child {
+            Node::Element(element) => {
+                element.name != "stanza-id"
+                    || element.namespace.as_deref() != Some(namespaces::STANZA_ID)
+                    || element.get_attribute("by", None) != Some(by.as_str())
+            }
+            _ => true,
+        });
+
+        self.element.children.push(Node::Element(Element {
+            name: "stanza-id".to_string(),
+            namespace: Some(namespaces::STANZA_ID.to_string()),
+            attributes: vec![
+                (
+                    ("xmlns".to_string(), None),
+                    namespaces::STANZA_ID.to_string(),
+                ),
+                (("id".to_string(), None), uuid::Uuid::new_v4().to_string()),
+                (("by".to_string(), None), by),
+            ]
+            .into_iter()
+            .collect(),
+            children: vec![],
+        }));
+    }
+
+    // Marks a stanza delivered later than `from` received it (XEP-0203).
+    pub fn add_delay(&mut self, stamp: SystemTime, from: &Jid) {
+        self.element
+            .children
+            .push(Node::Element(delay_element(stamp, from)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::xml::stream_writer::StreamWriter;
+
+    use super::*;
+
+    fn stanza_id(by: &str) -> Node {
+        Node::Element(Element {
+            name: "stanza-id".to_string(),
+            namespace: Some(namespaces::STANZA_ID.to_string()),
+            attributes: vec![
+                (("id".to_string(), None), "spoofed".to_string()),
+                (("by".to_string(), None), by.to_string()),
+            ]
+            .into_iter()
+            .collect(),
+            children: vec![],
+        })
+    }
+
+    #[test]
+    fn stamping_preserves_ids_and_replaces_spoofed_stanza_ids() {
+        let origin_id = Node::Element(Element {
+            name: "origin-id".to_string(),
+            namespace: Some(namespaces::STANZA_ID.to_string()),
+            attributes: vec![(("id".to_string(), None), "origin".to_string())]
+                .into_iter()
+                .collect(),
+            children: vec![],
+        });
+        let mut stanza = Stanza {
+            element: Element {
+                name: "message".to_string(),
+                namespace: Some(namespaces::XMPP_CLIENT.to_string()),
+                attributes: vec![(("id".to_string(), None), "abc".to_string())]
+                    .into_iter()
+                    .collect(),
+                children: vec![
+                    origin_id,
+                    stanza_id("localhost"),
+                    stanza_id("muc.localhost"),
+                ],
+            },
+        };
+
+        stanza.stamp_stanza_id(&"localhost".parse().unwrap());
+
+        assert_eq!(stanza.element.get_attribute("id", None), Some("abc"));
+        let origin_id = stanza
+            .element
+            .get_child("origin-id", Some(namespaces::STANZA_ID))
+            .unwrap();
+        assert_eq!(origin_id.get_attribute("id", None), Some("origin"));
+
+        let stanza_ids = stanza
+            .element
+            .children
+            .iter()
+            .filter_map(|child| match child {
+                Node::Element(element) if element.name == "stanza-id" => Some(element),
+                _ => None,
+            })
+            .map(|element| {
+                (
+                    element.get_attribute("by", None).unwrap(),
+                    element.get_attribute("id", None).unwrap(),
+                )
+            })
+            .collect::<HashMap<_, _>>();
+        assert_eq!(stanza_ids.len(), 2);
+        assert_eq!(stanza_ids["muc.localhost"], "spoofed");
+        assert_ne!(stanza_ids["localhost"], "spoofed");
+    }
+
+    fn stanza(name: &str, to: Option<&str>, children: Vec<Node>) -> Stanza {
+        let mut attributes = HashMap::new();
+        attributes.insert(("id".to_string(), None), "abc".to_string());
+        if let Some(to) = to {
+            attributes.insert(("to".to_string(), None), to.to_string());
+        }
+
+        Stanza {
+            element: Element {
+                name: name.to_string(),
+                namespace: Some(namespaces::XMPP_CLIENT.to_string()),
+                attributes,
+                children,
+            },
+        }
+    }
+
+    #[test]
+    fn roster_iq_without_to_is_addressed_to_account() {
+        let query = Node::Element(Element {
+            name: "query".to_string(),
+            namespace: Some(namespaces::ROSTER.to_string()),
+            attributes: HashMap::new(),
+            children: vec![],
+        });
+        let mut stanza = stanza("iq", None, vec![query]);
+
+        stanza.default_to(&"juliet@example.com".parse().unwrap());
+
+        assert_eq!(
+            stanza.element.get_attribute("to", None),
+            Some("juliet@example.com")
+        );
+    }
+
+    #[test]
+    fn explicit_to_is_preserved() {
+        let mut stanza = stanza("message", Some("romeo@example.net"), vec![]);
+
+        stanza.default_to(&"juliet@example.com".parse().unwrap());
+
+        assert_eq!(
+            stanza.element.get_attribute("to", None),
+            Some("romeo@example.net")
+        );
+    }
+
+    #[test]
+    fn presence_broadcast_is_not_addressed() {
+        let mut stanza = stanza("presence", None, vec![]);
+
+        stanza.default_to(&"juliet@example.com".parse().unwrap());
+
+        assert_eq!(stanza.element.get_attribute("to", None), None);
+    }
+
+    fn typed(name: &str, stanza_type: Option<&str>) -> Stanza {
+        let mut stanza = stanza(name, Some("romeo@example.net"), vec![]);
+        if let Some(stanza_type) = stanza_type {
+            stanza
+                .element
+                .attributes
+                .insert(("type".to_string(), None), stanza_type.to_string());
+        }
+        stanza
+    }
+
+    #[test]
+    fn message_types_are_validated() {
+        assert_eq!(typed("message", None).validate_type(), Ok(()));
+        assert_eq!(typed("message", Some("chat")).validate_type(), Ok(()));
+        assert_eq!(
+            typed("message", Some("bogus")).validate_type(),
+            Err(StanzaError::BadRequest)
+        );
+    }
+
+    #[test]
+    fn presence_types_are_validated() {
+        assert_eq!(typed("presence", None).validate_type(), Ok(()));
+        assert_eq!(typed("presence", Some("subscribe")).validate_type(), Ok(()));
+        assert_eq!(
+            typed("presence", Some("available")).validate_type(),
+            Err(StanzaError::BadRequest)
+        );
+    }
+
+    #[test]
+    fn iq_types_are_validated() {
+        assert_eq!(typed("iq", Some("get")).validate_type(), Ok(()));
+        assert_eq!(typed("iq", Some("result")).validate_type(), Ok(()));
+        assert_eq!(
+            typed("iq", None).validate_type(),
+            Err(StanzaError::BadRequest)
+        );
+        assert_eq!(
+            typed("iq", Some("chat")).validate_type(),
+            Err(StanzaError::BadRequest)
+        );
+    }
+
+    #[test]
+    fn error_reply_is_addressed_to_sender() {
+        let mut stanza = typed("iq", Some("bogus"));
+        stanza
+            .element
+            .attributes
+            .insert(("from".to_string(), None), "juliet@example.com".to_string());
+
+        let reply = stanza.error_reply(StanzaError::BadRequest, None).unwrap();
+
+        assert_eq!(reply.element.name, "iq");
+        assert_eq!(reply.element.get_attribute("type", None), Some("error"));
+        assert_eq!(reply.element.get_attribute("id", None), Some("abc"));
+        assert_eq!(
+            reply.element.get_attribute("to", None),
+            Some("juliet@example.com")
+        );
+        assert_eq!(
+            reply.element.get_attribute("from", None),
+            Some("romeo@example.net")
+        );
+        let condition = reply.element.path(&[
+            ("error", Some(namespaces::XMPP_CLIENT)),
+            ("bad-request", Some(namespaces::XMPP_STANZAS)),
+        ]);
+        assert!(condition.is_some());
+    }
+
+    #[test]
+    fn message_error_reply_keeps_the_message_namespace() {
+        let mut stanza = typed("message", Some("chat"));
+        stanza
+            .element
+            .attributes
+            .insert(("from".to_string(), None), "juliet@example.com".to_string());
+
+        let reply = stanza
+            .error_reply(StanzaError::RecipientUnavailable, None)
+            .unwrap();
+
+        assert_eq!(reply.element.name, "message");
+        assert_eq!(reply.element.get_attribute("type", None), Some("error"));
+        assert_eq!(reply.element.get_attribute("id", None), Some("abc"));
+        assert_eq!(
+            reply.element.get_attribute("to", None),
+            Some("juliet@example.com")
+        );
+        assert_eq!(
+            reply.element.get_attribute("from", None),
+            Some("romeo@example.net")
+        );
+        let error = reply
+            .element
+            .get_child("error", Some(namespaces::XMPP_CLIENT))
+            .unwrap();
+        assert_eq!(error.get_attribute("type", None), Some("wait"));
+        assert!(error
+            .get_child("recipient-unavailable", Some(namespaces::XMPP_STANZAS))
+            .is_some());
+    }
+
+    #[test]
+    fn unhandled_iq_request_is_service_unavailable() {
+        let mut stanza = typed("iq", Some("get"));
+        stanza
+            .element
+            .attributes
+            .insert(("from".to_string(), None), "juliet@example.com".to_string());
+
+        let reply = stanza.unhandled_reply(None).unwrap();
+
+        assert_eq!(reply.element.get_attribute("type", None), Some("error"));
+        assert_eq!(reply.element.get_attribute("id", None), Some("abc"));
+        assert_eq!(
+            reply.element.get_attribute("to", None),
+            Some("juliet@example.com")
+        );
+        assert_eq!(
+            reply.element.get_attribute("from", None),
+            Some("romeo@example.net")
+        );
+        let condition = reply.element.path(&[
+            ("error", Some(namespaces::XMPP_CLIENT)),
+            ("service-unavailable", Some(namespaces::XMPP_STANZAS)),
+        ]);
+        assert!(condition.is_some());
+    }
+
+    #[test]
+    fn iq_responses_are_not_answered() {
+        assert!(typed("iq", Some("result")).unhandled_reply(None).is_none());
+        assert!(typed("iq", Some("error")).unhandled_reply(None).is_none());
+        assert!(typed("message", None).unhandled_reply(None).is_none());
+    }
+
+    #[test]
+    fn errors_are_not_answered() {
+        let stanza = typed("message", Some("error"));
+        assert!(stanza.error_reply(StanzaError::BadRequest, None).is_none());
+    }
+
+    #[test]
+    fn presence_priority_defaults_to_zero() {
+        let mut presence = typed("presence", None);
+        assert_eq!(presence.presence_priority(), Some(0));
+
+        presence.element.children.push(Node::Element(Element {
+            name: "priority".to_string(),
+            namespace: Some(namespaces::XMPP_CLIENT.to_string()),
+            attributes: HashMap::new(),
+            children: vec![Node::Text("-1".to_string())],
+        }));
+        assert_eq!(presence.presence_priority(), Some(-1));
+
+        let unavailable = typed("presence", Some("unavailable"));
+        assert_eq!(unavailable.presence_priority(), None);
+    }
+
+    #[test]
+    fn absent_and_malformed_addresses_are_reported() {
+        let mut stanza = stanza("message", None, vec![]);
+        assert_eq!(stanza.to(), Ok(None));
+
+        stanza
+            .element
+            .attributes
+            .insert(("from".to_string(), None), String::new());
+        assert_eq!(stanza.from(), Err(StanzaError::JidMalformed));
+    }
+
+    #[tokio::test]
+    async fn rewritten_addresses_are_serialized() {
+        let original = Stanza {
+            element: Element {
+                name: "message".to_string(),
+                namespace: None,
+                attributes: HashMap::new(),
+                children: vec![],
+            },
+        };
+        let romeo = "romeo@example.net".parse::<Jid>().unwrap();
+
+        let rewritten = original.with_to(&romeo);
+
+        assert_eq!(original.to(), Ok(None));
+        assert_eq!(rewritten.to(), Ok(Some(romeo)));
+        let mut writer = StreamWriter::new(Vec::new());
+        assert_eq!(
+            writer.serialize_xml_element(&rewritten.element),
+            r#"<message to="romeo@example.net"/>"#
+        );
+
+        let mut rewritten = rewritten;
+        rewritten.set_from(&"juliet@example.com".parse().unwrap());
+        assert_eq!(
+            rewritten.from().unwrap().map(|jid| jid.to_string()),
+            Some("juliet@example.com".to_string())
+        );
+    }
+}
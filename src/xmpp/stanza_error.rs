@@ -0,0 +1,276 @@
+use crate::xml::{namespaces, Element, Node};
+use crate::xmpp::error_text;
+
+// The conditions defined in RFC 6120, section 8.3.3.
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StanzaError {
+    #[error("the sender has sent a stanza that is malformed or cannot be processed")]
+    BadRequest,
+    #[error("access cannot be granted because an existing resource exists with the same name")]
+    Conflict,
+    #[error("the feature represented in the stanza is not implemented by the recipient")]
+    FeatureNotImplemented,
+    #[error("the requesting entity does not possess the necessary permissions")]
+    Forbidden,
+    #[error("the recipient or server can no longer be contacted at this address")]
+    Gone,
+    #[error("the server has experienced an internal error")]
+    InternalServerError,
+    #[error("the addressed item cannot be found")]
+    ItemNotFound,
+    #[error("the sending entity has provided an address that is malformed")]
+    JidMalformed,
+    #[error("the request does not meet criteria defined by the recipient")]
+    NotAcceptable,
+    #[error("the recipient does not allow any entity to perform the action")]
+    NotAllowed,
+    #[error("the sender needs to provide credentials before being allowed to perform the action")]
+    NotAuthorized,
+    #[error("the entity has violated a local service policy")]
+    PolicyViolation,
+    #[error("the intended recipient is temporarily unavailable")]
+    RecipientUnavailable,
+    #[error("the recipient is redirecting requests to another entity")]
+    Redirect,
+    #[error("the requesting entity is not authorized without registering first")]
+    RegistrationRequired,
+    #[error("a remote server or service does not exist or cannot be resolved")]
+    RemoteServerNotFound,
+    #[error("a remote server or service could not be contacted in time")]
+    RemoteServerTimeout,
+    #[error("the server or recipient is too busy to process the stanza")]
+    ResourceConstraint,
+    #[error("the feature requested is not supported by the intended recipient")]
+    ServiceUnavailable,
+    #[error("the requesting entity is not authorized without a subscription")]
+    SubscriptionRequired,
+    #[error("the error condition is not one of the defined conditions")]
+    UndefinedCondition,
+    #[error("the recipient understood the request but was not expecting it at this time")]
+    UnexpectedRequest,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorType {
+    Auth,
+    Cancel,
+    Modify,
+    Wait,
+}
+
+impl ErrorType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorType::Auth => "auth",
+            ErrorType::Cancel => "cancel",
+            ErrorType::Modify => "modify",
+            ErrorType::Wait => "wait",
+        }
+    }
+}
+
+impl StanzaError {
+    pub fn condition(&self) -> &'static str {
+        match self {
+            StanzaError::BadRequest => "bad-request",
+            StanzaError::Conflict => "conflict",
+            StanzaError::FeatureNotImplemented => "feature-not-implemented",
+            StanzaError::Forbidden => "forbidden",
+            StanzaError::Gone => "gone",
+            StanzaError::InternalServerError => "internal-server-error",
+            StanzaError::ItemNotFound => "item-not-found",
+            StanzaError::JidMalformed => "jid-malformed",
+            StanzaError::NotAcceptable => "not-acceptable",
+            StanzaError::NotAllowed => "not-allowed",
+            StanzaError::NotAuthorized => "not-authorized",
+            StanzaError::PolicyViolation => "policy-violation",
+            StanzaError::RecipientUnavailable => "recipient-unavailable",
+            StanzaError::Redirect => "redirect",
+            StanzaError::RegistrationRequired => "registration-required",
+            StanzaError::RemoteServerNotFound => "remote-server-not-found",
+            StanzaError::RemoteServerTimeout => "remote-server-timeout",
+            StanzaError::ResourceConstraint => "resource-constraint",
+            StanzaError::ServiceUnavailable => "service-unavailable",
+            StanzaError::SubscriptionRequired => "subscription-required",
+            StanzaError::UndefinedCondition => "undefined-condition",
+            StanzaError::UnexpectedRequest => "unexpected-request",
+        }
+    }
+
+    // The type RFC 6120 suggests for each condition, which can be overridden with the builder.
+    pub fn error_type(&self) -> ErrorType {
+        match self {
+            StanzaError::Forbidden
+            | StanzaError::NotAuthorized
+            | StanzaError::RegistrationRequired
+            | StanzaError::SubscriptionRequired => ErrorType::Auth,
+            StanzaError::Conflict
+            | StanzaError::FeatureNotImplemented
+            | StanzaError::Gone
+            | StanzaError::InternalServerError
+            | StanzaError::ItemNotFound
+            | StanzaError::NotAllowed
+            | StanzaError::RemoteServerNotFound
+            | StanzaError::ServiceUnavailable
+            | StanzaError::UndefinedCondition => ErrorType::Cancel,
+            StanzaError::BadRequest
+            | StanzaError::JidMalformed
+            | StanzaError::NotAcceptable
+            | StanzaError::PolicyViolation
+            | StanzaError::Redirect
+            | StanzaError::UnexpectedRequest => ErrorType::Modify,
+            StanzaError::RecipientUnavailable
+            | StanzaError::RemoteServerTimeout
+            | StanzaError::ResourceConstraint => ErrorType::Wait,
+        }
+    }
+
+    pub fn builder(self) -> StanzaErrorBuilder {
+        StanzaErrorBuilder {
+            error: self,
+            error_type: self.error_type(),
+            text: None,
+        }
+    }
+
+    pub fn to_element(&self, stanza_namespace: Option<&str>, language: Option<&str>) -> Element {
+        self.builder().to_element(stanza_namespace, language)
+    }
+}
+
+// Everything that goes into an `<error/>` element. Without an explicit text, the localized
+// description of the condition is used if there is one.
+#[derive(Debug, Clone)]
+pub struct StanzaErrorBuilder {
+    error: StanzaError,
+    error_type: ErrorType,
+    text: Option<(String, String)>, // language and text
+}
+
+impl StanzaErrorBuilder {
+    pub fn error_type(mut self, error_type: ErrorType) -> Self {
+        self.error_type = error_type;
+        self
+    }
+
+    pub fn text(mut self, language: &str, text: impl Into<String>) -> Self {
+        self.text = Some((language.to_string(), text.into()));
+        self
+    }
+
+    // The error element is qualified by the namespace of the stanza it is part of.
+    pub fn to_element(&self, stanza_namespace: Option<&str>, language: Option<&str>) -> Element {
+        let mut children = vec![Node::Element(Element {
+            name: self.error.condition().to_string(),
+            namespace: Some(namespaces::XMPP_STANZAS.to_string()),
+            attributes: vec![(
+                ("xmlns".to_string(), None),
+                namespaces::XMPP_STANZAS.to_string(),
+            )]
+            .into_iter()
+            .collect(),
+            children: vec![],
+        })];
+        let text = match &self.text {
+            Some((language, text)) => Some(error_text::custom_text_element(
+                text,
+                language,
+                namespaces::XMPP_STANZAS,
+            )),
+            None => {
+                error_text::text_element(self.error.condition(), language, namespaces::XMPP_STANZAS)
+            }
+        };
+        if let Some(text) = text {
+            children.push(Node::Element(text));
+        }
+
+        Element {
+            name: "error".to_string(),
+            namespace: stanza_namespace.map(str::to_string),
+            attributes: vec![(
+                ("type".to_string(), None),
+                self.error_type.as_str().to_string(),
+            )]
+            .into_iter()
+            .collect(),
+            children,
+        }
+    }
+}
+
+impl From<StanzaError> for StanzaErrorBuilder {
+    fn from(error: StanzaError) -> Self {
+        error.builder()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_type_defaults_to_the_suggested_one() {
+        let element = StanzaError::Forbidden.to_element(Some(namespaces::XMPP_CLIENT), None);
+        assert_eq!(element.get_attribute("type", None), Some("auth"));
+
+        let element = StanzaError::ResourceConstraint.to_element(None, None);
+        assert_eq!(element.get_attribute("type", None), Some("wait"));
+    }
+
+    #[test]
+    fn builder_overrides_type_and_text() {
+        let element = StanzaError::UnexpectedRequest
+            .builder()
+            .error_type(ErrorType::Wait)
+            .text("en", "Try again after the current request.")
+            .to_element(Some(namespaces::XMPP_CLIENT), Some("de"));
+
+        assert_eq!(element.get_attribute("type", None), Some("wait"));
+        assert!(element
+            .get_child("unexpected-request", Some(namespaces::XMPP_STANZAS))
+            .is_some());
+        let text = element
+            .get_child("text", Some(namespaces::XMPP_STANZAS))
+            .unwrap();
+        assert_eq!(
+            text.get_attribute("lang", Some(namespaces::XML)),
+            Some("en")
+        );
+        assert_eq!(text.get_text(), "Try again after the current request.");
+    }
+
+    #[test]
+    fn error_text_uses_negotiated_language() {
+        let element = StanzaError::BadRequest.to_element(Some(namespaces::XMPP_CLIENT), Some("de"));
+        let text = element
+            .get_child("text", Some(namespaces::XMPP_STANZAS))
+            .unwrap();
+
+        assert_eq!(
+            text.get_attribute("lang", Some(namespaces::XML)),
+            Some("de")
+        );
+        assert_eq!(
+            text.get_text(),
+            "Das Stanza ist fehlerhaft oder kann nicht verarbeitet werden."
+        );
+    }
+
+    #[test]
+    fn error_text_defaults_to_english() {
+        let element = StanzaError::BadRequest.to_element(Some(namespaces::XMPP_CLIENT), None);
+        let text = element
+            .get_child("text", Some(namespaces::XMPP_STANZAS))
+            .unwrap();
+
+        assert_eq!(
+            text.get_attribute("lang", Some(namespaces::XML)),
+            Some("en")
+        );
+        assert_eq!(
+            text.get_text(),
+            "The stanza is malformed or cannot be processed."
+        );
+    }
+}
@@ -1,23 +1,34 @@
+use std::fmt::{Display, Formatter};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::task::{ready, Context, Poll};
 
 use anyhow::Error;
 use base64::prelude::*;
 use futures::Future;
-use rand::{RngCore, SeedableRng};
-use tokio::io::{split, AsyncRead, AsyncWrite, ReadHalf, WriteHalf};
-use tokio_rustls::rustls::ServerConfig;
+use tokio::io::{split, AsyncRead, AsyncWrite, ReadBuf, ReadHalf, WriteHalf};
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use tokio_rustls::rustls::{CipherSuite, ProtocolVersion, ServerConfig};
 
 use crate::{
     settings::get_settings,
+    utils::random,
     xml::{
-        stream_parser::{rusty_xml::StreamParser as ConcreteStreamParser, StreamParser},
+        stream_parser::{AnyStreamParser, ParserConfig},
         stream_writer::StreamWriter,
     },
 };
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct StreamId(String);
 
+impl Display for StreamId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
 impl StreamId {
     pub fn new() -> Self {
         let id = Self::generate_id();
@@ -25,14 +36,60 @@ impl StreamId {
     }
 
     fn generate_id() -> String {
-        let mut rng = rand_chacha::ChaCha20Rng::from_entropy();
         let mut id_raw = [0u8; 16];
-        rng.fill_bytes(&mut id_raw);
+        random::fill_bytes(&mut id_raw);
 
         BASE64_STANDARD.encode(id_raw)
     }
 }
 
+impl Default for StreamId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Ids the peer assigned are taken as they are.
+impl From<String> for StreamId {
+    fn from(id: String) -> Self {
+        Self(id)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChannelBinding {
+    TlsServerEndPoint(Vec<u8>),
+    TlsExporter(Vec<u8>),
+}
+
+impl ChannelBinding {
+    pub fn cb_name(&self) -> &'static str {
+        match self {
+            ChannelBinding::TlsServerEndPoint(_) => "tls-server-end-point",
+            ChannelBinding::TlsExporter(_) => "tls-exporter",
+        }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        match self {
+            ChannelBinding::TlsServerEndPoint(data) => data,
+            ChannelBinding::TlsExporter(data) => data,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TlsInfo {
+    pub protocol_version: ProtocolVersion,
+    pub cipher_suite: CipherSuite,
+}
+
+impl Display for TlsInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} {:?}", self.protocol_version, self.cipher_suite)
+    }
+}
+
 pub trait Connection: AsyncRead + AsyncWrite + Unpin + Sized {
     type Upgrade: Future<Output = Result<Self, Error>> + Send + 'static;
 
@@ -40,6 +97,83 @@ pub trait Connection: AsyncRead + AsyncWrite + Unpin + Sized {
     fn is_starttls_allowed(&self) -> bool;
     fn is_secure(&self) -> bool;
     fn is_authenticated(&self) -> bool;
+    fn tls_info(&self) -> Option<TlsInfo>;
+    fn tls_exporter(&self) -> Option<Vec<u8>>;
+    fn tls_server_end_point(&self) -> Option<Vec<u8>>;
+    // The end-entity certificate the peer authenticated with, if any
+    fn peer_certificate(&self) -> Option<CertificateDer<'static>>;
+
+    fn channel_binding(&self) -> Option<ChannelBinding> {
+        // RFC 9266 recommends `tls-exporter` for TLS 1.3, where `tls-unique` is undefined
+        match self.tls_info()?.protocol_version {
+            ProtocolVersion::TLSv1_3 => self.tls_exporter().map(ChannelBinding::TlsExporter),
+            _ => self
+                .tls_server_end_point()
+                .map(ChannelBinding::TlsServerEndPoint),
+        }
+    }
+}
+
+// Counts every byte read from the peer, including whitespace the parsers discard.
+pub struct CountingReader<R> {
+    inner: R,
+    bytes_read: Arc<AtomicU64>,
+}
+
+impl<R> CountingReader<R> {
+    fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for CountingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let filled_before = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        let bytes_read = (buf.filled().len() - filled_before) as u64;
+        self.bytes_read.fetch_add(bytes_read, Ordering::Relaxed);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+// Counts every byte written to the peer, so the keepalive knows whether the peer heard from us
+// lately.
+pub struct CountingWriter<W> {
+    inner: W,
+    bytes_written: Arc<AtomicU64>,
+}
+
+impl<W> CountingWriter<W> {
+    fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for CountingWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let bytes_written = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        self.bytes_written
+            .fetch_add(bytes_written as u64, Ordering::Relaxed);
+
+        Poll::Ready(Ok(bytes_written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
 }
 
 pub struct XmppStream<C>
@@ -49,26 +183,51 @@ where
     starttls_allowed: bool,
     secure: bool,
     authenticated: bool,
-    reader: Option<ConcreteStreamParser<ReadHalf<C>>>,
-    writer: Option<StreamWriter<WriteHalf<C>>>,
+    channel_binding: Option<ChannelBinding>,
+    tls_info: Option<TlsInfo>,
+    peer_certificate: Option<CertificateDer<'static>>,
+    parser_config: ParserConfig,
+    bytes_read: Arc<AtomicU64>,
+    bytes_written: Arc<AtomicU64>,
+    reader: Option<AnyStreamParser<CountingReader<ReadHalf<C>>>>,
+    writer: Option<StreamWriter<CountingWriter<WriteHalf<C>>>>,
 }
 
 impl<C> XmppStream<C>
 where
     C: Connection,
 {
-    pub fn new(connection: C) -> Self {
+    pub fn new(connection: C, parser_config: ParserConfig) -> Self {
         let starttls_allowed = connection.is_starttls_allowed();
         let secure = connection.is_secure();
         let authenticated = connection.is_authenticated();
+        let channel_binding = connection.channel_binding();
+        let tls_info = connection.tls_info();
+        let peer_certificate = connection.peer_certificate();
+        let bytes_read = Arc::new(AtomicU64::new(0));
+        let bytes_written = Arc::new(AtomicU64::new(0));
         let (reader, writer) = split(connection);
-        let reader = Some(ConcreteStreamParser::new(reader));
+        let reader = CountingReader {
+            inner: reader,
+            bytes_read: bytes_read.clone(),
+        };
+        let writer = CountingWriter {
+            inner: writer,
+            bytes_written: bytes_written.clone(),
+        };
+        let reader = Some(AnyStreamParser::new(parser_config, reader));
         let writer = Some(StreamWriter::new(writer));
 
         Self {
             starttls_allowed,
             secure,
             authenticated,
+            channel_binding,
+            tls_info,
+            peer_certificate,
+            parser_config,
+            bytes_read,
+            bytes_written,
             reader,
             writer,
         }
@@ -77,7 +236,7 @@ where
     pub fn reset(&mut self) {
         let reader = self.reader.take().unwrap().into_inner();
         let writer = self.writer.take().unwrap().into_inner();
-        self.reader = Some(ConcreteStreamParser::new(reader));
+        self.reader = Some(AnyStreamParser::new(self.parser_config, reader));
         self.writer = Some(StreamWriter::new(writer));
     }
 
@@ -93,31 +252,152 @@ where
         self.authenticated
     }
 
-    pub fn reader(&mut self) -> &mut ConcreteStreamParser<ReadHalf<C>> {
+    pub fn channel_binding(&self) -> Option<&ChannelBinding> {
+        self.channel_binding.as_ref()
+    }
+
+    pub fn tls_info(&self) -> Option<&TlsInfo> {
+        self.tls_info.as_ref()
+    }
+
+    pub fn peer_certificate(&self) -> Option<&CertificateDer<'static>> {
+        self.peer_certificate.as_ref()
+    }
+
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written.load(Ordering::Relaxed)
+    }
+
+    pub fn reader(&mut self) -> &mut AnyStreamParser<CountingReader<ReadHalf<C>>> {
         self.reader.as_mut().unwrap()
     }
 
-    pub fn writer(&mut self) -> &mut StreamWriter<WriteHalf<C>> {
+    pub fn writer(&mut self) -> &mut StreamWriter<CountingWriter<WriteHalf<C>>> {
         self.writer.as_mut().unwrap()
     }
 
     pub async fn upgrade_to_tls(&mut self) -> Result<(), Error> {
-        let reader = self.reader.take().unwrap().into_inner();
-        let writer = self.writer.take().unwrap().into_inner();
+        self.upgrade_to_tls_with_config(get_settings().tls.server_config.config.clone())
+            .await
+    }
+
+    pub async fn upgrade_to_tls_with_config(
+        &mut self,
+        config: Arc<ServerConfig>,
+    ) -> Result<(), Error> {
+        let reader = self.reader.take().unwrap().into_inner().into_inner();
+        let writer = self.writer.take().unwrap().into_inner().into_inner();
         let connection = reader.unsplit(writer);
 
-        let connection = connection
-            .upgrade(get_settings().tls.server_config.clone())?
-            .await?;
+        let connection = connection.upgrade(config)?.await?;
 
         self.starttls_allowed = connection.is_starttls_allowed();
         self.secure = connection.is_secure();
         self.authenticated = connection.is_authenticated();
+        self.channel_binding = connection.channel_binding();
+        self.tls_info = connection.tls_info();
+        self.peer_certificate = connection.peer_certificate();
 
         let (reader, writer) = split(connection);
-        self.reader = Some(ConcreteStreamParser::new(reader));
+        let reader = CountingReader {
+            inner: reader,
+            bytes_read: self.bytes_read.clone(),
+        };
+        let writer = CountingWriter {
+            inner: writer,
+            bytes_written: self.bytes_written.clone(),
+        };
+        self.reader = Some(AnyStreamParser::new(self.parser_config, reader));
         self.writer = Some(StreamWriter::new(writer));
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::duplex;
+    use tokio_rustls::rustls::server::ResolvesServerCertUsingSni;
+
+    use crate::inbound::connection::fake::FakeConnection;
+    use crate::xml::stream_parser::{ElementLimits, ParserKind};
+
+    use super::*;
+
+    #[test]
+    fn seeded_stream_ids_are_reproducible() {
+        random::seed(42);
+        let first = StreamId::new();
+        random::seed(42);
+        let second = StreamId::new();
+        random::unseed();
+
+        assert_eq!(first, second);
+        assert_ne!(StreamId::new(), first);
+    }
+
+    fn tls_connection(version: ProtocolVersion) -> FakeConnection {
+        let (stream, _) = duplex(64);
+        let mut connection = FakeConnection::new(stream);
+        connection.secure = true;
+        connection.tls_info = Some(TlsInfo {
+            protocol_version: version,
+            cipher_suite: CipherSuite::TLS13_AES_128_GCM_SHA256,
+        });
+        connection.tls_exporter = Some(vec![1; 32]);
+        connection.tls_server_end_point = Some(vec![2; 32]);
+        connection
+    }
+
+    #[test]
+    fn tls_exporter_is_used_for_tls13() {
+        let connection = tls_connection(ProtocolVersion::TLSv1_3);
+        assert_eq!(
+            connection.channel_binding(),
+            Some(ChannelBinding::TlsExporter(vec![1; 32]))
+        );
+    }
+
+    #[test]
+    fn tls_server_end_point_is_used_for_tls12() {
+        let connection = tls_connection(ProtocolVersion::TLSv1_2);
+        assert_eq!(
+            connection.channel_binding(),
+            Some(ChannelBinding::TlsServerEndPoint(vec![2; 32]))
+        );
+    }
+
+    #[test]
+    fn plaintext_connection_has_no_channel_binding() {
+        let (stream, _) = duplex(64);
+        let connection = FakeConnection::new(stream);
+        assert_eq!(connection.channel_binding(), None);
+    }
+
+    #[tokio::test]
+    async fn tls_info_is_populated_after_upgrade() {
+        let (stream, _peer) = duplex(64);
+        let parser_config = ParserConfig {
+            kind: ParserKind::RustyXml,
+            limits: ElementLimits::default(),
+        };
+        let mut stream = XmppStream::new(FakeConnection::new(stream), parser_config);
+        assert_eq!(stream.tls_info(), None);
+
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(ResolvesServerCertUsingSni::new()));
+        stream
+            .upgrade_to_tls_with_config(Arc::new(config))
+            .await
+            .unwrap();
+
+        let tls_info = stream.tls_info().unwrap();
+        assert_eq!(tls_info.protocol_version, ProtocolVersion::TLSv1_3);
+        assert_eq!(tls_info.cipher_suite, CipherSuite::TLS13_AES_128_GCM_SHA256);
+    }
+}
@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+
+use crate::xml::{namespaces, Element, Node};
+use crate::xmpp::error_text;
+
+// The conditions defined in RFC 6120, section 4.9.3. `<see-other-host/>` is left out until
+// there is another host to redirect to.
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamError {
+    #[error("the entity has sent XML that cannot be processed")]
+    BadFormat,
+    #[error("the entity has sent a namespace prefix that is unsupported")]
+    BadNamespacePrefix,
+    #[error("a new stream has been initiated that conflicts with the existing stream")]
+    Conflict,
+    #[error("the peer has not generated any traffic over the stream for some period of time")]
+    ConnectionTimeout,
+    #[error("the `to` address is no longer serviced by the server")]
+    HostGone,
+    #[error("the `to` address is not serviced by the server")]
+    HostUnknown,
+    #[error("a stanza lacks a `to` or `from` address where one is required")]
+    ImproperAddressing,
+    #[error("the server has experienced an internal error")]
+    InternalServerError,
+    #[error("the `from` address does not match an authorized domain")]
+    InvalidFrom,
+    #[error("the stream or content namespace is not supported")]
+    InvalidNamespace,
+    #[error("the entity has sent invalid XML")]
+    InvalidXml,
+    #[error("the entity has attempted to send data before it has been authenticated")]
+    NotAuthorized,
+    #[error("the entity has sent data that violates the rules for well-formed XML")]
+    NotWellFormed,
+    #[error("the entity has violated a local service policy")]
+    PolicyViolation,
+    #[error("the server is unable to connect to a remote entity needed for the stream")]
+    RemoteConnectionFailed,
+    #[error("the stream needs to be reset due to changed features or security context")]
+    Reset,
+    #[error("the server lacks the resources necessary to service the stream")]
+    ResourceConstraint,
+    #[error("the entity has sent restricted XML features such as comments or DTDs")]
+    RestrictedXml,
+    #[error("the server is being shut down")]
+    SystemShutdown,
+    #[error("the error condition is not one of the defined conditions")]
+    UndefinedCondition,
+    #[error("the entity has encoded the stream in an unsupported encoding")]
+    UnsupportedEncoding,
+    #[error("a feature required by the receiving entity is not supported")]
+    UnsupportedFeature,
+    #[error("the entity has sent a first-level child that is not supported")]
+    UnsupportedStanzaType,
+    #[error("the stream version is not supported")]
+    UnsupportedVersion,
+}
+
+impl StreamError {
+    pub fn condition(&self) -> &'static str {
+        match self {
+            StreamError::BadFormat => "bad-format",
+            StreamError::BadNamespacePrefix => "bad-namespace-prefix",
+            StreamError::Conflict => "conflict",
+            StreamError::ConnectionTimeout => "connection-timeout",
+            StreamError::HostGone => "host-gone",
+            StreamError::HostUnknown => "host-unknown",
+            StreamError::ImproperAddressing => "improper-addressing",
+            StreamError::InternalServerError => "internal-server-error",
+            StreamError::InvalidFrom => "invalid-from",
+            StreamError::InvalidNamespace => "invalid-namespace",
+            StreamError::InvalidXml => "invalid-xml",
+            StreamError::NotAuthorized => "not-authorized",
+            StreamError::NotWellFormed => "not-well-formed",
+            StreamError::PolicyViolation => "policy-violation",
+            StreamError::RemoteConnectionFailed => "remote-connection-failed",
+            StreamError::Reset => "reset",
+            StreamError::ResourceConstraint => "resource-constraint",
+            StreamError::RestrictedXml => "restricted-xml",
+            StreamError::SystemShutdown => "system-shutdown",
+            StreamError::UndefinedCondition => "undefined-condition",
+            StreamError::UnsupportedEncoding => "unsupported-encoding",
+            StreamError::UnsupportedFeature => "unsupported-feature",
+            StreamError::UnsupportedStanzaType => "unsupported-stanza-type",
+            StreamError::UnsupportedVersion => "unsupported-version",
+        }
+    }
+
+    pub fn to_element(&self, language: Option<&str>) -> Element {
+        let mut children = vec![Node::Element(Element {
+            name: self.condition().to_string(),
+            namespace: Some(namespaces::XMPP_STREAM_ERRORS.to_string()),
+            attributes: vec![(
+                ("xmlns".to_string(), None),
+                namespaces::XMPP_STREAM_ERRORS.to_string(),
+            )]
+            .into_iter()
+            .collect(),
+            children: vec![],
+        })];
+        if let Some(text) =
+            error_text::text_element(self.condition(), language, namespaces::XMPP_STREAM_ERRORS)
+        {
+            children.push(Node::Element(text));
+        }
+
+        Element {
+            name: "error".to_string(),
+            namespace: Some(namespaces::XMPP_STREAMS.to_string()),
+            attributes: HashMap::new(),
+            children,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(element: &Element) -> (Option<&str>, String) {
+        let text = element
+            .get_child("text", Some(namespaces::XMPP_STREAM_ERRORS))
+            .unwrap();
+        (
+            text.get_attribute("lang", Some(namespaces::XML)),
+            text.get_text(),
+        )
+    }
+
+    const ALL: [StreamError; 24] = [
+        StreamError::BadFormat,
+        StreamError::BadNamespacePrefix,
+        StreamError::Conflict,
+        StreamError::ConnectionTimeout,
+        StreamError::HostGone,
+        StreamError::HostUnknown,
+        StreamError::ImproperAddressing,
+        StreamError::InternalServerError,
+        StreamError::InvalidFrom,
+        StreamError::InvalidNamespace,
+        StreamError::InvalidXml,
+        StreamError::NotAuthorized,
+        StreamError::NotWellFormed,
+        StreamError::PolicyViolation,
+        StreamError::RemoteConnectionFailed,
+        StreamError::Reset,
+        StreamError::ResourceConstraint,
+        StreamError::RestrictedXml,
+        StreamError::SystemShutdown,
+        StreamError::UndefinedCondition,
+        StreamError::UnsupportedEncoding,
+        StreamError::UnsupportedFeature,
+        StreamError::UnsupportedStanzaType,
+        StreamError::UnsupportedVersion,
+    ];
+
+    #[test]
+    fn every_condition_is_serialized_in_the_streams_namespace() {
+        for error in ALL {
+            let element = error.to_element(None);
+            assert_eq!(element.name, "error");
+            assert_eq!(element.namespace.as_deref(), Some(namespaces::XMPP_STREAMS));
+            assert!(element
+                .get_child(error.condition(), Some(namespaces::XMPP_STREAM_ERRORS))
+                .is_some());
+
+            let xml = element.to_string();
+            let condition = format!(
+                "<{} xmlns=\"{}\"/>",
+                error.condition(),
+                namespaces::XMPP_STREAM_ERRORS
+            );
+            assert!(xml.contains(&condition), "{xml}");
+        }
+    }
+
+    #[test]
+    fn text_is_only_included_when_there_is_one() {
+        let element = StreamError::HostUnknown.to_element(None);
+        assert_eq!(
+            text(&element),
+            (
+                Some("en"),
+                "The requested host is not served here.".to_string()
+            )
+        );
+
+        let element = StreamError::UndefinedCondition.to_element(None);
+        assert!(element
+            .get_child("text", Some(namespaces::XMPP_STREAM_ERRORS))
+            .is_none());
+    }
+
+    #[test]
+    fn error_text_uses_negotiated_language() {
+        let element = StreamError::PolicyViolation.to_element(Some("de"));
+        assert_eq!(
+            text(&element),
+            (
+                Some("de"),
+                "Eine Richtlinie des Dienstes wurde verletzt.".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn error_text_defaults_to_english() {
+        let element = StreamError::NotWellFormed.to_element(None);
+        assert_eq!(
+            text(&element),
+            (Some("en"), "The XML sent is not well-formed.".to_string())
+        );
+    }
+}
@@ -0,0 +1,159 @@
+use anyhow::{bail, Error};
+
+use crate::xml::{namespaces, Element};
+
+// What a receiving entity offers in its `<stream:features>`, as seen by the initiating entity.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct OfferedFeatures {
+    pub starttls: Option<StartTlsOffer>,
+    pub sasl_mechanisms: Vec<String>,
+    pub bind: bool,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct StartTlsOffer {
+    pub required: bool,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum NegotiationStep<'a> {
+    StartTls,
+    Authenticate(&'a [String]),
+    Bind,
+    Done,
+}
+
+impl OfferedFeatures {
+    // The receiving entity only offers what is currently negotiable, so STARTTLS takes
+    // precedence over SASL, and SASL over resource binding (RFC 6120, section 4.3.2).
+    pub fn next_step(&self) -> NegotiationStep {
+        if self.starttls.is_some() {
+            NegotiationStep::StartTls
+        } else if !self.sasl_mechanisms.is_empty() {
+            NegotiationStep::Authenticate(&self.sasl_mechanisms)
+        } else if self.bind {
+            NegotiationStep::Bind
+        } else {
+            NegotiationStep::Done
+        }
+    }
+}
+
+impl TryFrom<&Element> for OfferedFeatures {
+    type Error = Error;
+
+    fn try_from(element: &Element) -> Result<Self, Self::Error> {
+        if element.name != "features"
+            || element.namespace.as_deref() != Some(namespaces::XMPP_STREAMS)
+        {
+            bail!("expected stream features");
+        }
+
+        let starttls = element
+            .get_child("starttls", Some(namespaces::XMPP_STARTTLS))
+            .map(|starttls| StartTlsOffer {
+                required: starttls
+                    .get_child("required", Some(namespaces::XMPP_STARTTLS))
+                    .is_some(),
+            });
+
+        let sasl_mechanisms = element
+            .get_child("mechanisms", Some(namespaces::XMPP_SASL))
+            .map(|mechanisms| {
+                mechanisms
+                    .find_children("mechanism", Some(namespaces::XMPP_SASL))
+                    .map(|mechanism| mechanism.get_text().trim().to_string())
+                    .filter(|mechanism| !mechanism.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let bind = element
+            .get_child("bind", Some(namespaces::XMPP_BIND))
+            .is_some();
+
+        Ok(Self {
+            starttls,
+            sasl_mechanisms,
+            bind,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(xml: &str) -> Result<OfferedFeatures, Error> {
+        OfferedFeatures::try_from(&Element::parse(xml)?)
+    }
+
+    #[test]
+    fn parses_starttls_offer() {
+        let features = parse(
+            "<stream:features xmlns:stream='http://etherx.jabber.org/streams'>\
+                <starttls xmlns='urn:ietf:params:xml:ns:xmpp-tls'><required/></starttls>\
+                <mechanisms xmlns='urn:ietf:params:xml:ns:xmpp-sasl'>\
+                    <mechanism>EXTERNAL</mechanism>\
+                </mechanisms>\
+            </stream:features>",
+        )
+        .unwrap();
+
+        assert_eq!(features.starttls, Some(StartTlsOffer { required: true }));
+        assert_eq!(features.sasl_mechanisms, vec!["EXTERNAL"]);
+        assert!(!features.bind);
+        assert_eq!(features.next_step(), NegotiationStep::StartTls);
+    }
+
+    #[test]
+    fn parses_sasl_mechanisms_in_order() {
+        let features = parse(
+            "<stream:features xmlns:stream='http://etherx.jabber.org/streams'>\
+                <mechanisms xmlns='urn:ietf:params:xml:ns:xmpp-sasl'>\
+                    <mechanism>SCRAM-SHA-1-PLUS</mechanism>\
+                    <mechanism>SCRAM-SHA-1</mechanism>\
+                    <mechanism>PLAIN</mechanism>\
+                </mechanisms>\
+                <sasl-channel-binding xmlns='urn:xmpp:sasl-cb:0'>\
+                    <channel-binding type='tls-exporter'/>\
+                </sasl-channel-binding>\
+            </stream:features>",
+        )
+        .unwrap();
+
+        assert_eq!(features.starttls, None);
+        assert_eq!(
+            features.sasl_mechanisms,
+            vec!["SCRAM-SHA-1-PLUS", "SCRAM-SHA-1", "PLAIN"]
+        );
+        assert_eq!(
+            features.next_step(),
+            NegotiationStep::Authenticate(&features.sasl_mechanisms)
+        );
+    }
+
+    #[test]
+    fn parses_bind_offer() {
+        let features = parse(
+            "<stream:features xmlns:stream='http://etherx.jabber.org/streams'>\
+                <bind xmlns='urn:ietf:params:xml:ns:xmpp-bind'/>\
+            </stream:features>",
+        )
+        .unwrap();
+
+        assert!(features.bind);
+        assert_eq!(features.next_step(), NegotiationStep::Bind);
+        assert_eq!(
+            parse("<stream:features xmlns:stream='http://etherx.jabber.org/streams'/>")
+                .unwrap()
+                .next_step(),
+            NegotiationStep::Done
+        );
+    }
+
+    #[test]
+    fn rejects_other_elements() {
+        assert!(parse("<features xmlns='jabber:client'/>").is_err());
+    }
+}
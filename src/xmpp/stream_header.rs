@@ -1,7 +1,7 @@
 use super::jid::Jid;
 use super::stream::StreamId;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct LanguageTag(pub String);
 
 #[derive(Debug)]
@@ -10,4 +10,8 @@ pub struct StreamHeader {
     pub to: Option<Jid>,
     pub id: Option<StreamId>,
     pub language: Option<LanguageTag>,
+    // Only known for inbound headers, which are validated against what the writer sends
+    pub stream_namespace: Option<String>,
+    pub content_namespace: Option<String>,
+    pub version: Option<String>,
 }